@@ -0,0 +1,36 @@
+//! Prints an admin API token signed with `jwt_private_key_path`.
+//!
+//! Usage: `admin_token <subject> [admin|read_only] [ttl_secs]` (defaults: admin, 3600)
+
+use admin_auth::{issue_token, Role};
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "usage: admin_token <subject> [admin|read_only] [ttl_secs]";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(subject) = args.first() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+    let Some(role) = Role::parse(args.get(1).map_or("admin", String::as_str)) else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+    let Ok(ttl) = args.get(2).map_or(Ok(3600), |ttl| ttl.parse::<u64>()) else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+
+    match issue_token(subject, role, Duration::from_secs(ttl)) {
+        Ok(token) => {
+            println!("{}", token);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Failed to issue admin token: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
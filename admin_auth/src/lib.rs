@@ -0,0 +1,325 @@
+//! Authentication, role checks and audit logging for the local admin endpoints of the bridge
+//! (port 3030) and the collector (port 3033).
+//!
+//! Callers send `Authorization: Bearer <token>`, where the token is either one of the static
+//! tokens from `ADMIN_TOKEN` / `ADMIN_READ_TOKEN` or an RS256 JWT signed with
+//! `jwt_private_key_path` (see the `admin_token` binary). With neither configured every call is refused.
+
+use chrono::Local;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use models_database::db::record_admin_audit;
+use models_database::with_connection;
+use models_database::models::AdminAudit;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use shared_config::CONFIG;
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Audience of admin tokens, so other JWTs signed with the same key are not accepted
+const AUDIENCE: &str = "agent-admin";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    ReadOnly,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::ReadOnly => "read_only",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Role> {
+        match value {
+            "read_only" | "read" => Some(Role::ReadOnly),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    /// Admin may do everything read-only may
+    pub fn allows(self, required: Role) -> bool {
+        self == Role::Admin || required == Role::ReadOnly
+    }
+}
+
+/// Who made an authenticated call
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub subject: String,
+    pub role: Role,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Missing,
+    Invalid(String),
+    /// Authenticated, but the role is not enough for the call
+    Forbidden(Principal),
+}
+
+impl AuthError {
+    pub fn status(&self) -> u16 {
+        match self {
+            AuthError::Missing | AuthError::Invalid(_) => 401,
+            AuthError::Forbidden(_) => 403,
+        }
+    }
+
+    fn outcome(&self) -> &'static str {
+        match self {
+            AuthError::Missing => "missing_token",
+            AuthError::Invalid(_) => "invalid_token",
+            AuthError::Forbidden(_) => "forbidden",
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "missing bearer token"),
+            AuthError::Invalid(reason) => write!(f, "invalid token: {}", reason),
+            AuthError::Forbidden(principal) => write!(f, "role {} may not do this", principal.role.as_str()),
+        }
+    }
+}
+
+impl Error for AuthError {}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    role: Role,
+    aud: String,
+    iat: u64,
+    exp: u64,
+}
+
+static DECODING_KEY: Lazy<Option<DecodingKey>> = Lazy::new(|| {
+    let pem = match std::fs::read(&CONFIG.jwt_public_key_path) {
+        Ok(pem) => pem,
+        Err(e) => {
+            warn!("Admin JWTs disabled, cannot read {}: {}", CONFIG.jwt_public_key_path, e);
+            return None;
+        }
+    };
+    match DecodingKey::from_rsa_pem(&pem) {
+        Ok(key) => Some(key),
+        Err(e) => {
+            warn!("Admin JWTs disabled, {} is not an RSA public key: {}", CONFIG.jwt_public_key_path, e);
+            None
+        }
+    }
+});
+
+/// Whether any way of authenticating is configured; logs a warning when admin calls will all be refused
+pub fn check_configuration() -> bool {
+    let configured = CONFIG.admin_token.is_some() || CONFIG.admin_read_token.is_some() || DECODING_KEY.is_some();
+    if !configured {
+        warn!("No ADMIN_TOKEN, ADMIN_READ_TOKEN or JWT public key configured; admin endpoints will refuse every call");
+    }
+    configured
+}
+
+/// Checks the `Authorization` header value against the role a call needs
+pub fn authorize(authorization: Option<&str>, required: Role) -> Result<Principal, AuthError> {
+    let token = authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .ok_or(AuthError::Missing)?;
+    let principal = authenticate(token)?;
+    if principal.role.allows(required) {
+        Ok(principal)
+    } else {
+        Err(AuthError::Forbidden(principal))
+    }
+}
+
+fn authenticate(token: &str) -> Result<Principal, AuthError> {
+    if CONFIG.admin_token.as_deref().is_some_and(|expected| constant_time_eq(expected, token)) {
+        return Ok(Principal { subject: "admin-token".to_string(), role: Role::Admin });
+    }
+    if CONFIG.admin_read_token.as_deref().is_some_and(|expected| constant_time_eq(expected, token)) {
+        return Ok(Principal { subject: "read-token".to_string(), role: Role::ReadOnly });
+    }
+
+    let key = DECODING_KEY.as_ref().ok_or_else(|| AuthError::Invalid("unknown token".to_string()))?;
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_audience(&[AUDIENCE]);
+    // Tokens are minted on this machine, so there is no clock skew to allow for
+    validation.leeway = 0;
+    let data = decode::<Claims>(token, key, &validation).map_err(|e| AuthError::Invalid(e.to_string()))?;
+    Ok(Principal { subject: data.claims.sub, role: data.claims.role })
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Signs an admin token with `jwt_private_key_path`
+pub fn issue_token(subject: &str, role: Role, ttl: Duration) -> Result<String, BoxError> {
+    let path = &CONFIG.jwt_private_key_path;
+    let pem = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let key = EncodingKey::from_rsa_pem(&pem).map_err(|e| format!("{} is not an RSA private key: {}", path, e))?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let claims = Claims {
+        sub: subject.to_string(),
+        role,
+        aud: AUDIENCE.to_string(),
+        iat: now,
+        exp: now + ttl.as_secs(),
+    };
+    Ok(encode(&Header::new(Algorithm::RS256), &claims, &key)?)
+}
+
+/// Records an admin call and whether it was let through
+pub async fn audit(service: &str, method: &str, path: &str, result: &Result<Principal, AuthError>) {
+    let (principal, outcome) = match result {
+        Ok(principal) => (Some(principal), "allowed"),
+        Err(AuthError::Forbidden(principal)) => (Some(principal), "forbidden"),
+        Err(e) => (None, e.outcome()),
+    };
+    let subject = principal.map(|p| p.subject.clone());
+    match result {
+        Ok(_) => info!("Admin call {} {} on {} by {}", method, path, service, subject.as_deref().unwrap_or("-")),
+        Err(e) => warn!("Admin call {} {} on {} refused: {}", method, path, service, e),
+    }
+
+    let entry = AdminAudit {
+        id: None,
+        at: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        service: service.to_string(),
+        method: method.to_string(),
+        path: path.to_string(),
+        subject,
+        role: principal.map(|p| p.role.as_str().to_string()),
+        outcome: outcome.to_string(),
+    };
+    if let Err(e) = with_connection(move |conn| Ok(record_admin_audit(conn, &entry)?)).await {
+        error!("Failed to write admin audit entry: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Once;
+
+    const ADMIN_TOKEN: &str = "test-admin-token";
+    const READ_TOKEN: &str = "test-read-token";
+
+    /// An RSA key pair made with openssl for these tests only
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/jwt/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    /// Points `CONFIG` at the test tokens and keys; it is read once, so every test calls this first
+    fn configure() {
+        static CONFIGURE: Once = Once::new();
+        CONFIGURE.call_once(|| {
+            // SAFETY: tests only read the environment through CONFIG, which waits for this to finish
+            unsafe {
+                std::env::set_var("ADMIN_TOKEN", ADMIN_TOKEN);
+                std::env::set_var("ADMIN_READ_TOKEN", READ_TOKEN);
+                std::env::set_var("JWT_PRIVATE_KEY_PATH", fixture("private.pem"));
+                std::env::set_var("JWT_PUBLIC_KEY_PATH", fixture("public.pem"));
+            }
+        });
+    }
+
+    fn bearer(token: &str) -> String {
+        format!("Bearer {}", token)
+    }
+
+    /// A token signed with the test key but with claims `issue_token` would never write
+    fn forged(aud: &str, exp_offset: i64) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let claims = Claims {
+            sub: "someone".to_string(),
+            role: Role::Admin,
+            aud: aud.to_string(),
+            iat: now,
+            exp: now.saturating_add_signed(exp_offset),
+        };
+        let key = EncodingKey::from_rsa_pem(&std::fs::read(fixture("private.pem")).unwrap()).unwrap();
+        encode(&Header::new(Algorithm::RS256), &claims, &key).unwrap()
+    }
+
+    #[test]
+    fn read_only_may_not_reach_admin_calls() {
+        assert!(Role::Admin.allows(Role::Admin));
+        assert!(Role::Admin.allows(Role::ReadOnly));
+        assert!(Role::ReadOnly.allows(Role::ReadOnly));
+        assert!(!Role::ReadOnly.allows(Role::Admin));
+    }
+
+    #[test]
+    fn tokens_must_come_as_bearer() {
+        configure();
+        for header in [None, Some(""), Some(ADMIN_TOKEN), Some("Basic test-admin-token"), Some("Bearer "), Some("bearer test-admin-token")] {
+            assert!(matches!(authorize(header, Role::ReadOnly), Err(AuthError::Missing)), "{:?}", header);
+        }
+        let err = authorize(Some(&bearer("nonsense")), Role::ReadOnly).unwrap_err();
+        assert_eq!((err.status(), err.outcome()), (401, "invalid_token"));
+    }
+
+    #[test]
+    fn static_tokens_carry_their_role() {
+        configure();
+        let admin = authorize(Some(&bearer(ADMIN_TOKEN)), Role::Admin).unwrap();
+        assert_eq!((admin.subject.as_str(), admin.role), ("admin-token", Role::Admin));
+
+        let reader = authorize(Some(&bearer(READ_TOKEN)), Role::ReadOnly).unwrap();
+        assert_eq!((reader.subject.as_str(), reader.role), ("read-token", Role::ReadOnly));
+        let err = authorize(Some(&bearer(READ_TOKEN)), Role::Admin).unwrap_err();
+        assert!(matches!(err, AuthError::Forbidden(ref principal) if principal.role == Role::ReadOnly));
+        assert_eq!(err.status(), 403);
+    }
+
+    #[test]
+    fn issued_jwts_are_accepted_for_their_role() {
+        configure();
+        let token = issue_token("alice", Role::ReadOnly, Duration::from_secs(60)).unwrap();
+        let principal = authorize(Some(&bearer(&token)), Role::ReadOnly).unwrap();
+        assert_eq!((principal.subject.as_str(), principal.role), ("alice", Role::ReadOnly));
+        assert!(matches!(authorize(Some(&bearer(&token)), Role::Admin), Err(AuthError::Forbidden(_))));
+
+        let token = issue_token("root", Role::Admin, Duration::from_secs(60)).unwrap();
+        assert_eq!(authorize(Some(&bearer(&token)), Role::Admin).unwrap().role, Role::Admin);
+    }
+
+    #[test]
+    fn expired_or_foreign_jwts_are_refused() {
+        configure();
+        let expired = forged(AUDIENCE, -60);
+        assert!(matches!(authorize(Some(&bearer(&expired)), Role::ReadOnly), Err(AuthError::Invalid(reason)) if reason.contains("Expired")));
+
+        let foreign = forged("another-service", 60);
+        assert!(matches!(authorize(Some(&bearer(&foreign)), Role::ReadOnly), Err(AuthError::Invalid(reason)) if reason.contains("Audience")));
+
+        // Tampering with a valid token breaks its signature
+        let token = issue_token("alice", Role::ReadOnly, Duration::from_secs(60)).unwrap();
+        let tampered = format!("{}x", token);
+        assert!(matches!(authorize(Some(&bearer(&tampered)), Role::ReadOnly), Err(AuthError::Invalid(_))));
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_strings() {
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(constant_time_eq("", ""));
+        assert!(!constant_time_eq("secret", "secreT"));
+        assert!(!constant_time_eq("secret", "secret2"));
+        assert!(!constant_time_eq("secret", ""));
+    }
+}
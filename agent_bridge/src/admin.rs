@@ -0,0 +1,61 @@
+use admin_auth::{audit, authorize, AuthError, Role};
+use models_database::db::recent_admin_audit;
+use models_database::with_connection;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::error;
+use warp::filters::path::FullPath;
+use warp::http::{Method, StatusCode};
+use warp::{Filter, Rejection, Reply};
+
+const SERVICE: &str = "bridge";
+const DEFAULT_AUDIT_LIMIT: i64 = 100;
+
+#[derive(Debug)]
+struct Denied(AuthError);
+
+impl warp::reject::Reject for Denied {}
+
+/// Lets a call through only with a token whose role covers `role`; every decision is audited
+pub fn require(role: Role) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |method: Method, path: FullPath, authorization: Option<String>| async move {
+            let result = authorize(authorization.as_deref(), role);
+            audit(SERVICE, method.as_str(), path.as_str(), &result).await;
+            result.map(|_| ()).map_err(|e| warp::reject::custom(Denied(e)))
+        })
+        .untuple_one()
+}
+
+/// Turns a refused admin call into a 401 or 403 with a JSON error
+pub async fn handle_rejection(rejection: Rejection) -> Result<warp::reply::Response, Rejection> {
+    let Some(Denied(e)) = rejection.find::<Denied>() else {
+        return Err(rejection);
+    };
+    let status = StatusCode::from_u16(e.status()).unwrap_or(StatusCode::UNAUTHORIZED);
+    let reply = warp::reply::with_status(warp::reply::json(&json!({ "error": e.to_string() })), status);
+    if status == StatusCode::UNAUTHORIZED {
+        Ok(warp::reply::with_header(reply, "WWW-Authenticate", "Bearer").into_response())
+    } else {
+        Ok(reply.into_response())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    limit: Option<i64>,
+}
+
+// Most recent admin calls on the bridge and the collector
+pub async fn get_audit_handler(query: AuditQuery) -> Result<impl Reply, Rejection> {
+    let limit = query.limit.unwrap_or(DEFAULT_AUDIT_LIMIT).clamp(1, 1000);
+    match with_connection(move |conn| Ok(recent_admin_audit(conn, limit)?)).await {
+        Ok(entries) => Ok(warp::reply::json(&entries)),
+        Err(e) => {
+            error!("Failed to read admin audit log: {}", e);
+            Err(warp::reject())
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! Retry delays shared by the handler supervisor, the upstream outbox and the WebSocket session
+
+use rand::Rng;
+use std::time::Duration;
+
+/// Delay before the next attempt after `failures` consecutive failures: `initial` after the first,
+/// doubling with each one after it up to `max`, plus up to 50% jitter so retries do not arrive in lockstep
+pub fn delay(failures: u32, initial: Duration, max: Duration) -> Duration {
+    let exponent = failures.saturating_sub(1).min(16);
+    let base = initial.saturating_mul(1 << exponent).min(max);
+    let jitter = rand::thread_rng().gen_range(0..=base.as_millis() as u64 / 2);
+    base + Duration::from_millis(jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INITIAL: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(60);
+
+    fn within_jitter(delay: Duration, base: Duration) -> bool {
+        delay >= base && delay <= base + base / 2
+    }
+
+    #[test]
+    fn doubles_from_the_initial_delay_up_to_the_maximum() {
+        for (failures, base) in [(0, 1), (1, 1), (2, 2), (3, 4), (6, 32), (7, 60), (1000, 60)] {
+            let delay = delay(failures, INITIAL, MAX);
+            assert!(within_jitter(delay, Duration::from_secs(base)), "{} failures gave {:?}", failures, delay);
+        }
+    }
+}
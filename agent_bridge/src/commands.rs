@@ -0,0 +1,431 @@
+//! Commands from the central server, found in its answers to monitoring batches or pushed over the
+//! WebSocket session. Each one is parsed into a typed `Command`, checked against the
+//! `SERVER_COMMANDS` allow-list and carried out; the outcome goes back to the server as a
+//! `COMMAND_RESULT` event through the upstream outbox.
+
+use chrono::Local;
+use log_store::LogQuery;
+use messages::{
+    subjects, CollectionPolicy, ControlAction, ControlCommand, ControlReply, Envelope, LogsReply, LogsRequest, ScanAction,
+    ScanRequest, ScanResult,
+};
+use models_database::db::delete_initial_data;
+use models_database::with_connection;
+use nats::publisher::NatsPublisher;
+use nats::request::RequestOptions;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use shared_config::CONFIG;
+use std::path::Path;
+use tracing::{error, info, warn};
+
+use crate::outbox::{self, UpstreamCall};
+
+/// Inventory tables the server may delete entries from
+const INVENTORY_TABLES: &[&str] = &["partition", "storage", "nic", "port"];
+const MAX_DELETE_UUIDS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogSource {
+    Bridge,
+    Collector,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    /// `deleted_<table>`: entries to remove from the local inventory
+    DeleteInventory { table: String, uuids: Vec<String> },
+    /// `disk`, `partition` or `nic` (or `rescan` with a `component`): the collector rescans that part
+    /// of the inventory and the result is uploaded
+    Rescan(ScanRequest),
+    /// `policy`: forwarded to the collector, which acknowledges it separately
+    UpdatePolicy(CollectionPolicy),
+    /// `restart_collector`: the collector's monitoring loop starts over
+    RestartCollector,
+    /// `fetch_logs`: log entries, with the parameters of the log query API in `query`
+    FetchLogs { source: LogSource, query: Map<String, Value> },
+    Ping,
+}
+
+impl Command {
+    /// Name used by the allow-list and in results
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::DeleteInventory { .. } => "delete_inventory",
+            Command::Rescan(_) => "rescan",
+            Command::UpdatePolicy(_) => "update_policy",
+            Command::RestartCollector => "restart_collector",
+            Command::FetchLogs { .. } => "fetch_logs",
+            Command::Ping => "ping",
+        }
+    }
+
+    /// Parses and validates one command object
+    pub fn parse(value: &Value) -> Result<Command, String> {
+        let action = value.get("action").and_then(Value::as_str).ok_or("command has no action")?;
+
+        if let Some(table) = action.strip_prefix("deleted_") {
+            let table = table.trim_end_matches('s');
+            if !INVENTORY_TABLES.contains(&table) {
+                return Err(format!("cannot delete from unknown inventory table '{}'", table));
+            }
+            let uuids = match value.get("uuid") {
+                Some(Value::String(uuid)) => vec![uuid.clone()],
+                Some(Value::Array(uuids)) => uuids
+                    .iter()
+                    .map(|uuid| uuid.as_str().map(str::to_string).ok_or("uuid list holds a non-string"))
+                    .collect::<Result<Vec<_>, _>>()?,
+                _ => return Err("delete command has no uuid".to_string()),
+            };
+            if uuids.is_empty() || uuids.len() > MAX_DELETE_UUIDS {
+                return Err(format!("delete command needs 1 to {} uuids", MAX_DELETE_UUIDS));
+            }
+            if let Some(uuid) = uuids.iter().find(|uuid| !is_valid_uuid(uuid)) {
+                return Err(format!("invalid uuid '{}'", uuid));
+            }
+            return Ok(Command::DeleteInventory { table: table.to_string(), uuids });
+        }
+
+        match action {
+            "disk" | "partition" | "nic" | "rescan" => {
+                let component = if action == "rescan" {
+                    value.get("component").and_then(Value::as_str).ok_or("rescan command has no component")?
+                } else {
+                    action
+                };
+                let component = serde_json::from_value::<ScanAction>(json!(component))
+                    .map_err(|_| format!("cannot rescan unknown component '{}'", component))?;
+                let uuid = value.get("uuid").and_then(Value::as_str).unwrap_or_default();
+                if !uuid.is_empty() && !is_valid_uuid(uuid) {
+                    return Err(format!("invalid uuid '{}'", uuid));
+                }
+                Ok(Command::Rescan(ScanRequest { action: component, uuid: uuid.to_string() }))
+            }
+            "policy" => {
+                let policy = value.get("policy").unwrap_or(value);
+                let policy = serde_json::from_value::<CollectionPolicy>(policy.clone())
+                    .map_err(|e| format!("invalid collection policy: {}", e))?;
+                policy.validate()?;
+                Ok(Command::UpdatePolicy(policy))
+            }
+            "restart_collector" => Ok(Command::RestartCollector),
+            "fetch_logs" => {
+                let source = match value.get("source").and_then(Value::as_str).unwrap_or("bridge") {
+                    "bridge" => LogSource::Bridge,
+                    "collector" => LogSource::Collector,
+                    other => return Err(format!("unknown log source '{}'", other)),
+                };
+                let query = match value.get("query") {
+                    Some(Value::Object(query)) => query.clone(),
+                    None => Map::new(),
+                    Some(_) => return Err("log query must be an object".to_string()),
+                };
+                // Checked here so a bad query is rejected rather than failing later
+                serde_json::from_value::<LogQuery>(Value::Object(query.clone()))
+                    .map_err(|e| format!("invalid log query: {}", e))?
+                    .filter()
+                    .map_err(|e| format!("invalid log query: {}", e))?;
+                Ok(Command::FetchLogs { source, query })
+            }
+            "ping" => Ok(Command::Ping),
+            other => Err(format!("unknown command '{}'", other)),
+        }
+    }
+}
+
+/// Inventory uuids end up in database queries and NATS payloads, so only plain ids are accepted
+fn is_valid_uuid(uuid: &str) -> bool {
+    !uuid.is_empty() && uuid.len() <= 64 && uuid.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn is_allowed(name: &str) -> bool {
+    CONFIG.server_commands.iter().any(|allowed| allowed == name)
+}
+
+enum Outcome {
+    Done(Value),
+    /// Not carried out: unknown, invalid or not allowed
+    Rejected(String),
+    Failed(String),
+}
+
+#[derive(Debug, Serialize)]
+struct CommandResult {
+    event_type: &'static str,
+    agent_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_id: Option<String>,
+    command: String,
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    at: String,
+}
+
+/// Carries out the commands in a server answer or push: one object or an array of them. Answers
+/// without an `action`, such as plain acknowledgements, carry no command.
+pub async fn dispatch(publisher: &NatsPublisher, agent_id: &str, response: &str) {
+    let Ok(value) = serde_json::from_str::<Value>(response) else {
+        return;
+    };
+    let commands = match value {
+        Value::Array(commands) => commands,
+        command => vec![command],
+    };
+    for command in commands.into_iter().filter(|command| command.get("action").is_some()) {
+        // Scans and collector requests can take a while, so they must not hold up the next batch
+        tokio::spawn(run(publisher.clone(), agent_id.to_string(), command));
+    }
+}
+
+async fn run(publisher: NatsPublisher, agent_id: String, value: Value) {
+    let command_id = ["command_id", "id"].iter().find_map(|key| match value.get(*key) {
+        Some(Value::String(id)) => Some(id.clone()),
+        Some(Value::Number(id)) => Some(id.to_string()),
+        _ => None,
+    });
+    let action = value.get("action").and_then(Value::as_str).unwrap_or_default().to_string();
+
+    let (name, outcome) = match Command::parse(&value) {
+        Err(reason) => (action, Outcome::Rejected(reason)),
+        Ok(command) if !is_allowed(command.name()) => {
+            (command.name().to_string(), Outcome::Rejected(format!("command '{}' is not allowed on this agent", command.name())))
+        }
+        Ok(_) if !subjects::is_valid_agent_id(&agent_id) => (action, Outcome::Rejected(format!("invalid agent id '{}'", agent_id))),
+        Ok(command) => {
+            let name = command.name().to_string();
+            info!(agent_uuid = %agent_id, "Running server command {}", name);
+            match execute(&publisher, &agent_id, command).await {
+                Ok(result) => (name, Outcome::Done(result)),
+                Err(e) => (name, Outcome::Failed(e)),
+            }
+        }
+    };
+
+    let (status, result, error) = match outcome {
+        Outcome::Done(result) => ("ok", Some(result), None),
+        Outcome::Rejected(reason) => {
+            warn!(agent_uuid = %agent_id, "Rejected server command '{}': {}", name, reason);
+            ("rejected", None, Some(reason))
+        }
+        Outcome::Failed(e) => {
+            error!(agent_uuid = %agent_id, "Server command {} failed: {}", name, e);
+            ("failed", None, Some(e))
+        }
+    };
+    let report = CommandResult {
+        event_type: "COMMAND_RESULT",
+        agent_id: agent_id.clone(),
+        command_id,
+        command: name,
+        status,
+        result,
+        error,
+        at: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+    };
+    let queued = match serde_json::to_string(&report) {
+        Ok(payload) => outbox::enqueue(&UpstreamCall::CommandResult { agent_id, payload }).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = queued {
+        error!("Failed to queue command result for the server: {}", e);
+    }
+}
+
+async fn execute(publisher: &NatsPublisher, agent_id: &str, command: Command) -> Result<Value, String> {
+    match command {
+        Command::DeleteInventory { table, uuids } => {
+            let request = json!({ "action": format!("deleted_{}", table), "uuid": uuids });
+            with_connection(move |conn| Ok(delete_initial_data(conn, &request)?))
+                .await
+                .map_err(|e| format!("failed to delete {} entries: {}", table, e))?;
+            Ok(json!({ "table": table, "deleted": uuids }))
+        }
+        Command::Rescan(request) => request_scan(publisher, agent_id, request).await,
+        Command::UpdatePolicy(policy) => {
+            let version = policy.version;
+            publisher
+                .publish(&subjects::agent(agent_id, subjects::POLICY), &Envelope::new(policy))
+                .await
+                .map_err(|e| format!("failed to forward collection policy: {}", e))?;
+            info!("Collection policy forwarded to the collector");
+            Ok(json!({ "version": version, "forwarded": true }))
+        }
+        Command::RestartCollector => {
+            let subject = subjects::agent(agent_id, subjects::CONTROL);
+            let command = ControlCommand { action: ControlAction::Restart };
+            let reply = publisher
+                .request(&subject, &Envelope::new(command), &RequestOptions::default())
+                .await
+                .map_err(|e| format!("collector did not answer: {}", e))?;
+            let reply = messages::decode::<ControlReply>(&reply.payload).map_err(|e| format!("invalid control reply: {}", e))?;
+            match reply.error {
+                Some(e) => Err(e),
+                None => Ok(json!({ "status": reply.status })),
+            }
+        }
+        Command::FetchLogs { source: LogSource::Bridge, query } => {
+            let query = serde_json::from_value::<LogQuery>(Value::Object(query)).map_err(|e| e.to_string())?;
+            let page = tokio::task::spawn_blocking(move || log_store::query(Path::new(crate::LOG_PATH), &query))
+                .await
+                .map_err(|e| e.to_string())?
+                .map_err(|e| e.to_string())?;
+            serde_json::to_value(page).map_err(|e| e.to_string())
+        }
+        Command::FetchLogs { source: LogSource::Collector, query } => {
+            let subject = subjects::agent(agent_id, subjects::LOGS);
+            let request = LogsRequest { query: query.into_iter().collect() };
+            let reply = publisher
+                .request(&subject, &Envelope::new(request), &RequestOptions::default())
+                .await
+                .map_err(|e| format!("collector did not answer: {}", e))?;
+            let reply = messages::decode::<LogsReply>(&reply.payload).map_err(|e| format!("invalid log reply: {}", e))?;
+            match (reply.page, reply.error) {
+                (_, Some(e)) => Err(e),
+                (Some(page), None) => Ok(page),
+                (None, None) => Err("collector sent no log entries".to_string()),
+            }
+        }
+        Command::Ping => Ok(json!({ "pong": true, "at": Local::now().format("%Y-%m-%d %H:%M:%S").to_string() })),
+    }
+}
+
+/// Asks a collector to rescan part of its inventory and queues the result for the server
+async fn request_scan(publisher: &NatsPublisher, agent_id: &str, request: ScanRequest) -> Result<Value, String> {
+    let subject = subjects::scan(agent_id, request.action.as_str());
+    let reply = publisher
+        .request(&subject, &Envelope::new(request), &RequestOptions::default())
+        .await
+        .map_err(|e| format!("scan request to agent {} failed: {}", agent_id, e))?;
+    let scan = messages::decode::<ScanResult>(&reply.payload).map_err(|e| format!("rejected scan result: {}", e))?;
+    if let Some(e) = scan.error {
+        return Err(format!("agent failed to scan {}: {}", scan.action.as_str(), e));
+    }
+    info!(agent_uuid = %agent_id, subject = %subject, "Action: {}, UUID: {}, Result: {}", scan.action.as_str(), scan.uuid, scan.result);
+
+    // The server expects the scan JSON as an encoded string
+    let result = json!({ "component": scan.action.as_str(), "uuid": scan.uuid });
+    let call = UpstreamCall::Scan {
+        uuid: scan.uuid,
+        action: scan.action.as_str().to_string(),
+        data: Value::String(scan.result),
+    };
+    outbox::enqueue(&call).await.map_err(|e| format!("failed to queue scan data for the server: {}", e))?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: Value) -> Result<Command, String> {
+        Command::parse(&value)
+    }
+
+    #[test]
+    fn deletes_take_one_uuid_or_a_list() {
+        match parse(json!({ "action": "deleted_partitions", "uuid": "p-1" })) {
+            Ok(Command::DeleteInventory { table, uuids }) => {
+                assert_eq!(table, "partition");
+                assert_eq!(uuids, ["p-1"]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse(json!({ "action": "deleted_nic", "uuid": ["n-1", "n_2"] })) {
+            Ok(Command::DeleteInventory { table, uuids }) => {
+                assert_eq!(table, "nic");
+                assert_eq!(uuids, ["n-1", "n_2"]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bad_deletes_are_rejected() {
+        let too_many: Vec<String> = (0..=MAX_DELETE_UUIDS).map(|i| format!("u{}", i)).collect();
+        for command in [
+            json!({ "action": "deleted_agents", "uuid": "a-1" }),
+            json!({ "action": "deleted_storage" }),
+            json!({ "action": "deleted_storage", "uuid": [] }),
+            json!({ "action": "deleted_storage", "uuid": [1] }),
+            json!({ "action": "deleted_storage", "uuid": "x'; DROP TABLE storage; --" }),
+            json!({ "action": "deleted_storage", "uuid": too_many }),
+        ] {
+            assert!(parse(command.clone()).is_err(), "{}", command);
+        }
+    }
+
+    #[test]
+    fn rescans_name_their_component() {
+        match parse(json!({ "action": "disk", "uuid": "d-1" })) {
+            Ok(Command::Rescan(request)) => {
+                assert!(matches!(request.action, ScanAction::Disk));
+                assert_eq!(request.uuid, "d-1");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse(json!({ "action": "rescan", "component": "nic" })) {
+            Ok(Command::Rescan(request)) => {
+                assert!(matches!(request.action, ScanAction::Nic));
+                assert_eq!(request.uuid, "");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(parse(json!({ "action": "rescan" })).is_err());
+        assert!(parse(json!({ "action": "rescan", "component": "bios" })).is_err());
+        assert!(parse(json!({ "action": "nic", "uuid": "n/1" })).is_err());
+    }
+
+    #[test]
+    fn policies_are_validated() {
+        let policy = json!({ "version": 3, "interval_secs": 10, "batch_size": 5 });
+        match parse(json!({ "action": "policy", "policy": policy })) {
+            Ok(Command::UpdatePolicy(policy)) => assert_eq!(policy.version, 3),
+            other => panic!("unexpected {:?}", other),
+        }
+        // The policy may also be the command object itself
+        assert!(matches!(
+            parse(json!({ "action": "policy", "version": 4, "interval_secs": 10, "batch_size": 5 })),
+            Ok(Command::UpdatePolicy(_))
+        ));
+        let err = parse(json!({ "action": "policy", "policy": { "version": 3, "interval_secs": 0, "batch_size": 5 } })).unwrap_err();
+        assert!(err.starts_with("interval_secs"), "{}", err);
+        assert!(parse(json!({ "action": "policy", "policy": "fast" })).is_err());
+    }
+
+    #[test]
+    fn log_fetches_check_their_query() {
+        match parse(json!({ "action": "fetch_logs", "source": "collector", "query": { "level": "warn", "size": 50 } })) {
+            Ok(Command::FetchLogs { source, query }) => {
+                assert_eq!(source, LogSource::Collector);
+                assert_eq!(query.get("level"), Some(&json!("warn")));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            parse(json!({ "action": "fetch_logs" })),
+            Ok(Command::FetchLogs { source: LogSource::Bridge, .. })
+        ));
+        for command in [
+            json!({ "action": "fetch_logs", "source": "server" }),
+            json!({ "action": "fetch_logs", "query": "level=warn" }),
+            json!({ "action": "fetch_logs", "query": { "level": "loud" } }),
+            json!({ "action": "fetch_logs", "query": { "from": "yesterday" } }),
+            json!({ "action": "fetch_logs", "query": { "size": "many" } }),
+        ] {
+            assert!(parse(command.clone()).is_err(), "{}", command);
+        }
+    }
+
+    #[test]
+    fn simple_and_unknown_actions() {
+        assert_eq!(parse(json!({ "action": "ping" })).unwrap().name(), "ping");
+        assert_eq!(parse(json!({ "action": "restart_collector" })).unwrap().name(), "restart_collector");
+        assert_eq!(parse(json!({ "action": "format_disk" })).unwrap_err(), "unknown command 'format_disk'");
+        assert!(parse(json!({ "uuid": "d-1" })).is_err());
+        assert!(parse(json!({ "action": 7 })).is_err());
+    }
+}
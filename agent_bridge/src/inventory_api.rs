@@ -0,0 +1,99 @@
+use models_database::history::{self, ENTITIES};
+use models_database::{entity_history, inventory_at, inventory_diff, with_connection};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::error;
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Rejection, Reply};
+
+#[derive(Debug, Deserialize)]
+pub struct InventoryAtQuery {
+    at: Option<String>,
+    device: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InventoryDiffQuery {
+    from: String,
+    to: Option<String>,
+    device: Option<String>,
+}
+
+fn bad_request(reason: String) -> Response {
+    warp::reply::with_status(warp::reply::json(&json!({ "error": reason })), StatusCode::BAD_REQUEST).into_response()
+}
+
+/// A query's point in time in history format; missing means now
+fn point_in_time(name: &str, value: Option<&str>) -> Result<String, String> {
+    match value {
+        None => Ok(history::now()),
+        Some(value) => history::parse_time(value).ok_or_else(|| {
+            format!("'{}' must be RFC 3339, 'YYYY-MM-DD HH:MM:SS' (UTC) or 'YYYY-MM-DD', got '{}'", name, value)
+        }),
+    }
+}
+
+// Inventory as it was at `at` (default now), optionally for one device
+pub async fn get_inventory_at_handler(query: InventoryAtQuery) -> Result<Response, Rejection> {
+    let at = match point_in_time("at", query.at.as_deref()) {
+        Ok(at) => at,
+        Err(reason) => return Ok(bad_request(reason)),
+    };
+    let device = query.device;
+    let result = with_connection({
+        let (at, device) = (at.clone(), device.clone());
+        move |conn| Ok(inventory_at(conn, &at, device.as_deref())?)
+    })
+    .await;
+    match result {
+        Ok(inventory) => Ok(warp::reply::json(&json!({ "at": at, "device": device, "inventory": inventory })).into_response()),
+        Err(e) => {
+            error!("Failed to read inventory at {}: {}", at, e);
+            Err(warp::reject())
+        }
+    }
+}
+
+// Entities added, changed and removed between `from` and `to` (default now)
+pub async fn get_inventory_diff_handler(query: InventoryDiffQuery) -> Result<Response, Rejection> {
+    let (from, to) = match (point_in_time("from", Some(&query.from)), point_in_time("to", query.to.as_deref())) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(reason), _) | (_, Err(reason)) => return Ok(bad_request(reason)),
+    };
+    let device = query.device;
+    let result = with_connection({
+        let (from, to, device) = (from.clone(), to.clone(), device.clone());
+        move |conn| Ok(inventory_diff(conn, &from, &to, device.as_deref())?)
+    })
+    .await;
+    match result {
+        Ok(changes) => {
+            Ok(warp::reply::json(&json!({ "from": from, "to": to, "device": device, "changes": changes })).into_response())
+        }
+        Err(e) => {
+            error!("Failed to diff inventory between {} and {}: {}", from, to, e);
+            Err(warp::reject())
+        }
+    }
+}
+
+// Every recorded version of one inventory entity
+pub async fn get_entity_history_handler(entity: String, uuid: String) -> Result<Response, Rejection> {
+    if !ENTITIES.iter().any(|(name, _)| *name == entity) {
+        let known: Vec<&str> = ENTITIES.iter().map(|(name, _)| *name).collect();
+        return Ok(bad_request(format!("unknown entity '{}'; expected one of {}", entity, known.join(", "))));
+    }
+    let result = with_connection({
+        let (entity, uuid) = (entity.clone(), uuid.clone());
+        move |conn| Ok(entity_history(conn, &entity, &uuid)?)
+    })
+    .await;
+    match result {
+        Ok(versions) => Ok(warp::reply::json(&json!({ "entity": entity, "uuid": uuid, "versions": versions })).into_response()),
+        Err(e) => {
+            error!("Failed to read history of {} {}: {}", entity, uuid, e);
+            Err(warp::reject())
+        }
+    }
+}
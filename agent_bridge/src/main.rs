@@ -1,21 +1,38 @@
-use tracing::{info, error};
+use tracing::{info, error, warn};
 use serde_json::Value;
-use tokio::signal;
-use regex::Regex;
+use log_store::{LogQuery, QueryError};
 
 use shared_config::CONFIG;
 
 use nats::publisher::NatsPublisher;
+use nats::jetstream::{durable_consumer, INVENTORY_STREAM, MONITOR_STREAM};
+use nats::request::ReplyCache;
+use async_nats::jetstream::{AckKind, Message as JetStreamMessage};
 use nats::subscriber::NatsSubscriber;
 use futures::StreamExt;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 mod server_api; 
-use server_api::{send_master_key_to_server, send_to_server, get_new_access_token,send_to_monitor_server,scan_data_to_server};
-use models_database::db::{
-    establish_connection,save_token,get_token,token_exists,delete_initial_data,
+mod token_manager;
+mod outbox;
+mod tls;
+mod ws_session;
+mod admin;
+mod supervisor;
+mod backoff;
+mod commands;
+mod inventory_api;
+use server_api::{send_master_key_to_server, send_to_monitor_server};
+use token_manager::{with_access_token, TOKENS};
+use outbox::UpstreamCall;
+use models_database::with_connection;
+use crate::server_api::send_wss_status;
+use admin_auth::Role;
+use supervisor::SUPERVISOR;
+use messages::{
+    subjects, AgentData, Permissions, BridgeResponse, CredentialsReply, CredentialsRequest, MasterKey, MonitorBatch,
+    MonitoringState, MonitoringStatus, PolicyAck, TokenStatus,
 };
-use crate::server_api::{send_wss_status, MONITORING_RUNNING};
 
 
 use serde_json::json;
@@ -24,23 +41,30 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH, Duration};
 use std::fs;
 use warp::ws::{WebSocket, Message};
-use tokio::fs::File;
-use tokio::io::{BufReader, AsyncBufReadExt};
+use std::path::Path;
 use tokio::time;
 use futures::SinkExt;
 use warp::reply::Json;
 use models_database::models::{Cpu, Memory, Agent, Ip};
-use tower_http::cors::{CorsLayer, Any};
-use warp::Filter;
+use warp::{Filter, Reply};
 use tokio::sync::broadcast;
 use std::sync::Mutex as StdMutex;
 use once_cell::sync::Lazy;
-use tracing_appender::rolling;
-use tracing_subscriber::fmt::writer::MakeWriterExt;
 
 
 //mod config; // Add this line to include the config module
 
+/// Replies remembered per handler for answering retried collector requests
+const REPLY_CACHE_SIZE: usize = 64;
+/// Durable consumer names, so delivery resumes where it stopped after a bridge restart
+const INVENTORY_CONSUMER: &str = "bridge-inventory";
+const MONITOR_CONSUMER: &str = "bridge-monitor";
+/// Redelivery delay for messages that could not be written to the upstream outbox
+const OUTBOX_RETRY_DELAY: Duration = Duration::from_secs(30);
+const LOG_PATH: &str = "agent_bridge/logs.txt";
+/// How often `/ws/logs` checks the log file for new lines
+const LOG_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
 pub async fn create_publisher() -> Result<NatsPublisher, Box<dyn std::error::Error + Send + Sync>> {
     let publisher = NatsPublisher::new(
         &CONFIG.nats_url,
@@ -49,6 +73,7 @@ pub async fn create_publisher() -> Result<NatsPublisher, Box<dyn std::error::Err
         &CONFIG.ca_cert_path,
         &CONFIG.bridge_cert_path,
         &CONFIG.bridge_key_path,
+        subjects::BRIDGE_INBOX,
     )
     .await?;
     Ok(publisher)
@@ -62,6 +87,7 @@ pub async fn create_subscriber() -> Result<Arc<Mutex<NatsSubscriber>>, Box<dyn s
         &CONFIG.ca_cert_path,
         &CONFIG.bridge_cert_path,
         &CONFIG.bridge_key_path,
+        subjects::BRIDGE_INBOX,
     )
     .await?;
     
@@ -69,184 +95,179 @@ pub async fn create_subscriber() -> Result<Arc<Mutex<NatsSubscriber>>, Box<dyn s
     Ok(Arc::new(Mutex::new(subscriber)))
 }
 
-pub async fn handle_nats_operations() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    info!("Starting NATS operations handler");
+pub(crate) async fn process_monitor_data(agent_id: &str, payload: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    info!("Processing monitor data: ");
+
+    with_access_token(agent_id, |token| async move { send_to_monitor_server(agent_id, payload, &token).await })
+        .await
+        .inspect_err(|e| error!("Failed to send to monitor server: {}", e))
+}
 
-    let publisher = create_publisher().await?;
-    let subscriber_master = create_subscriber().await?;
-    let subscriber_agent = create_subscriber().await?;
-    let subscriber_monitor = create_subscriber().await?;
-    let subscriber_scan =create_subscriber().await?;
+/// Agent id of the collector that sent a message, taken from its `agent.<agent id>.<leaf>` subject
+fn sender_agent_id(subject: &str) -> Option<String> {
+    let agent_id = subjects::agent_id(subject).map(str::to_string);
+    if agent_id.is_none() {
+        error!("Ignoring message on {}: subject carries no agent id", subject);
+    }
+    agent_id
+}
 
+/// Replies to a collector request and remembers the reply for retries of the same request
+async fn send_reply<T: serde::Serialize>(publisher: &NatsPublisher, replies: &mut ReplyCache, request: &async_nats::Message, body: T) {
+    let payload = match messages::encode(&body) {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!("Failed to encode reply on {}: {}", request.subject, e);
+            return;
+        }
+    };
+    replies.insert(request, payload.clone().into());
+    if let Err(e) = publisher.respond(request, payload.into()).await {
+        error!("Failed to reply on {}: {}", request.subject, e);
+    }
+}
 
-    let http_client = reqwest::Client::builder()
-        .danger_accept_invalid_certs(true)
-        .build()?;
+/// Answers a retried request from the reply cache; returns false if the request is new
+async fn replay_reply(publisher: &NatsPublisher, replies: &ReplyCache, request: &async_nats::Message) -> bool {
+    let Some(payload) = replies.get(request) else {
+        return false;
+    };
+    info!("Replaying cached reply for retried request on {}", request.subject);
+    if let Err(e) = publisher.respond(request, payload).await {
+        error!("Failed to reply on {}: {}", request.subject, e);
+    }
+    true
+}
 
-    // Spawn independent handlers
-    let master_key_handler = handle_master_key_operations(subscriber_master, publisher.clone(), http_client.clone());
-    let agent_data_handler = handle_agent_data_operations(subscriber_agent, publisher.clone(), http_client.clone());
-    let monitor_data_handler = handle_monitor_data_operations(subscriber_monitor, publisher.clone(), http_client.clone());
-    let scan_data_handler = handle_scan_data_operations(subscriber_scan, publisher.clone(), http_client.clone());
+/// Settles a JetStream delivery; a lost ack only means the message is delivered again
+async fn settle(msg: &JetStreamMessage, kind: AckKind) {
+    if let Err(e) = msg.ack_with(kind).await {
+        error!("Failed to acknowledge message on {}: {}", msg.subject, e);
+    }
+}
 
-    tokio::select! {
-        res = master_key_handler => {
-            if let Err(e) = res {
-                error!("Master key handler failed: {}", e);
-                return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())));
-            }
-        }
-        res = agent_data_handler => {
-            if let Err(e) = res {
-                error!("Agent data handler failed: {}", e);
-                return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())));
-            }
-        }
-        res = monitor_data_handler => {
-            if let Err(e) = res {
-                error!("Monitor data handler failed: {}", e);
-                return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())));
-            }
+/// Ties an agent id to the first user key it is issued credentials for, so no other collector can
+/// later obtain credentials for the same namespace
+fn bind_agent_key(agent_id: &str, public_key: &str) -> Result<(), String> {
+    let path = Path::new(&CONFIG.nats_permissions_dir).join(format!("agent-{}.pub", agent_id));
+    fs::create_dir_all(&CONFIG.nats_permissions_dir).map_err(|e| e.to_string())?;
+    match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+        Ok(mut file) => {
+            use std::io::Write;
+            file.write_all(public_key.as_bytes()).map_err(|e| e.to_string())?;
+            info!("Agent {} bound to NATS user {}", agent_id, public_key);
+            Ok(())
         }
-        res = scan_data_handler => {
-            if let Err(e) = res {
-                error!("Monitor data handler failed: {}", e);
-                return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())));
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            let bound = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+            if bound.trim() == public_key {
+                Ok(())
+            } else {
+                Err(format!("agent {} is bound to another key", agent_id))
             }
         }
+        Err(e) => Err(e.to_string()),
     }
-
-    Ok(())
 }
 
+/// Signs NATS credentials confined to the requesting agent's namespace
+fn issue_credentials(agent_id: &str, request: &CredentialsRequest) -> Result<String, String> {
+    let account_seed = fs::read_to_string(&CONFIG.nats_account_seed_path)
+        .map_err(|e| format!("cannot read NATS account seed {}: {}", CONFIG.nats_account_seed_path, e))?;
+    let jwt = nats::credentials::issue_user_jwt(
+        &account_seed,
+        CONFIG.nats_issuer_account.as_deref(),
+        &format!("agent-{}", agent_id),
+        &request.public_key,
+        &Permissions::collector(agent_id),
+    )
+    .map_err(|e| e.to_string())?;
+    // Only a key that was signed for is bound
+    bind_agent_key(agent_id, &request.public_key)?;
+    Ok(jwt)
+}
 
-async fn process_monitor_data(http_client: &reqwest::Client, payload: &str) -> Result<String, Box<dyn std::error::Error>> {
-    info!("Processing monitor data: ");
-
-    let mut conn = establish_connection(&CONFIG.db_path);
-    
-    let token = match get_token(&mut conn, "access_token") {
-        Some(token) => token.token,
-        None => {
-            match get_new_access_token("access_token").await {
-                Ok(token) => {
-
-                    let token_json: Value = match serde_json::from_str(&token) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            error!("Failed to parse token JSON: {}", e);
-                            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Failed to parse token JSON")));
-                        }
-                    };
+// Credentials handler: collectors connect with the shared bootstrap user and ask for their own
+async fn handle_credential_requests(subscriber: Arc<Mutex<NatsSubscriber>>, publisher: NatsPublisher) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut subscriber = subscriber.lock().await.client().subscribe(subjects::all_agents(subjects::CREDENTIALS)).await?;
+    info!("Credentials handler started");
+    let mut replies = ReplyCache::new(REPLY_CACHE_SIZE);
 
-                    let expires_in = token_json.get("expires_in")
-                        .and_then(Value::as_i64)
-                        .unwrap_or(0);
-            
-                    let access_token_str = token_json.get("access_token")
-                        .and_then(Value::as_str)
-                        .unwrap_or("");
-            
-                    let expiration_time = (chrono::Local::now().naive_local()
-                        + chrono::Duration::seconds(expires_in))
-                        .format("%Y-%m-%d %H:%M:%S")
-                        .to_string();
-            
-
-                    if let Err(e) = save_token(&mut conn, access_token_str, &expiration_time, "access_token") {
-                        error!("Failed to save token to DB: {}", e);
-                    }
-        
-                    match get_token(&mut conn, "access_token") {
-                        Some(token) => token.token,
-                        None => {
-                            error!("Refresh token also expired or not found");
-                            
-                            String::new()
-                        }
-                    }
-                    
+    while let Some(msg) = subscriber.next().await {
+        let Some(agent_id) = sender_agent_id(&msg.subject) else {
+            continue;
+        };
+        if replay_reply(&publisher, &replies, &msg).await {
+            continue;
+        }
+        let reply = match messages::decode::<CredentialsRequest>(&msg.payload) {
+            Ok(request) => match issue_credentials(&agent_id, &request) {
+                Ok(jwt) => {
+                    info!(agent_uuid = %agent_id, "Issued NATS credentials");
+                    CredentialsReply { jwt: Some(jwt), error: None }
                 }
                 Err(e) => {
-                    error!("Failed to fetch access token: {}", e);
-                    String::new() 
+                    warn!(agent_uuid = %agent_id, "Refused NATS credentials: {}", e);
+                    CredentialsReply { jwt: None, error: Some(e) }
                 }
+            },
+            Err(e) => {
+                error!(agent_uuid = %agent_id, subject = %msg.subject, "Rejected credentials request: {}", e);
+                continue;
             }
-        }
-    };
-
-    let result = send_to_monitor_server(payload, &token).await;
-
-    match result {
-        Ok(response_data) => Ok(response_data),
-        Err(e) => {
-            error!("Failed to send to monitor server: {}", e);
-            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, e)));
-        }
+        };
+        send_reply(&publisher, &mut replies, &msg, reply).await;
     }
+    Ok(())
 }
 
 // Master key operations handler
-async fn handle_master_key_operations(subscriber: Arc<Mutex<NatsSubscriber>>,publisher: NatsPublisher,http_client: reqwest::Client,) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+async fn handle_master_key_operations(subscriber: Arc<Mutex<NatsSubscriber>>, publisher: NatsPublisher) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     info!("Master key handler started");
-    let mut subscriber = subscriber.lock().await;
-    let mut subscriber = subscriber.client().subscribe("master.key".to_string()).await?; 
+    let subscriber = subscriber.lock().await;
+    let mut subscriber = subscriber.client().subscribe(subjects::all_agents(subjects::MASTER_KEY)).await?;
     info!("Master key handler started");
+    let mut replies = ReplyCache::new(REPLY_CACHE_SIZE);
 
     while let Some(msg) = subscriber.next().await {
-        let received_payload = String::from_utf8_lossy(&msg.payload);
-        info!("Received master key payload {}", received_payload);
-
-        if let Err(e) = send_master_key_to_server(&received_payload).await {
-            error!("Failed to send master key: {}", e);
+        let Some(agent_id) = sender_agent_id(&msg.subject) else {
+            continue;
+        };
+        if replay_reply(&publisher, &replies, &msg).await {
+            continue;
         }
-        let mut conn = establish_connection(&CONFIG.db_path);
+        let master_key = match messages::decode::<MasterKey>(&msg.payload) {
+            Ok(master_key) => master_key,
+            Err(e) => {
+                error!(agent_uuid = %agent_id, subject = %msg.subject, "Rejected master.key message: {}", e);
+                continue;
+            }
+        };
+        info!(agent_uuid = %agent_id, subject = %msg.subject, "Received master key from {} (agent {})", master_key.hostname, agent_id);
 
-        if !token_exists(&mut conn, "access_token") {
-            info!("Token not found in the database, fetching new token...");
-       
-            match get_new_access_token("token").await {
+        if let Err(e) = send_master_key_to_server(&agent_id, &master_key).await {
+            error!(agent_uuid = %agent_id, "Failed to send master key: {}", e);
+        }
+        // Each collector reaches the server over a session of its own
+        ws_session::SESSIONS.session(&agent_id);
+        if !TOKENS.has_token(&agent_id).await {
+            info!("Token not found, fetching new token...");
+            match TOKENS.issue_initial(&agent_id).await {
                 Ok(token) => {
-                    let expiration_time = (chrono::Local::now().naive_local()
-                    + chrono::Duration::seconds(
-                        serde_json::from_str::<Value>(&token)?
-                            .get("expires_in")
-                            .and_then(Value::as_i64)
-                            .unwrap_or(0),
-                    ))
-                    .format("%Y-%m-%d %H:%M:%S")
-                    .to_string();
-
-                    if let Err(e) = save_token(
-                        &mut conn,
-                        &serde_json::from_str::<Value>(&token)?.get("access_token").and_then(Value::as_str).unwrap_or(""),
-                        &expiration_time,
-                        "access_token",
-                    ) {
-                        error!("Failed to save token to DB: {}", e);
-                    } else {
-                        broadcast_token_connected();
-                        // After saving the token (onboarding or refresh), also broadcast collector status
-                    }
-
-
-                    let response = serde_json::json!({
-                        "status": "ok",
-                        "token": token,
-                    });
-
-                    if let Err(e) = publisher.publish("bridge.response", &response).await {
-                        error!("Failed to publish token response: {}", e);
-                    }
+                    let response = BridgeResponse {
+                        status: TokenStatus::Ok,
+                        token: Some(token),
+                    };
+                    send_reply(&publisher, &mut replies, &msg, response).await;
                 }
+                // No reply: the collector retries the request with backoff
                 Err(e) => error!("Failed to fetch access token: {}", e),
             }
         } else {
-            info!("Token already exists in the database");
-            if let Err(e) = publisher.publish("bridge.response", &serde_json::json!({"message": "Token is already exists"})).await {
-                error!("Failed to publish token response: {}", e);
-            }
+            info!("Token already exists");
+            let response = BridgeResponse { status: TokenStatus::TokenExists, token: None };
+            send_reply(&publisher, &mut replies, &msg, response).await;
         }
     }
 
@@ -254,84 +275,43 @@ async fn handle_master_key_operations(subscriber: Arc<Mutex<NatsSubscriber>>,pub
 }
 
 // Agent data operations handler
-async fn handle_agent_data_operations(subscriber: Arc<Mutex<NatsSubscriber>>,publisher: NatsPublisher,http_client: reqwest::Client,) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+async fn handle_agent_data_operations(subscriber: Arc<Mutex<NatsSubscriber>>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     info!("Agent data handler started");
 
-    let mut subscriber = subscriber.lock().await;
-    let mut subscriber = subscriber.client().subscribe("agent.data".to_string()).await?;
+    let subscriber = subscriber.lock().await;
+    let consumer = durable_consumer(subscriber.client(), INVENTORY_STREAM, INVENTORY_CONSUMER).await?;
+    let mut deliveries = consumer.messages().await?;
 
 
-    while let Some(msg) = subscriber.next().await {
+    while let Some(msg) = deliveries.next().await {
         info!("Bridge: Listening for 'agent.data'...");
-        let data_payload = String::from_utf8_lossy(&msg.payload);
-            let mut conn = establish_connection(&CONFIG.db_path);
-        
-
-            let token = match get_token(&mut conn, "access_token") {
-                Some(token) => token.token,
-                None => {
-                    match get_new_access_token("access_token").await {
-                        Ok(token) => {
-
-                            let token_json: Value = match serde_json::from_str(&token) {
-                                Ok(v) => v,
-                                Err(e) => {
-                                    error!("Failed to parse token JSON: {}", e);
-                                    return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Failed to parse token JSON")));
-                                }
-                            };
-
-                            let expires_in = token_json.get("expires_in")
-                                .and_then(Value::as_i64)
-                                .unwrap_or(0);
-                    
-                            let access_token_str = token_json.get("access_token")
-                                .and_then(Value::as_str)
-                                .unwrap_or("");
-                    
-                            let expiration_time = (chrono::Local::now().naive_local()
-                                + chrono::Duration::seconds(expires_in))
-                                .format("%Y-%m-%d %H:%M:%S")
-                                .to_string();
-                    
-       
-                            if let Err(e) = save_token(&mut conn, access_token_str, &expiration_time, "access_token") {
-                                error!("Failed to save token to DB: {}", e);
-                            } else {
-                                broadcast_token_connected();
-                                // After saving the token (onboarding or refresh), also broadcast collector status
-                            }
-                
-                            match get_token(&mut conn, "access_token") {
-                                Some(token) => token.token,
-                                None => {
-                                    error!("Refresh token also expired or not found");
-                                    
-                                    String::new()
-                                }
-                            }
-                            
-                        }
-                        Err(e) => {
-                            error!("Failed to fetch access token: {}", e);
-                            String::new() 
-                        }
-                    }
-                }
-            };
-            match send_to_server(&data_payload, &token).await {
-                Ok(response_msg) => {
-                    info!("Bridge: Server responded: {}", response_msg);
-
-                    if let Err(e) = publisher.publish("agent.response", &response_msg).await {
-                        error!("Bridge: Failed to publish response: {:?}", e);
-                    } else {
-                        info!("Bridge: Response sent successfully");
-                    }
-                }
-                Err(e) => error!("Bridge: Failed to send data to server: {:?}", e),
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("Bridge: Failed to pull inventory: {}", e);
+                continue;
             }
-        
+        };
+        let Some(agent_id) = sender_agent_id(&msg.subject) else {
+            settle(&msg, AckKind::Term).await;
+            continue;
+        };
+        info!(agent_uuid = %agent_id, subject = %msg.subject, "Bridge: Inventory received from agent {}", agent_id);
+        let agent_data = match messages::decode::<AgentData>(&msg.payload) {
+            Ok(agent_data) => agent_data,
+            Err(e) => {
+                error!(agent_uuid = %agent_id, subject = %msg.subject, "Bridge: Rejected agent.data message: {}", e);
+                settle(&msg, AckKind::Term).await;
+                continue;
+            }
+        };
+        // The stream keeps the inventory until the outbox has taken it over
+        let call = UpstreamCall::Inventory {
+            agent_id,
+            inventory_id: agent_data.inventory_id,
+            inventory: agent_data.inventory,
+        };
+        hand_over(&msg, &call).await;
     }
 
     Ok(())
@@ -340,93 +320,117 @@ async fn handle_agent_data_operations(subscriber: Arc<Mutex<NatsSubscriber>>,pub
 
 
 // Monitor data operations handler
-async fn handle_monitor_data_operations(subscriber: Arc<Mutex<NatsSubscriber>>,publisher: NatsPublisher,http_client: reqwest::Client) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+async fn handle_monitor_data_operations(subscriber: Arc<Mutex<NatsSubscriber>>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     info!("Monitor data handler started");
-    let mut subscriber = subscriber.lock().await;
-    let mut subscriber = subscriber.client().subscribe("monitor.data".to_string()).await?;
+    let subscriber = subscriber.lock().await;
+    let consumer = durable_consumer(subscriber.client(), MONITOR_STREAM, MONITOR_CONSUMER).await?;
+    let mut deliveries = consumer.messages().await?;
     
 
-    while let Some(msg) = subscriber.next().await {
-        let payload = String::from_utf8_lossy(&msg.payload);
-        info!("Received monitor data batch ({} bytes)", payload.len());
-
-        match process_monitor_data(&http_client, &payload).await {
-            Ok(response_data) => {
-                info!("Received monitor server response: {}", response_data);
-                  if let Ok(json_value) = serde_json::from_str::<serde_json::Value>(&response_data) {
-
-                    if let Some(action) = json_value.get("action").and_then(|v| v.as_str()) {
-                        if action.contains("deleted") {
-                            info!("Action contains 'deleted', calling delete_action");
-                            let mut conn = establish_connection(&CONFIG.db_path);
-                            if let Err(e) = delete_initial_data(&mut conn,&json_value) {
-                                error!("Failed to delete initial data: {}", e);
-                            }
-                        }
-                
-                         else{
-                            if let Err(e) = publisher.publish(&format!("scan.{}", action), &json_value).await {
-                                error!("Bridge: Failed to publish response: {:?}", e);
-                            } else {
-                                info!("Scan response sent successfully to the collector");
-                            }
-                         }
-                    }          
-                }
-
-                
+    while let Some(msg) = deliveries.next().await {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("Failed to pull monitor data: {}", e);
+                continue;
             }
-            Err(e) => error!("Failed to process monitor data batch: {}", e),
-        }
+        };
+        // Actions in the server's response go back to the collector that sent the batch
+        let Some(agent_id) = sender_agent_id(&msg.subject) else {
+            settle(&msg, AckKind::Term).await;
+            continue;
+        };
+        let payload = match messages::decode::<MonitorBatch>(&msg.payload) {
+            Ok(batch) => serde_json::to_string(&batch.checkpoints)?,
+            Err(e) => {
+                error!(agent_uuid = %agent_id, subject = %msg.subject, "Rejected monitor.data batch: {}", e);
+                settle(&msg, AckKind::Term).await;
+                continue;
+            }
+        };
+        info!(agent_uuid = %agent_id, subject = %msg.subject, "Received monitor data batch ({} bytes)", payload.len());
+
+        hand_over(&msg, &UpstreamCall::Monitor { agent_id, payload }).await;
     }
 
     Ok(())
 }
 
-pub async fn handle_scan_data_operations(
-    subscriber: Arc<Mutex<NatsSubscriber>>,
-    _publisher: NatsPublisher,
-    _http_client: reqwest::Client,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut subscribers = subscriber.lock().await;
-    let mut new_subscriber = subscribers.client().subscribe("send.scan.>".to_string()).await?;
-    info!("Listening for scan data...");
-
-    while let Some(response_msg) = new_subscriber.next().await {
-        let response_payload = String::from_utf8_lossy(&response_msg.payload);
-        info!("Received raw response: {}", response_payload);
-
-        let json: Value = match serde_json::from_str(&response_payload) {
-            Ok(j) => j,
-            Err(e) => {
-                error!("Failed to parse JSON: {}", e);
+// Commands the central server pushes over the WebSocket session outside of any reply
+async fn handle_server_push_operations(publisher: NatsPublisher) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    info!("Server push handler started");
+    let mut commands = ws_session::SESSIONS.commands();
+
+    loop {
+        let command = match commands.recv().await {
+            Ok(command) => command,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                error!("Server push handler fell behind, {} commands dropped", skipped);
                 continue;
             }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
         };
+        // Pushed commands are not tied to a batch; they are for the collector whose session carried
+        // them unless they name another
+        let agent_id = serde_json::from_str::<Value>(&command.text)
+            .ok()
+            .and_then(|value| value.get("agent_id").and_then(Value::as_str).map(str::to_string))
+            .unwrap_or(command.agent_id);
+        commands::dispatch(&publisher, &agent_id, &command.text).await;
+    }
+}
 
+/// Moves a JetStream delivery into the upstream outbox, acking it only once the outbox has it.
+///
+/// This is the ack point: the stream forgets a message as soon as it is in the bridge's SQLite outbox,
+/// not when the central server accepts it. From then on the outbox owns delivery, with its own retries,
+/// per-kind ordering and dead letters, so losing the bridge's database loses calls the stream had handed
+/// over.
+async fn hand_over(msg: &JetStreamMessage, call: &UpstreamCall) {
+    match outbox::enqueue(call).await {
+        Ok(()) => settle(msg, AckKind::Ack).await,
+        Err(e) => {
+            error!("Failed to queue upstream call, retrying in {:?}: {}", OUTBOX_RETRY_DELAY, e);
+            settle(msg, AckKind::Nak(Some(OUTBOX_RETRY_DELAY))).await;
+        }
+    }
+}
 
-        if let (Some(action), Some(uuid), Some(result)) = (
-            json.get("action").and_then(|v| v.as_str()),
-            json.get("uuid").and_then(|v| v.as_str()),
-            json.get("result"),
-        ) {
-            info!("Action: {}, UUID: {}, Result: {}", action, uuid, result);
-
+// Collection policy acknowledgement handler
+async fn handle_policy_ack_operations(subscriber: Arc<Mutex<NatsSubscriber>>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let subscriber = subscriber.lock().await;
+    let mut subscriber = subscriber.client().subscribe(subjects::all_agents(subjects::POLICY_ACK)).await?;
+    info!("Policy ack handler started");
 
-            if let Err(e) = scan_data_to_server(result, uuid, action).await {
-                error!("Failed to send scan data to server: {}", e);
+    while let Some(msg) = subscriber.next().await {
+        let Some(agent_id) = sender_agent_id(&msg.subject) else {
+            continue;
+        };
+        let ack = match messages::decode::<PolicyAck>(&msg.payload) {
+            Ok(ack) => ack,
+            Err(e) => {
+                error!(agent_uuid = %agent_id, subject = %msg.subject, "Rejected policy ack: {}", e);
+                continue;
             }
-        } else {
-            error!("Missing required fields in received message.");
+        };
+        info!(agent_uuid = %agent_id, subject = %msg.subject, "Collector {} acknowledged collection policy: {:?}", agent_id, ack);
+
+        // Report back on the same channel the policy arrived on
+        let mut ack = serde_json::to_value(&ack)?;
+        ack["event_type"] = json!("POLICY_ACK");
+        ack["agent_id"] = json!(agent_id);
+        if let Err(e) = outbox::enqueue(&UpstreamCall::PolicyAck { agent_id, payload: ack.to_string() }).await {
+            error!("Failed to queue policy ack for the server: {}", e);
         }
     }
 
     Ok(())
 }
+
 // Heartbeat WebSocket
 /// Simple function to check if bridge is running
 pub async fn check_bridge_status(running: Arc<AtomicBool>) -> String {
-    if (!running.load(Ordering::SeqCst)) {
+    if !running.load(Ordering::SeqCst) {
         return "Paused".to_string();
     }
     match create_publisher().await {
@@ -464,74 +468,64 @@ async fn send_bridge_status(mut socket: WebSocket, running: Arc<AtomicBool>) {
         }
     }
 }
-async fn send_logs(mut socket: warp::ws::WebSocket) {
-    let mut interval = time::interval(Duration::from_secs(1)); // Send logs every second
+/// Sends the newest log entries (or those after `since`) and then every new entry as it is written
+async fn send_logs(mut socket: WebSocket, query: LogQuery) {
+    let (page, mut tail) = match log_store::follow(Path::new(LOG_PATH), &query) {
+        Ok(followed) => followed,
+        Err(e) => {
+            let _ = socket.send(Message::text(json!({ "error": e.to_string() }).to_string())).await;
+            return;
+        }
+    };
+    if socket.send(Message::text(json!(page.entries).to_string())).await.is_err() {
+        return;
+    }
 
+    let mut interval = time::interval(LOG_POLL_INTERVAL);
     loop {
-        interval.tick().await;
-
-        match fetch_logs().await {
-            Ok(logs_json) => {
-                if socket.send(Message::text(logs_json)).await.is_err() {
-                    error!("❌ Frontend disconnected from logs WebSocket");
+        tokio::select! {
+            _ = interval.tick() => {
+                let entries = match tail.poll() {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        warn!("Failed to read {}: {}", LOG_PATH, e);
+                        continue;
+                    }
+                };
+                if !entries.is_empty() && socket.send(Message::text(json!(entries).to_string())).await.is_err() {
                     break;
                 }
             }
-            Err(e) => {
-                let error_message = json!(["Failed to read logs", e.to_string()]);
-                let _ = socket.send(Message::text(error_message.to_string())).await;
-                break;
+            message = socket.next() => {
+                if !matches!(message, Some(Ok(_))) {
+                    break;
+                }
             }
         }
     }
+    info!("Frontend disconnected from logs WebSocket");
 }
 
-
-async fn fetch_logs() -> Result<String, std::io::Error> {
-    let file = File::open("agent_bridge/logs.txt").await?;
-    let reader = BufReader::new(file);
-    let mut lines = reader.lines();
-    let mut logs = Vec::new();
-    // Regex to match ANSI escape codes
-    let ansi_regex = Regex::new(r"\x1b\[[0-9;]*[a-zA-Z]").unwrap();
-
-    while let Some(line) = lines.next_line().await? {
-        // Strip ANSI escape codes
-        let clean_line = ansi_regex.replace_all(&line, "").to_string();
-        logs.push(clean_line);
-    }
-
-    // Return all logs, no line limit
-    let logs_json = serde_json::to_string(&logs)?;
-    Ok(logs_json)
-}
-
-// Pagination handler for logs
-#[derive(serde::Deserialize)]
-struct LogPage {
-    page: usize // 0-based page index
-}
-
-async fn get_logs_handler(query: LogPage) -> Result<impl warp::Reply, warp::Rejection> {
-    use tokio::io::{AsyncBufReadExt, BufReader};
-    use tokio::fs::File;
-    let file = File::open("agent_bridge/logs.txt").await.map_err(|_| warp::reject())?;
-    let reader = BufReader::new(file);
-    let mut lines = reader.lines();
-    let mut all_logs = Vec::new();
-    let ansi_regex = Regex::new(r"\x1b\[[0-9;]*[a-zA-Z]").unwrap();
-    while let Some(line) = lines.next_line().await.map_err(|_| warp::reject())? {
-        let clean_line = ansi_regex.replace_all(&line, "").to_string();
-        all_logs.push(clean_line);
-    }
-    Ok(warp::reply::json(&all_logs))
+// Log entries, newest page first, or those written after a `since` cursor
+async fn get_logs_handler(query: LogQuery) -> Result<warp::reply::Response, warp::Rejection> {
+    let result = tokio::task::spawn_blocking(move || log_store::query(Path::new(LOG_PATH), &query))
+        .await
+        .unwrap_or_else(|e| Err(QueryError::Io(std::io::Error::other(e))));
+    let (status, body) = match result {
+        Ok(page) => return Ok(warp::reply::json(&page).into_response()),
+        Err(QueryError::Invalid(reason)) => (warp::http::StatusCode::BAD_REQUEST, reason),
+        Err(e) => {
+            error!("Log query failed: {}", e);
+            (warp::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        }
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&json!({ "error": body })), status).into_response())
 }
 
 
 // ✅ WSS server with multiple routes
 async fn run_ws_servers(running: Arc<AtomicBool>) {
     let running_for_bridge_ws = running.clone();
-    let running_for_toggle = running.clone();
     let wss_route = warp::path!("ws" / "wss")
         .and(warp::ws())
         .map(|ws: warp::ws::Ws| ws.on_upgrade(send_wss_status));
@@ -551,17 +545,49 @@ async fn run_ws_servers(running: Arc<AtomicBool>) {
    
     let system_info_route = warp::path!("api" / "system-info")
         .and(warp::get())
+        .and(admin::require(Role::ReadOnly))
         .and_then(get_system_info_handler);
 
     let logs_route = warp::path!("ws" / "logs")
+        .and(admin::require(Role::ReadOnly))
         .and(warp::ws())
-        .map(|ws: warp::ws::Ws| ws.on_upgrade(send_logs));
+        .and(warp::query::<LogQuery>())
+        .map(|ws: warp::ws::Ws, query: LogQuery| ws.on_upgrade(move |socket| send_logs(socket, query)));
 
     let logs_api_route = warp::path!("api" / "logs")
         .and(warp::get())
-        .and(warp::query::<LogPage>())
+        .and(admin::require(Role::ReadOnly))
+        .and(warp::query::<LogQuery>())
         .and_then(get_logs_handler);
 
+    let outbox_route = warp::path!("api" / "outbox")
+        .and(warp::get())
+        .and(admin::require(Role::ReadOnly))
+        .and_then(get_outbox_handler);
+
+    let audit_route = warp::path!("api" / "admin" / "audit")
+        .and(warp::get())
+        .and(admin::require(Role::Admin))
+        .and(warp::query::<admin::AuditQuery>())
+        .and_then(admin::get_audit_handler);
+
+    let inventory_at_route = warp::path!("api" / "inventory" / "at")
+        .and(warp::get())
+        .and(admin::require(Role::ReadOnly))
+        .and(warp::query::<inventory_api::InventoryAtQuery>())
+        .and_then(inventory_api::get_inventory_at_handler);
+
+    let inventory_diff_route = warp::path!("api" / "inventory" / "diff")
+        .and(warp::get())
+        .and(admin::require(Role::ReadOnly))
+        .and(warp::query::<inventory_api::InventoryDiffQuery>())
+        .and_then(inventory_api::get_inventory_diff_handler);
+
+    let inventory_history_route = warp::path!("api" / "inventory" / "history" / String / String)
+        .and(warp::get())
+        .and(admin::require(Role::ReadOnly))
+        .and_then(inventory_api::get_entity_history_handler);
+
     // Updated health check handler
     fn health_check_handler() -> impl warp::Reply {
         "OK"
@@ -576,21 +602,36 @@ async fn run_ws_servers(running: Arc<AtomicBool>) {
     let running_for_toggle_2 = running.clone();
     let toggle_bridge_route = warp::path!("api" / "service" / "bridge" / "toggle")
         .and(warp::post())
+        .and(admin::require(Role::Admin))
         .and(warp::any().map(move || running_for_toggle_1.clone()))
         .and_then(toggle_bridge_handler);
 
     let restart_bridge_route = warp::path!("api" / "service" / "bridge" / "restart")
         .and(warp::post())
+        .and(admin::require(Role::Admin))
         .and(warp::any().map(move || running_for_toggle_2.clone()))
         .and_then(restart_bridge_handler);
 
+    let handlers_route = warp::path!("api" / "service" / "bridge" / "handlers")
+        .and(warp::get())
+        .and(admin::require(Role::ReadOnly))
+        .and_then(get_handlers_handler);
+
+    let restart_handler_route = warp::path!("api" / "service" / "bridge" / "handlers" / String / "restart")
+        .and(warp::post())
+        .and(admin::require(Role::Admin))
+        .and_then(restart_handler_handler);
+
         println!("✅ WSS running at ws://127.0.0.1:3030/ws/wss");
         println!("✅ HTTPS status check running at ws://127.0.0.1:3030/ws/https");
         println!("✅ Bridge status running at ws://127.0.0.1:3030/ws/bridge");
         println!("✅ Agent connection status running at ws://127.0.0.1:3030/ws/agent");
         println!("✅ System info API running at http://127.0.0.1:3030/api/system_info");
-
-    let cors = CorsLayer::new().allow_origin(Any);
+        println!("✅ Log query API running at http://127.0.0.1:3030/api/logs, live logs at ws://127.0.0.1:3030/ws/logs");
+        println!("✅ Upstream outbox status running at http://127.0.0.1:3030/api/outbox");
+        println!("✅ Admin audit log running at http://127.0.0.1:3030/api/admin/audit");
+        println!("✅ Handler health running at http://127.0.0.1:3030/api/service/bridge/handlers");
+        println!("✅ Inventory history running at http://127.0.0.1:3030/api/inventory/at, /api/inventory/diff and /api/inventory/history/{{entity}}/{{uuid}}");
 
     let app = warp::serve(
         wss_route
@@ -606,6 +647,14 @@ async fn run_ws_servers(running: Arc<AtomicBool>) {
             .or(health_route)
             .or(toggle_bridge_route)
             .or(restart_bridge_route)
+            .or(outbox_route)
+            .or(audit_route)
+            .or(handlers_route)
+            .or(restart_handler_route)
+            .or(inventory_at_route)
+            .or(inventory_diff_route)
+            .or(inventory_history_route)
+            .recover(admin::handle_rejection)
     );
 
     app.run(([127, 0, 0, 1], 3030)).await;
@@ -623,26 +672,41 @@ struct SystemInfo {
 async fn get_system_info_handler() -> Result<Json, warp::Rejection> {
     info!("Fetching system information...");
 
-    let mut conn = establish_connection(&CONFIG.db_path);
-
-    let cpu = Cpu::first(&mut conn)
-        .map_or("Unknown".to_string(), |c| format!("{} @ {} ", c.model, c.speed));
-    let memory = Memory::first(&mut conn)
-        .map_or("Unknown".to_string(), |m| format!("{} ", m.size));
-    let os = Agent::first(&mut conn)
-        .map_or("Unknown".to_string(), |a| a.os);
-    let ip_address = Ip::first(&mut conn)
-        .map_or("Unknown".to_string(), |ip| ip.address);
-    let hostname = Agent::first(&mut conn)
-        .map_or("Unknown".to_string(), |a| a.hostname); // Fetch hostname // Fetch IP address
-   
-    let system_info = SystemInfo { cpu, memory, os, ip_address, hostname };
+    let system_info = with_connection(|conn| {
+        let cpu = Cpu::first(conn)
+            .map_or("Unknown".to_string(), |c| format!("{} @ {} ", c.model, c.speed));
+        let memory = Memory::first(conn)
+            .map_or("Unknown".to_string(), |m| format!("{} ", m.size));
+        let os = Agent::first(conn)
+            .map_or("Unknown".to_string(), |a| a.os);
+        let ip_address = Ip::first(conn)
+            .map_or("Unknown".to_string(), |ip| ip.address);
+        let hostname = Agent::first(conn)
+            .map_or("Unknown".to_string(), |a| a.hostname); // Fetch hostname // Fetch IP address
+        Ok(SystemInfo { cpu, memory, os, ip_address, hostname })
+    })
+    .await
+    .map_err(|e| {
+        error!("Failed to read system information: {}", e);
+        warp::reject()
+    })?;
 
     info!("System information fetched: {:?}", system_info);
 
     Ok(warp::reply::json(&system_info))
 }
 
+// Upstream outbox depth, age of the oldest queued call and dead-letter count
+async fn get_outbox_handler() -> Result<impl warp::Reply, warp::Rejection> {
+    match outbox::stats().await {
+        Ok(stats) => Ok(warp::reply::json(&stats)),
+        Err(e) => {
+            error!("Failed to read upstream outbox: {}", e);
+            Err(warp::reject())
+        }
+    }
+}
+
 // Handler to toggle (start/stop) the bridge service
 async fn toggle_bridge_handler(running: Arc<AtomicBool>) -> Result<impl warp::Reply, warp::Rejection> {
     let was_running = running.load(Ordering::SeqCst);
@@ -653,67 +717,40 @@ async fn toggle_bridge_handler(running: Arc<AtomicBool>) -> Result<impl warp::Re
     Ok(warp::reply::json(&serde_json::json!({"status": status})))
 }
 
-// Function to broadcast bridge status updates (for WebSocket, similar to collector)
-fn broadcast_bridge_status(status_tx: &Arc<tokio::sync::broadcast::Sender<String>>, status: &str) {
-    let status_json = serde_json::json!({ "bridge": status }).to_string();
-    let _ = status_tx.send(status_json);
-}
-
-// Handler to restart the bridge service
+// Handler to restart the bridge service: new NATS connections, HTTP client and WebSocket session
 async fn restart_bridge_handler(running: Arc<AtomicBool>) -> Result<impl warp::Reply, warp::Rejection> {
     info!("Restarting bridge service via restart endpoint");
-    // Pause the bridge
-    running.store(false, Ordering::SeqCst);
-    info!("Bridge service paused for restart");
-    tokio::time::sleep(std::time::Duration::from_secs(2)).await; // Give time for shutdown
-    // Resume the bridge
     running.store(true, Ordering::SeqCst);
-    info!("Bridge service resumed after restart");
-    Ok(warp::reply::json(&serde_json::json!({"status": "restarted"})))
+    let generation = SUPERVISOR.restart();
+    Ok(warp::reply::json(&serde_json::json!({"status": "restarted", "generation": generation})))
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Ensure logs.txt exists and is valid UTF-8, recreate if needed
-    let log_path = std::path::Path::new("agent_bridge/logs.txt");
-    if !log_path.exists() {
-        match std::fs::File::create(log_path) {
-            Ok(_) => info!("logs.txt did not exist, created new file at {:?}", log_path),
-            Err(e) => error!("Failed to create logs.txt at {:?}: {}", log_path, e),
-        }
+// State, restart counters and last error of every supervised handler
+async fn get_handlers_handler() -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&SUPERVISOR.health()))
+}
+
+async fn restart_handler_handler(name: String) -> Result<impl warp::Reply, warp::Rejection> {
+    if SUPERVISOR.restart_handler(&name) {
+        Ok(warp::reply::json(&serde_json::json!({"status": "restarting", "handler": name})))
     } else {
-        // Try to read as UTF-8, recreate if invalid
-        match std::fs::read_to_string(log_path) {
-            Ok(_) => {},
-            Err(e) => {
-                error!("logs.txt exists but is not valid UTF-8 or unreadable: {}. Recreating...", e);
-                match std::fs::File::create(log_path) {
-                    Ok(_) => info!("logs.txt recreated at {:?}", log_path),
-                    Err(e) => error!("Failed to recreate logs.txt at {:?}: {}", log_path, e),
-                }
-            }
-        }
+        Err(warp::reject::not_found())
     }
+}
 
-    let file_appender = rolling::never("agent_bridge", "logs.txt");
-    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
-
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .with_writer(non_blocking.and(std::io::stdout))
-        .init();
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Stdout plus agent_bridge/logs.txt, rotated and kept as configured by LOG_FORMAT, LOG_ROTATION, LOG_MAX_BYTES and LOG_MAX_FILES
+    let _guard = log_store::init(LOG_PATH)?;
 
     info!("Bridge Application starting...");
 
-    // --- On startup, check if a valid access token exists and broadcast status ---
-    {
-        let mut conn = establish_connection(&CONFIG.db_path);
-        if let Some(token) = get_token(&mut conn, "access_token") {
-            if !token.token.is_empty() {
-                broadcast_token_connected();
-            }
-        }
-    }
+    // Loads the stored access tokens, reports them and keeps them renewed ahead of expiry
+    TOKENS.spawn_refresh_task();
+    // Keeps the monitoring uplinks to the central server connected
+    ws_session::SESSIONS.spawn();
+
+    admin_auth::check_configuration();
 
     // Start the WebSocket server for frontend connections
     let running = Arc::new(AtomicBool::new(true));
@@ -744,46 +781,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // Set up NATS subscriber for monitoring.status
     let subscriber = create_subscriber().await?;
     let subscriber = subscriber.lock().await;
-    let mut sub = subscriber.client().subscribe("monitoring.status").await?;
+    let mut sub = subscriber.client().subscribe(subjects::all_agents(subjects::MONITORING_STATUS)).await?;
     tokio::spawn(async move {
         use std::sync::atomic::Ordering;
         use crate::server_api::MONITORING_RUNNING;
-        use crate::server_api::set_token_available;
         while let Some(msg) = sub.next().await {
             tracing::info!("[NATS] Received monitoring.status message: {:?}", msg.payload);
-            if let Ok(json) = serde_json::from_slice::<serde_json::Value>(&msg.payload) {
-                if let Some(status) = json.get("status").and_then(|v| v.as_str()) {
-                    tracing::info!("[NATS] monitoring.status value: {}", status);
+            match messages::decode::<MonitoringStatus>(&msg.payload) {
+                Ok(MonitoringStatus { status }) => {
+                    tracing::info!("[NATS] monitoring.status value from {}: {:?}", msg.subject, status);
                     match status {
-                        "running" => {
+                        MonitoringState::Running => {
                             MONITORING_RUNNING.store(true, Ordering::SeqCst);
-                            set_token_available(true); // Ensure token is available for WSS
-                            tracing::info!("[NATS] MONITORING_RUNNING set to true, token available");
+                            tracing::info!("[NATS] MONITORING_RUNNING set to true");
                         },
-                        "stopped" => {
+                        MonitoringState::Stopped => {
                             MONITORING_RUNNING.store(false, Ordering::SeqCst);
-                            set_token_available(false);
-                            tracing::info!("[NATS] MONITORING_RUNNING set to false, token unavailable");
+                            tracing::info!("[NATS] MONITORING_RUNNING set to false");
                         },
-                        _ => {}
                     }
                 }
-            } else {
-                tracing::warn!("[NATS] Failed to parse monitoring.status payload as JSON");
+                Err(e) => tracing::warn!("[NATS] Rejected monitoring.status message: {}", e),
             }
         }
     });
 
-    // Main NATS operations loop, only runs when running is true
+    // Handlers run under the supervisor while `running` is true; the toggle and NATS health check flip it
     loop {
         if running.load(Ordering::SeqCst) {
-            if let Err(e) = handle_nats_operations().await {
-                error!("Error in NATS operations: {:?}", e);
-            }
-        } else {
-            // Paused, wait until running is true again
-            tokio::time::sleep(Duration::from_secs(2)).await;
+            SUPERVISOR.start();
+        } else if SUPERVISOR.is_running() {
+            SUPERVISOR.stop();
         }
+        tokio::time::sleep(Duration::from_secs(1)).await;
     }
 
     //signal::ctrl_c().await?;
@@ -805,11 +835,12 @@ static HTTPS_STATUS_CHANNEL: once_cell::sync::Lazy<broadcast::Sender<String>> =
 static LAST_AGENT_STATUS: Lazy<StdMutex<String>> = Lazy::new(|| StdMutex::new("Disconnected".to_string()));
 static LAST_HTTPS_STATUS: Lazy<StdMutex<String>> = Lazy::new(|| StdMutex::new("Disconnected".to_string()));
 
-fn broadcast_token_connected() {
-    let _ = AGENT_STATUS_CHANNEL.send("Connected".to_string());
-    let _ = HTTPS_STATUS_CHANNEL.send("Connected".to_string());
-    *LAST_AGENT_STATUS.lock().unwrap() = "Connected".to_string();
-    *LAST_HTTPS_STATUS.lock().unwrap() = "Connected".to_string();
+/// Reports the access token state ("Connected", "Refreshing" or "Disconnected") on `/ws/agent` and `/ws/https`
+fn broadcast_token_status(status: &str) {
+    let _ = AGENT_STATUS_CHANNEL.send(status.to_string());
+    let _ = HTTPS_STATUS_CHANNEL.send(status.to_string());
+    *LAST_AGENT_STATUS.lock().unwrap() = status.to_string();
+    *LAST_HTTPS_STATUS.lock().unwrap() = status.to_string();
 }
 
 // Agent connection status WebSocket
@@ -818,16 +849,11 @@ async fn send_agent_connection_status(mut socket: WebSocket) {
     // Send latest status
     let last = { LAST_AGENT_STATUS.lock().unwrap().clone() };
     let _ = socket.send(Message::text(json!({"agent": last}).to_string())).await;
-    loop {
-        match rx.recv().await {
-            Ok(status) => {
-                *LAST_AGENT_STATUS.lock().unwrap() = status.clone();
-                let msg = json!({"agent": status}).to_string();
-                if socket.send(Message::text(msg)).await.is_err() {
-                    break;
-                }
-            }
-            Err(_) => break,
+    while let Ok(status) = rx.recv().await {
+        *LAST_AGENT_STATUS.lock().unwrap() = status.clone();
+        let msg = json!({"agent": status}).to_string();
+        if socket.send(Message::text(msg)).await.is_err() {
+            break;
         }
     }
 }
@@ -837,16 +863,11 @@ async fn send_https_status(mut socket: WebSocket) {
     // Send latest status
     let last = { LAST_HTTPS_STATUS.lock().unwrap().clone() };
     let _ = socket.send(Message::text(json!({"https": last}).to_string())).await;
-    loop {
-        match rx.recv().await {
-            Ok(status) => {
-                *LAST_HTTPS_STATUS.lock().unwrap() = status.clone();
-                let msg = json!({"https": status}).to_string();
-                if socket.send(Message::text(msg)).await.is_err() {
-                    break;
-                }
-            }
-            Err(_) => break,
+    while let Ok(status) = rx.recv().await {
+        *LAST_HTTPS_STATUS.lock().unwrap() = status.clone();
+        let msg = json!({"https": status}).to_string();
+        if socket.send(Message::text(msg)).await.is_err() {
+            break;
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,219 @@
+use chrono::{Local, NaiveDateTime};
+use messages::{subjects, AgentResponse, Envelope};
+use models_database::db::{
+    dead_letter_count, dead_letter_outbox_item, delete_outbox_item, enqueue_outbox, next_due_outbox_item,
+    outbox_depth, reschedule_outbox_item,
+};
+use models_database::models::OutboxItem;
+use models_database::with_connection;
+use nats::publisher::NatsPublisher;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use shared_config::CONFIG;
+use std::error::Error;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::server_api::{scan_data_to_server, send_to_server};
+use crate::token_manager::with_access_token;
+use crate::backoff;
+use crate::commands;
+use crate::process_monitor_data;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// How often the worker looks for due calls when the outbox is idle
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Breather after a failed delivery so an unreachable server is not hammered with the rest of the queue
+const FAILURE_PAUSE: Duration = Duration::from_secs(5);
+
+/// A central-server call, kept in the outbox until the server has accepted it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum UpstreamCall {
+    /// Inventory upload; the collector is told once the server has stored it
+    Inventory { agent_id: String, inventory_id: String, inventory: String },
+    /// Monitoring batch; the server's answer may carry actions for the collector
+    Monitor { agent_id: String, payload: String },
+    /// Collection policy acknowledgement, reported on the monitoring channel
+    PolicyAck {
+        /// Missing from calls queued before each collector had its own credentials
+        #[serde(default)]
+        agent_id: String,
+        payload: String,
+    },
+    /// Rescan result for one part of the inventory
+    Scan { uuid: String, action: String, data: Value },
+    /// Outcome of a server command, reported on the monitoring channel
+    CommandResult {
+        #[serde(default)]
+        agent_id: String,
+        payload: String,
+    },
+}
+
+impl UpstreamCall {
+    fn kind(&self) -> &'static str {
+        match self {
+            UpstreamCall::Inventory { .. } => "inventory",
+            UpstreamCall::Monitor { .. } => "monitor",
+            UpstreamCall::PolicyAck { .. } => "policy_ack",
+            UpstreamCall::Scan { .. } => "scan",
+            UpstreamCall::CommandResult { .. } => "command_result",
+        }
+    }
+}
+
+/// Outbox depth reported by the bridge API
+#[derive(Debug, Serialize)]
+pub struct OutboxStats {
+    pub depth: i64,
+    pub oldest_age_secs: Option<i64>,
+    pub dead_letters: i64,
+}
+
+fn now() -> NaiveDateTime {
+    Local::now().naive_local()
+}
+
+fn format_time(time: NaiveDateTime) -> String {
+    time.format(TIMESTAMP_FORMAT).to_string()
+}
+
+/// Persists a call for the outbox worker; once this returns Ok the call survives a restart
+pub async fn enqueue(call: &UpstreamCall) -> Result<(), BoxError> {
+    let payload = serde_json::to_string(call)?;
+    let kind = call.kind();
+    with_connection(move |conn| Ok(enqueue_outbox(conn, kind, &payload, &format_time(now()))?)).await
+}
+
+pub async fn stats() -> Result<OutboxStats, BoxError> {
+    let (depth, oldest, dead_letters) = with_connection(|conn| {
+        let (depth, oldest) = outbox_depth(conn)?;
+        Ok((depth, oldest, dead_letter_count(conn)?))
+    })
+    .await?;
+    let oldest_age_secs = oldest
+        .and_then(|created| NaiveDateTime::parse_from_str(&created, TIMESTAMP_FORMAT).ok())
+        .map(|created| (now() - created).num_seconds().max(0));
+    Ok(OutboxStats { depth, oldest_age_secs, dead_letters })
+}
+
+/// Delivers queued calls oldest first, retrying failures with backoff until they are dead-lettered. A
+/// failed call holds back the later calls of its kind, so monitoring batches reach the server in order.
+pub async fn run_outbox_worker(publisher: NatsPublisher) -> Result<(), BoxError> {
+    info!("Upstream outbox worker started");
+    loop {
+        let item = with_connection(|conn| Ok(next_due_outbox_item(conn, &format_time(now()))?)).await?;
+        let Some(item) = item else {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            continue;
+        };
+        let call = match serde_json::from_str::<UpstreamCall>(&item.payload) {
+            Ok(call) => call,
+            Err(e) => {
+                error!("Outbox item {:?} is unreadable, dead-lettering it: {}", item.id, e);
+                dead_letter(item, e.to_string()).await?;
+                continue;
+            }
+        };
+
+        match deliver(&publisher, &call).await {
+            Ok(()) => {
+                let id = item.id.unwrap_or_default();
+                with_connection(move |conn| Ok(delete_outbox_item(conn, id)?)).await?;
+            }
+            Err(e) => {
+                let attempts = item.attempts as u32 + 1;
+                if attempts >= CONFIG.upstream_max_attempts {
+                    error!("Giving up on {} call after {} attempts: {}", call.kind(), attempts, e);
+                    dead_letter(item, e.clone()).await?;
+                    on_dead_letter(&publisher, &call, &e).await;
+                } else {
+                    let delay = backoff::delay(attempts, INITIAL_BACKOFF, MAX_BACKOFF);
+                    warn!("{} call failed (attempt {}), retrying in {:?}: {}", call.kind(), attempts, delay, e);
+                    let next_at = now() + chrono::Duration::from_std(delay).unwrap_or_else(|_| chrono::Duration::zero());
+                    let id = item.id.unwrap_or_default();
+                    let last_error = e.clone();
+                    with_connection(move |conn| {
+                        Ok(reschedule_outbox_item(conn, id, attempts as i32, &last_error, &format_time(next_at))?)
+                    })
+                    .await?;
+                }
+                tokio::time::sleep(FAILURE_PAUSE).await;
+            }
+        }
+    }
+}
+
+async fn dead_letter(item: OutboxItem, error: String) -> Result<(), BoxError> {
+    with_connection(move |conn| Ok(dead_letter_outbox_item(conn, &item, &error, &format_time(now()))?)).await
+}
+
+/// Makes one attempt at a call and handles the server's answer
+async fn deliver(publisher: &NatsPublisher, call: &UpstreamCall) -> Result<(), String> {
+    match call {
+        UpstreamCall::Inventory { agent_id, inventory_id, inventory } => {
+            let inventory = inventory.as_str();
+            let changes = with_access_token(agent_id, |token| async move { send_to_server(agent_id, inventory, &token).await })
+                .await
+                .map_err(|e| e.to_string())?;
+            let response = AgentResponse {
+                inventory_id: inventory_id.clone(),
+                stored: true,
+                message: format!(
+                    "Data stored successfully: {} added, {} changed, {} removed",
+                    changes.added.len(),
+                    changes.changed.len(),
+                    changes.removed.len()
+                ),
+            };
+            publish_inventory_response(publisher, agent_id, response).await;
+        }
+        UpstreamCall::Monitor { agent_id, payload } => {
+            let response = process_monitor_data(agent_id, payload).await.map_err(|e| e.to_string())?;
+            info!("Received monitor server response: {}", response);
+            commands::dispatch(publisher, agent_id, &response).await;
+        }
+        UpstreamCall::PolicyAck { agent_id, payload } | UpstreamCall::CommandResult { agent_id, payload } => {
+            // Both reports name their collector, so older calls can still be sent with its credentials
+            let agent_id = match agent_id.as_str() {
+                "" => reported_agent_id(payload).ok_or("report names no collector")?,
+                agent_id => agent_id.to_string(),
+            };
+            process_monitor_data(&agent_id, payload).await.map_err(|e| e.to_string())?;
+        }
+        UpstreamCall::Scan { uuid, action, data } => {
+            scan_data_to_server(data, uuid, action).await.map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+fn reported_agent_id(payload: &str) -> Option<String> {
+    let report = serde_json::from_str::<Value>(payload).ok()?;
+    report.get("agent_id")?.as_str().map(str::to_string)
+}
+
+/// Tells the collector its inventory will not reach the server, so it does not wait forever
+async fn on_dead_letter(publisher: &NatsPublisher, call: &UpstreamCall, error: &str) {
+    if let UpstreamCall::Inventory { agent_id, inventory_id, .. } = call {
+        let response = AgentResponse {
+            inventory_id: inventory_id.clone(),
+            stored: false,
+            message: format!("Server did not accept the inventory: {}", error),
+        };
+        publish_inventory_response(publisher, agent_id, response).await;
+    }
+}
+
+async fn publish_inventory_response(publisher: &NatsPublisher, agent_id: &str, response: AgentResponse) {
+    if let Err(e) = publisher.publish(&subjects::agent(agent_id, subjects::AGENT_RESPONSE), &Envelope::new(response)).await {
+        error!("Bridge: Failed to publish response: {:?}", e);
+    } else {
+        info!("Bridge: Response sent successfully");
+    }
+}
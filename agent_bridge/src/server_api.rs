@@ -1,40 +1,20 @@
-use serde::{Serialize,Deserialize};
 use tracing::{info, error,warn};
 use std::collections::HashMap;
-use reqwest::Client;
-use reqwest::Response;
 use serde_json::Value;
-use base64::engine::general_purpose;
-use base64::Engine;
 use shared_config::CONFIG;
 
 use models_database::db::{
-    establish_connection, get_agent_credential, initial_data_save, is_agent_onboarded, save_agent ,update_initial_data, get_token
+    get_agent_credential, initial_data_save, is_agent_onboarded, save_agent ,update_initial_data
 };
-use tokio_tungstenite::{connect_async_tls_with_config, Connector, WebSocketStream};
-use tokio_tungstenite::tungstenite::protocol::Message;
-type WSStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 use futures::SinkExt;
-use tokio::sync::Mutex;
-use tokio::net::TcpStream;
-use std::sync::Arc;
-
-use url::Url;
-use tokio_tungstenite::tungstenite::http::HeaderValue;
-use anyhow::{Result, Context};
-
-use native_tls::TlsConnector as NativeTlsConnector;
-use tungstenite::client::IntoClientRequest;
-use tokio_tungstenite::MaybeTlsStream;
-use futures_util::StreamExt;
-use warp::ws::Message as WarpMessage;
-use tokio::time::{self, Duration, timeout};
-use serde_json::json;
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::fs;
-use std::path::Path;
-use crate::create_publisher;
-use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+
+use crate::tls;
+use crate::ws_session::{SessionError, SessionState, SESSIONS};
+use messages::MasterKey;
+use models_database::{with_connection, ChangeSet};
+use std::sync::atomic::AtomicBool;
 use once_cell::sync::Lazy;
 
 fn base_url() -> &'static str {
@@ -42,43 +22,41 @@ fn base_url() -> &'static str {
 }
 
 
-lazy_static::lazy_static! {
-    static ref WS_CONNECTION: Arc<Mutex<Option<WSStream>>> = Arc::new(Mutex::new(None));
-}
+/// The central server rejected the access token (HTTP 401)
+#[derive(Debug)]
+pub struct Unauthorized;
 
-#[derive(Serialize,Deserialize, Debug)]
-struct MasterKeyPayload {
-    master_key: String,
-    hostname: String,
-    os: String,
-    os_version: String,
+impl std::fmt::Display for Unauthorized {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "access token rejected by the central server")
+    }
 }
 
-/// Submits the master key to the server for onboarding
-pub async fn send_master_key_to_server(received_payload: &str) -> Result<(), Box<dyn std::error::Error>> {
-    
-    let mut conn = establish_connection(&CONFIG.db_path);
+impl std::error::Error for Unauthorized {}
 
-    if is_agent_onboarded(&mut conn) {
+/// Submits a collector's master key to the server, which onboards the collector as an agent of its own
+pub async fn send_master_key_to_server(agent_id: &str, payload: &MasterKey) -> Result<(), Box<dyn std::error::Error>> {
+    let collector_id = agent_id.to_string();
+    let onboarded = with_connection(move |conn| Ok(is_agent_onboarded(conn, &collector_id))).await.map_err(|e| e.to_string())?;
+    if onboarded {
         println!("[INFO] Agent is already onboarded. Skipping server call.");
         info!("Agent already onboarded. Skipping master key submission.");
         return Ok(());
     }
 
-    let payload: MasterKeyPayload = serde_json::from_str(received_payload)?;
-
     let api_key = "1234567890abcdef1234567890abcdef";
 
     let central_server_url = base_url().to_string() + "/api/agent/onboard/";
 
-    let client = reqwest::Client::builder().danger_accept_invalid_certs(true).build()?;
+    let client = tls::http_client().map_err(|e| e.to_string())?;
 
     let response = client
         .post(central_server_url)
         .header("X-API-KEY", api_key)
-        .json(&payload)
+        .json(payload)
         .send()
-        .await?;
+        .await
+        .map_err(|e| tls::describe(&e))?;
 
     let status = response.status();
     let response_text = response.text().await?;
@@ -87,14 +65,15 @@ pub async fn send_master_key_to_server(received_payload: &str) -> Result<(), Box
     if status.is_success() {
         let parsed_response: models_database::db::ServerResponse = serde_json::from_str(&response_text)?;
 
-        match save_agent(&mut conn, &parsed_response) {
+        let collector_id = agent_id.to_string();
+        match with_connection(move |conn| Ok(save_agent(conn, &collector_id, &parsed_response)?)).await {
             Ok(_) => {
                 println!("[SUCCESS] Response saved to database!");
                 Ok(())
             }
             Err(e) => {
                 println!("[ERROR] Failed to save data: {}", e);
-                Err(Box::new(e))
+                Err(e.to_string().into())
             }
         }
     } else {
@@ -103,18 +82,17 @@ pub async fn send_master_key_to_server(received_payload: &str) -> Result<(), Box
     }
 }
 
-/// Retrieves a new access token using saved client credentials
-pub async fn get_new_access_token(token_type: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-    let mut conn = establish_connection(&CONFIG.db_path);
-
-    let credential = match get_agent_credential(&mut conn) {
+/// Retrieves a new access token using a collector's saved client credentials
+pub async fn get_new_access_token(agent_id: &str, token_type: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let collector_id = agent_id.to_string();
+    let credential = match with_connection(move |conn| Ok(get_agent_credential(conn, &collector_id))).await? {
         Some(cred) => cred,
         None => {
             println!("[ERROR] No agent credentials found in database.");
             return Err("No credentials found".into());
         }
     };
-    info!("Agent credentials found in database: {:?}", credential);
+    info!("Agent credentials found in database: uuid {}, client id {}", credential.uuid, credential.client_id);
 
     let mut form_data = HashMap::new();
     form_data.insert("grant_type".to_string(), "client_credentials".to_string());
@@ -127,14 +105,15 @@ pub async fn get_new_access_token(token_type: &str) -> Result<String, Box<dyn st
         _ => format!("{}/api/agent/get/jwt/", base_url()),
     };
 
-    let client = reqwest::Client::builder().danger_accept_invalid_certs(true).build()?;
+    let client = tls::http_client()?;
     let response = client
         .post(url)
         .header("Content-Type", "application/x-www-form-urlencoded")
         .header("uuid", credential.uuid)
         .form(&form_data)
         .send()
-        .await?;
+        .await
+        .map_err(|e| tls::describe(&e))?;
 
     let status = response.status();
     let body = response.text().await?;
@@ -148,95 +127,94 @@ pub async fn get_new_access_token(token_type: &str) -> Result<String, Box<dyn st
     }
 }
 
-pub async fn send_to_server(data: &str, token: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let mut conn = establish_connection(&CONFIG.db_path);
+/// The uuid the server assigned to a collector at onboarding, if it is onboarded
+async fn agent_uuid(agent_id: &str) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let collector_id = agent_id.to_string();
+    with_connection(move |conn| Ok(get_agent_credential(conn, &collector_id).map(|cred| cred.uuid))).await
+}
+
+/// Posts the collected inventory, which the server expects as a JSON-encoded string, and stores the
+/// server's answer; the result is what that changed in the bridge's copy of the inventory
+pub async fn send_to_server(agent_id: &str, inventory: &str, token: &str) -> Result<ChangeSet, Box<dyn std::error::Error + Send + Sync>> {
     let url = format!("{}/api/agent/init/data/", base_url());
-    let agent_uuid = match get_agent_credential(&mut conn) {
-        Some(cred) => cred.uuid,
+    let agent_uuid = match agent_uuid(agent_id).await? {
+        Some(uuid) => uuid,
         None => {
             println!("[ERROR] No agent UUID found in database.");
             return Err("No UUID found".into());
         }
     };
-    let client = Client::builder()
-        .danger_accept_invalid_certs(true)
-        .build()
-        .expect("Failed to create reqwest client with SSL validation");
+    let client = tls::http_client()?;
 
-
-    let json_data: serde_json::Value = serde_json::from_str(data).expect("Failed to parse JSON data");
     let response=client
                 .post(url)
                 .header("Content-Type", "application/json")
                 .header("Authorization", format!("Bearer {}", token))
                 .header("uuid", agent_uuid)
-                .json(&json_data)
+                .json(inventory)
                 .send()
-                .await?;
+                .await
+                .map_err(|e| tls::describe(&e))?;
     let status = response.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED {
+        return Err(Box::new(Unauthorized));
+    }
     let response_text = response.text().await?;
 
     if status.is_success() {
         info!("Response from server: {}", response_text);
         let json_data: Value = serde_json::from_str(&response_text)?;
-        match initial_data_save(&mut conn, &json_data) {
-            Ok(_) => {
-                info!("Response data stored successfully");
-                return Ok("Data stored successfully".to_string());
+        match with_connection(move |conn| Ok(initial_data_save(conn, &json_data)?)).await {
+            Ok(changes) => {
+                info!(
+                    "Response data stored successfully: {} added, {} changed, {} removed",
+                    changes.added.len(),
+                    changes.changed.len(),
+                    changes.removed.len()
+                );
+                if !changes.is_empty() {
+                    info!("Inventory changes: {}", serde_json::to_string(&changes).unwrap_or_default());
+                }
+                Ok(changes)
+            }
+            Err(e) => {
+                // The server has the inventory but the bridge does not; an error gets the call retried
+                error!("Failed to store the server's inventory response: {}", e);
+                Err(format!("inventory accepted by the server but not stored: {}", e).into())
             }
-            Err(e) => eprintln!("Error storing data: {:?}", e),
         }
     } else {
         error!(
             "[ERROR] Failed to send agent data. Status: {}",
             status
         );
+        Err(format!("server rejected agent data with status {}", status).into())
     }
-
-    Ok("Initial Data send to server not store in database".to_string())
 }
 
 
-pub async fn send_to_monitor_server(data: &str, access_token: &str) -> Result<String, String> {
-    let mut conn = establish_connection(&CONFIG.db_path);
+/// Sends monitoring data over the collector's WebSocket session, or over HTTPS only while the session is down
+pub async fn send_to_monitor_server(agent_id: &str, data: &str, access_token: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    match SESSIONS.session(agent_id).request(data).await {
+        Ok(response) => return Ok(response),
+        Err(SessionError::Down) => warn!("WebSocket session is down. Falling back to HTTPS."),
+        Err(e) => return Err(Box::new(e)),
+    }
 
-    let agent_uuid = match get_agent_credential(&mut conn) {
-        Some(cred) => cred.uuid,
+    let agent_uuid = match agent_uuid(agent_id).await? {
+        Some(uuid) => uuid,
         None => return Err("No UUID found".into()),
     };
-
-    match send_via_websocket(data, access_token, &agent_uuid).await {
-        Ok(response) => Ok(response), 
-        Err(e) => {
-            warn!("WebSocket failed: {}. Falling back to HTTPS.", e);
-            tokio::time::sleep(std::time::Duration::from_secs(2)).await;
-            match send_via_https(data, access_token, &agent_uuid).await {
-                Ok(response) => {
-                    match web_socket_connection(access_token, &agent_uuid).await {
-                        Ok(ws_stream) => {
-                             let mut ws_stream_lock = WS_CONNECTION.lock().await;
-                            *ws_stream_lock = Some(ws_stream);
-                             println!("WebSocket reconnected successfully!");
-                        },
-                        Err(reconnect_error) => {
-                            warn!("WebSocket reconnect failed after HTTPS. {}", reconnect_error);
-                        }
-                    }
-                    
-                    Ok(response)
-                },
-                
-                Err(e) => Err(format!("Both WebSocket and HTTPS failed: {}", e))
-            }
-        }
+    match send_via_https(data, access_token, &agent_uuid).await {
+        Ok(response) => Ok(response),
+        Err(e) if e.is::<Unauthorized>() => Err(Box::new(Unauthorized)),
+        Err(e) => Err(format!("WebSocket session down and HTTPS failed: {}", e).into()),
     }
 }
 
 // Function to send data via HTTPS
 async fn send_via_https(data: &str, access_token: &str, agent_uuid: &str) -> Result<String, anyhow::Error> {
-    let client = Client::builder()
-        .danger_accept_invalid_certs(true)
-        .build()?;
+    let client = tls::http_client().map_err(|e| anyhow::anyhow!(e))?;
     let bridge_url = format!("{}/api/agent/bridge/", base_url());
 
     println!("Sending data to the HTTPS server");
@@ -248,81 +226,20 @@ async fn send_via_https(data: &str, access_token: &str, agent_uuid: &str) -> Res
         .header("uuid", agent_uuid)
         .body(data.to_string())
         .send()
-        .await?;
+        .await
+        .map_err(|e| anyhow::anyhow!(tls::describe(&e)))?;
 
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(Unauthorized.into());
+        }
         let text_response = response.text().await?;
         Ok(text_response)
 }
 
-// Function to send data via WebSocket
-// 
-
-async fn send_via_websocket(data: &str, access_token: &str, agent_uuid: &str) -> Result<String, anyhow::Error> {
-    let mut ws_stream_lock = WS_CONNECTION.lock().await;
-
-    // If no WebSocket connection exists, create a new one
-    if ws_stream_lock.is_none() {
-        *ws_stream_lock = Some(web_socket_connection(access_token, agent_uuid).await?);
-    }
-
-    // Get the existing WebSocket stream (which is now guaranteed to be Some)
-    let mut _ws_stream = ws_stream_lock.as_mut().unwrap();
-
-    _ws_stream
-        .send(Message::Text(data.to_string()))
-        .await
-        .context("Failed to send message")?;
-    println!("Data sent");
-
-    if let Some(msg) = _ws_stream.next().await {
-            match msg {
-                Ok(Message::Text(response)) => {
-                    Ok(response)  
-                },
-            Ok(other) => {
-                println!("Received non-text WebSocket message: {:?}", other);
-                Err(anyhow::anyhow!("Unexpected WebSocket message type: {:?}", other))
-            },
-            Err(e) => {
-                eprintln!("Error receiving WebSocket message: {:?}", e);
-                Err(anyhow::anyhow!("WebSocket receive error: {:?}", e))
-            }
-        }
-    } else {
-        Err(anyhow::anyhow!("No response received over WebSocket"))
-    }
-}
-
-async fn web_socket_connection(access_token: &str, agent_uuid: &str) -> Result<WSStream> {
-    let url = Url::parse(&format!("{}/api/agent/bridge/", CONFIG.web_socket_url)).unwrap();
-    let mut req = url.clone().into_client_request().unwrap();
-
-    let headers = req.headers_mut();
-    headers.insert("uuid", HeaderValue::from_str(agent_uuid).expect("Invalid UUID header"));
-    headers.insert("access-token", HeaderValue::from_str(access_token).expect("Invalid access-token header"));
-    headers.insert("Content-Type", HeaderValue::from_static("application/json"));
-
-    let native_tls = NativeTlsConnector::builder()
-        .danger_accept_invalid_certs(true)
-        .build()
-        .expect("Failed to build native TLS connector");
-    let connector = Some(Connector::NativeTls(native_tls));
-
-    let (stream, _) = connect_async_tls_with_config(req, None, false, connector)
-        .await
-        .expect("WebSocket connection failed");
-
-    println!("WebSocket connected!");
-    Ok(stream)
-}
-
-pub async fn scan_data_to_server(data: &Value, uuid: &str,action :&str) -> Result<(), Box<dyn std::error::Error>> {
-    let mut conn = establish_connection(&CONFIG.db_path);
+pub async fn scan_data_to_server(data: &Value, uuid: &str,action :&str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let action = if action == "partition" { "disk" } else { action };
     let url = format!("{}/api/agent/init/data/{}/{}/", base_url(),uuid,action);
-    let client = reqwest::Client::builder()
-        .danger_accept_invalid_certs(true)
-        .build()?;
+    let client = tls::http_client()?;
     println!("[INFO] Sending data to server: {:?}", data);
 
     let response = client
@@ -330,29 +247,29 @@ pub async fn scan_data_to_server(data: &Value, uuid: &str,action :&str) -> Resul
         .header("Content-Type", "application/json")
         .json(&data)
         .send()
-        .await?;
+        .await
+        .map_err(|e| tls::describe(&e))?;
 
     if response.status().is_success() {
         println!("[INFO] Data sent successfully to server.");
         let response_text = response.text().await?;
         let json_data: Value = serde_json::from_str(&response_text)?;
         println!("[INFO] JSON data parsed successfully: {:?}", json_data);
-        match update_initial_data(&mut conn, &action, &json_data){
+        let stored_action = action.to_string();
+        match with_connection(move |conn| Ok(update_initial_data(conn, &stored_action, &json_data)?)).await {
             Ok(_) => {
                 println!("[INFO] Response updated data stored successfully");
-                return Ok(());
+                Ok(())
+            }
+            Err(e) => {
+                error!("Failed to store the server's scan response: {}", e);
+                Err(format!("scan accepted by the server but not stored: {}", e).into())
             }
-            Err(e) => eprintln!("[ERROR] Error storing data: {:?}", e),
         }
-      
     } else {
         println!("[ERROR] Failed to send data to server. Status: {}", response.status());
+        Err(format!("server rejected scan data with status {}", response.status()).into())
     }
-    
-
-    Ok(())
-    
-
 }
 
 /// Function to check and send WSS connection status to frontend
@@ -361,49 +278,15 @@ pub async fn send_wss_status(mut socket: warp::ws::WebSocket) {
     use tokio::time::{self, Duration};
     use warp::ws::Message as WarpMessage;
 
-    // Send initial waiting status
-    let initial_status = json!({ "wss": "Waiting" });
-    if socket.send(WarpMessage::text(initial_status.to_string())).await.is_err() {
-        return;
-    }
-
-    // Wait for token to be available
-    while !is_token_available() {
-        tokio::time::sleep(Duration::from_secs(1)).await;
-        // Send waiting status periodically
-        let waiting_status = json!({ "wss": "Waiting" });
-        if socket.send(WarpMessage::text(waiting_status.to_string())).await.is_err() {
-            return;
-        }
-    }
-
     let mut interval = time::interval(Duration::from_secs(5)); // Check every 5 seconds
 
     loop {
         interval.tick().await;
 
-        // Get agent credentials and token for WSS connection test
-        let mut conn = establish_connection(&CONFIG.db_path);
-        let (agent_uuid, access_token) = match (
-            get_agent_credential(&mut conn),
-            get_token(&mut conn, "access_token")
-        ) {
-            (Some(cred), Some(token)) => (cred.uuid, token.token),
-            _ => {
-                // If we can't get credentials, send waiting status
-                let status = json!({ "wss": "Waiting" });
-                if socket.send(WarpMessage::text(status.to_string())).await.is_err() {
-                    return;
-                }
-                continue;
-            }
-        };
-
-        // Use the monitoring status (replace with NATS-based check if available)
-        let wss_status = if MONITORING_RUNNING.load(Ordering::SeqCst) {
-            "Connected"
-        } else {
-            "Waiting"
+        let wss_status = match SESSIONS.state() {
+            SessionState::Up => "Connected",
+            SessionState::Connecting | SessionState::Down => "Reconnecting",
+            SessionState::Waiting => "Waiting",
         };
         let status = json!({ "wss": wss_status });
         if socket.send(WarpMessage::text(status.to_string())).await.is_err() {
@@ -412,18 +295,5 @@ pub async fn send_wss_status(mut socket: warp::ws::WebSocket) {
     }
 }
 
-// Add static TOKEN_AVAILABLE for token status
-static TOKEN_AVAILABLE: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
-
-// Add public function to set token availability
-pub fn set_token_available(available: bool) {
-    TOKEN_AVAILABLE.store(available, Ordering::SeqCst);
-}
-
-// Add public function to check token availability
-pub fn is_token_available() -> bool {
-    TOKEN_AVAILABLE.load(Ordering::SeqCst)
-}
-
 // Add a static for monitoring status
 pub static MONITORING_RUNNING: Lazy<AtomicBool> = Lazy::new(|| AtomicBool::new(false));
@@ -0,0 +1,320 @@
+use chrono::Local;
+use nats::jetstream::ensure_streams;
+use nats::publisher::NatsPublisher;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::outbox::run_outbox_worker;
+use crate::{backoff, create_publisher, create_subscriber, ws_session};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+type HandlerFuture = Pin<Box<dyn Future<Output = Result<(), BoxError>> + Send>>;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A handler that ran this long before failing starts over from the initial backoff
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Owns the bridge's NATS handlers and the upstream outbox worker
+pub static SUPERVISOR: Lazy<Supervisor> = Lazy::new(|| Supervisor::new(HANDLERS));
+
+/// Connections shared by every handler of one generation
+#[derive(Clone)]
+pub struct HandlerContext {
+    publisher: NatsPublisher,
+}
+
+impl HandlerContext {
+    async fn connect() -> Result<Self, BoxError> {
+        let publisher = create_publisher().await?;
+        let subscriber = create_subscriber().await?;
+        ensure_streams(subscriber.lock().await.client()).await?;
+        Ok(Self { publisher })
+    }
+}
+
+struct Handler {
+    name: &'static str,
+    run: fn(HandlerContext) -> HandlerFuture,
+}
+
+const HANDLERS: &[Handler] = &[
+    Handler {
+        name: "master_key",
+        run: |ctx| Box::pin(async move { crate::handle_master_key_operations(create_subscriber().await?, ctx.publisher).await }),
+    },
+    Handler {
+        name: "credentials",
+        run: |ctx| Box::pin(async move { crate::handle_credential_requests(create_subscriber().await?, ctx.publisher).await }),
+    },
+    Handler {
+        name: "agent_data",
+        run: |_| Box::pin(async move { crate::handle_agent_data_operations(create_subscriber().await?).await }),
+    },
+    Handler {
+        name: "monitor_data",
+        run: |_| Box::pin(async move { crate::handle_monitor_data_operations(create_subscriber().await?).await }),
+    },
+    Handler {
+        name: "policy_ack",
+        run: |_| Box::pin(async move { crate::handle_policy_ack_operations(create_subscriber().await?).await }),
+    },
+    Handler {
+        name: "outbox_worker",
+        run: |ctx| Box::pin(run_outbox_worker(ctx.publisher)),
+    },
+    Handler {
+        name: "server_push",
+        run: |ctx| Box::pin(crate::handle_server_push_operations(ctx.publisher)),
+    },
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HandlerState {
+    /// Waiting for the shared NATS and upstream connections
+    Connecting,
+    Running,
+    /// Failed; restarting after a backoff
+    Backoff,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HandlerHealth {
+    pub name: &'static str,
+    pub state: HandlerState,
+    /// Restarts after a failure or on request, over the life of the process
+    pub restarts: u32,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub last_failure_at: Option<String>,
+    pub running_since: Option<String>,
+    pub next_attempt_at: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SupervisorHealth {
+    /// Bumped on every start, so a full restart is visible to callers
+    pub generation: u64,
+    pub full_restarts: u32,
+    pub handlers: Vec<HandlerHealth>,
+}
+
+struct Shared {
+    generation: u64,
+    full_restarts: u32,
+    handlers: Vec<HandlerHealth>,
+}
+
+pub struct Supervisor {
+    handlers: &'static [Handler],
+    shared: Mutex<Shared>,
+    /// One per handler, to restart it without touching the others
+    restart_signals: Vec<Notify>,
+    current: Mutex<Option<JoinHandle<()>>>,
+}
+
+fn now() -> String {
+    Local::now().format(TIMESTAMP_FORMAT).to_string()
+}
+
+fn time_after(delay: Duration) -> String {
+    let delay = chrono::Duration::from_std(delay).unwrap_or_else(|_| chrono::Duration::zero());
+    (Local::now() + delay).format(TIMESTAMP_FORMAT).to_string()
+}
+
+fn backoff(failures: u32) -> Duration {
+    backoff::delay(failures, INITIAL_BACKOFF, MAX_BACKOFF)
+}
+
+impl Supervisor {
+    fn new(handlers: &'static [Handler]) -> Self {
+        let health = handlers
+            .iter()
+            .map(|handler| HandlerHealth {
+                name: handler.name,
+                state: HandlerState::Stopped,
+                restarts: 0,
+                consecutive_failures: 0,
+                last_error: None,
+                last_failure_at: None,
+                running_since: None,
+                next_attempt_at: None,
+            })
+            .collect();
+        Self {
+            handlers,
+            shared: Mutex::new(Shared { generation: 0, full_restarts: 0, handlers: health }),
+            restart_signals: handlers.iter().map(|_| Notify::new()).collect(),
+            current: Mutex::new(None),
+        }
+    }
+
+    pub fn health(&self) -> SupervisorHealth {
+        let shared = self.shared.lock().unwrap();
+        SupervisorHealth {
+            generation: shared.generation,
+            full_restarts: shared.full_restarts,
+            handlers: shared.handlers.clone(),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.current.lock().unwrap().is_some()
+    }
+
+    /// Connects and starts every handler; a no-op when already running
+    pub fn start(&'static self) {
+        let mut current = self.current.lock().unwrap();
+        if current.is_some() {
+            return;
+        }
+        let generation = {
+            let mut shared = self.shared.lock().unwrap();
+            shared.generation += 1;
+            for health in &mut shared.handlers {
+                health.state = HandlerState::Connecting;
+                health.consecutive_failures = 0;
+                health.running_since = None;
+                health.next_attempt_at = None;
+            }
+            shared.generation
+        };
+        info!("Starting bridge handlers (generation {})", generation);
+        *current = Some(tokio::spawn(self.run_generation()));
+    }
+
+    /// Stops every handler and drops the connections they shared
+    pub fn stop(&self) {
+        let Some(task) = self.current.lock().unwrap().take() else {
+            return;
+        };
+        task.abort();
+        for health in &mut self.shared.lock().unwrap().handlers {
+            health.state = HandlerState::Stopped;
+            health.running_since = None;
+            health.next_attempt_at = None;
+        }
+        info!("Bridge handlers stopped");
+    }
+
+    /// Tears everything down and starts over with new NATS connections and upstream sessions
+    pub fn restart(&'static self) -> u64 {
+        info!("Full bridge restart requested");
+        self.stop();
+        self.shared.lock().unwrap().full_restarts += 1;
+        ws_session::SESSIONS.reconnect();
+        self.start();
+        self.shared.lock().unwrap().generation
+    }
+
+    /// Restarts one handler at once, skipping any pending backoff; false if there is no such handler
+    pub fn restart_handler(&self, name: &str) -> bool {
+        let Some(index) = self.handlers.iter().position(|handler| handler.name == name) else {
+            return false;
+        };
+        info!("Restart of handler {} requested", name);
+        // Only a handler waiting on the signal is restarted; a stored permit would restart it again later
+        self.restart_signals[index].notify_waiters();
+        true
+    }
+
+    fn update(&self, index: usize, change: impl FnOnce(&mut HandlerHealth)) {
+        change(&mut self.shared.lock().unwrap().handlers[index]);
+    }
+
+    async fn run_generation(&'static self) {
+        let mut failures = 0;
+        let ctx = loop {
+            match HandlerContext::connect().await {
+                Ok(ctx) => break ctx,
+                Err(e) => {
+                    failures += 1;
+                    let delay = backoff(failures);
+                    error!("Bridge handlers cannot connect, retrying in {:?}: {}", delay, e);
+                    let error = e.to_string();
+                    for index in 0..self.handlers.len() {
+                        self.update(index, |health| {
+                            health.last_error = Some(error.clone());
+                            health.next_attempt_at = Some(time_after(delay));
+                        });
+                    }
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        };
+
+        // Dropping the set when this task is aborted aborts every handler with it
+        let mut handlers = JoinSet::new();
+        for index in 0..self.handlers.len() {
+            handlers.spawn(self.supervise(index, ctx.clone()));
+        }
+        while handlers.join_next().await.is_some() {}
+    }
+
+    /// Runs one handler forever, restarting it with backoff whenever it fails or returns
+    async fn supervise(&'static self, index: usize, ctx: HandlerContext) {
+        let handler = &self.handlers[index];
+        loop {
+            self.update(index, |health| {
+                health.state = HandlerState::Running;
+                health.running_since = Some(now());
+                health.next_attempt_at = None;
+            });
+            let started = Instant::now();
+
+            let (error, requested) = tokio::select! {
+                // Everything the handler logs carries its name
+                result = (handler.run)(ctx.clone()).instrument(info_span!("handler", handler = handler.name)) => match result {
+                    Ok(()) => ("handler exited".to_string(), false),
+                    Err(e) => (e.to_string(), false),
+                },
+                _ = self.restart_signals[index].notified() => (String::new(), true),
+            };
+
+            if requested {
+                info!("Restarting handler {}", handler.name);
+                self.update(index, |health| {
+                    health.restarts += 1;
+                    health.consecutive_failures = 0;
+                });
+                continue;
+            }
+
+            let mut failures = 0;
+            self.update(index, |health| {
+                if started.elapsed() >= STABLE_AFTER {
+                    health.consecutive_failures = 0;
+                }
+                health.consecutive_failures += 1;
+                failures = health.consecutive_failures;
+            });
+            let delay = backoff(failures);
+            warn!("Handler {} stopped ({}), restarting in {:?}", handler.name, error, delay);
+            self.update(index, |health| {
+                health.state = HandlerState::Backoff;
+                health.restarts += 1;
+                health.last_error = Some(error);
+                health.last_failure_at = Some(now());
+                health.running_since = None;
+                health.next_attempt_at = Some(time_after(delay));
+            });
+
+            // A restart request cuts the backoff short
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = self.restart_signals[index].notified() => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use base64::engine::general_purpose;
+use base64::Engine;
+use once_cell::sync::Lazy;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use shared_config::CONFIG;
+use std::error::Error;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use tracing::warn;
+use url::Url;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// TLS settings for the central server, built once from `CONFIG` and shared by HTTPS and WSS
+static CENTRAL_TLS: Lazy<Result<Arc<ClientConfig>, String>> = Lazy::new(|| {
+    central_client_config(CONFIG.central_ca_path.as_deref(), &CONFIG.central_spki_pins, CONFIG.central_tls_insecure)
+        .map(Arc::new)
+        .map_err(|e| format!("invalid central server TLS configuration: {}", e))
+});
+
+/// Client TLS config that trusts `ca_path` (or the bundled web PKI roots) and, if any pins are
+/// given, also requires the server key to match one of them.
+///
+/// `insecure` skips chain and hostname checks; pins are still enforced, which allows pinning a
+/// self-signed server certificate.
+pub fn central_client_config(ca_path: Option<&str>, pins: &[String], insecure: bool) -> Result<ClientConfig, BoxError> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let pins = pins.iter().map(|pin| parse_pin(pin)).collect::<Result<Vec<_>, _>>()?;
+    let chain = if insecure {
+        warn!("Central server certificate verification is DISABLED (CENTRAL_TLS_INSECURE)");
+        None
+    } else {
+        let mut roots = RootCertStore::empty();
+        match ca_path {
+            Some(path) => {
+                for cert in CertificateDer::pem_file_iter(path).map_err(|e| format!("cannot read CA bundle {}: {}", path, e))? {
+                    roots.add(cert.map_err(|e| format!("bad certificate in {}: {}", path, e))?)?;
+                }
+                if roots.is_empty() {
+                    return Err(format!("CA bundle {} contains no certificates", path).into());
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        Some(WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()?)
+    };
+
+    let verifier = CentralServerVerifier { chain, pins, provider: provider.clone() };
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(config)
+}
+
+/// Pins are base64 SHA-256 digests of the server's SubjectPublicKeyInfo, optionally prefixed `sha256//`
+fn parse_pin(pin: &str) -> Result<[u8; 32], BoxError> {
+    let encoded = pin.strip_prefix("sha256//").unwrap_or(pin);
+    let digest = general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|e| format!("SPKI pin {:?} is not base64: {}", pin, e))?;
+    digest
+        .try_into()
+        .map_err(|_| format!("SPKI pin {:?} is not a SHA-256 digest", pin).into())
+}
+
+fn spki_digest(cert: &CertificateDer<'_>) -> Result<[u8; 32], rustls::Error> {
+    let cert = webpki::EndEntityCert::try_from(cert).map_err(|e| rustls::Error::General(format!("cannot parse server certificate: {}", e)))?;
+    let spki = cert.subject_public_key_info();
+    let digest = ring::digest::digest(&ring::digest::SHA256, spki.as_ref());
+    let mut out = [0u8; 32];
+    out.copy_from_slice(digest.as_ref());
+    Ok(out)
+}
+
+#[derive(Debug)]
+struct CentralServerVerifier {
+    /// `None` when verification was explicitly disabled
+    chain: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<[u8; 32]>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for CentralServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(chain) = &self.chain {
+            chain.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        if !self.pins.is_empty() {
+            let digest = spki_digest(end_entity)?;
+            if !self.pins.contains(&digest) {
+                return Err(rustls::Error::General(format!(
+                    "central server certificate for {} does not match any pinned SPKI hash (server key is sha256//{})",
+                    server_name.to_str(),
+                    general_purpose::STANDARD.encode(digest)
+                )));
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn central_tls() -> Result<Arc<ClientConfig>, BoxError> {
+    CENTRAL_TLS.clone().map_err(Into::into)
+}
+
+/// HTTPS client for the central server
+pub fn http_client() -> Result<reqwest::Client, BoxError> {
+    let config = central_tls()?;
+    Ok(reqwest::Client::builder().use_preconfigured_tls((*config).clone()).build()?)
+}
+
+/// Opens the TLS connection a `wss://` WebSocket to the central server runs over
+pub async fn connect_wss(url: &Url) -> Result<TlsStream<TcpStream>, BoxError> {
+    if url.scheme() != "wss" {
+        return Err(format!("central server WebSocket URL must use wss://, got {}", url).into());
+    }
+    let host = url.host_str().ok_or("central server WebSocket URL has no host")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(443);
+    let server_name = ServerName::try_from(host.to_string())?;
+
+    let tcp = TcpStream::connect((host, port)).await?;
+    let stream = TlsConnector::from(central_tls()?)
+        .connect(server_name, tcp)
+        .await
+        .map_err(|e| format!("TLS handshake with {} failed: {}", host, e))?;
+    Ok(stream)
+}
+
+/// Error text including its causes; reqwest hides TLS failures such as a pin mismatch in the source chain
+pub fn describe(error: &(dyn Error + 'static)) -> String {
+    let mut text = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        text.push_str(": ");
+        text.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::PrivateKeyDer;
+    use rustls::ServerConfig;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    /// A CA, a `localhost` certificate it signed, and an unrelated CA; made with openssl, valid for 100 years
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn server_cert() -> CertificateDer<'static> {
+        CertificateDer::from_pem_file(fixture("server.pem")).unwrap()
+    }
+
+    fn server_pin() -> String {
+        format!("sha256//{}", general_purpose::STANDARD.encode(spki_digest(&server_cert()).unwrap()))
+    }
+
+    /// Serves TLS as `localhost` on an ephemeral port until the test ends
+    async fn test_server() -> u16 {
+        let key = PrivateKeyDer::from_pem_file(fixture("server.key")).unwrap();
+        let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![server_cert()], key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let _ = acceptor.accept(tcp).await;
+                });
+            }
+        });
+        port
+    }
+
+    async fn handshake(config: ClientConfig) -> Result<(), String> {
+        let port = test_server().await;
+        let tcp = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        TlsConnector::from(Arc::new(config)).connect(server_name, tcp).await.map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    #[tokio::test]
+    async fn server_signed_by_the_configured_ca_is_trusted() {
+        let config = central_client_config(Some(&fixture("ca.pem")), &[], false).unwrap();
+        handshake(config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn server_signed_by_another_ca_is_rejected() {
+        let config = central_client_config(Some(&fixture("other-ca.pem")), &[], false).unwrap();
+        assert!(handshake(config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pin_mismatch_fails_closed_even_with_a_trusted_chain() {
+        let wrong_pin = format!("sha256//{}", general_purpose::STANDARD.encode([0u8; 32]));
+        let config = central_client_config(Some(&fixture("ca.pem")), &[wrong_pin], false).unwrap();
+        let error = handshake(config).await.unwrap_err();
+        assert!(error.contains("does not match any pinned SPKI hash"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn pinned_certificate_is_trusted_with_chain_checks_disabled() {
+        let config = central_client_config(None, &[server_pin()], true).unwrap();
+        handshake(config).await.unwrap();
+
+        let wrong_pin = format!("sha256//{}", general_purpose::STANDARD.encode([0u8; 32]));
+        let config = central_client_config(None, &[wrong_pin], true).unwrap();
+        assert!(handshake(config).await.is_err());
+    }
+
+    #[test]
+    fn malformed_pins_are_rejected() {
+        assert!(central_client_config(None, &["not base64!".to_string()], true).is_err());
+        assert!(central_client_config(None, &[general_purpose::STANDARD.encode([0u8; 16])], true).is_err());
+    }
+
+    #[tokio::test]
+    async fn plain_websocket_urls_are_rejected() {
+        let error = connect_wss(&Url::parse("ws://localhost:1/ws").unwrap()).await.unwrap_err();
+        assert!(error.to_string().contains("must use wss://"), "{}", error);
+    }
+}
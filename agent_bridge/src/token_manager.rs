@@ -0,0 +1,308 @@
+use chrono::{Duration as ChronoDuration, Local, NaiveDateTime};
+use models_database::db::{get_agent_credential, get_token, onboarded_agent_ids, save_token};
+use models_database::with_connection;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::broadcast_token_status;
+use crate::server_api::{get_new_access_token, Unauthorized};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+const TOKEN_TYPE: &str = "access_token";
+const EXPIRATION_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+/// Tokens are renewed this long before they expire
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+/// Wait before the background task tries again after a failed refresh or before onboarding
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Shortest wait between two renewals by the background task, however short-lived the tokens are
+const MIN_RENEWAL_DELAY: Duration = Duration::from_secs(10);
+
+/// The bridge's access tokens for the central server, one per onboarded collector, shared by every handler
+pub static TOKENS: Lazy<TokenManager> = Lazy::new(TokenManager::new);
+
+struct CachedToken {
+    token: String,
+    /// `REFRESH_MARGIN` before expiry, or halfway through the lifetime of a token that lives less
+    /// than twice the margin, so a fresh token is never due at once
+    renew_at: NaiveDateTime,
+}
+
+impl CachedToken {
+    fn new(token: String, issued_at: NaiveDateTime, expires_at: NaiveDateTime) -> Self {
+        let margin = ChronoDuration::from_std(REFRESH_MARGIN).unwrap_or_else(|_| ChronoDuration::zero());
+        let lifetime = (expires_at - issued_at).max(ChronoDuration::zero());
+        Self { token, renew_at: expires_at - margin.min(lifetime / 2) }
+    }
+
+    fn is_due(&self, now: NaiveDateTime) -> bool {
+        self.renew_at <= now
+    }
+
+    /// How long the background task waits before renewing this token
+    fn renewal_delay(&self, now: NaiveDateTime) -> Duration {
+        (self.renew_at - now).to_std().unwrap_or(Duration::ZERO).max(MIN_RENEWAL_DELAY)
+    }
+}
+
+/// Keeps an access token per collector, since each collector is an agent of its own on the central server
+pub struct TokenManager {
+    agents: StdMutex<HashMap<String, Arc<AgentToken>>>,
+}
+
+impl TokenManager {
+    fn new() -> Self {
+        Self { agents: StdMutex::new(HashMap::new()) }
+    }
+
+    /// The token slot of a collector, created with its refresh task on first use
+    fn agent(&self, agent_id: &str) -> Arc<AgentToken> {
+        let mut agents = self.agents.lock().unwrap();
+        if let Some(agent) = agents.get(agent_id) {
+            return agent.clone();
+        }
+        let agent = Arc::new(AgentToken { agent_id: agent_id.to_string(), cached: Mutex::new(None) });
+        agents.insert(agent_id.to_string(), agent.clone());
+        tokio::spawn(agent.clone().refresh_loop());
+        agent
+    }
+
+    /// Valid access token of a collector, fetched first if there is none or it is about to expire
+    pub async fn access_token(&self, agent_id: &str) -> Result<String, BoxError> {
+        self.agent(agent_id).access_token().await
+    }
+
+    /// Replaces a token the server rejected, unless another caller already has
+    pub async fn refresh_rejected(&self, agent_id: &str, rejected: &str) -> Result<String, BoxError> {
+        self.agent(agent_id).refresh_rejected(rejected).await
+    }
+
+    /// First token after onboarding; returns the raw token response the collector is sent
+    pub async fn issue_initial(&self, agent_id: &str) -> Result<String, BoxError> {
+        self.agent(agent_id).issue_initial().await
+    }
+
+    /// Whether a token is held or stored for a collector, without fetching one
+    pub async fn has_token(&self, agent_id: &str) -> bool {
+        self.agent(agent_id).has_token().await
+    }
+
+    /// Starts renewing the tokens of every onboarded collector ahead of expiry, so handlers never
+    /// wait on a refresh; collectors onboarded later get theirs on first use
+    pub fn spawn_refresh_task(&'static self) {
+        tokio::spawn(async move {
+            match with_connection(|conn| Ok(onboarded_agent_ids(conn)?)).await {
+                Ok(agent_ids) => {
+                    for agent_id in agent_ids {
+                        self.agent(&agent_id);
+                    }
+                }
+                Err(e) => error!("Cannot read onboarded collectors: {}", e),
+            }
+        });
+    }
+}
+
+/// Caches a collector's access token in memory and renews it before it expires.
+///
+/// The lock is held for the whole refresh, so concurrent callers wait for one
+/// fetch instead of each asking the server for a new token.
+struct AgentToken {
+    agent_id: String,
+    cached: Mutex<Option<CachedToken>>,
+}
+
+impl AgentToken {
+    async fn access_token(&self) -> Result<String, BoxError> {
+        let mut cached = self.cached.lock().await;
+        if cached.is_none() {
+            *cached = load_from_db(&self.agent_id).await;
+        }
+        match cached.as_ref() {
+            Some(current) if !current.is_due(Local::now().naive_local()) => Ok(current.token.clone()),
+            _ => self.refresh_locked(&mut cached, TOKEN_TYPE).await.map(|(token, _)| token),
+        }
+    }
+
+    async fn refresh_rejected(&self, rejected: &str) -> Result<String, BoxError> {
+        let mut cached = self.cached.lock().await;
+        if let Some(current) = cached.as_ref()
+            && current.token != rejected
+            && !current.is_due(Local::now().naive_local())
+        {
+            return Ok(current.token.clone());
+        }
+        warn!(agent_uuid = %self.agent_id, "Central server rejected the access token, refreshing");
+        *cached = None;
+        self.refresh_locked(&mut cached, TOKEN_TYPE).await.map(|(token, _)| token)
+    }
+
+    async fn issue_initial(&self) -> Result<String, BoxError> {
+        let mut cached = self.cached.lock().await;
+        self.refresh_locked(&mut cached, "token").await.map(|(_, body)| body)
+    }
+
+    async fn has_token(&self) -> bool {
+        let mut cached = self.cached.lock().await;
+        if cached.is_none() {
+            *cached = load_from_db(&self.agent_id).await;
+        }
+        cached.is_some()
+    }
+
+    async fn refresh_locked(&self, cached: &mut Option<CachedToken>, token_type: &str) -> Result<(String, String), BoxError> {
+        broadcast_token_status("Refreshing");
+        match fetch(&self.agent_id, token_type).await {
+            Ok((fresh, body)) => {
+                let token = fresh.token.clone();
+                *cached = Some(fresh);
+                broadcast_token_status("Connected");
+                Ok((token, body))
+            }
+            Err(e) => {
+                error!(agent_uuid = %self.agent_id, "Failed to fetch access token: {}", e);
+                *cached = None;
+                broadcast_token_status("Disconnected");
+                Err(e)
+            }
+        }
+    }
+
+    /// When the cached token is due for renewal, if there is one
+    async fn refresh_due_in(&self) -> Option<Duration> {
+        let cached = self.cached.lock().await;
+        Some(cached.as_ref()?.renewal_delay(Local::now().naive_local()))
+    }
+
+    /// Renews the token ahead of expiry so handlers never wait on a refresh
+    async fn refresh_loop(self: Arc<Self>) {
+        if self.has_token().await {
+            broadcast_token_status("Connected");
+        }
+        loop {
+            if let Some(due_in) = self.refresh_due_in().await {
+                tokio::time::sleep(due_in).await;
+            } else if !is_onboarded(&self.agent_id).await {
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+            match self.access_token().await {
+                Ok(_) => info!(agent_uuid = %self.agent_id, "Access token is valid until the next scheduled refresh"),
+                Err(_) => tokio::time::sleep(RETRY_DELAY).await,
+            }
+        }
+    }
+}
+
+/// Runs an authenticated server call for a collector, refreshing its token and retrying once if it is rejected
+pub async fn with_access_token<T, F, Fut>(agent_id: &str, call: F) -> Result<T, BoxError>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<T, BoxError>>,
+{
+    let token = TOKENS.access_token(agent_id).await?;
+    match call(token.clone()).await {
+        Err(e) if e.is::<Unauthorized>() => {
+            let token = TOKENS.refresh_rejected(agent_id, &token).await?;
+            call(token).await
+        }
+        result => result,
+    }
+}
+
+async fn is_onboarded(agent_id: &str) -> bool {
+    let agent_id = agent_id.to_string();
+    with_connection(move |conn| Ok(get_agent_credential(conn, &agent_id).is_some())).await.unwrap_or_else(|e| {
+        error!("Cannot read agent credentials: {}", e);
+        false
+    })
+}
+
+async fn load_from_db(agent_id: &str) -> Option<CachedToken> {
+    let agent_id = agent_id.to_string();
+    let stored = match with_connection(move |conn| Ok(get_token(conn, &agent_id, TOKEN_TYPE))).await {
+        Ok(stored) => stored?,
+        Err(e) => {
+            error!("Cannot read the stored access token: {}", e);
+            return None;
+        }
+    };
+    if stored.token.is_empty() {
+        return None;
+    }
+    let expires_at = NaiveDateTime::parse_from_str(&stored.expiration, EXPIRATION_FORMAT).ok()?;
+    // When it was issued is not stored, so it is treated as long-lived
+    Some(CachedToken::new(stored.token, NaiveDateTime::MIN, expires_at))
+}
+
+/// Fetches a token from the server and persists it; returns it with the raw response body
+async fn fetch(agent_id: &str, token_type: &str) -> Result<(CachedToken, String), BoxError> {
+    let body = get_new_access_token(agent_id, token_type).await?;
+    let parsed: Value = serde_json::from_str(&body)?;
+    let token = parsed
+        .get("access_token")
+        .and_then(Value::as_str)
+        .filter(|token| !token.is_empty())
+        .ok_or("token response has no access_token")?
+        .to_string();
+    let expires_in = parsed
+        .get("expires_in")
+        .and_then(Value::as_i64)
+        .ok_or("token response has no expires_in")?;
+    let issued_at = Local::now().naive_local();
+    let expires_at = issued_at + ChronoDuration::seconds(expires_in);
+
+    let (owner, stored, expiration) = (agent_id.to_string(), token.clone(), expires_at.format(EXPIRATION_FORMAT).to_string());
+    if let Err(e) = with_connection(move |conn| Ok(save_token(conn, &owner, &stored, &expiration, TOKEN_TYPE)?)).await {
+        error!("Failed to save token to DB: {}", e);
+    }
+    Ok((CachedToken::new(token, issued_at, expires_at), body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token_lasting(seconds: i64) -> (CachedToken, NaiveDateTime) {
+        let issued_at = NaiveDateTime::parse_from_str("2026-01-01 12:00:00", EXPIRATION_FORMAT).unwrap();
+        (CachedToken::new("t".to_string(), issued_at, issued_at + ChronoDuration::seconds(seconds)), issued_at)
+    }
+
+    #[test]
+    fn long_lived_tokens_are_renewed_a_margin_before_expiry() {
+        let (token, now) = token_lasting(3600);
+        assert_eq!(token.renewal_delay(now), Duration::from_secs(3600) - REFRESH_MARGIN);
+        assert!(!token.is_due(now));
+
+        // Stored tokens have no issue time
+        let stored = CachedToken::new("t".to_string(), NaiveDateTime::MIN, now + ChronoDuration::seconds(3600));
+        assert_eq!(stored.renewal_delay(now), Duration::from_secs(3600) - REFRESH_MARGIN);
+    }
+
+    #[test]
+    fn short_lived_tokens_are_renewed_halfway() {
+        let (token, now) = token_lasting(60);
+        assert!(!token.is_due(now));
+        assert_eq!(token.renewal_delay(now), Duration::from_secs(30));
+
+        let (token, now) = token_lasting(4);
+        assert!(!token.is_due(now));
+        assert!(token.is_due(now + ChronoDuration::seconds(2)));
+    }
+
+    #[test]
+    fn renewals_never_follow_each_other_without_a_pause() {
+        for seconds in [-30, 0, 1, 20] {
+            let (token, now) = token_lasting(seconds);
+            assert_eq!(token.renewal_delay(now), MIN_RENEWAL_DELAY, "token lasting {}s", seconds);
+            assert_eq!(token.renewal_delay(now + ChronoDuration::hours(1)), MIN_RENEWAL_DELAY);
+        }
+    }
+}
@@ -0,0 +1,352 @@
+use futures::{SinkExt, StreamExt};
+use models_database::db::{get_agent_credential, onboarded_agent_ids};
+use models_database::with_connection;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use shared_config::CONFIG;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Notify};
+use tokio_rustls::client::TlsStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::{client_async, WebSocketStream};
+use tracing::{error, info, warn};
+use url::Url;
+
+use crate::backoff;
+use crate::server_api::Unauthorized;
+use crate::tls;
+use crate::token_manager::TOKENS;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+type WSStream = WebSocketStream<TlsStream<TcpStream>>;
+
+/// Field the server echoes in a reply so it can be matched to its request
+const MESSAGE_ID_FIELD: &str = "message_id";
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+/// The session is considered dead when nothing has been heard for this long
+const PONG_TIMEOUT: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How often to check whether onboarding has finished
+const ONBOARDING_POLL: Duration = Duration::from_secs(10);
+
+/// The monitoring uplinks to the central server's `/api/agent/bridge/` socket, one per onboarded collector
+pub static SESSIONS: Lazy<Sessions> = Lazy::new(Sessions::new);
+
+/// Ordered from healthiest to least healthy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SessionState {
+    Up,
+    /// Not onboarded yet, or no access token
+    Waiting,
+    Connecting,
+    /// Lost or refused; reconnecting after a backoff
+    Down,
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    /// No live session; the caller may fall back to HTTPS
+    Down,
+    /// Sent, but the session broke or no reply arrived in time
+    NoReply(String),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Down => write!(f, "WebSocket session is down"),
+            SessionError::NoReply(reason) => write!(f, "no WebSocket reply: {}", reason),
+        }
+    }
+}
+
+impl Error for SessionError {}
+
+/// A frame the server pushed on its own over the session of one collector
+#[derive(Debug, Clone)]
+pub struct PushedCommand {
+    pub agent_id: String,
+    pub text: String,
+}
+
+/// The central server knows every collector as an agent of its own, each with its own session
+pub struct Sessions {
+    agents: StdMutex<HashMap<String, &'static WsSession>>,
+    commands: broadcast::Sender<PushedCommand>,
+}
+
+impl Sessions {
+    fn new() -> Self {
+        let (commands, _) = broadcast::channel(64);
+        Self { agents: StdMutex::new(HashMap::new()), commands }
+    }
+
+    /// The session of a collector, started on first use. Sessions live as long as the bridge, so
+    /// they are leaked rather than counted.
+    pub fn session(&self, agent_id: &str) -> &'static WsSession {
+        let mut agents = self.agents.lock().unwrap();
+        if let Some(session) = agents.get(agent_id) {
+            return session;
+        }
+        let (session, inbox) = WsSession::new(agent_id, self.commands.clone());
+        let session: &'static WsSession = Box::leak(Box::new(session));
+        agents.insert(agent_id.to_string(), session);
+        tokio::spawn(session.run(inbox));
+        session
+    }
+
+    /// Starts the sessions of every onboarded collector; collectors onboarded later get theirs on first use
+    pub fn spawn(&'static self) {
+        tokio::spawn(async move {
+            match with_connection(|conn| Ok(onboarded_agent_ids(conn)?)).await {
+                Ok(agent_ids) => {
+                    for agent_id in agent_ids {
+                        self.session(&agent_id);
+                    }
+                }
+                Err(e) => error!("Cannot read onboarded collectors: {}", e),
+            }
+        });
+    }
+
+    /// The least healthy state across sessions; `Waiting` while there are none
+    pub fn state(&self) -> SessionState {
+        let agents = self.agents.lock().unwrap();
+        agents.values().map(|session| session.state()).max().unwrap_or(SessionState::Waiting)
+    }
+
+    /// Frames the server pushed on its own over any session, i.e. anything that is not a reply
+    pub fn commands(&self) -> broadcast::Receiver<PushedCommand> {
+        self.commands.subscribe()
+    }
+
+    /// Replaces every live session with a fresh connection and token lookup
+    pub fn reconnect(&self) {
+        for session in self.agents.lock().unwrap().values() {
+            session.reconnect();
+        }
+    }
+}
+
+struct Outgoing {
+    payload: String,
+    reply: oneshot::Sender<String>,
+}
+
+pub struct WsSession {
+    agent_id: String,
+    requests: mpsc::Sender<Outgoing>,
+    state: watch::Sender<SessionState>,
+    commands: broadcast::Sender<PushedCommand>,
+    next_id: AtomicU64,
+    /// Drops the live session, or cuts a reconnect backoff short
+    reconnect: Notify,
+}
+
+impl WsSession {
+    fn new(agent_id: &str, commands: broadcast::Sender<PushedCommand>) -> (Self, mpsc::Receiver<Outgoing>) {
+        let (requests, inbox) = mpsc::channel(64);
+        let (state, _) = watch::channel(SessionState::Waiting);
+        let session = Self {
+            agent_id: agent_id.to_string(),
+            requests,
+            state,
+            commands,
+            next_id: AtomicU64::new(1),
+            reconnect: Notify::new(),
+        };
+        (session, inbox)
+    }
+
+    pub fn state(&self) -> SessionState {
+        *self.state.borrow()
+    }
+
+    /// Sends a payload over the live session and waits for the reply carrying its message id
+    pub async fn request(&self, payload: &str) -> Result<String, SessionError> {
+        if self.state() != SessionState::Up {
+            return Err(SessionError::Down);
+        }
+        let (reply, response) = oneshot::channel();
+        self.requests
+            .send(Outgoing { payload: payload.to_string(), reply })
+            .await
+            .map_err(|_| SessionError::Down)?;
+        match tokio::time::timeout(REQUEST_TIMEOUT, response).await {
+            Ok(Ok(text)) => Ok(text),
+            Ok(Err(_)) => Err(SessionError::NoReply("session closed before the reply".to_string())),
+            Err(_) => Err(SessionError::NoReply(format!("timed out after {:?}", REQUEST_TIMEOUT))),
+        }
+    }
+
+    /// Replaces the live session with a fresh connection and token lookup
+    pub fn reconnect(&self) {
+        info!(agent_uuid = %self.agent_id, "WebSocket session reconnect requested");
+        // Only a session that is up or backing off is dropped; a stored permit would tear down the
+        // next session as soon as it connected
+        self.reconnect.notify_waiters();
+    }
+
+    async fn run(&'static self, mut inbox: mpsc::Receiver<Outgoing>) {
+        let mut failures = 0u32;
+        loop {
+            let Some(agent_uuid) = onboarded_uuid(&self.agent_id).await else {
+                self.state.send_replace(SessionState::Waiting);
+                self.pause(ONBOARDING_POLL).await;
+                continue;
+            };
+            let token = match TOKENS.access_token(&self.agent_id).await {
+                Ok(token) => token,
+                Err(_) => {
+                    self.state.send_replace(SessionState::Waiting);
+                    failures += 1;
+                    self.pause(backoff(failures)).await;
+                    continue;
+                }
+            };
+
+            self.state.send_replace(SessionState::Connecting);
+            match connect(&token, &agent_uuid).await {
+                Ok(ws) => {
+                    info!(agent_uuid = %self.agent_id, "WebSocket session to the central server is up");
+                    failures = 0;
+                    self.state.send_replace(SessionState::Up);
+                    let reason = self.serve(ws, &mut inbox).await;
+                    warn!(agent_uuid = %self.agent_id, "WebSocket session lost: {}", reason);
+                }
+                Err(e) if e.is::<Unauthorized>() => {
+                    warn!(agent_uuid = %self.agent_id, "WebSocket session refused the access token");
+                    let _ = TOKENS.refresh_rejected(&self.agent_id, &token).await;
+                }
+                Err(e) => warn!(agent_uuid = %self.agent_id, "WebSocket connect failed: {}", e),
+            }
+            self.state.send_replace(SessionState::Down);
+            // Anything queued while the session was failing would only time out
+            while inbox.try_recv().is_ok() {}
+
+            failures += 1;
+            let delay = backoff(failures);
+            info!(agent_uuid = %self.agent_id, "Reconnecting WebSocket session in {:?}", delay);
+            if self.pause(delay).await {
+                failures = 0;
+            }
+        }
+    }
+
+    /// Sleeps for `delay` unless a reconnect is requested first; returns whether one was
+    async fn pause(&self, delay: Duration) -> bool {
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => false,
+            _ = self.reconnect.notified() => true,
+        }
+    }
+
+    /// Runs one connected session until it breaks; returns why it ended
+    async fn serve(&self, ws: WSStream, inbox: &mut mpsc::Receiver<Outgoing>) -> String {
+        let (mut sink, mut stream) = ws.split();
+        let mut pending: HashMap<String, oneshot::Sender<String>> = HashMap::new();
+        let mut ping = tokio::time::interval(PING_INTERVAL);
+        let mut last_heard = Instant::now();
+
+        loop {
+            tokio::select! {
+                Some(outgoing) = inbox.recv() => {
+                    let id = self.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+                    let payload = serde_json::from_str::<Value>(&outgoing.payload)
+                        .unwrap_or_else(|_| Value::String(outgoing.payload.clone()));
+                    let frame = json!({ MESSAGE_ID_FIELD: id, "payload": payload }).to_string();
+                    if let Err(e) = sink.send(Message::Text(frame)).await {
+                        return format!("send failed: {}", e);
+                    }
+                    pending.insert(id, outgoing.reply);
+                }
+                frame = stream.next() => {
+                    last_heard = Instant::now();
+                    match frame {
+                        Some(Ok(Message::Text(text))) => self.route(text, &mut pending),
+                        Some(Ok(Message::Close(frame))) => return format!("closed by server: {:?}", frame),
+                        // Pings are answered by tungstenite; pongs only refresh `last_heard`
+                        Some(Ok(_)) => {}
+                        Some(Err(e)) => return format!("receive failed: {}", e),
+                        None => return "stream ended".to_string(),
+                    }
+                }
+                _ = self.reconnect.notified() => {
+                    let _ = sink.send(Message::Close(None)).await;
+                    return "reconnect requested".to_string();
+                }
+                _ = ping.tick() => {
+                    if last_heard.elapsed() > PONG_TIMEOUT {
+                        return format!("no traffic for {:?}", PONG_TIMEOUT);
+                    }
+                    if let Err(e) = sink.send(Message::Ping(Vec::new())).await {
+                        return format!("ping failed: {}", e);
+                    }
+                    // Callers that timed out no longer need their slot
+                    pending.retain(|_, reply| !reply.is_closed());
+                }
+            }
+        }
+    }
+
+    /// Hands a reply to its waiting request; any other frame is a server-pushed command
+    fn route(&self, text: String, pending: &mut HashMap<String, oneshot::Sender<String>>) {
+        let id = serde_json::from_str::<Value>(&text).ok().and_then(|frame| match frame.get(MESSAGE_ID_FIELD) {
+            Some(Value::String(id)) => Some(id.clone()),
+            Some(Value::Number(id)) => Some(id.to_string()),
+            _ => None,
+        });
+        match id.and_then(|id| pending.remove(&id)) {
+            Some(reply) => {
+                let _ = reply.send(text);
+            }
+            None => {
+                if self.commands.send(PushedCommand { agent_id: self.agent_id.clone(), text }).is_err() {
+                    warn!("Dropping server command: nothing is listening for pushed commands");
+                }
+            }
+        }
+    }
+}
+
+async fn onboarded_uuid(agent_id: &str) -> Option<String> {
+    let agent_id = agent_id.to_string();
+    match with_connection(move |conn| Ok(get_agent_credential(conn, &agent_id))).await {
+        Ok(credential) => credential.map(|cred| cred.uuid),
+        Err(e) => {
+            warn!("Cannot read agent credentials: {}", e);
+            None
+        }
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    backoff::delay(failures, INITIAL_BACKOFF, MAX_BACKOFF)
+}
+
+async fn connect(access_token: &str, agent_uuid: &str) -> Result<WSStream, BoxError> {
+    let url = Url::parse(&format!("{}/api/agent/bridge/", CONFIG.web_socket_url))?;
+    let mut req = url.as_str().into_client_request()?;
+
+    let headers = req.headers_mut();
+    headers.insert("uuid", HeaderValue::from_str(agent_uuid)?);
+    headers.insert("access-token", HeaderValue::from_str(access_token)?);
+    headers.insert("Content-Type", HeaderValue::from_static("application/json"));
+
+    let tls_stream = tls::connect_wss(&url).await?;
+    match client_async(req, tls_stream).await {
+        Ok((stream, _)) => Ok(stream),
+        Err(tungstenite::Error::Http(response)) if response.status() == StatusCode::UNAUTHORIZED => Err(Box::new(Unauthorized)),
+        Err(e) => Err(format!("WebSocket handshake failed: {}", e).into()),
+    }
+}
@@ -312,7 +312,8 @@ class AgentData:
                     "model": processor.Name.strip(),
                     "p_cores": processor.NumberOfCores,
                     "l_cores": processor.NumberOfLogicalProcessors,
-                    "speed": processor.MaxClockSpeed
+                    # WMI reports MHz; a bare number would be read as Hz
+                    "speed": f"{processor.MaxClockSpeed} MHz" if processor.MaxClockSpeed else 0
                 
                 })
                 
@@ -0,0 +1,64 @@
+use admin_auth::{audit, authorize, Role};
+use axum::extract::Request;
+use axum::http::header::AUTHORIZATION;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+
+const SERVICE: &str = "collector";
+
+/// Every control endpoint changes state, so all of them need the admin role; each call is audited
+pub async fn require_admin(request: Request, next: Next) -> Response {
+    require(Role::Admin, request, next).await
+}
+
+/// For endpoints that only read, such as the log query
+pub async fn require_read_only(request: Request, next: Next) -> Response {
+    require(Role::ReadOnly, request, next).await
+}
+
+async fn require(role: Role, request: Request, next: Next) -> Response {
+    let authorization = request.headers().get(AUTHORIZATION).and_then(|value| value.to_str().ok());
+    let result = authorize(authorization, role);
+    audit(SERVICE, request.method().as_str(), request.uri().path(), &result).await;
+    match result {
+        Ok(_) => next.run(request).await,
+        Err(e) => {
+            let status = StatusCode::from_u16(e.status()).unwrap_or(StatusCode::UNAUTHORIZED);
+            (status, Json(json!({ "error": e.to_string() }))).into_response()
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Denied(admin_auth::AuthError);
+
+impl warp::reject::Reject for Denied {}
+
+/// The same check for the warp routes, such as the log stream
+pub fn warp_require(role: Role) -> impl warp::Filter<Extract = (), Error = warp::Rejection> + Clone {
+    use warp::Filter;
+
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |method: warp::http::Method, path: warp::filters::path::FullPath, authorization: Option<String>| async move {
+            let result = authorize(authorization.as_deref(), role);
+            audit(SERVICE, method.as_str(), path.as_str(), &result).await;
+            result.map(|_| ()).map_err(|e| warp::reject::custom(Denied(e)))
+        })
+        .untuple_one()
+}
+
+/// Turns a refused warp call into a 401 or 403 with a JSON error
+pub async fn handle_warp_rejection(rejection: warp::Rejection) -> Result<warp::reply::Response, warp::Rejection> {
+    use warp::Reply;
+
+    let Some(Denied(e)) = rejection.find::<Denied>() else {
+        return Err(rejection);
+    };
+    let status = warp::http::StatusCode::from_u16(e.status()).unwrap_or(warp::http::StatusCode::UNAUTHORIZED);
+    Ok(warp::reply::with_status(warp::reply::json(&json!({ "error": e.to_string() })), status).into_response())
+}
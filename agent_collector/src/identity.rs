@@ -0,0 +1,66 @@
+use messages::subjects::is_valid_agent_id;
+use rand::RngCore;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// Loads this collector's agent id, generating and persisting one on first start.
+///
+/// The id namespaces every NATS subject the collector uses, so it must stay stable
+/// across restarts and is never derived from the master key.
+pub fn load_or_create_agent_id(path: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    if let Ok(raw) = fs::read_to_string(path) {
+        let agent_id = raw.trim().to_string();
+        if is_valid_agent_id(&agent_id) {
+            return Ok(agent_id);
+        }
+        return Err(format!("agent id in {} is not a valid subject token: {:?}", path, agent_id).into());
+    }
+
+    let agent_id = random_uuid();
+    write_atomically(path, &agent_id)?;
+    Ok(agent_id)
+}
+
+/// NATS credentials of this agent: its own user seed and the JWT the bridge signed for it
+#[derive(Clone)]
+pub struct NatsCredentials {
+    pub jwt: String,
+    pub seed: String,
+}
+
+/// Loads this collector's NATS user seed, generating and persisting one on first start. The seed never
+/// leaves the host; the bridge only ever sees its public key.
+pub fn load_or_create_user_seed(path: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    if let Ok(raw) = fs::read_to_string(path) {
+        let seed = raw.trim().to_string();
+        nats::credentials::user_public_key(&seed).map_err(|e| format!("NATS seed in {} is not usable: {}", path, e))?;
+        return Ok(seed);
+    }
+
+    let seed = nats::credentials::new_user_seed()?;
+    write_atomically(path, &seed)?;
+    Ok(seed)
+}
+
+/// Writes `contents` to `path` through a temporary file, so a crash never leaves it half written
+pub fn write_atomically(path: &str, contents: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, contents)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Random version 4 UUID
+pub fn random_uuid() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
@@ -0,0 +1,866 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use sysinfo::{Disks, Networks, System};
+
+use models_database::db::{
+    find_nic_uuid, find_partition_uuid, find_port_uuid, find_storage_uuid,
+};
+use models_database::get_connection;
+use models_database::units::{BitsPerSecond, Bytes, Hertz};
+use shared_config::CONFIG;
+
+const UNKNOWN: &str = "Unknown";
+
+#[derive(Debug, Serialize)]
+pub struct Inventory {
+    pub agent: AgentInfo,
+    pub device: DeviceInfo,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AgentInfo {
+    pub os: String,
+    pub hostname: String,
+    pub os_version: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceInfo {
+    pub make: String,
+    pub model: String,
+    pub serial_number: String,
+    pub dev_phy_vm: String,
+    pub cpu: Vec<CpuInfo>,
+    pub memory: Vec<MemoryInfo>,
+    pub storage: Vec<StorageInfo>,
+    pub nic: Vec<NicInfo>,
+    pub gpu: Vec<GpuInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CpuInfo {
+    pub os_uuid: Option<String>,
+    pub make: String,
+    pub model: String,
+    pub p_cores: i32,
+    pub l_cores: i32,
+    /// Sent with its unit: the bridge reads a bare CPU speed as MHz
+    #[serde(serialize_with = "models_database::units::readable::serialize")]
+    pub speed: Hertz,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MemoryInfo {
+    pub make: String,
+    pub model: String,
+    pub speed: String,
+    pub size: Bytes,
+    pub serial_number: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StorageInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    pub os_uuid: Option<String>,
+    pub hw_disk_type: String,
+    pub make: String,
+    pub model: String,
+    pub serial_number: String,
+    pub base_fs_type: String,
+    pub free_space: Bytes,
+    pub total_disk_usage: Bytes,
+    pub total_disk_size: Bytes,
+    pub partition: Vec<PartitionInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PartitionInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    pub os_uuid: Option<String>,
+    pub serial_number: String,
+    pub name: String,
+    pub fs_type: String,
+    pub free_space: Bytes,
+    pub used_space: Bytes,
+    pub total_size: Bytes,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NicInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    pub os_uuid: Option<String>,
+    pub make: String,
+    pub model: String,
+    pub number_of_ports: i32,
+    pub max_speed: BitsPerSecond,
+    pub supported_speeds: String,
+    pub serial_number: String,
+    pub mac_address: String,
+    pub port: Vec<PortInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PortInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    pub interface_name: String,
+    pub operating_speed: BitsPerSecond,
+    pub is_physical_logical: String,
+    pub logical_type: String,
+    pub ip: Vec<IpInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IpInfo {
+    pub address: String,
+    pub gateway: Option<String>,
+    pub subnet_mask: String,
+    pub dns: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GpuInfo {
+    pub make: String,
+    pub model: String,
+    pub serial_number: String,
+    pub size: String,
+    pub driver: String,
+}
+
+/// Collects the full inventory document published on `agent.data`
+pub fn agent_data() -> Result<String, Box<dyn Error + Send + Sync>> {
+    let inventory = Inventory {
+        agent: agent_details()?,
+        device: device_details(),
+    };
+    Ok(serde_json::to_string(&inventory)?)
+}
+
+/// Rescans disks and partitions, tagging each with the uuid already stored for it
+pub fn scan_disk(action: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut storage = storage_details();
+    let mut conn = get_connection(&CONFIG.db_path)?;
+
+    for disk in &mut storage {
+        disk.uuid = Some(find_storage_uuid(&mut conn, &disk.serial_number).unwrap_or_else(|| "unknown".to_string()));
+        for part in &mut disk.partition {
+            let found = part.os_uuid.as_deref().and_then(|id| find_partition_uuid(&mut conn, id));
+            part.uuid = Some(found.unwrap_or_else(|| "unknown".to_string()));
+        }
+    }
+
+    let key = if action == "partition" { "disk" } else { action };
+    Ok(serde_json::json!({ key: storage }).to_string())
+}
+
+/// Rescans network adapters, tagging each NIC and port with its stored uuid
+pub fn scan_nic(action: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut nics = network_details();
+    let mut conn = get_connection(&CONFIG.db_path)?;
+
+    for nic in &mut nics {
+        let found = nic.os_uuid.as_deref().and_then(|id| find_nic_uuid(&mut conn, id));
+        nic.uuid = Some(found.unwrap_or_else(|| "unknown".to_string()));
+        for port in &mut nic.port {
+            port.uuid = Some(find_port_uuid(&mut conn, &port.interface_name).unwrap_or_else(|| "unknown".to_string()));
+        }
+    }
+
+    Ok(serde_json::json!({ action: nics }).to_string())
+}
+
+fn agent_details() -> Result<AgentInfo, Box<dyn Error + Send + Sync>> {
+    // Same format as the master key payload so the server sees one OS string per host
+    let release = sys_info::os_release()?;
+    Ok(AgentInfo {
+        os: format!("{} {}", sys_info::os_type()?, release),
+        hostname: hostname::get()?.to_string_lossy().to_string(),
+        os_version: release,
+    })
+}
+
+pub fn device_details() -> DeviceInfo {
+    let make = read_sysfs("/sys/class/dmi/id/sys_vendor").unwrap_or_else(|| UNKNOWN.to_string());
+    let model = device_model();
+    // product_serial is root-only on most distributions
+    let serial_number = read_sysfs("/sys/class/dmi/id/product_serial")
+        .or_else(|| read_sysfs("/sys/class/dmi/id/board_serial"))
+        .unwrap_or_else(|| UNKNOWN.to_string());
+    let dev_phy_vm = if is_virtual_machine(&make, &model) { "vm" } else { "physical" };
+
+    DeviceInfo {
+        make,
+        model,
+        serial_number,
+        dev_phy_vm: dev_phy_vm.to_string(),
+        cpu: cpu_details(),
+        memory: memory_details(),
+        storage: storage_details(),
+        nic: network_details(),
+        gpu: gpu_details(),
+    }
+}
+
+/// Product name of the host as recorded in the `device` table
+pub fn device_model() -> String {
+    read_sysfs("/sys/class/dmi/id/product_name").unwrap_or_else(|| UNKNOWN.to_string())
+}
+
+fn is_virtual_machine(make: &str, model: &str) -> bool {
+    const HYPERVISORS: [&str; 8] = ["vmware", "virtualbox", "kvm", "qemu", "virtual machine", "xen", "bochs", "openstack"];
+    let dmi = format!("{} {}", make, model).to_lowercase();
+    if HYPERVISORS.iter().any(|h| dmi.contains(h)) {
+        return true;
+    }
+
+    fs::read_to_string("/proc/cpuinfo")
+        .map(|info| info.lines().any(|l| l.starts_with("flags") && l.split_whitespace().any(|f| f == "hypervisor")))
+        .unwrap_or(false)
+}
+
+pub fn cpu_details() -> Vec<CpuInfo> {
+    #[derive(Default)]
+    struct Socket {
+        vendor: Option<String>,
+        model: Option<String>,
+        cores: std::collections::BTreeSet<String>,
+        threads: i32,
+        first_cpu: Option<String>,
+        mhz: Option<f64>,
+    }
+
+    let cpuinfo = fs::read_to_string("/proc/cpuinfo").unwrap_or_default();
+    let mut sockets: BTreeMap<String, Socket> = BTreeMap::new();
+
+    for block in cpuinfo.split("\n\n").filter(|b| b.contains("processor")) {
+        let fields: HashMap<&str, &str> = block
+            .lines()
+            .filter_map(|l| l.split_once(':'))
+            .map(|(k, v)| (k.trim(), v.trim()))
+            .collect();
+
+        let socket = sockets.entry(fields.get("physical id").unwrap_or(&"0").to_string()).or_default();
+        socket.threads += 1;
+        if socket.vendor.is_none() {
+            socket.vendor = fields.get("vendor_id").or_else(|| fields.get("CPU implementer")).map(|s| s.to_string());
+        }
+        if socket.model.is_none() {
+            socket.model = fields.get("model name").or_else(|| fields.get("Processor")).map(|s| s.to_string());
+        }
+        if socket.first_cpu.is_none() {
+            socket.first_cpu = fields.get("processor").map(|s| s.to_string());
+        }
+        if socket.mhz.is_none() {
+            socket.mhz = fields.get("cpu MHz").and_then(|s| s.parse().ok());
+        }
+        if let Some(core) = fields.get("core id") {
+            socket.cores.insert(core.to_string());
+        }
+    }
+
+    if sockets.is_empty() {
+        // Fall back to sysinfo for architectures without a parsable cpuinfo
+        let mut sys = System::new();
+        sys.refresh_cpu_all();
+        let Some(first) = sys.cpus().first() else {
+            return Vec::new();
+        };
+        return vec![CpuInfo {
+            os_uuid: None,
+            make: first.vendor_id().to_string(),
+            model: first.brand().to_string(),
+            p_cores: sys.physical_core_count().unwrap_or(sys.cpus().len()) as i32,
+            l_cores: sys.cpus().len() as i32,
+            speed: Hertz::from_mhz(first.frequency()),
+        }];
+    }
+
+    sockets
+        .into_values()
+        .map(|socket| {
+            // cpuinfo_max_freq is in kHz
+            let max_mhz = socket
+                .first_cpu
+                .as_ref()
+                .and_then(|cpu| read_sysfs(format!("/sys/devices/system/cpu/cpu{}/cpufreq/cpuinfo_max_freq", cpu)))
+                .and_then(|khz| khz.parse::<u64>().ok())
+                .map(|khz| khz / 1000)
+                .or_else(|| socket.mhz.map(|mhz| mhz.round() as u64));
+
+            CpuInfo {
+                os_uuid: None,
+                make: socket.vendor.unwrap_or_else(|| UNKNOWN.to_string()),
+                model: socket.model.unwrap_or_else(|| UNKNOWN.to_string()),
+                p_cores: if socket.cores.is_empty() { socket.threads } else { socket.cores.len() as i32 },
+                l_cores: socket.threads,
+                speed: max_mhz.map_or_else(Hertz::default, Hertz::from_mhz),
+            }
+        })
+        .collect()
+}
+
+pub fn memory_details() -> Vec<MemoryInfo> {
+    // SMBIOS type 17 (Memory Device) entries, readable by root only
+    let mut modules: Vec<MemoryInfo> = fs::read_dir("/sys/firmware/dmi/entries")
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter(|e| e.file_name().to_string_lossy().starts_with("17-"))
+                .filter_map(|e| fs::read(e.path().join("raw")).ok())
+                .filter_map(|raw| parse_memory_device(&raw))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    if modules.is_empty() {
+        let total_kb = fs::read_to_string("/proc/meminfo")
+            .ok()
+            .and_then(|info| {
+                info.lines()
+                    .find(|l| l.starts_with("MemTotal:"))
+                    .and_then(|l| l.split_whitespace().nth(1))
+                    .and_then(|kb| kb.parse::<u64>().ok())
+            })
+            .unwrap_or(0);
+        modules.push(MemoryInfo {
+            make: "Virtual".to_string(),
+            model: "System RAM".to_string(),
+            speed: "0".to_string(),
+            size: Bytes(total_kb * 1024),
+            serial_number: UNKNOWN.to_string(),
+        });
+    }
+
+    modules
+}
+
+fn parse_memory_device(raw: &[u8]) -> Option<MemoryInfo> {
+    let len = *raw.get(1)? as usize;
+    if len < 0x1B || raw.len() < len {
+        return None;
+    }
+    let word = |offset: usize| u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+
+    let size_bytes: u64 = match word(0x0C) {
+        0 | 0xFFFF => return None, // empty slot or unknown size
+        0x7FFF if len >= 0x20 => {
+            let mb = u32::from_le_bytes([raw[0x1C], raw[0x1D], raw[0x1E], raw[0x1F]]) & 0x7FFF_FFFF;
+            u64::from(mb) << 20
+        }
+        size if size & 0x8000 != 0 => u64::from(size & 0x7FFF) << 10,
+        size => u64::from(size) << 20,
+    };
+
+    let strings: Vec<String> = raw[len..]
+        .split(|b| *b == 0)
+        .take_while(|s| !s.is_empty())
+        .map(|s| String::from_utf8_lossy(s).trim().to_string())
+        .collect();
+    let string_at = |offset: usize| {
+        let index = raw[offset] as usize;
+        strings
+            .get(index.wrapping_sub(1))
+            .filter(|s| !s.is_empty())
+            .cloned()
+            .unwrap_or_else(|| UNKNOWN.to_string())
+    };
+
+    Some(MemoryInfo {
+        make: string_at(0x17),
+        model: string_at(0x1A),
+        speed: word(0x15).to_string(),
+        size: Bytes(size_bytes),
+        serial_number: string_at(0x18),
+    })
+}
+
+pub fn storage_details() -> Vec<StorageInfo> {
+    let disks = Disks::new_with_refreshed_list();
+    let mounts: HashMap<String, &sysinfo::Disk> = disks
+        .list()
+        .iter()
+        .map(|d| (d.name().to_string_lossy().to_string(), d))
+        .collect();
+    let fs_uuids = by_id_links("/dev/disk/by-uuid");
+    let part_uuids = by_id_links("/dev/disk/by-partuuid");
+
+    let mut storage = Vec::new();
+    for name in block_devices() {
+        let sys_path = Path::new("/sys/block").join(&name);
+        let udev_id = read_sysfs(sys_path.join("dev")).map(|dev| format!("b{}", dev));
+        let udev = |key: &str| udev_id.as_deref().and_then(|id| udev_property(id, key));
+
+        let mut partitions: Vec<PartitionInfo> = disk_partitions(&name)
+            .into_iter()
+            .map(|part| {
+                let dev_path = format!("/dev/{}", part);
+                let total = sectors_to_bytes(sys_path.join(&part).join("size"));
+                let part_udev = read_sysfs(sys_path.join(&part).join("dev")).map(|dev| format!("b{}", dev));
+                let os_uuid = fs_uuids.get(&part).cloned();
+                let (fs_type, free, used) = match mounts.get(&dev_path) {
+                    Some(mount) => (
+                        mount.file_system().to_string_lossy().to_string(),
+                        mount.available_space(),
+                        mount.total_space().saturating_sub(mount.available_space()),
+                    ),
+                    None => (
+                        part_udev
+                            .as_deref()
+                            .and_then(|id| udev_property(id, "ID_FS_TYPE"))
+                            .unwrap_or_else(|| UNKNOWN.to_string()),
+                        0,
+                        0,
+                    ),
+                };
+
+                PartitionInfo {
+                    uuid: None,
+                    serial_number: partition_serial(&part, &fs_uuids, &part_uuids),
+                    os_uuid,
+                    name: dev_path,
+                    fs_type,
+                    free_space: Bytes(free),
+                    used_space: Bytes(used),
+                    total_size: Bytes(total),
+                }
+            })
+            .collect();
+        partitions.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut total_free: u64 = partitions.iter().map(|p| p.free_space.0).sum();
+        let mut total_used: u64 = partitions.iter().map(|p| p.used_space.0).sum();
+        let mut base_fs_type = partitions.iter().map(|p| p.fs_type.clone()).find(|fs| fs != UNKNOWN);
+
+        // A filesystem created directly on the disk has no partition table
+        if let Some(mount) = mounts.get(&format!("/dev/{}", name)) {
+            total_free += mount.available_space();
+            total_used += mount.total_space().saturating_sub(mount.available_space());
+            base_fs_type.get_or_insert_with(|| mount.file_system().to_string_lossy().to_string());
+        }
+
+        storage.push(StorageInfo {
+            uuid: None,
+            os_uuid: read_sysfs(sys_path.join("wwid"))
+                .or_else(|| read_sysfs(sys_path.join("device/wwid")))
+                .or_else(|| udev("ID_WWN")),
+            hw_disk_type: disk_type(&name, udev("ID_BUS").as_deref()),
+            // virtio and NVMe controllers expose a PCI vendor id rather than a name
+            make: read_sysfs(sys_path.join("device/vendor"))
+                .map(|vendor| pci_vendor_name(&vendor).unwrap_or(vendor))
+                .or_else(|| udev("ID_VENDOR"))
+                .unwrap_or_else(|| UNKNOWN.to_string()),
+            model: read_sysfs(sys_path.join("device/model"))
+                .or_else(|| udev("ID_MODEL"))
+                .unwrap_or_else(|| UNKNOWN.to_string()),
+            serial_number: disk_serial(&name),
+            base_fs_type: base_fs_type.unwrap_or_else(|| UNKNOWN.to_string()),
+            free_space: Bytes(total_free),
+            total_disk_usage: Bytes(total_used),
+            total_disk_size: Bytes(sectors_to_bytes(sys_path.join("size"))),
+            partition: partitions,
+        });
+    }
+
+    storage
+}
+
+/// Whole-disk block devices, skipping loop, RAM, optical and device-mapper nodes
+pub fn block_devices() -> Vec<String> {
+    const VIRTUAL_PREFIXES: [&str; 8] = ["loop", "ram", "zram", "dm-", "sr", "fd", "nbd", "md"];
+    let mut names: Vec<String> = list_dir("/sys/block")
+        .into_iter()
+        .filter(|name| !VIRTUAL_PREFIXES.iter().any(|p| name.starts_with(p)))
+        .filter(|name| sectors_to_bytes(Path::new("/sys/block").join(name).join("size")) > 0)
+        .collect();
+    names.sort();
+    names
+}
+
+/// Partition names of a disk, e.g. `sda1` or `nvme0n1p2`
+fn disk_partitions(disk: &str) -> Vec<String> {
+    let sys_path = Path::new("/sys/block").join(disk);
+    list_dir(&sys_path)
+        .into_iter()
+        .filter(|entry| entry.starts_with(disk) && sys_path.join(entry).join("partition").exists())
+        .collect()
+}
+
+/// Serial numbers stored for every partition, keyed by device path (`/dev/sda1`)
+pub fn partition_serials() -> HashMap<String, String> {
+    let fs_uuids = by_id_links("/dev/disk/by-uuid");
+    let part_uuids = by_id_links("/dev/disk/by-partuuid");
+    block_devices()
+        .iter()
+        .flat_map(|disk| disk_partitions(disk))
+        .map(|part| (format!("/dev/{}", part), partition_serial(&part, &fs_uuids, &part_uuids)))
+        .collect()
+}
+
+fn partition_serial(part: &str, fs_uuids: &HashMap<String, String>, part_uuids: &HashMap<String, String>) -> String {
+    // PARTUUID survives reformatting; MBR disks without one fall back to the filesystem UUID
+    part_uuids
+        .get(part)
+        .or_else(|| fs_uuids.get(part))
+        .cloned()
+        .unwrap_or_else(|| format!("/dev/{}", part))
+}
+
+/// Serial number stored for a disk, falling back to its kernel name like Windows falls back to PHYSICALDRIVEn
+pub fn disk_serial(name: &str) -> String {
+    let sys_path = Path::new("/sys/block").join(name);
+    read_sysfs(sys_path.join("device/serial"))
+        .or_else(|| {
+            read_sysfs(sys_path.join("dev"))
+                .and_then(|dev| udev_property(&format!("b{}", dev), "ID_SERIAL_SHORT"))
+        })
+        .unwrap_or_else(|| name.to_string())
+}
+
+fn disk_type(name: &str, bus: Option<&str>) -> String {
+    let kind = if name.starts_with("nvme") {
+        "nvme"
+    } else if name.starts_with("vd") {
+        "virtio"
+    } else if name.starts_with("mmcblk") {
+        "mmc"
+    } else {
+        match bus {
+            Some("usb") => "usb",
+            Some("scsi") => "scsi",
+            _ => "sata",
+        }
+    };
+    kind.to_string()
+}
+
+pub fn network_details() -> Vec<NicInfo> {
+    let networks = Networks::new_with_refreshed_list();
+    let gateways = default_gateways();
+    let dns = resolv_nameserver().unwrap_or_else(|| UNKNOWN.to_string());
+
+    let mut nics = Vec::new();
+    for name in list_dir("/sys/class/net") {
+        if name == "lo" {
+            continue;
+        }
+        let sys_path = Path::new("/sys/class/net").join(&name);
+        let physical = sys_path.join("device").exists();
+        let udev_id = read_sysfs(sys_path.join("ifindex")).map(|index| format!("n{}", index));
+        let udev = |key: &str| udev_id.as_deref().and_then(|id| udev_property(id, key));
+        let driver = link_name(sys_path.join("device/driver"));
+
+        let mac_address = read_sysfs(sys_path.join("address")).unwrap_or_else(|| UNKNOWN.to_string());
+        let os_uuid = if mac_address == UNKNOWN || mac_address == "00:00:00:00:00:00" {
+            name.clone()
+        } else {
+            mac_address.clone()
+        };
+
+        // Link speed is reported in Mb/s and is unreadable while the link is down
+        let speed_mbps = read_sysfs(sys_path.join("speed"))
+            .and_then(|s| s.parse::<i64>().ok())
+            .filter(|s| *s > 0);
+        let speed_bps = speed_mbps.map_or_else(BitsPerSecond::default, |mbps| BitsPerSecond::from_mbps(mbps as u64));
+
+        let (is_physical_logical, logical_type) = if physical {
+            let medium = if sys_path.join("wireless").exists() { "wireless" } else { "ethernet" };
+            ("physical", medium)
+        } else {
+            ("logical", virtual_link_kind(&sys_path, &name))
+        };
+
+        let ip = networks
+            .get(&name)
+            .map(|data| {
+                data.ip_networks()
+                    .iter()
+                    .filter_map(|net| match net.addr {
+                        IpAddr::V4(addr) => Some(IpInfo {
+                            address: addr.to_string(),
+                            gateway: gateways.get(&name).map(|gw| gw.to_string()),
+                            subnet_mask: prefix_to_mask(net.prefix).to_string(),
+                            dns: dns.clone(),
+                        }),
+                        IpAddr::V6(_) => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        nics.push(NicInfo {
+            uuid: None,
+            os_uuid: Some(os_uuid),
+            make: udev("ID_VENDOR_FROM_DATABASE")
+                .or_else(|| (!physical).then(|| "Linux".to_string()))
+                .unwrap_or_else(|| UNKNOWN.to_string()),
+            model: udev("ID_MODEL_FROM_DATABASE")
+                .or(driver)
+                .unwrap_or_else(|| logical_type.to_string()),
+            number_of_ports: 1,
+            max_speed: speed_bps,
+            supported_speeds: speed_mbps.map_or_else(|| UNKNOWN.to_string(), |mbps| mbps.to_string()),
+            serial_number: link_name(sys_path.join("device")).unwrap_or_else(|| UNKNOWN.to_string()),
+            mac_address,
+            port: vec![PortInfo {
+                uuid: None,
+                interface_name: name.clone(),
+                operating_speed: speed_bps,
+                is_physical_logical: is_physical_logical.to_string(),
+                logical_type: logical_type.to_string(),
+                ip,
+            }],
+        });
+    }
+
+    nics
+}
+
+fn virtual_link_kind(sys_path: &Path, name: &str) -> &'static str {
+    if sys_path.join("bridge").exists() {
+        "bridge"
+    } else if sys_path.join("bonding").exists() {
+        "bond"
+    } else if Path::new("/proc/net/vlan").join(name).exists() {
+        "vlan"
+    } else if sys_path.join("tun_flags").exists() {
+        "tun"
+    } else {
+        "virtual"
+    }
+}
+
+/// Default gateway per interface, parsed from the kernel routing table
+fn default_gateways() -> HashMap<String, Ipv4Addr> {
+    parse_default_gateways(&fs::read_to_string("/proc/net/route").unwrap_or_default())
+}
+
+fn parse_default_gateways(routes: &str) -> HashMap<String, Ipv4Addr> {
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let cols: Vec<&str> = line.split_whitespace().collect();
+            if cols.len() < 3 || cols[1] != "00000000" {
+                return None;
+            }
+            // Addresses are printed as host-order hex of a network-order value
+            let raw = u32::from_str_radix(cols[2], 16).ok()?;
+            Some((cols[0].to_string(), Ipv4Addr::from(raw.to_le_bytes())))
+        })
+        .collect()
+}
+
+fn resolv_nameserver() -> Option<String> {
+    fs::read_to_string("/etc/resolv.conf").ok()?.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        (parts.next() == Some("nameserver")).then(|| parts.next().map(str::to_string)).flatten()
+    })
+}
+
+fn prefix_to_mask(prefix: u8) -> Ipv4Addr {
+    let bits = if prefix == 0 { 0 } else { u32::MAX << (32 - u32::from(prefix.min(32))) };
+    Ipv4Addr::from(bits)
+}
+
+pub fn gpu_details() -> Vec<GpuInfo> {
+    let mut gpus = Vec::new();
+    for card in list_dir("/sys/class/drm") {
+        // cardN-<connector> entries are outputs of the same card
+        if !card.starts_with("card") || !card[4..].chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        let device = Path::new("/sys/class/drm").join(&card).join("device");
+        let Some(slot) = link_name(&device) else {
+            continue;
+        };
+        let udev = |key: &str| udev_property(&format!("+pci:{}", slot), key);
+        let driver = link_name(device.join("driver")).map(|drv| {
+            match read_sysfs(Path::new("/sys/module").join(&drv).join("version")) {
+                Some(version) => format!("{} {}", drv, version),
+                None => drv,
+            }
+        });
+
+        gpus.push(GpuInfo {
+            make: udev("ID_VENDOR_FROM_DATABASE")
+                .or_else(|| read_sysfs(device.join("vendor")).and_then(|id| pci_vendor_name(&id)))
+                .unwrap_or_else(|| UNKNOWN.to_string()),
+            model: udev("ID_MODEL_FROM_DATABASE").unwrap_or_else(|| UNKNOWN.to_string()),
+            serial_number: slot,
+            size: read_sysfs(device.join("mem_info_vram_total")).unwrap_or_else(|| "0".to_string()),
+            driver: driver.unwrap_or_else(|| UNKNOWN.to_string()),
+        });
+    }
+    gpus
+}
+
+fn pci_vendor_name(id: &str) -> Option<String> {
+    let name = match id {
+        "0x10de" => "NVIDIA Corporation",
+        "0x1002" => "Advanced Micro Devices, Inc. [AMD/ATI]",
+        "0x8086" => "Intel Corporation",
+        "0x15ad" => "VMware",
+        "0x1af4" => "Red Hat, Inc.",
+        "0x1234" => "QEMU",
+        "0x1414" => "Microsoft Corporation",
+        _ => return None,
+    };
+    Some(name.to_string())
+}
+
+/// Reads a sysfs attribute, returning `None` for missing, unreadable or blank values
+fn read_sysfs(path: impl AsRef<Path>) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+/// Reads a property recorded by udev for a device (`b8:0`, `n2`, `+pci:0000:00:02.0`)
+fn udev_property(device_id: &str, key: &str) -> Option<String> {
+    let prefix = format!("E:{}=", key);
+    fs::read_to_string(Path::new("/run/udev/data").join(device_id))
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix(&prefix).map(|v| v.trim().to_string()))
+        .filter(|v| !v.is_empty())
+}
+
+fn list_dir(path: impl AsRef<Path>) -> Vec<String> {
+    fs::read_dir(path)
+        .map(|entries| entries.flatten().map(|e| e.file_name().to_string_lossy().to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// Final path component of a symlink target, e.g. the PCI slot behind `device`
+fn link_name(path: impl AsRef<Path>) -> Option<String> {
+    fs::canonicalize(path)
+        .ok()
+        .and_then(|target| target.file_name().map(|n| n.to_string_lossy().to_string()))
+}
+
+/// Maps kernel device names to the identifiers udev publishes under /dev/disk/by-*
+fn by_id_links(dir: &str) -> HashMap<String, String> {
+    list_dir(dir)
+        .into_iter()
+        .filter_map(|id| {
+            let target: PathBuf = fs::canonicalize(Path::new(dir).join(&id)).ok()?;
+            Some((target.file_name()?.to_string_lossy().to_string(), id))
+        })
+        .collect()
+}
+
+fn sectors_to_bytes(path: impl AsRef<Path>) -> u64 {
+    // sysfs sizes are always in 512-byte sectors regardless of the logical block size
+    read_sysfs(path).and_then(|s| s.parse::<u64>().ok()).unwrap_or(0) * 512
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A type-17 record of `len` formatted bytes: size word, speed, the manufacturer, serial and part
+    /// number string indexes, an extended size when `len` has room for it, then the string set
+    fn memory_device(len: u8, size: u16, extended_mb: u32, speed: u16, indexes: [u8; 3], strings: &[&str]) -> Vec<u8> {
+        let mut raw = vec![0u8; len as usize];
+        raw[0] = 17;
+        raw[1] = len;
+        raw[0x0C..0x0E].copy_from_slice(&size.to_le_bytes());
+        raw[0x15..0x17].copy_from_slice(&speed.to_le_bytes());
+        [raw[0x17], raw[0x18], raw[0x1A]] = indexes;
+        if len >= 0x20 {
+            raw[0x1C..0x20].copy_from_slice(&extended_mb.to_le_bytes());
+        }
+        for string in strings {
+            raw.extend_from_slice(string.as_bytes());
+            raw.push(0);
+        }
+        raw.push(0);
+        raw
+    }
+
+    #[test]
+    fn memory_device_sizes() {
+        let cases: &[(u8, u16, u32, Option<u64>)] = &[
+            (0x28, 0x2000, 0, Some(8 << 30)),          // 8192 MB
+            (0x28, 0x8000 | 512, 0, Some(512 << 10)),  // KB granularity
+            (0x28, 0x7FFF, 32768, Some(32 << 30)),     // too big for the word, see the extended size
+            (0x28, 0x7FFF, 0x8000_0000 | 1024, Some(1 << 30)), // the extended size's top bit is reserved
+            (0x1B, 0x7FFF, 0, Some(32767 << 20)),      // SMBIOS 2.3 record without an extended size
+            (0x28, 0, 0, None),                        // empty slot
+            (0x28, 0xFFFF, 0, None),                   // unknown size
+        ];
+        for &(len, size, extended_mb, expected) in cases {
+            let raw = memory_device(len, size, extended_mb, 3200, [1, 2, 3], &["Samsung", "S123", "M471A"]);
+            let parsed = parse_memory_device(&raw).map(|module| module.size.0);
+            assert_eq!(parsed, expected, "len {:#x}, size {:#x}, extended {}", len, size, extended_mb);
+        }
+
+        // Too short for a memory device
+        let mut raw = memory_device(0x28, 0x2000, 0, 0, [1, 2, 3], &[]);
+        raw[1] = 0x1A;
+        assert!(parse_memory_device(&raw).is_none());
+    }
+
+    #[test]
+    fn memory_device_strings() {
+        let raw = memory_device(0x28, 0x4000, 0, 2666, [1, 3, 2], &["Kingston", "KF426", " 0A1B2C "]);
+        let module = parse_memory_device(&raw).unwrap();
+        assert_eq!(
+            (module.make.as_str(), module.model.as_str(), module.serial_number.as_str(), module.speed.as_str()),
+            ("Kingston", "KF426", "0A1B2C", "2666")
+        );
+
+        // Index 0 means no string, and blank or missing strings are unknown too
+        let raw = memory_device(0x28, 0x4000, 0, 0, [0, 2, 9], &["Acme", "   "]);
+        let module = parse_memory_device(&raw).unwrap();
+        assert_eq!([module.make, module.serial_number, module.model], [UNKNOWN; 3]);
+
+        // A record cut off before its formatted section ends
+        let raw = memory_device(0x28, 0x4000, 0, 0, [1, 2, 3], &[]);
+        assert!(parse_memory_device(&raw[..0x20]).is_none());
+    }
+
+    #[test]
+    fn prefixes_become_masks() {
+        let cases = [
+            (0, "0.0.0.0"),
+            (1, "128.0.0.0"),
+            (8, "255.0.0.0"),
+            (20, "255.255.240.0"),
+            (24, "255.255.255.0"),
+            (31, "255.255.255.254"),
+            (32, "255.255.255.255"),
+            (40, "255.255.255.255"),
+        ];
+        for (prefix, mask) in cases {
+            assert_eq!(prefix_to_mask(prefix).to_string(), mask, "/{}", prefix);
+        }
+    }
+
+    #[test]
+    fn default_gateways_come_from_default_routes() {
+        let routes = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0
+eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+wlan0\t00000000\tFE01000A\t0003\t0\t0\t600\t00000000\t0\t0\t0
+tun0\t00000000\tnot-hex\t0003\t0\t0\t50\t00000000\t0\t0\t0
+short\t00000000
+";
+        let gateways = parse_default_gateways(routes);
+        let expected = [
+            ("eth0".to_string(), Ipv4Addr::new(192, 168, 1, 1)),
+            ("wlan0".to_string(), Ipv4Addr::new(10, 0, 1, 254)),
+        ];
+        assert_eq!(gateways, HashMap::from(expected));
+        assert!(parse_default_gateways("").is_empty());
+    }
+}
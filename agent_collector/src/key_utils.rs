@@ -3,20 +3,54 @@ use base64::engine::general_purpose;
 use base64::Engine as _;
 use rand::RngCore;
 use std::env;
-use std::fs::{self, File};
-use std::io::{Read, Write};
+use std::error::Error;
+use std::fs;
 use std::path::PathBuf;
+use tracing::{error, info, warn};
+
+#[cfg(not(windows))]
+use aes_gcm::aead::{Aead, AeadCore, KeyInit};
+#[cfg(not(windows))]
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+#[cfg(not(windows))]
+use std::fs::OpenOptions;
+#[cfg(not(windows))]
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+#[cfg(not(windows))]
+use std::path::Path;
+
+#[cfg(windows)]
 use windows::Win32::Foundation::HLOCAL;
+#[cfg(windows)]
 use windows::Win32::Security::Credentials::{
-    CredReadW, CredWriteW, CREDENTIALW, CRED_FLAGS, CRED_PERSIST_LOCAL_MACHINE, CRED_TYPE_GENERIC,
+    CredFree, CredReadW, CredWriteW, CREDENTIALW, CRED_FLAGS, CRED_PERSIST_LOCAL_MACHINE, CRED_TYPE_GENERIC,
 };
+#[cfg(windows)]
 use windows::Win32::Security::Cryptography::{
     CryptProtectData, CryptUnprotectData, CRYPT_INTEGER_BLOB, CRYPTPROTECT_LOCAL_MACHINE,
 };
+#[cfg(windows)]
 use windows::Win32::System::Memory::LocalFree;
+#[cfg(windows)]
 use windows::core::{PCWSTR, PWSTR};
 
+#[cfg(windows)]
 const CRED_KEY_NAME: &str = "MasterKeyStore";
+const MASTER_KEY_LEN: usize = 32;
+
+pub type KeyError = Box<dyn Error + Send + Sync>;
+
+/// Platform backend that persists the collector master key
+pub trait KeyStore {
+    /// Human readable name used in log lines
+    fn name(&self) -> &'static str;
+    /// Returns the stored master key, or None when nothing has been stored yet
+    fn load(&self) -> Result<Option<Vec<u8>>, KeyError>;
+    /// Persists a freshly generated master key
+    fn store(&self, key: &[u8]) -> Result<(), KeyError>;
+}
 
 pub struct KeyManager;
 
@@ -25,7 +59,7 @@ impl KeyManager {
     pub fn mark_as_onboarded() {
         let onboard_file = Self::get_onboarded_file_path();
         if let Err(e) = fs::write(onboard_file, "onboarded") {
-            println!("[ERROR] Failed to mark as onboarded: {}", e);
+            error!("Failed to mark as onboarded: {}", e);
         }
     }
 
@@ -36,170 +70,417 @@ impl KeyManager {
         path
     }
 
-    pub fn get_master_key_path() -> PathBuf {
-        let mut path = dirs::data_dir().expect("Failed to get data directory");
+    pub fn get_master_key_path() -> Result<PathBuf, KeyError> {
+        let mut path = dirs::data_dir().ok_or("failed to resolve the data directory")?;
         path.push("master_keyyys.dat");
-        path
+        Ok(path)
     }
 
-    pub fn generate_master_key() -> Vec<u8> {
-        let mut master_key = vec![0u8; 32];
+    /// Keystore used on this platform
+    pub fn platform_store() -> Result<Box<dyn KeyStore>, KeyError> {
+        #[cfg(windows)]
+        {
+            Ok(Box::new(WindowsKeyStore { path: Self::get_master_key_path()? }))
+        }
+        #[cfg(not(windows))]
+        {
+            Ok(Box::new(FileKeyStore::new(Self::get_master_key_path()?)))
+        }
+    }
+
+    pub fn generate_master_key() -> Result<Vec<u8>, KeyError> {
+        let mut master_key = vec![0u8; MASTER_KEY_LEN];
         OsRng.fill_bytes(&mut master_key);
 
-        let encrypted_master_key = Self::encrypt_with_dpapi(&master_key);
-        let path = Self::get_master_key_path();
+        let store = Self::platform_store()?;
+        store.store(&master_key)?;
+        info!("New master key generated and stored in {}.", store.name());
+        Ok(master_key)
+    }
+
+    /// Loads the master key from the platform keystore, falling back to the
+    /// `ENCRYPT_MASTER_KEY` environment variable and finally generating a new key
+    pub fn load_master_key() -> Result<Vec<u8>, KeyError> {
+        let store = Self::platform_store()?;
+        let stored = store.load();
+        match &stored {
+            Ok(Some(key)) => {
+                info!("Master key loaded from {}.", store.name());
+                return Ok(key.clone());
+            }
+            Ok(None) => {}
+            Err(e) => error!("Failed to read master key from {}: {}", store.name(), e),
+        }
+
+        if let Ok(encoded_key) = env::var("ENCRYPT_MASTER_KEY")
+            && let Some(key) = decode_env_key(&encoded_key)?
+        {
+            info!("Master key loaded from OS Environment Variable.");
+            return Ok(key);
+        }
 
-        fs::create_dir_all(path.parent().unwrap()).expect("Failed to create directories");
-        let mut file = File::create(path).expect("Failed to create master key file");
-        file.write_all(&encrypted_master_key)
-            .expect("Failed to write master key");
+        // An unreadable keystore must not be silently replaced with a new key
+        stored?;
 
-        Self::store_in_windows_cred(&master_key);
-        println!("[INFO] New master key generated and stored securely.");
-        master_key
+        warn!("Master key not found, generating a new one.");
+        Self::generate_master_key()
     }
+}
 
-    pub fn load_master_key() -> Vec<u8> {
-        if let Some(key) = Self::read_from_windows_cred() {
-            println!("[INFO] Master key loaded from Windows Credential Manager.");
-            return key;
+/// Reads a base64 `ENCRYPT_MASTER_KEY`; None if it is not base64
+fn decode_env_key(encoded_key: &str) -> Result<Option<Vec<u8>>, KeyError> {
+    match general_purpose::STANDARD.decode(encoded_key) {
+        // A wrong-sized key is an error rather than a reason to generate a new one
+        Ok(decoded_key) if decoded_key.len() != MASTER_KEY_LEN => Err(format!(
+            "ENCRYPT_MASTER_KEY decodes to {} bytes, expected {}",
+            decoded_key.len(),
+            MASTER_KEY_LEN
+        )
+        .into()),
+        Ok(decoded_key) => Ok(Some(decoded_key)),
+        Err(e) => {
+            warn!("ENCRYPT_MASTER_KEY is not valid base64: {}", e);
+            Ok(None)
         }
+    }
+}
 
-        if let Ok(encoded_key) = env::var("ENCRYPT_MASTER_KEY") {
-            if let Ok(decoded_key) = general_purpose::STANDARD.decode(encoded_key) {
-                println!("[INFO] Master key loaded from OS Environment Variable.");
-                return decoded_key;
-            }
+/// Keeps the master key AES-GCM encrypted on disk, with the wrapping key in a
+/// separate file that only the owner may read
+#[cfg(not(windows))]
+pub struct FileKeyStore {
+    path: PathBuf,
+    wrap_key_path: PathBuf,
+}
+
+#[cfg(not(windows))]
+impl FileKeyStore {
+    pub fn new(path: PathBuf) -> Self {
+        let wrap_key_path = path.with_extension("key");
+        Self { path, wrap_key_path }
+    }
+
+    fn load_wrap_key(&self) -> Result<Option<Vec<u8>>, KeyError> {
+        if !self.wrap_key_path.exists() {
+            return Ok(None);
         }
+        check_owner_only(&self.wrap_key_path)?;
 
-        let path = Self::get_master_key_path();
-        if path.exists() {
-            let mut file = File::open(&path).expect("Failed to open master key file");
-            let mut encrypted_master_key = Vec::new();
-            file.read_to_end(&mut encrypted_master_key)
-                .expect("Failed to read master key");
-            let decrypted_key = Self::decrypt_with_dpapi(&encrypted_master_key);
-            println!("[INFO] Master key loaded from file.");
-            return decrypted_key;
+        let key = fs::read(&self.wrap_key_path)?;
+        if key.len() != MASTER_KEY_LEN {
+            return Err(format!("{} is not a valid key file", self.wrap_key_path.display()).into());
         }
+        Ok(Some(key))
+    }
 
-        println!("[WARNING] Master key not found, generating a new one.");
-        Self::generate_master_key()
+    fn load_or_create_wrap_key(&self) -> Result<Vec<u8>, KeyError> {
+        if let Some(key) = self.load_wrap_key()? {
+            return Ok(key);
+        }
+
+        let mut key = vec![0u8; MASTER_KEY_LEN];
+        OsRng.fill_bytes(&mut key);
+        write_owner_only(&self.wrap_key_path, &key)?;
+        Ok(key)
     }
+}
 
-    pub fn encrypt_with_dpapi(data: &[u8]) -> Vec<u8> {
-        unsafe {
-            let data_blob = CRYPT_INTEGER_BLOB {
-                cbData: data.len() as u32,
-                pbData: data.as_ptr() as *mut u8,
-            };
-
-            let mut encrypted_blob = CRYPT_INTEGER_BLOB {
-                cbData: 0,
-                pbData: std::ptr::null_mut(),
-            };
-
-            let success = CryptProtectData(
-                &data_blob,
-                None,
-                None,
-                None,
-                None,
-                CRYPTPROTECT_LOCAL_MACHINE,
-                &mut encrypted_blob,
-            );
-
-            if success.as_bool() {
-                let slice =
-                    std::slice::from_raw_parts(encrypted_blob.pbData, encrypted_blob.cbData as usize);
-                let result = slice.to_vec();
-                let _ = LocalFree(HLOCAL(encrypted_blob.pbData as isize)); // Handle unused Result
-                result
-            } else {
-                panic!("DPAPI encryption failed");
-            }
+#[cfg(not(windows))]
+impl KeyStore for FileKeyStore {
+    fn name(&self) -> &'static str {
+        "the encrypted key file"
+    }
+
+    fn load(&self) -> Result<Option<Vec<u8>>, KeyError> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let wrap_key = self
+            .load_wrap_key()?
+            .ok_or_else(|| format!("{} is missing, cannot decrypt the master key", self.wrap_key_path.display()))?;
+
+        let sealed = fs::read(&self.path)?;
+        if sealed.len() <= 12 {
+            return Err(format!("{} is truncated", self.path.display()).into());
         }
+        let (nonce, ciphertext) = sealed.split_at(12);
+
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&wrap_key));
+        let key = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "master key decryption failed")?;
+        Ok(Some(key))
     }
 
-    pub fn decrypt_with_dpapi(encrypted_data: &[u8]) -> Vec<u8> {
-        unsafe {
-            let encrypted_blob = CRYPT_INTEGER_BLOB {
-                cbData: encrypted_data.len() as u32,
-                pbData: encrypted_data.as_ptr() as *mut u8,
-            };
-
-            let mut decrypted_blob = CRYPT_INTEGER_BLOB {
-                cbData: 0,
-                pbData: std::ptr::null_mut(),
-            };
-
-            let success = CryptUnprotectData(
-                &encrypted_blob,
-                None,
-                None,
-                None,
-                None,
-                0,
-                &mut decrypted_blob,
-            );
-
-            if success.as_bool() {
-                let slice =
-                    std::slice::from_raw_parts(decrypted_blob.pbData, decrypted_blob.cbData as usize);
-                let result = slice.to_vec();
-                let _ = LocalFree(HLOCAL(decrypted_blob.pbData as isize)); // Handle unused Result
-                result
-            } else {
-                panic!("DPAPI decryption failed");
-            }
+    fn store(&self, key: &[u8]) -> Result<(), KeyError> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let wrap_key = self.load_or_create_wrap_key()?;
+
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&wrap_key));
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, key).map_err(|_| "master key encryption failed")?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        write_owner_only(&self.path, &sealed)
+    }
+}
+
+/// Writes a file readable and writable by the owner only (0600)
+#[cfg(not(windows))]
+fn write_owner_only(path: &Path, data: &[u8]) -> Result<(), KeyError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(path)?;
+    // The mode only applies on creation, so tighten files that already existed
+    #[cfg(unix)]
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Refuses key files that group or other users can access
+#[cfg(not(windows))]
+fn check_owner_only(path: &Path) -> Result<(), KeyError> {
+    #[cfg(unix)]
+    {
+        let mode = fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(format!("{} has permissions {:o}, expected 600", path.display(), mode & 0o777).into());
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Keeps the master key in Windows Credential Manager with a DPAPI-protected file copy
+#[cfg(windows)]
+pub struct WindowsKeyStore {
+    path: PathBuf,
+}
+
+#[cfg(windows)]
+impl KeyStore for WindowsKeyStore {
+    fn name(&self) -> &'static str {
+        "Windows Credential Manager"
+    }
+
+    fn load(&self) -> Result<Option<Vec<u8>>, KeyError> {
+        if let Some(key) = read_from_windows_cred() {
+            return Ok(Some(key));
+        }
+        if !self.path.exists() {
+            return Ok(None);
         }
+
+        let encrypted_master_key = fs::read(&self.path)?;
+        info!("Master key not in Credential Manager, using the DPAPI file.");
+        Ok(Some(decrypt_with_dpapi(&encrypted_master_key)?))
     }
 
-    pub fn store_in_windows_cred(key: &[u8]) {
-        let key_utf16: Vec<u16> = CRED_KEY_NAME.encode_utf16().chain([0]).collect();
-        let target_name = PWSTR(key_utf16.as_ptr() as *mut _);
-
-        let cred = CREDENTIALW {
-            Flags: CRED_FLAGS(0),
-            Type: CRED_TYPE_GENERIC,
-            TargetName: target_name,
-            Comment: PWSTR::null(),
-            LastWritten: Default::default(),
-            CredentialBlobSize: key.len() as u32,
-            CredentialBlob: key.as_ptr() as *mut u8,
-            Persist: CRED_PERSIST_LOCAL_MACHINE,
-            AttributeCount: 0,
-            Attributes: std::ptr::null_mut(),
-            TargetAlias: PWSTR::null(),
-            UserName: PWSTR::null(),
+    fn store(&self, key: &[u8]) -> Result<(), KeyError> {
+        let encrypted_master_key = encrypt_with_dpapi(key)?;
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, encrypted_master_key)?;
+
+        // The DPAPI file is enough to recover the key, so a Credential Manager failure is not fatal
+        if let Err(e) = store_in_windows_cred(key) {
+            error!("{}", e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+pub fn encrypt_with_dpapi(data: &[u8]) -> Result<Vec<u8>, KeyError> {
+    unsafe {
+        let data_blob = CRYPT_INTEGER_BLOB {
+            cbData: data.len() as u32,
+            pbData: data.as_ptr() as *mut u8,
         };
 
-        unsafe {
-            let result = CredWriteW(&cred, 0);
-            if !result.as_bool() {
-                println!("[ERROR] Failed to store master key in Windows Credential Manager.");
-            }
+        let mut encrypted_blob = CRYPT_INTEGER_BLOB {
+            cbData: 0,
+            pbData: std::ptr::null_mut(),
+        };
+
+        let success = CryptProtectData(
+            &data_blob,
+            None,
+            None,
+            None,
+            None,
+            CRYPTPROTECT_LOCAL_MACHINE,
+            &mut encrypted_blob,
+        );
+
+        if success.as_bool() {
+            let slice = std::slice::from_raw_parts(encrypted_blob.pbData, encrypted_blob.cbData as usize);
+            let result = slice.to_vec();
+            let _ = LocalFree(HLOCAL(encrypted_blob.pbData as isize)); // Handle unused Result
+            Ok(result)
+        } else {
+            Err("DPAPI encryption failed".into())
         }
     }
+}
 
-    pub fn read_from_windows_cred() -> Option<Vec<u8>> {
-        let key_utf16: Vec<u16> = CRED_KEY_NAME.encode_utf16().chain([0]).collect();
-        let credential_name = PCWSTR(key_utf16.as_ptr());
+#[cfg(windows)]
+pub fn decrypt_with_dpapi(encrypted_data: &[u8]) -> Result<Vec<u8>, KeyError> {
+    unsafe {
+        let encrypted_blob = CRYPT_INTEGER_BLOB {
+            cbData: encrypted_data.len() as u32,
+            pbData: encrypted_data.as_ptr() as *mut u8,
+        };
 
-        let mut pcred: *mut CREDENTIALW = std::ptr::null_mut();
+        let mut decrypted_blob = CRYPT_INTEGER_BLOB {
+            cbData: 0,
+            pbData: std::ptr::null_mut(),
+        };
 
-        unsafe {
-            let result = CredReadW(credential_name, CRED_TYPE_GENERIC.0, 0, &mut pcred);
-            if result.as_bool() {
-                let cred = &*pcred;
-                let key_slice = std::slice::from_raw_parts(
-                    cred.CredentialBlob,
-                    cred.CredentialBlobSize as usize,
-                );
-                Some(key_slice.to_vec())
-            } else {
-                None
-            }
+        let success = CryptUnprotectData(
+            &encrypted_blob,
+            None,
+            None,
+            None,
+            None,
+            0,
+            &mut decrypted_blob,
+        );
+
+        if success.as_bool() {
+            let slice = std::slice::from_raw_parts(decrypted_blob.pbData, decrypted_blob.cbData as usize);
+            let result = slice.to_vec();
+            let _ = LocalFree(HLOCAL(decrypted_blob.pbData as isize)); // Handle unused Result
+            Ok(result)
+        } else {
+            Err("DPAPI decryption failed".into())
+        }
+    }
+}
+
+#[cfg(windows)]
+pub fn store_in_windows_cred(key: &[u8]) -> Result<(), KeyError> {
+    let key_utf16: Vec<u16> = CRED_KEY_NAME.encode_utf16().chain([0]).collect();
+    let target_name = PWSTR(key_utf16.as_ptr() as *mut _);
+
+    let cred = CREDENTIALW {
+        Flags: CRED_FLAGS(0),
+        Type: CRED_TYPE_GENERIC,
+        TargetName: target_name,
+        Comment: PWSTR::null(),
+        LastWritten: Default::default(),
+        CredentialBlobSize: key.len() as u32,
+        CredentialBlob: key.as_ptr() as *mut u8,
+        Persist: CRED_PERSIST_LOCAL_MACHINE,
+        AttributeCount: 0,
+        Attributes: std::ptr::null_mut(),
+        TargetAlias: PWSTR::null(),
+        UserName: PWSTR::null(),
+    };
+
+    unsafe {
+        if CredWriteW(&cred, 0).as_bool() {
+            Ok(())
+        } else {
+            Err("Failed to store master key in Windows Credential Manager.".into())
         }
     }
 }
+
+#[cfg(windows)]
+pub fn read_from_windows_cred() -> Option<Vec<u8>> {
+    let key_utf16: Vec<u16> = CRED_KEY_NAME.encode_utf16().chain([0]).collect();
+    let credential_name = PCWSTR(key_utf16.as_ptr());
+
+    let mut pcred: *mut CREDENTIALW = std::ptr::null_mut();
+
+    unsafe {
+        if CredReadW(credential_name, CRED_TYPE_GENERIC.0, 0, &mut pcred).as_bool() {
+            let cred = &*pcred;
+            let key_slice = std::slice::from_raw_parts(cred.CredentialBlob, cred.CredentialBlobSize as usize);
+            let key = key_slice.to_vec();
+            CredFree(pcred as *const _);
+            Some(key)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn store(name: &str) -> FileKeyStore {
+        let dir = std::env::temp_dir().join(format!("key-utils-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        FileKeyStore::new(dir.join("master_key.dat"))
+    }
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn stored_keys_load_back() {
+        let store = store("round-trip");
+        assert!(store.load().unwrap().is_none());
+
+        let key = vec![7u8; MASTER_KEY_LEN];
+        store.store(&key).unwrap();
+        assert_eq!(store.load().unwrap(), Some(key.clone()));
+        // Only the encrypted form is on disk
+        assert_ne!(fs::read(&store.path).unwrap()[12..][..MASTER_KEY_LEN], key[..]);
+    }
+
+    #[test]
+    fn key_files_are_owner_only() {
+        let store = store("mode");
+        store.store(&[1u8; MASTER_KEY_LEN]).unwrap();
+        assert_eq!(mode(&store.path), 0o600);
+        assert_eq!(mode(&store.wrap_key_path), 0o600);
+
+        fs::set_permissions(&store.wrap_key_path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(store.load().unwrap_err().to_string().contains("expected 600"));
+    }
+
+    #[test]
+    fn damaged_key_files_are_errors() {
+        let store = store("damaged");
+        store.store(&[2u8; MASTER_KEY_LEN]).unwrap();
+        let sealed = fs::read(&store.path).unwrap();
+
+        fs::write(&store.path, &sealed[..12]).unwrap();
+        assert!(store.load().unwrap_err().to_string().contains("truncated"));
+
+        let mut corrupt = sealed.clone();
+        *corrupt.last_mut().unwrap() ^= 0xff;
+        fs::write(&store.path, &corrupt).unwrap();
+        assert!(store.load().unwrap_err().to_string().contains("decryption failed"));
+
+        fs::write(&store.path, &sealed).unwrap();
+        fs::write(&store.wrap_key_path, [0u8; 5]).unwrap();
+        assert!(store.load().unwrap_err().to_string().contains("not a valid key file"));
+
+        fs::remove_file(&store.wrap_key_path).unwrap();
+        assert!(store.load().unwrap_err().to_string().contains("is missing"));
+    }
+
+    #[test]
+    fn environment_keys_must_be_32_bytes() {
+        let key = [3u8; MASTER_KEY_LEN];
+        assert_eq!(decode_env_key(&general_purpose::STANDARD.encode(key)).unwrap(), Some(key.to_vec()));
+        assert!(decode_env_key(&general_purpose::STANDARD.encode([3u8; 16])).unwrap_err().to_string().contains("16 bytes"));
+        assert!(decode_env_key("not base64!").unwrap().is_none());
+    }
+}
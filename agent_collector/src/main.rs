@@ -1,270 +1,615 @@
-use tracing::{info}; 
+use tracing::{info, info_span, error, warn, Instrument};
 use tokio::signal;
-use serde::Serialize;
-use tokio::io::AsyncBufReadExt; 
 use futures::StreamExt; 
 use base64::{engine::general_purpose, Engine as _};
 use agent_lib; 
 use tokio_tungstenite::accept_async;
 use tokio::net::{TcpListener, TcpStream};
 use futures_util::SinkExt;
-use std::sync::Arc;
-use tokio::sync::broadcast;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::{broadcast, Notify};
 use serde_json::json;
 use std::time::Duration;
 use shared_config::CONFIG;
-use axum::{Router, routing::{post, get}, Json};
+use axum::{Router, routing::{get, post}, middleware, Json};
+use axum::extract::Query;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use admin_auth::Role;
+use log_store::{LogQuery, QueryError};
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
 use serde::Deserialize;
 use tokio::spawn;
 use hyper::Server;
 use axum::serve;
 use warp::Filter;
-use tracing_appender::rolling;
-use tracing_subscriber::fmt::writer::MakeWriterExt;
 
 
 
+mod admin;
+mod identity;
 mod key_utils;
+mod outbox;
+mod policy;
+use identity::{load_or_create_agent_id, load_or_create_user_seed, random_uuid, write_atomically, NatsCredentials};
 use key_utils::KeyManager;
+use outbox::{Outbox, OutboxLimits};
+use policy::{load_policy, save_policy, GroupSchedule};
 
 use nats::publisher::NatsPublisher;
+use nats::request::RequestOptions;
 use nats::subscriber::NatsSubscriber;
-use models_database::db::{
-    establish_connection, get_agent_details
-};
+use models_database::db::get_agent_details;
+use models_database::with_connection;
 use async_nats::Client;
+use messages::{
+    subjects, AgentData, AgentResponse, BridgeResponse, CollectionPolicy, ControlAction, ControlCommand, ControlReply,
+    CredentialsReply, CredentialsRequest, Envelope, LogsReply, LogsRequest, MasterKey, MonitorBatch, MonitoringState, MonitoringStatus, PolicyAck, PolicyStatus, ScanAction,
+    ScanRequest, ScanResult,
+};
 use hostname;
 use sys_info;
 
-#[derive(Serialize,Debug)]
-struct MasterKeyPayload {
-    master_key: String,
-    hostname: String,
-    os: String,
-    os_version: String,
+/// Batches replayed from the outbox per monitoring tick, so a long backlog does not stall sampling
+const OUTBOX_REPLAY_PER_TICK: usize = 50;
+const LOG_PATH: &str = "logs.txt";
+/// How often `/ws/collector-logs` checks the log file for new lines
+const LOG_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How often onboarding re-checks whether the inventory was accepted while waiting for the bridge
+const INVENTORY_RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// State shared between the monitoring loop, the NATS health loop and the status WebSocket
+#[derive(Clone)]
+struct MonitorContext {
+    /// Namespace of every NATS subject this collector uses
+    agent_id: String,
+    monitoring_started: Arc<AtomicBool>,
+    manual_running: Arc<AtomicBool>,
+    nats_healthy: Arc<AtomicBool>,
+    running: Arc<AtomicBool>,
+    outbox: Arc<Mutex<Outbox>>,
+    status_tx: Arc<broadcast::Sender<String>>,
+    policy: Arc<RwLock<CollectionPolicy>>,
+    policy_changed: Arc<Notify>,
+    /// A `Restart` control command, for the monitoring loop to start over
+    restart: Arc<Notify>,
+}
+
+/// Loads this agent's NATS credentials. On first start the JWT is requested from the bridge over a
+/// connection as the shared bootstrap user, which may do nothing else; a refusal is retried with backoff.
+async fn agent_credentials(agent_id: &str) -> Result<NatsCredentials, Box<dyn std::error::Error + Send + Sync>> {
+    let seed = load_or_create_user_seed(&CONFIG.agent_nkey_path)?;
+    if let Ok(jwt) = std::fs::read_to_string(&CONFIG.agent_jwt_path)
+        && !jwt.trim().is_empty()
+    {
+        return Ok(NatsCredentials { jwt, seed });
+    }
+
+    let bootstrap = NatsPublisher::new(
+        &CONFIG.nats_url,
+        &std::fs::read_to_string(&CONFIG.c_jwt_path)?,
+        &std::fs::read_to_string(&CONFIG.c_nkey_path)?,
+        &CONFIG.ca_cert_path,
+        &CONFIG.client_cert_path,
+        &CONFIG.client_key_path,
+        subjects::BOOTSTRAP_INBOX,
+    )
+    .await?;
+    let subject = subjects::agent(agent_id, subjects::CREDENTIALS);
+    let request = Envelope::new(CredentialsRequest { public_key: nats::credentials::user_public_key(&seed)? });
+    let options = RequestOptions::default().forever();
+    let mut backoff = options.initial_backoff;
+    let jwt = loop {
+        let reply = bootstrap.request(&subject, &request, &options).await?;
+        match messages::decode::<CredentialsReply>(&reply.payload) {
+            Ok(CredentialsReply { jwt: Some(jwt), .. }) => break jwt,
+            Ok(CredentialsReply { error, .. }) => {
+                error!("Bridge refused NATS credentials, retrying in {:?}: {}", backoff, error.unwrap_or_default())
+            }
+            Err(e) => error!("Rejected credentials reply, retrying in {:?}: {}", backoff, e),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(options.max_backoff);
+    };
+    write_atomically(&CONFIG.agent_jwt_path, &jwt)?;
+    info!("NATS credentials for agent {} stored in {}", agent_id, CONFIG.agent_jwt_path);
+    Ok(NatsCredentials { jwt, seed })
 }
 
-async fn setup_nats_client(master_key: Vec<u8>, running: Arc<AtomicBool>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+async fn setup_nats_client(master_key: Vec<u8>, ctx: MonitorContext, creds: &NatsCredentials) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     
+    let inbox = subjects::inbox(&ctx.agent_id);
+
     // === PUBLISHER SETUP ===
     let publisher = NatsPublisher::new(
         &CONFIG.nats_url,
-        &std::fs::read_to_string(&CONFIG.c_jwt_path)?,
-        &std::fs::read_to_string(&CONFIG.c_nkey_path)?,
-        
+        &creds.jwt,
+        &creds.seed,
         &CONFIG.ca_cert_path,
         &CONFIG.client_cert_path,
         &CONFIG.client_key_path,
+        &inbox,
     )
     .await?;
 
      // === SUBSCRIBER SETUP ===
     let subscriber = NatsSubscriber::new(
         &CONFIG.nats_url,
-        &std::fs::read_to_string(&CONFIG.c_jwt_path)?,
-        &std::fs::read_to_string(&CONFIG.c_nkey_path)?,
+        &creds.jwt,
+        &creds.seed,
         &CONFIG.ca_cert_path,
         &CONFIG.client_cert_path,
         &CONFIG.client_key_path,
+        &inbox,
     )
     .await?;
 
-    println!("CONFIG.c_nkey_path: {}", &CONFIG.c_nkey_path);
-
-    // Publish master_key to "master_key"
-    let payload = MasterKeyPayload {
+    // Onboard with the master key
+    let payload = MasterKey {
         master_key: general_purpose::STANDARD.encode(&master_key),
         hostname : hostname::get()?.to_string_lossy().to_string(),
         os : format!("{} {}", sys_info::os_type()?, sys_info::os_release()?),
         os_version: sys_info::os_release()?
     };
-    publisher.publish("master.key", &payload).await?;
-    info!("Master key published to NATs........... ");
-
-    // Subscribe to bridge.response topic and handle it
+    let agent_id = ctx.agent_id.clone();
     let client = subscriber.client().clone();
     let subscribe_for_sacn = subscriber.client().clone(); 
-    
-    let pub_clone1 = publisher.clone(); // Clone for move into async
     let pub_clone2 = publisher.clone(); // For scan topic handler
 
-    let mut sub = subscriber.client().subscribe("bridge.response".to_string()).await?;
-    tokio::spawn(async move {
-        while let Some(msg) = sub.next().await {
-            let payload = String::from_utf8_lossy(&msg.payload);
-            let mut conn = establish_connection(&CONFIG.db_path);
-            
-            if get_agent_details(&mut conn).is_some() {
-                println!("[INFO] Device details stored in database. Skipping the collecting agent data ");
-                info!("Skipping the collecting agent data ");
-                start_monitoring(client.clone(), running.clone(), publisher.clone()).await;
-            } else {
-                println!("[INFO] Device details not found in database. Collecting the agent data...................");
-                info!("Device details not found in database. Collecting the agent data...................");
-            }
-            if let Ok(json) = serde_json::from_str::<serde_json::Value>(&payload) {
-                if json.get("status") == Some(&serde_json::Value::String("ok".to_string())) 
-                || json.get("message") == Some(&serde_json::Value::String("Token is already exists".to_string()))
-                {
-                    info!("Collecting the agent data...................");
-                    match agent_lib::agent_data() {
-                        Ok(agent_data) => { 
-                            if let Err(e) = pub_clone1.publish("agent.data", &agent_data).await {
-                                eprintln!("Failed to publish agent data: {e}");
-                            }                            
-
-                            println!("Waiting for agent response...................");
-                            let mut agent_response_sub = match subscriber.client().subscribe("agent.response".to_string()).await {
-                                Ok(sub) => sub,
-                                Err(e) => {
-                                    eprintln!("Failed to subscribe to agent.response: {e}");
-                                    return;
-                                }
-                            };
-
-                            while let Some(msg) = agent_response_sub.next().await {
-                                let payload = String::from_utf8_lossy(&msg.payload);
-                                println!("Agent response: {}", payload);
-
-                                if payload.contains("Data stored successfully") {
-                                    println!("[INFO] Valid response received");
-
-                                    start_monitoring(client.clone(), running.clone(), pub_clone1.clone()).await; // <-- FIX: add running.clone()
-                                } else {
-                                    eprintln!("[WARN] Unexpected response: {}", payload);
-                                }
-                            }
-                        }
-                        Err(e) => eprintln!("Failed to collect agent data: {e}"),
-                    }
-                }
-            }
-            else {
-                eprintln!("[ERROR] Failed to parse JSON response: {}", payload);
-            }
-        }
-    });
+    spawn_policy_listener(subscriber.client().clone(), publisher.clone(), ctx.clone());
+    spawn_logs_listener(subscriber.client().clone(), ctx.clone());
+    tokio::spawn(onboard(client, publisher, ctx, payload));
 
 //handling the scan the new added topic
+let scan_span = info_span!("handler", handler = "scan", agent_uuid = %agent_id);
 tokio::spawn(async move {
-    let mut new_sub = match subscribe_for_sacn.subscribe("scan.>".to_string()).await {
+    let mut new_sub = match subscribe_for_sacn.subscribe(subjects::scan(&agent_id, "*")).await {
         Ok(sub) => sub,
         Err(e) => {
-            eprintln!("Failed to subscribe to scan.partition: {e}");
+            error!("Failed to subscribe to scan requests: {e}");
             return;
         }
     };
 
     while let Some(msg) = new_sub.next().await {
-        let payload = String::from_utf8_lossy(&msg.payload);
-        println!("Received scan request: {}", payload);
-
-        if let Ok(json) = serde_json::from_str::<serde_json::Value>(&payload) {
-            if let Some(action) = json.get("action").and_then(|v| v.as_str()) {
-                let uuid_value = json.get("uuid").and_then(|v| v.as_str()).unwrap_or("");
-                match action {
-                    "disk" | "partition" => {
-                        info!("Scanning disk............................................");
-                        match agent_lib::scan_disk(action) {
-                            Ok(disk) => send_scan_response(&pub_clone2, action, uuid_value, disk).await,
-                            Err(e) => eprintln!("Failed to scan disk: {e}"),
-                        }
-                    },
-                    "nic" => {
-                        info!("Scanning nic details............................................");
-                        match agent_lib::scan_nic(action) {
-                            Ok(nic_data) => send_scan_response(&pub_clone2, action, uuid_value, nic_data).await,
-                            Err(e) => eprintln!("Failed to scan disk: {e}"),
-                        }
-                    }
-                    _ => {
-                        eprintln!("Unknown action received: {}", action);
-                    }
-                }
+        let request = match messages::decode::<ScanRequest>(&msg.payload) {
+            Ok(request) => request,
+            Err(e) => {
+                error!("Rejected scan request on {}: {e}", msg.subject);
+                continue;
             }
-        }
+        };
+        info!(subject = %msg.subject, "Received scan request: {:?}", request);
+
+        let action = request.action.as_str();
+        let kind = request.action;
+        // Scans read the hardware and the inventory tables, so they run off the async executor
+        let scanned = tokio::task::spawn_blocking(move || match kind {
+            ScanAction::Disk | ScanAction::Partition => {
+                info!("Scanning disk............................................");
+                agent_lib::scan_disk(action)
+            },
+            ScanAction::Nic => {
+                info!("Scanning nic details............................................");
+                agent_lib::scan_nic(action)
+            }
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.into()));
+        let result = match scanned {
+            Ok(result) => ScanResult { uuid: request.uuid, action: request.action, result, error: None },
+            Err(e) => {
+                error!("Failed to scan {action}: {e}");
+                ScanResult { uuid: request.uuid, action: request.action, result: String::new(), error: Some(e.to_string()) }
+            }
+        };
+        send_scan_response(&pub_clone2, &msg, &result).await;
     }
-});
+}.instrument(scan_span));
 
 Ok(())
 }
 
-async fn send_scan_response<T: serde::Serialize>(publisher: &NatsPublisher,   action: &str,uuid: &str ,data: T) {
-    let original_json = serde_json::json!(data);
-    
-        let message_json = serde_json::json!({
-            "uuid": uuid,
-            "result": original_json,
-            "action": action
-        });
-    
-            if let Err(e) = publisher.publish(&format!("send.scan.{}", action), &message_json).await {
-                eprintln!("Failed to publish agent data: {e}");
-            }
-       
+/// Sends the master key and, for a new device, the inventory, then starts monitoring.
+///
+/// Both steps are requests that are retried with backoff until the bridge replies.
+async fn onboard(client: Client, publisher: NatsPublisher, ctx: MonitorContext, master_key: MasterKey) {
+    let options = RequestOptions::default().forever();
+    let mut backoff = options.initial_backoff;
+    while let Err(e) = register(&client, &publisher, &ctx, &master_key, &options).await {
+        error!("Onboarding failed, retrying in {:?}: {}", backoff, e);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(options.max_backoff);
+    }
+    start_monitoring(client, ctx, publisher).await;
+}
+
+/// One onboarding attempt; `Ok` once the bridge has this host's inventory
+async fn register(
+    client: &Client,
+    publisher: &NatsPublisher,
+    ctx: &MonitorContext,
+    master_key: &MasterKey,
+    options: &RequestOptions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let subject = subjects::agent(&ctx.agent_id, subjects::MASTER_KEY);
+    let reply = publisher
+        .request(&subject, &Envelope::new(master_key.clone()), options)
+        .await
+        .map_err(|e| format!("onboarding request failed: {}", e))?;
+    info!("Master key published to NATs........... ");
+    let response = messages::decode::<BridgeResponse>(&reply.payload)
+        .map_err(|e| format!("rejected onboarding reply: {}", e))?;
+    info!("Bridge token status: {:?}", response.status);
 
+    if has_inventory().await {
+        info!("Device details stored in database. Skipping the collecting agent data");
+        return Ok(());
+    }
+    info!("Device details not found in database. Collecting the agent data...................");
+
+    // A full scan reads /proc, /sys and udev, which is blocking I/O
+    let inventory = tokio::task::spawn_blocking(agent_lib::agent_data)
+        .await?
+        .map_err(|e| format!("failed to collect agent data: {}", e))?;
+    // Subscribe before publishing so the bridge's answer cannot be missed
+    let mut responses = client.subscribe(subjects::agent(&ctx.agent_id, subjects::AGENT_RESPONSE)).await?;
+    let inventory_id = random_uuid();
+    let payload = messages::encode(&AgentData { inventory_id: inventory_id.clone(), inventory })?;
+    let subject = subjects::agent(&ctx.agent_id, subjects::AGENT_DATA);
+    publisher
+        .publish_durable(&subject, Some(&inventory_id), payload.into(), options)
+        .await
+        .map_err(|e| format!("failed to publish agent data: {}", e))?;
+    info!("Inventory {} stored for the bridge, waiting for the server to accept it", inventory_id);
+
+    // The stream holds the inventory until the bridge delivers it, so this only waits, never resends
+    loop {
+        match tokio::time::timeout(INVENTORY_RESPONSE_TIMEOUT, responses.next()).await {
+            Ok(Some(msg)) => match messages::decode::<AgentResponse>(&msg.payload) {
+                Ok(response) if response.inventory_id != inventory_id => continue,
+                Ok(response) if response.stored => {
+                    info!("Inventory {} accepted by the server", inventory_id);
+                    return Ok(());
+                }
+                Ok(response) => return Err(format!("inventory not stored: {}", response.message).into()),
+                Err(e) => warn!("Rejected inventory response: {}", e),
+            },
+            Ok(None) => return Err("inventory response subscription ended".into()),
+            Err(_) => {
+                // The response may have been lost while this collector was disconnected
+                if has_inventory().await {
+                    return Ok(());
+                }
+                info!("Inventory {} not accepted yet, still waiting", inventory_id);
+            }
+        }
+    }
+}
 
+/// Whether the bridge already stored this host's inventory
+async fn has_inventory() -> bool {
+    with_connection(|conn| Ok(get_agent_details(conn).is_some())).await.unwrap_or_else(|e| {
+        error!("Cannot read stored device details: {e}");
+        false
+    })
 }
 
+async fn send_scan_response(publisher: &NatsPublisher, request: &async_nats::Message, result: &ScanResult) {
+    let sent = match messages::encode(result) {
+        Ok(payload) => publisher.respond(request, payload.into()).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = sent {
+        error!("Failed to reply with scan result: {e}");
+    }
+}
 
-async fn start_monitoring(client: Client, running: Arc<AtomicBool>, publisher: NatsPublisher) {
-    // Publish monitoring running status to NATS
-    let _ = publisher.publish("monitoring.status", &serde_json::json!({"status": "running"})).await;
-    tracing::info!("[NATS] Published monitoring.status: running");
 
-    println!("Type 'scan' to start collecting monitoring data:");
-    let mut input = String::new();
-    let mut stdin = tokio::io::BufReader::new(tokio::io::stdin());
-    if let Err(e) = stdin.read_line(&mut input).await {
-        eprintln!("Failed to read input: {e}");
-        // Publish stopped status on error
-        let _ = publisher.publish("monitoring.status", &serde_json::json!({"status": "stopped"})).await;
-        tracing::info!("[NATS] Published monitoring.status: stopped (input error)");
+/// Starts the monitoring loop once onboarding has completed; later calls are no-ops
+async fn start_monitoring(client: Client, ctx: MonitorContext, publisher: NatsPublisher) {
+    // bridge.response can arrive more than once, but only one loop may own the outbox
+    if ctx.monitoring_started.swap(true, Ordering::SeqCst) {
         return;
     }
 
-    if input.trim().eq_ignore_ascii_case("scan") {
-        println!("Collecting the monitoring data...................");
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
-        let mut data_queue: Vec<String> = Vec::new();
-        loop {
-            interval.tick().await;
-            if !running.load(Ordering::SeqCst) {
-                tokio::time::sleep(Duration::from_secs(1)).await;
-                continue;
+    spawn_control_listener(client.clone(), ctx.clone());
+    tokio::spawn(monitoring_loop(ctx, publisher));
+}
+
+async fn monitoring_loop(ctx: MonitorContext, publisher: NatsPublisher) {
+    info!("Collecting the monitoring data");
+    let mut next_tick = tokio::time::Instant::now();
+    let mut schedule = GroupSchedule::default();
+    let mut data_queue: Vec<serde_json::Value> = Vec::new();
+    let mut reported_depth = None;
+    let mut reported_active = None;
+    let status_subject = subjects::agent(&ctx.agent_id, subjects::MONITORING_STATUS);
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(next_tick) => {}
+            // A new policy takes effect right away instead of after the old interval
+            _ = ctx.policy_changed.notified() => next_tick = tokio::time::Instant::now(),
+            // Queued checkpoints are kept; only the schedule and the reported state start over
+            _ = ctx.restart.notified() => {
+                info!("Monitoring restarted");
+                schedule = GroupSchedule::default();
+                reported_active = None;
+                next_tick = tokio::time::Instant::now();
+            }
+        }
+        let policy = ctx.policy.read().unwrap().clone();
+        next_tick = (next_tick + std::time::Duration::from_secs(policy.interval_secs)).max(tokio::time::Instant::now());
+        let active = ctx.manual_running.load(Ordering::SeqCst);
+
+        // Every start/stop transition is published; a failed publish is retried on the next tick
+        if reported_active != Some(active) {
+            let status = if active { MonitoringState::Running } else { MonitoringState::Stopped };
+            match publisher.publish(&status_subject, &Envelope::new(MonitoringStatus { status })).await {
+                Ok(()) => {
+                    reported_active = Some(active);
+                    tracing::info!("[NATS] Published monitoring.status: {:?}", status);
+                }
+                Err(e) => error!("Failed to publish monitoring status: {e}"),
             }
-            match agent_lib::monitor_data() {
+        }
+
+        // Sampling only stops on a manual pause; while NATS is down batches wait in the outbox
+        if active {
+            let sampled = tokio::task::spawn_blocking(agent_lib::monitor_data).await.unwrap_or_else(|e| Err(e.into()));
+            match sampled {
                 Ok(monitor_data) => {
-                    data_queue.push(monitor_data);
-                    if data_queue.len() == 5 {
-                        let payload = format!("[{}]", data_queue.join(","));
-                        if let Err(e) = client
-                            .publish("monitor.data".to_string(), payload.into_bytes().into())
-                            .await
-                        {
-                            eprintln!("Failed to publish batch: {e}");
-                        } else {
-                            println!("[INFO] Sent 5-point batch to bridge");
-                            data_queue.clear();
+                    match serde_json::from_str::<serde_json::Value>(&monitor_data) {
+                        Ok(mut checkpoint) => {
+                            schedule.filter(&policy, &mut checkpoint, std::time::Instant::now());
+                            data_queue.push(checkpoint);
+                        }
+                        Err(e) => error!("Monitoring checkpoint is not valid JSON: {e}"),
+                    }
+                    if data_queue.len() >= policy.batch_size {
+                        let batch = MonitorBatch { batch_id: random_uuid(), checkpoints: std::mem::take(&mut data_queue) };
+                        let queued = serde_json::to_string(&Envelope::new(batch))
+                            .map_err(|e| e.to_string())
+                            .and_then(|payload| ctx.outbox.lock().unwrap().push(&payload).map_err(|e| e.to_string()));
+                        if let Err(e) = queued {
+                            error!("Failed to queue monitoring batch: {e}");
                         }
                     }
                 }
                 Err(e) => {
-                    eprintln!("Failed to collect monitor data: {e}");
+                    error!("Failed to collect monitor data: {e}");
                 }
             }
         }
-        // On loop exit, publish stopped status
-        let _ = publisher.publish("monitoring.status", &serde_json::json!({"status": "stopped"})).await;
-        tracing::info!("[NATS] Published monitoring.status: stopped (loop exit)");
+
+        if ctx.nats_healthy.load(Ordering::SeqCst) {
+            flush_outbox(&publisher, &ctx).await;
+        }
+
+        let depth = ctx.outbox.lock().unwrap().depth();
+        if reported_depth != Some(depth) {
+            reported_depth = Some(depth);
+            broadcast_outbox_status(&ctx);
+        }
+    }
+}
+
+/// Listens for start/stop `ControlCommand`s on `agent.<agent id>.control`
+fn spawn_control_listener(client: Client, ctx: MonitorContext) {
+    let subject = subjects::agent(&ctx.agent_id, subjects::CONTROL);
+    let span = info_span!("handler", handler = "control", agent_uuid = %ctx.agent_id);
+
+    tokio::spawn(async move {
+        let mut sub = match client.subscribe(subject.clone()).await {
+            Ok(sub) => sub,
+            Err(e) => {
+                error!("Failed to subscribe to {subject}: {e}");
+                return;
+            }
+        };
+        info!("Listening for monitoring control commands on {}", subject);
+
+        while let Some(msg) = sub.next().await {
+            let response = match messages::decode::<ControlCommand>(&msg.payload) {
+                Ok(command) => {
+                    info!(subject = %msg.subject, "Monitoring control command: {:?}", command.action);
+                    let status = match command.action {
+                        ControlAction::Start => set_monitoring(&ctx, true),
+                        ControlAction::Stop => set_monitoring(&ctx, false),
+                        ControlAction::Restart => {
+                            ctx.restart.notify_one();
+                            set_monitoring(&ctx, true)
+                        }
+                    };
+                    ControlReply { status: Some(status.to_string()), error: None }
+                }
+                Err(e) => {
+                    error!("Rejected control command: {e}");
+                    ControlReply { status: None, error: Some(e.to_string()) }
+                }
+            };
+            let encoded = match messages::encode(&response) {
+                Ok(encoded) => encoded,
+                Err(e) => {
+                    error!("Failed to encode control reply: {e}");
+                    continue;
+                }
+            };
+            if let Some(reply) = msg.reply
+                && let Err(e) = client.publish(reply, encoded.into()).await
+            {
+                error!("Failed to reply to control command: {e}");
+            }
+        }
+    }.instrument(span));
+}
+
+/// Answers the bridge's `LogsRequest`s on `agent.<agent id>.logs` from the local log file
+fn spawn_logs_listener(client: Client, ctx: MonitorContext) {
+    let subject = subjects::agent(&ctx.agent_id, subjects::LOGS);
+    let span = info_span!("handler", handler = "logs", agent_uuid = %ctx.agent_id);
+    tokio::spawn(async move {
+        let mut sub = match client.subscribe(subject.clone()).await {
+            Ok(sub) => sub,
+            Err(e) => {
+                error!("Failed to subscribe to {subject}: {e}");
+                return;
+            }
+        };
+
+        while let Some(msg) = sub.next().await {
+            let page = messages::decode::<LogsRequest>(&msg.payload)
+                .map_err(|e| e.to_string())
+                .and_then(|request| {
+                    serde_json::from_value::<LogQuery>(serde_json::Value::Object(request.query.into_iter().collect()))
+                        .map_err(|e| format!("invalid log query: {e}"))
+                });
+            let page = match page {
+                Ok(query) => tokio::task::spawn_blocking(move || log_store::query(Path::new(LOG_PATH), &query))
+                    .await
+                    .map_err(|e| e.to_string())
+                    .and_then(|page| page.map_err(|e| e.to_string()))
+                    .and_then(|page| serde_json::to_value(page).map_err(|e| e.to_string())),
+                Err(e) => Err(e),
+            };
+            info!(subject = %msg.subject, "Log request from the bridge answered");
+            let reply = match page {
+                Ok(page) => LogsReply { page: Some(page), error: None },
+                Err(e) => LogsReply { page: None, error: Some(e) },
+            };
+            let encoded = match messages::encode(&reply) {
+                Ok(encoded) => encoded,
+                Err(e) => {
+                    error!("Failed to encode log reply: {e}");
+                    continue;
+                }
+            };
+            if let Some(reply) = msg.reply
+                && let Err(e) = client.publish(reply, encoded.into()).await
+            {
+                error!("Failed to reply to log request: {e}");
+            }
+        }
+    }.instrument(span));
+}
+
+/// Applies collection policies pushed by the bridge on `agent.<agent id>.policy` and acknowledges each one
+fn spawn_policy_listener(client: Client, publisher: NatsPublisher, ctx: MonitorContext) {
+    let span = info_span!("handler", handler = "policy", agent_uuid = %ctx.agent_id);
+    tokio::spawn(async move {
+        let ack_subject = subjects::agent(&ctx.agent_id, subjects::POLICY_ACK);
+        let mut sub = match client.subscribe(subjects::agent(&ctx.agent_id, subjects::POLICY)).await {
+            Ok(sub) => sub,
+            Err(e) => {
+                error!("Failed to subscribe to collection policy: {e}");
+                return;
+            }
+        };
+
+        while let Some(msg) = sub.next().await {
+            let ack = apply_policy(&ctx, &msg.payload);
+            info!(subject = %msg.subject, "Collection policy ack: {:?}", ack);
+            if let Err(e) = publisher.publish(&ack_subject, &Envelope::new(ack)).await {
+                error!("Failed to acknowledge collection policy: {e}");
+            }
+        }
+    }.instrument(span));
+}
+
+/// Validates, persists and activates a policy, returning the acknowledgement for the bridge
+fn apply_policy(ctx: &MonitorContext, payload: &[u8]) -> PolicyAck {
+    let requested = serde_json::from_slice::<serde_json::Value>(payload)
+        .ok()
+        .and_then(|raw| raw.get("version").and_then(|v| v.as_u64()));
+    let mut current = ctx.policy.write().unwrap();
+    let ack = |status: PolicyStatus, applied_version: u64, error: Option<String>| PolicyAck {
+        version: requested,
+        applied_version,
+        status,
+        error,
+    };
+
+    let policy = match messages::decode::<CollectionPolicy>(payload) {
+        Ok(policy) => policy,
+        Err(e) => return ack(PolicyStatus::Rejected, current.version, Some(e.to_string())),
+    };
+    if let Err(e) = policy.validate() {
+        return ack(PolicyStatus::Rejected, current.version, Some(e));
+    }
+    if policy == *current {
+        return ack(PolicyStatus::Applied, current.version, None);
+    }
+    if policy.version <= current.version {
+        return ack(PolicyStatus::Stale, current.version, None);
+    }
+    // Persist first so a restart never falls back to a policy the server considers replaced
+    if let Err(e) = save_policy(&policy, &CONFIG.policy_path) {
+        return ack(PolicyStatus::Rejected, current.version, Some(format!("failed to persist policy: {e}")));
+    }
+
+    *current = policy;
+    ctx.policy_changed.notify_one();
+    ack(PolicyStatus::Applied, current.version, None)
+}
+
+/// Starts or stops monitoring and broadcasts the resulting collector status
+fn set_monitoring(ctx: &MonitorContext, on: bool) -> &'static str {
+    ctx.manual_running.store(on, Ordering::SeqCst);
+    let nats_ok = ctx.nats_healthy.load(Ordering::SeqCst);
+    ctx.running.store(nats_ok && on, Ordering::SeqCst);
+    let status = collector_status(nats_ok, on);
+    broadcast_collector_status(&ctx.status_tx, status);
+    status
+}
+
+fn collector_status(nats_ok: bool, manual: bool) -> &'static str {
+    if !manual {
+        "Paused (Manual)"
+    } else if !nats_ok {
+        "Buffering (NATS)"
     } else {
-        // If scan is not entered, publish stopped status
-        let _ = publisher.publish("monitoring.status", &serde_json::json!({"status": "stopped"})).await;
-        tracing::info!("[NATS] Published monitoring.status: stopped (scan not entered)");
+        "Running"
     }
 }
-async fn handle_collector_connection(stream: TcpStream, status_tx: Arc<broadcast::Sender<String>>) {
+
+/// Replays queued monitoring batches in order, stopping at the first one the stream does not store
+async fn flush_outbox(publisher: &NatsPublisher, ctx: &MonitorContext) {
+    let subject = subjects::agent(&ctx.agent_id, subjects::MONITOR_DATA);
+    // A failed batch is retried on the next tick rather than blocking sampling here
+    let options = RequestOptions { timeout: Duration::from_secs(5), max_attempts: Some(1), ..RequestOptions::default() };
+    for _ in 0..OUTBOX_REPLAY_PER_TICK {
+        let next = ctx.outbox.lock().unwrap().peek();
+        let payload = match next {
+            Ok(Some(payload)) => payload,
+            Ok(None) => break,
+            Err(e) => {
+                error!("Failed to read monitoring outbox: {e}");
+                break;
+            }
+        };
+
+        let batch = messages::decode::<MonitorBatch>(payload.as_bytes()).ok();
+        let points = batch.as_ref().map_or(0, |batch| batch.checkpoints.len());
+        let batch_id = batch.map(|batch| batch.batch_id).filter(|id| !id.is_empty());
+        match publisher.publish_durable(&subject, batch_id.as_deref(), payload.into_bytes().into(), &options).await {
+            Ok(ack) if ack.duplicate => info!("Batch was already stored by the stream"),
+            Ok(_) => {}
+            Err(e) => {
+                error!("Failed to publish batch: {e}");
+                break;
+            }
+        }
+
+        if let Err(e) = ctx.outbox.lock().unwrap().ack() {
+            error!("Failed to advance monitoring outbox: {e}");
+            break;
+        }
+        info!("Sent {}-point batch to bridge", points);
+    }
+}
+
+async fn handle_collector_connection(stream: TcpStream, status_tx: Arc<broadcast::Sender<String>>, outbox: Arc<Mutex<Outbox>>) {
     let ws_stream = accept_async(stream).await.expect("Failed to accept websocket connection");
     let (mut ws_sender, _) = ws_stream.split();
 
@@ -276,6 +621,8 @@ async fn handle_collector_connection(stream: TcpStream, status_tx: Arc<broadcast
         "collector": "Running"
     }).to_string();
     let _ = ws_sender.send(initial_status.into()).await;
+    let outbox_status = outbox_status_json(&outbox);
+    let _ = ws_sender.send(outbox_status.into()).await;
 
     // Forward status updates to the WebSocket client
     while let Ok(status) = rx.recv().await {
@@ -294,38 +641,70 @@ pub fn broadcast_collector_status(status_tx: &Arc<broadcast::Sender<String>>, st
     let _ = status_tx.send(status_json); // Ignore send errors
 }
 
+fn outbox_status_json(outbox: &Mutex<Outbox>) -> String {
+    let outbox = outbox.lock().unwrap();
+    json!({
+        "outbox_depth": outbox.depth(),
+        "outbox_dropped": outbox.dropped()
+    }).to_string()
+}
+
+// Function to broadcast the monitoring outbox depth
+fn broadcast_outbox_status(ctx: &MonitorContext) {
+    let _ = ctx.status_tx.send(outbox_status_json(&ctx.outbox)); // Ignore send errors
+}
+
+
+// Log entries, newest page first, or those written after a `since` cursor
+async fn get_logs_handler(Query(query): Query<LogQuery>) -> Response {
+    let result = tokio::task::spawn_blocking(move || log_store::query(Path::new(LOG_PATH), &query))
+        .await
+        .unwrap_or_else(|e| Err(QueryError::Io(std::io::Error::other(e))));
+    match result {
+        Ok(page) => Json(page).into_response(),
+        Err(QueryError::Invalid(reason)) => (StatusCode::BAD_REQUEST, Json(json!({ "error": reason }))).into_response(),
+        Err(e) => {
+            error!("Log query failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": e.to_string() }))).into_response()
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Set up file logging
-    let file_appender = rolling::never(".", "logs.txt");
-    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
-
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .with_writer(non_blocking.and(std::io::stdout))
-        .with_ansi(false) // <--- enables color codes for terminal
-        .compact()        // <--- makes logs more compact/readable
-        .init();
+    // Stdout plus logs.txt, rotated and kept as configured by LOG_FORMAT, LOG_ROTATION, LOG_MAX_BYTES and LOG_MAX_FILES
+    let _guard = log_store::init(LOG_PATH)?;
 
     info!("Loading master key...");
-    let master_key = KeyManager::load_master_key();
+    let master_key = KeyManager::load_master_key()?;
     info!("Master key loaded successfully");
 
+    let agent_id = load_or_create_agent_id(&CONFIG.agent_id_path)?;
+    info!("Collector agent id: {}", agent_id);
+
+    let outbox_limits = OutboxLimits {
+        max_bytes: CONFIG.outbox_max_bytes,
+        max_age: Duration::from_secs(CONFIG.outbox_max_age_secs),
+    };
+    let outbox = Arc::new(Mutex::new(Outbox::open(&CONFIG.outbox_dir, outbox_limits)?));
+    info!("Monitoring outbox opened with {} queued batches", outbox.lock().unwrap().depth());
+
     // === WebSocket server for collector status ===
     let (status_tx, _status_rx) = broadcast::channel::<String>(16);
     let status_tx_arc = Arc::new(status_tx);
     let status_tx_clone = status_tx_arc.clone();
+    let outbox_for_status = outbox.clone();
     spawn(async move {
         let listener = TcpListener::bind("127.0.0.1:3032").await.expect("Failed to bind collector status WebSocket port");
-        println!("✅ Collector status WebSocket running at ws://127.0.0.1:3032/ws/collector");
+        info!("Collector status WebSocket running at ws://127.0.0.1:3032/ws/collector");
         loop {
             let (stream, _) = listener.accept().await.expect("Failed to accept connection");
             let status_tx_clone = status_tx_clone.clone();
+            let outbox = outbox_for_status.clone();
             spawn(async move {
                 // Only accept connections to /ws/collector
                 // For simplicity, accept all connections on this port
-                handle_collector_connection(stream, status_tx_clone).await;
+                handle_collector_connection(stream, status_tx_clone, outbox).await;
             });
         }
     });
@@ -333,116 +712,135 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     broadcast_collector_status(&status_tx_arc, "Running");
 
     // --- Service state flags ---
-    let manual_running = Arc::new(AtomicBool::new(true)); // controlled by toggle endpoint
-    let nats_healthy = Arc::new(AtomicBool::new(true));  // controlled by NATS health check
-    let running = Arc::new(AtomicBool::new(true));       // true if both above are true
+    let monitor_ctx = MonitorContext {
+        agent_id,
+        monitoring_started: Arc::new(AtomicBool::new(false)),
+        manual_running: Arc::new(AtomicBool::new(true)), // controlled by start/stop commands and the toggle endpoint
+        nats_healthy: Arc::new(AtomicBool::new(true)),   // controlled by NATS health check
+        running: Arc::new(AtomicBool::new(true)),        // true if both above are true
+        outbox: outbox.clone(),
+        status_tx: status_tx_arc.clone(),
+        policy: Arc::new(RwLock::new(load_policy(&CONFIG.policy_path))),
+        policy_changed: Arc::new(Notify::new()),
+        restart: Arc::new(Notify::new()),
+    };
+    info!("Collection policy version {} loaded", monitor_ctx.policy.read().unwrap().version);
+
+    let creds = agent_credentials(&monitor_ctx.agent_id).await?;
 
     // --- NATS health polling loop ---
-    let nats_healthy_for_health = nats_healthy.clone();
-    let manual_running_for_health = manual_running.clone();
-    let running_for_health = running.clone();
-    let status_tx_for_health = status_tx_arc.clone();
+    let ctx_for_health = monitor_ctx.clone();
+    let creds_for_health = creds.clone();
     tokio::spawn(async move {
         loop {
             let nats_ok = NatsPublisher::new(
                 &CONFIG.nats_url,
-                &std::fs::read_to_string(&CONFIG.c_jwt_path).unwrap_or_default(),
-                &std::fs::read_to_string(&CONFIG.c_nkey_path).unwrap_or_default(),
+                &creds_for_health.jwt,
+                &creds_for_health.seed,
                 &CONFIG.ca_cert_path,
                 &CONFIG.client_cert_path,
                 &CONFIG.client_key_path,
+                &subjects::inbox(&ctx_for_health.agent_id),
             ).await.is_ok();
-            let prev_nats = nats_healthy_for_health.load(Ordering::SeqCst);
-            nats_healthy_for_health.store(nats_ok, Ordering::SeqCst);
-            let manual = manual_running_for_health.load(Ordering::SeqCst);
+            ctx_for_health.nats_healthy.store(nats_ok, Ordering::SeqCst);
+            let manual = ctx_for_health.manual_running.load(Ordering::SeqCst);
             let should_run = nats_ok && manual;
-            let was_running = running_for_health.load(Ordering::SeqCst);
+            let was_running = ctx_for_health.running.load(Ordering::SeqCst);
             if should_run != was_running {
-                running_for_health.store(should_run, Ordering::SeqCst);
-                let status = if should_run {
-                    "Running"
-                } else if !nats_ok {
-                    "Paused (NATS)"
-                } else {
-                    "Paused (Manual)"
-                };
-                broadcast_collector_status(&status_tx_for_health, status);
+                ctx_for_health.running.store(should_run, Ordering::SeqCst);
+                broadcast_collector_status(&ctx_for_health.status_tx, collector_status(nats_ok, manual));
             }
             tokio::time::sleep(Duration::from_secs(5)).await;
         }
     });
 
-    // Pass running to setup_nats_client
-    setup_nats_client(master_key, running.clone()).await?;
-
-    // --- Toggle endpoint uses manual_running ---
-    let manual_running_for_toggle = manual_running.clone();
-    let nats_healthy_for_toggle = nats_healthy.clone();
-    let running_for_toggle = running.clone();
-    let status_tx_for_toggle = status_tx_arc.clone();
-    let app = Router::new().route(
-        "/api/service/collector/toggle",
-        get({
-            move || {
-                let manual_running = manual_running_for_toggle.clone();
-                let nats_healthy = nats_healthy_for_toggle.clone();
-                let running = running_for_toggle.clone();
-                let status_tx = status_tx_for_toggle.clone();
+    setup_nats_client(master_key, monitor_ctx.clone(), &creds).await?;
+
+    // --- Control endpoints drive manual_running; callers need an admin token ---
+    admin_auth::check_configuration();
+    let ctx_for_toggle = monitor_ctx.clone();
+    let ctx_for_start = monitor_ctx.clone();
+    let ctx_for_stop = monitor_ctx.clone();
+    let app = Router::new()
+        .route(
+            "/api/service/collector/toggle",
+            post(move || {
+                let ctx = ctx_for_toggle.clone();
                 async move {
-                    let new_manual = !manual_running.load(Ordering::SeqCst);
-                    manual_running.store(new_manual, Ordering::SeqCst);
-                    let nats_ok = nats_healthy.load(Ordering::SeqCst);
-                    let should_run = nats_ok && new_manual;
-                    running.store(should_run, Ordering::SeqCst);
-                    let status = if should_run {
-                        "Running"
-                    } else if !nats_ok {
-                        "Paused (NATS) "
-                    } else {
-                        "Paused (Manual) "
-                    };
-                    broadcast_collector_status(&status_tx, status);
+                    let status = set_monitoring(&ctx, !ctx.manual_running.load(Ordering::SeqCst));
                     Json(json!({ "status": status }))
                 }
-            }
-        }),
-    );
+            }),
+        )
+        .route(
+            "/api/service/collector/start",
+            post(move || {
+                let ctx = ctx_for_start.clone();
+                async move { Json(json!({ "status": set_monitoring(&ctx, true) })) }
+            }),
+        )
+        .route(
+            "/api/service/collector/stop",
+            post(move || {
+                let ctx = ctx_for_stop.clone();
+                async move { Json(json!({ "status": set_monitoring(&ctx, false) })) }
+            }),
+        )
+        .route_layer(middleware::from_fn(admin::require_admin))
+        .merge(
+            Router::new()
+                .route("/api/logs", get(get_logs_handler))
+                .route_layer(middleware::from_fn(admin::require_read_only)),
+        );
 
     tokio::spawn(async move {
         let listener = TcpListener::bind("127.0.0.1:3033").await.unwrap();
         serve(listener, app).await.unwrap();
     });
 
-    // WebSocket handler for collector logs
-async fn send_collector_logs(mut socket: warp::ws::WebSocket) {
-    let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    // Sends the newest log entries (or those after `since`) and then every new entry as it is written
+async fn send_collector_logs(mut socket: warp::ws::WebSocket, query: LogQuery) {
+    let (page, mut tail) = match log_store::follow(Path::new(LOG_PATH), &query) {
+        Ok(followed) => followed,
+        Err(e) => {
+            let _ = socket.send(warp::ws::Message::text(json!({ "error": e.to_string() }).to_string())).await;
+            return;
+        }
+    };
+    if socket.send(warp::ws::Message::text(json!(page.entries).to_string())).await.is_err() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(LOG_POLL_INTERVAL);
     loop {
-        interval.tick().await;
-        match fetch_collector_logs().await {
-            Ok(logs_json) => {
-                if socket.send(warp::ws::Message::text(logs_json)).await.is_err() {
+        tokio::select! {
+            _ = interval.tick() => {
+                let entries = match tail.poll() {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        warn!("Failed to read {}: {}", LOG_PATH, e);
+                        continue;
+                    }
+                };
+                if !entries.is_empty() && socket.send(warp::ws::Message::text(json!(entries).to_string())).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.next() => {
+                if !matches!(message, Some(Ok(_))) {
                     break;
                 }
             }
-            Err(_) => break,
         }
     }
 }
 
-async fn fetch_collector_logs() -> Result<String, std::io::Error> {
-    let file = tokio::fs::File::open("logs.txt").await?;
-    let reader = tokio::io::BufReader::new(file);
-    let mut lines = reader.lines();
-    let mut logs = Vec::new();
-    while let Some(line) = lines.next_line().await? {
-        logs.push(line);
-    }
-    Ok(serde_json::to_string(&logs)?)
-}
-
     let collector_logs_route = warp::path("ws").and(warp::path("collector-logs"))
+        .and(admin::warp_require(Role::ReadOnly))
         .and(warp::ws())
-        .map(|ws: warp::ws::Ws| ws.on_upgrade(send_collector_logs));
+        .and(warp::query::<LogQuery>())
+        .map(|ws: warp::ws::Ws, query: LogQuery| ws.on_upgrade(move |socket| send_collector_logs(socket, query)))
+        .recover(admin::handle_warp_rejection);
 
     tokio::spawn(async move {
         warp::serve(collector_logs_route)
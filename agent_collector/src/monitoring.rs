@@ -0,0 +1,469 @@
+use chrono::Local;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use sysinfo::{CpuRefreshKind, Disks, MemoryRefreshKind, RefreshKind, System};
+use tracing::warn;
+
+use crate::inventory;
+use models_database::db::{
+    find_cpu_uuid, find_device_uuid, find_memory_uuid, find_partition_by_serial,
+    find_port_by_interface, find_storage_uuid,
+};
+use models_database::get_connection;
+use shared_config::CONFIG;
+
+/// Monitoring sections and the inventory table each one's uuid is resolved from
+const TABLE_UUID_MAP: [(&str, &str); 6] = [
+    ("memory_monitoring", "memory"),
+    ("cpu_monitoring", "cpu"),
+    ("disk_monitoring", "storage"),
+    ("partition_monitoring", "partition"),
+    ("network_monitoring", "port"),
+    ("device", "device"),
+];
+
+const UNKNOWN_UUID: &str = "unknown";
+
+static MONITOR: Mutex<Option<Monitor>> = Mutex::new(None);
+
+#[derive(Debug, Serialize)]
+pub struct Checkpoint {
+    pub device_uuid: String,
+    pub event_type: &'static str,
+    pub description: &'static str,
+    pub date: String,
+    pub time: String,
+    pub memory_monitoring: MemorySample,
+    pub cpu_monitoring: CpuSample,
+    pub disk_monitoring: Vec<DiskSample>,
+    pub partition_monitoring: Vec<PartitionSample>,
+    pub network_monitoring: Vec<NetworkSample>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MemorySample {
+    pub memory_uuid: String,
+    pub memory_used: u64,
+    pub memory_available: u64,
+    pub total_memory: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CpuSample {
+    pub cpu_uuid: String,
+    pub p_cores_perc: BTreeMap<String, f64>,
+    pub l_cores_perc: BTreeMap<String, f64>,
+    pub ctx_switches: u64,
+    pub sw_irq: u64,
+    pub hw_irq: u64,
+    pub syscalls: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiskSample {
+    pub disk_uuid: String,
+    pub read_count_io: u64,
+    pub write_count_io: u64,
+    pub bytes_read_io: u64,
+    pub bytes_write_io: u64,
+    pub read_time_io: u64,
+    pub write_time_io: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PartitionSample {
+    pub partition_uuid: String,
+    pub disk_uuid: String,
+    pub mount_point: String,
+    pub free_space: u64,
+    pub used_space: u64,
+    pub used_space_perc: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NetworkSample {
+    pub port_uuid: String,
+    pub nic_uuid: String,
+    pub interface: String,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub error_in: u64,
+    pub error_out: u64,
+    pub drop_in: u64,
+    pub drop_out: u64,
+}
+
+/// Takes one monitoring checkpoint, keeping the sampler alive between calls so
+/// CPU usage is measured over the collection interval
+pub fn monitor_data() -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut lock = MONITOR.lock().map_err(|_| "monitoring sampler lock poisoned")?;
+    let monitor = lock.get_or_insert_with(Monitor::new);
+    Ok(serde_json::to_string(&monitor.checkpoint())?)
+}
+
+/// Identifiers that tie live samples to rows in the inventory tables
+struct HardwareIdentifiers {
+    device_model: String,
+    memory_make: String,
+    cpu_model: String,
+    disk_serials: HashMap<String, String>,
+    partition_serials: HashMap<String, String>,
+}
+
+impl HardwareIdentifiers {
+    fn collect() -> Self {
+        Self {
+            device_model: inventory::device_model(),
+            memory_make: inventory::memory_details()
+                .first()
+                .map_or_else(|| "Virtual".to_string(), |m| m.make.clone()),
+            cpu_model: inventory::cpu_details()
+                .first()
+                .map_or_else(|| "Unknown".to_string(), |c| c.model.clone()),
+            disk_serials: inventory::block_devices()
+                .into_iter()
+                .map(|disk| {
+                    let serial = inventory::disk_serial(&disk);
+                    (disk, serial)
+                })
+                .collect(),
+            partition_serials: inventory::partition_serials(),
+        }
+    }
+}
+
+/// A resolved uuid plus the parent uuid for partitions (storage) and ports (NIC)
+type Resolved = (String, String);
+
+pub struct Monitor {
+    sys: System,
+    disks: Disks,
+    core_groups: Vec<Vec<usize>>,
+    identifiers: HardwareIdentifiers,
+    uuid_cache: HashMap<(&'static str, String), Resolved>,
+    db_modified: [Option<SystemTime>; 2],
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        let sys = System::new_with_specifics(
+            RefreshKind::nothing()
+                .with_cpu(CpuRefreshKind::nothing().with_cpu_usage())
+                .with_memory(MemoryRefreshKind::nothing().with_ram()),
+        );
+        let core_groups = physical_core_groups(sys.cpus().len(), sys.physical_core_count());
+
+        Self {
+            sys,
+            disks: Disks::new_with_refreshed_list(),
+            core_groups,
+            identifiers: HardwareIdentifiers::collect(),
+            uuid_cache: HashMap::new(),
+            db_modified: db_modified(),
+        }
+    }
+
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        self.refresh_if_db_changed();
+        let now = Local::now();
+        let device_model = self.identifiers.device_model.clone();
+
+        Checkpoint {
+            device_uuid: self.resolve("device", &device_model).0,
+            event_type: "MON_DATA",
+            description: "monitoring data",
+            date: now.format("%Y-%m-%d").to_string(),
+            time: now.format("%H:%M:%S").to_string(),
+            memory_monitoring: self.memory_sample(),
+            cpu_monitoring: self.cpu_sample(),
+            disk_monitoring: self.disk_samples(),
+            partition_monitoring: self.partition_samples(),
+            network_monitoring: self.network_samples(),
+        }
+    }
+
+    /// Drops cached uuids and identifiers once the inventory database has been written to
+    fn refresh_if_db_changed(&mut self) {
+        let modified = db_modified();
+        if modified != self.db_modified {
+            self.db_modified = modified;
+            self.uuid_cache.clear();
+            self.identifiers = HardwareIdentifiers::collect();
+        }
+    }
+
+    fn resolve(&mut self, section: &'static str, key: &str) -> Resolved {
+        let cache_key = (section, key.to_string());
+        if let Some(hit) = self.uuid_cache.get(&cache_key) {
+            return hit.clone();
+        }
+
+        let table = TABLE_UUID_MAP.iter().find(|(s, _)| *s == section).map(|(_, t)| *t);
+        let mut conn = match get_connection(&CONFIG.db_path) {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Not cached, so the lookup is retried on the next sample
+                warn!("Cannot resolve {} uuid for {}: {}", section, key, e);
+                return (UNKNOWN_UUID.to_string(), UNKNOWN_UUID.to_string());
+            }
+        };
+        let found = match table {
+            Some("device") => find_device_uuid(&mut conn, key).map(|u| (u, String::new())),
+            Some("cpu") => find_cpu_uuid(&mut conn, key).map(|u| (u, String::new())),
+            Some("memory") => find_memory_uuid(&mut conn, key).map(|u| (u, String::new())),
+            Some("storage") => find_storage_uuid(&mut conn, key).map(|u| (u, String::new())),
+            Some("partition") => find_partition_by_serial(&mut conn, key),
+            Some("port") => find_port_by_interface(&mut conn, key),
+            _ => None,
+        };
+        let resolved = found.unwrap_or_else(|| (UNKNOWN_UUID.to_string(), UNKNOWN_UUID.to_string()));
+
+        self.uuid_cache.insert(cache_key, resolved.clone());
+        resolved
+    }
+
+    fn memory_sample(&mut self) -> MemorySample {
+        self.sys.refresh_memory_specifics(MemoryRefreshKind::nothing().with_ram());
+        let memory_make = self.identifiers.memory_make.clone();
+
+        MemorySample {
+            memory_uuid: self.resolve("memory_monitoring", &memory_make).0,
+            memory_used: self.sys.used_memory(),
+            memory_available: self.sys.available_memory(),
+            total_memory: self.sys.total_memory(),
+        }
+    }
+
+    fn cpu_sample(&mut self) -> CpuSample {
+        self.sys.refresh_cpu_usage();
+        let usages: Vec<f64> = self.sys.cpus().iter().map(|c| round(f64::from(c.cpu_usage()), 1)).collect();
+        let cpu_model = self.identifiers.cpu_model.clone();
+        let stat = ProcStat::read();
+
+        let p_cores_perc = self
+            .core_groups
+            .iter()
+            .enumerate()
+            .filter_map(|(i, group)| {
+                let samples: Vec<f64> = group.iter().filter_map(|cpu| usages.get(*cpu).copied()).collect();
+                (!samples.is_empty()).then(|| {
+                    let avg = samples.iter().sum::<f64>() / samples.len() as f64;
+                    (format!("physical_core_{}", i + 1), round(avg, 2))
+                })
+            })
+            .collect();
+        let l_cores_perc = usages
+            .iter()
+            .enumerate()
+            .map(|(i, usage)| (format!("logical_core_{}", i + 1), *usage))
+            .collect();
+
+        CpuSample {
+            cpu_uuid: self.resolve("cpu_monitoring", &cpu_model).0,
+            p_cores_perc,
+            l_cores_perc,
+            ctx_switches: stat.ctx_switches,
+            sw_irq: stat.soft_interrupts,
+            hw_irq: stat.interrupts,
+            // Linux does not count system calls; psutil reports 0 here as well
+            syscalls: 0,
+        }
+    }
+
+    fn disk_samples(&mut self) -> Vec<DiskSample> {
+        let diskstats = fs::read_to_string("/proc/diskstats").unwrap_or_default();
+        let mut samples = Vec::new();
+
+        for line in diskstats.lines() {
+            let cols: Vec<&str> = line.split_whitespace().collect();
+            if cols.len() < 11 {
+                continue;
+            }
+            let Some(serial) = self.identifiers.disk_serials.get(cols[2]).cloned() else {
+                continue;
+            };
+            let field = |i: usize| cols[i].parse::<u64>().unwrap_or(0);
+
+            samples.push(DiskSample {
+                disk_uuid: self.resolve("disk_monitoring", &serial).0,
+                read_count_io: field(3),
+                write_count_io: field(7),
+                bytes_read_io: field(5) * 512,
+                bytes_write_io: field(9) * 512,
+                read_time_io: field(6),
+                write_time_io: field(10),
+            });
+        }
+
+        samples
+    }
+
+    fn partition_samples(&mut self) -> Vec<PartitionSample> {
+        self.disks.refresh(true);
+        let mounts: Vec<(String, String, u64, u64)> = self
+            .disks
+            .list()
+            .iter()
+            .map(|d| {
+                (
+                    d.name().to_string_lossy().to_string(),
+                    d.mount_point().to_string_lossy().to_string(),
+                    d.total_space(),
+                    d.available_space(),
+                )
+            })
+            .collect();
+
+        let mut seen = HashSet::new();
+        let mut samples = Vec::new();
+        for (device, mount_point, total, free) in mounts {
+            // Bind mounts list the same block device more than once
+            if !device.starts_with("/dev/") || !seen.insert(device.clone()) {
+                continue;
+            }
+
+            let (partition_uuid, disk_uuid) = if let Some(serial) = self.identifiers.partition_serials.get(&device).cloned() {
+                self.resolve("partition_monitoring", &serial)
+            } else if let Some(serial) = self.identifiers.disk_serials.get(&device["/dev/".len()..]).cloned() {
+                // Filesystem directly on a disk: there is no partition row, only the storage one
+                (UNKNOWN_UUID.to_string(), self.resolve("disk_monitoring", &serial).0)
+            } else {
+                (UNKNOWN_UUID.to_string(), UNKNOWN_UUID.to_string())
+            };
+
+            let used = total.saturating_sub(free);
+            let percent = if total == 0 { 0.0 } else { used as f64 * 100.0 / total as f64 };
+            samples.push(PartitionSample {
+                partition_uuid,
+                disk_uuid,
+                mount_point,
+                free_space: free,
+                used_space: used,
+                used_space_perc: format!("{:.1} %", percent),
+            });
+        }
+
+        samples
+    }
+
+    fn network_samples(&mut self) -> Vec<NetworkSample> {
+        let mut samples = Vec::new();
+        let Ok(entries) = fs::read_dir("/sys/class/net") else {
+            return samples;
+        };
+
+        let mut interfaces: Vec<String> = entries.flatten().map(|e| e.file_name().to_string_lossy().to_string()).collect();
+        interfaces.sort();
+        for iface in interfaces.into_iter().filter(|i| i != "lo") {
+            let sys_path = Path::new("/sys/class/net").join(&iface);
+            // IFF_UP is bit 0 of the interface flags
+            let is_up = read_counter(&sys_path.join("flags")) & 0x1 == 1;
+            let stat = |name: &str| read_counter(&sys_path.join("statistics").join(name));
+            let (bytes_sent, bytes_received) = (stat("tx_bytes"), stat("rx_bytes"));
+            if !is_up || (bytes_sent == 0 && bytes_received == 0) {
+                continue;
+            }
+
+            let (port_uuid, nic_uuid) = self.resolve("network_monitoring", &iface);
+            samples.push(NetworkSample {
+                port_uuid,
+                nic_uuid,
+                interface: iface,
+                bytes_sent,
+                bytes_received,
+                packets_sent: stat("tx_packets"),
+                packets_received: stat("rx_packets"),
+                error_in: stat("rx_errors"),
+                error_out: stat("tx_errors"),
+                drop_in: stat("rx_dropped"),
+                drop_out: stat("tx_dropped"),
+            });
+        }
+
+        samples
+    }
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// System-wide counters from /proc/stat
+#[derive(Default)]
+struct ProcStat {
+    ctx_switches: u64,
+    interrupts: u64,
+    soft_interrupts: u64,
+}
+
+impl ProcStat {
+    fn read() -> Self {
+        let mut stat = Self::default();
+        let content = fs::read_to_string("/proc/stat").unwrap_or_default();
+        for line in content.lines() {
+            let mut cols = line.split_whitespace();
+            // intr and softirq lines start with the total followed by per-source counts
+            let target = match cols.next() {
+                Some("ctxt") => &mut stat.ctx_switches,
+                Some("intr") => &mut stat.interrupts,
+                Some("softirq") => &mut stat.soft_interrupts,
+                _ => continue,
+            };
+            *target = cols.next().and_then(|v| v.parse().ok()).unwrap_or(0);
+        }
+        stat
+    }
+}
+
+/// Groups logical CPU indices by physical core using the sysfs topology,
+/// falling back to the round-robin split the Python sampler used
+fn physical_core_groups(logical: usize, physical: Option<usize>) -> Vec<Vec<usize>> {
+    let mut by_core: BTreeMap<(u64, u64), Vec<usize>> = BTreeMap::new();
+    for cpu in 0..logical {
+        let topology = Path::new("/sys/devices/system/cpu").join(format!("cpu{}", cpu)).join("topology");
+        let package = fs::read_to_string(topology.join("physical_package_id")).ok().and_then(|v| v.trim().parse().ok());
+        let core = fs::read_to_string(topology.join("core_id")).ok().and_then(|v| v.trim().parse().ok());
+        match (package, core) {
+            (Some(package), Some(core)) => by_core.entry((package, core)).or_default().push(cpu),
+            _ => {
+                let cores = physical.unwrap_or(logical).max(1);
+                let mut groups = vec![Vec::new(); cores];
+                for cpu in 0..logical {
+                    groups[cpu % cores].push(cpu);
+                }
+                return groups;
+            }
+        }
+    }
+    by_core.into_values().collect()
+}
+
+/// When the database and its write-ahead log last changed. In WAL mode a commit only writes the
+/// `-wal` file; the database file itself changes only when the log is checkpointed into it.
+fn db_modified() -> [Option<SystemTime>; 2] {
+    let modified = |path: &str| fs::metadata(path).and_then(|m| m.modified()).ok();
+    [modified(&CONFIG.db_path), modified(&format!("{}-wal", CONFIG.db_path))]
+}
+
+fn read_counter(path: &Path) -> u64 {
+    let raw = fs::read_to_string(path).unwrap_or_default();
+    let raw = raw.trim();
+    match raw.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).unwrap_or(0),
+        None => raw.parse().unwrap_or(0),
+    }
+}
+
+fn round(value: f64, decimals: i32) -> f64 {
+    let factor = 10f64.powi(decimals);
+    (value * factor).round() / factor
+}
@@ -0,0 +1,332 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+const SEGMENT_EXT: &str = "seg";
+const CURSOR_FILE: &str = "cursor";
+const SEGMENT_BYTES: u64 = 1024 * 1024;
+const MIN_SEGMENT_BYTES: u64 = 4 * 1024;
+
+/// Bounds on how much unsent monitoring data is kept on disk
+#[derive(Debug, Clone, Copy)]
+pub struct OutboxLimits {
+    /// Enforced by dropping whole segments, which are sized to a quarter of this (between 4 KiB and
+    /// 1 MiB), so the outbox can exceed it by at most one segment
+    pub max_bytes: u64,
+    pub max_age: Duration,
+}
+
+struct Segment {
+    id: u64,
+    bytes: u64,
+    /// Records in this segment that have not been acknowledged yet
+    pending: usize,
+}
+
+/// Disk-backed FIFO of monitoring batches waiting to be published.
+///
+/// Batches are appended as `<unix secs>\t<payload>` lines to numbered segment
+/// files; a cursor file records the position of the oldest unsent batch.
+pub struct Outbox {
+    dir: PathBuf,
+    limits: OutboxLimits,
+    segments: VecDeque<Segment>,
+    next_id: u64,
+    cursor_offset: u64,
+    /// Length of the record returned by the last `peek`, consumed by `ack`
+    peeked_len: Option<u64>,
+    depth: usize,
+    dropped: u64,
+}
+
+impl Outbox {
+    pub fn open(dir: impl AsRef<Path>, limits: OutboxLimits) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut ids: Vec<u64> = fs::read_dir(&dir)?
+            .flatten()
+            .filter_map(|e| {
+                let path = e.path();
+                (path.extension().and_then(|x| x.to_str()) == Some(SEGMENT_EXT))
+                    .then(|| path.file_stem()?.to_str()?.parse().ok())
+                    .flatten()
+            })
+            .collect();
+        ids.sort_unstable();
+
+        let mut outbox = Self {
+            dir,
+            limits,
+            segments: VecDeque::new(),
+            next_id: 0,
+            cursor_offset: 0,
+            peeked_len: None,
+            depth: 0,
+            dropped: 0,
+        };
+
+        // A crash mid-append can leave a partial line at the end of the newest segment
+        if let Some(last) = ids.last() {
+            outbox.truncate_torn_tail(*last)?;
+        }
+
+        let (cursor_id, cursor_offset) = outbox.read_cursor();
+        // Segment ids never go below the cursor, or new data would look delivered on restart
+        outbox.next_id = ids.last().map_or(cursor_id, |last| (last + 1).max(cursor_id));
+        for id in ids {
+            if id < cursor_id {
+                // Fully delivered before the last shutdown
+                let _ = fs::remove_file(outbox.segment_path(id));
+                continue;
+            }
+            let offset = if id == cursor_id { cursor_offset } else { 0 };
+            if id == cursor_id {
+                outbox.cursor_offset = cursor_offset;
+            }
+            let bytes = fs::metadata(outbox.segment_path(id))?.len();
+            let pending = count_lines(&outbox.segment_path(id), offset)?;
+            outbox.depth += pending;
+            outbox.segments.push_back(Segment { id, bytes, pending });
+        }
+        if outbox.segments.front().is_some_and(|s| s.id != cursor_id) {
+            outbox.cursor_offset = 0;
+        }
+
+        outbox.prune()?;
+        Ok(outbox)
+    }
+
+    /// Number of batches waiting to be published
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Batches discarded by the size and age limits since the outbox was opened
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Appends a batch, dropping the oldest data if the outbox is over its limits
+    pub fn push(&mut self, payload: &str) -> io::Result<()> {
+        if payload.contains('\n') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "outbox payload must be a single line"));
+        }
+
+        let segment_bytes = self.segment_bytes();
+        let needs_segment = self.segments.back().is_none_or(|s| s.bytes >= segment_bytes);
+        if needs_segment {
+            let id = self.next_id;
+            self.next_id += 1;
+            self.segments.push_back(Segment { id, bytes: 0, pending: 0 });
+        }
+
+        let line = format!("{}\t{}\n", unix_now(), payload);
+        let segment = self.segments.back_mut().expect("segment was just ensured");
+        let mut file = OpenOptions::new().create(true).append(true).open(self.dir.join(segment_name(segment.id)))?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+
+        segment.bytes += line.len() as u64;
+        segment.pending += 1;
+        self.depth += 1;
+
+        self.prune()
+    }
+
+    /// Returns the oldest unsent batch without removing it, skipping batches past the age limit
+    pub fn peek(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let Some(front) = self.segments.front() else {
+                return Ok(None);
+            };
+
+            let mut reader = BufReader::new(File::open(self.segment_path(front.id))?);
+            reader.seek(SeekFrom::Start(self.cursor_offset))?;
+            let mut line = String::new();
+            let len = reader.read_line(&mut line)? as u64;
+
+            if len == 0 || !line.ends_with('\n') {
+                if self.segments.len() == 1 {
+                    return Ok(None);
+                }
+                // Everything in this segment has been delivered
+                self.remove_front()?;
+                continue;
+            }
+
+            let (written, payload) = line.trim_end_matches('\n').split_once('\t').unwrap_or(("0", ""));
+            let written: u64 = written.parse().unwrap_or(0);
+            if unix_now().saturating_sub(written) > self.limits.max_age.as_secs() {
+                self.advance(len)?;
+                self.dropped += 1;
+                continue;
+            }
+
+            self.peeked_len = Some(len);
+            return Ok(Some(payload.to_string()));
+        }
+    }
+
+    /// Removes the batch returned by the last `peek` once it has been published
+    pub fn ack(&mut self) -> io::Result<()> {
+        match self.peeked_len.take() {
+            Some(len) => self.advance(len),
+            None => Ok(()),
+        }
+    }
+
+    fn advance(&mut self, len: u64) -> io::Result<()> {
+        self.peeked_len = None;
+        self.cursor_offset += len;
+        if let Some(front) = self.segments.front_mut() {
+            front.pending = front.pending.saturating_sub(1);
+        }
+        self.depth = self.depth.saturating_sub(1);
+        self.write_cursor()
+    }
+
+    fn remove_front(&mut self) -> io::Result<()> {
+        self.peeked_len = None;
+        if let Some(front) = self.segments.pop_front() {
+            self.depth = self.depth.saturating_sub(front.pending);
+            match fs::remove_file(self.segment_path(front.id)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        self.cursor_offset = 0;
+        self.write_cursor()
+    }
+
+    /// Drops whole segments, oldest first, until the outbox is within its size and age limits
+    fn prune(&mut self) -> io::Result<()> {
+        while self.segments.len() > 1 {
+            let total: u64 = self.segments.iter().map(|s| s.bytes).sum();
+            let front = &self.segments[0];
+            let expired = fs::metadata(self.segment_path(front.id))
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.elapsed().ok())
+                .is_some_and(|age| age > self.limits.max_age);
+            if total <= self.limits.max_bytes && !expired {
+                break;
+            }
+
+            let lost = front.pending;
+            self.remove_front()?;
+            self.dropped += lost as u64;
+            warn!("Monitoring outbox over its limits, dropped {} oldest batches", lost);
+        }
+        Ok(())
+    }
+
+    fn read_cursor(&self) -> (u64, u64) {
+        fs::read_to_string(self.dir.join(CURSOR_FILE))
+            .ok()
+            .and_then(|raw| {
+                let (id, offset) = raw.trim().split_once(' ')?;
+                Some((id.parse().ok()?, offset.parse().ok()?))
+            })
+            .unwrap_or((0, 0))
+    }
+
+    fn write_cursor(&self) -> io::Result<()> {
+        let id = self.segments.front().map_or(self.next_id, |s| s.id);
+        let tmp = self.dir.join(format!("{}.tmp", CURSOR_FILE));
+        fs::write(&tmp, format!("{} {}", id, self.cursor_offset))?;
+        fs::rename(tmp, self.dir.join(CURSOR_FILE))
+    }
+
+    fn truncate_torn_tail(&self, id: u64) -> io::Result<()> {
+        let path = self.segment_path(id);
+        let mut data = Vec::new();
+        File::open(&path)?.read_to_end(&mut data)?;
+        let keep = data.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+        if keep < data.len() {
+            OpenOptions::new().write(true).open(&path)?.set_len(keep as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Small enough that dropping the oldest segment can bring the outbox back under `max_bytes`
+    fn segment_bytes(&self) -> u64 {
+        (self.limits.max_bytes / 4).clamp(MIN_SEGMENT_BYTES, SEGMENT_BYTES)
+    }
+
+    fn segment_path(&self, id: u64) -> PathBuf {
+        self.dir.join(segment_name(id))
+    }
+}
+
+fn segment_name(id: u64) -> String {
+    format!("{:020}.{}", id, SEGMENT_EXT)
+}
+
+fn count_lines(path: &Path, offset: u64) -> io::Result<usize> {
+    let mut reader = BufReader::new(File::open(path)?);
+    reader.seek(SeekFrom::Start(offset))?;
+    Ok(reader.split(b'\n').count())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("outbox-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn disk_bytes(dir: &Path) -> u64 {
+        fs::read_dir(dir)
+            .unwrap()
+            .flatten()
+            .filter(|e| e.path().extension().and_then(|x| x.to_str()) == Some(SEGMENT_EXT))
+            .map(|e| e.metadata().unwrap().len())
+            .sum()
+    }
+
+    #[test]
+    fn max_bytes_below_one_default_segment_is_enforced() {
+        let dir = scratch_dir("limit");
+        let limits = OutboxLimits { max_bytes: 32 * 1024, max_age: Duration::from_secs(3600) };
+        let mut outbox = Outbox::open(&dir, limits).unwrap();
+        let payload = "x".repeat(1000);
+        for _ in 0..200 {
+            outbox.push(&payload).unwrap();
+        }
+
+        let segment = outbox.segment_bytes();
+        assert!(disk_bytes(&dir) <= limits.max_bytes + segment, "{} bytes on disk", disk_bytes(&dir));
+        assert!(outbox.dropped() > 0);
+        assert_eq!(outbox.depth() as u64 + outbox.dropped(), 200);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn delivered_batches_are_not_replayed_after_reopen() {
+        let dir = scratch_dir("reopen");
+        let limits = OutboxLimits { max_bytes: 1024 * 1024, max_age: Duration::from_secs(3600) };
+        let mut outbox = Outbox::open(&dir, limits).unwrap();
+        for batch in ["a", "b", "c"] {
+            outbox.push(batch).unwrap();
+        }
+        assert_eq!(outbox.peek().unwrap().as_deref(), Some("a"));
+        outbox.ack().unwrap();
+        drop(outbox);
+
+        let mut outbox = Outbox::open(&dir, limits).unwrap();
+        assert_eq!(outbox.depth(), 2);
+        assert_eq!(outbox.peek().unwrap().as_deref(), Some("b"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+use messages::{CollectionPolicy, METRIC_GROUPS};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Loads the persisted policy, falling back to the built-in defaults
+pub fn load_policy(path: &str) -> CollectionPolicy {
+    let Ok(raw) = fs::read_to_string(path) else {
+        return CollectionPolicy::default();
+    };
+    let parsed = serde_json::from_str::<CollectionPolicy>(&raw)
+        .map_err(|e| e.to_string())
+        .and_then(|p| p.validate().map(|_| p));
+    match parsed {
+        Ok(policy) => policy,
+        Err(e) => {
+            eprintln!("[WARN] Ignoring invalid collection policy at {}: {}", path, e);
+            CollectionPolicy::default()
+        }
+    }
+}
+
+pub fn save_policy(policy: &CollectionPolicy, path: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, serde_json::to_string_pretty(policy)?)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Tracks when each metric group was last sampled so per-group intervals can be honoured
+#[derive(Default)]
+pub struct GroupSchedule {
+    last_sampled: BTreeMap<&'static str, Instant>,
+}
+
+impl GroupSchedule {
+    /// Removes the sections of a checkpoint that are disabled or not yet due under the policy
+    pub fn filter(&mut self, policy: &CollectionPolicy, checkpoint: &mut Value, now: Instant) {
+        let Some(sections) = checkpoint.as_object_mut() else {
+            return;
+        };
+        // Half a base tick of slack keeps timer jitter from skipping a due group
+        let slack = Duration::from_secs(policy.interval_secs) / 2;
+
+        for (group, section) in METRIC_GROUPS {
+            let interval = Duration::from_secs(policy.group_interval_secs(group));
+            let due = policy.group_enabled(group)
+                && self
+                    .last_sampled
+                    .get(group)
+                    .is_none_or(|last| now.duration_since(*last) + slack >= interval);
+            if due {
+                self.last_sampled.insert(group, now);
+            } else {
+                sections.remove(section);
+            }
+        }
+    }
+}
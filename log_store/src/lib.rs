@@ -0,0 +1,794 @@
+//! The bridge and collector log files: writing them (see `init`), queries over them and their rotated
+//! copies, and a `tail -F` style follower for streaming them.
+//!
+//! Lines are either `tracing_subscriber` JSON objects, whose fields can be filtered on, or its text
+//! format, `<RFC 3339 time> <LEVEL> <target>: <message>`, with or without ANSI colours. A line that
+//! is neither (a panic, a multi-line message) belongs to the entry before it.
+
+mod writer;
+
+pub use writer::{init, Rotation, RotatingFile};
+
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeZone};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, Metadata};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 1000;
+/// Suffix of rotated files, `<path>.<suffix>`; sorts oldest first
+const ROTATED_SUFFIX_FORMAT: &str = "%Y%m%d-%H%M%S%.3f";
+
+static ANSI_ESCAPE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\x1b\[[0-9;]*[a-zA-Z]").unwrap());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub fn parse(value: &str) -> Option<Level> {
+        match value.to_ascii_lowercase().as_str() {
+            "trace" => Some(Level::Trace),
+            "debug" => Some(Level::Debug),
+            "info" => Some(Level::Info),
+            "warn" | "warning" => Some(Level::Warn),
+            "error" => Some(Level::Error),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEntry {
+    pub time: Option<String>,
+    pub level: Option<Level>,
+    pub target: Option<String>,
+    pub message: String,
+    /// Event and span fields; JSON logs only
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub fields: Map<String, Value>,
+    #[serde(skip)]
+    timestamp: Option<DateTime<FixedOffset>>,
+}
+
+/// A position in one log file; a cursor into a file that has since been rotated or truncated
+/// restarts from the beginning of the current file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    file: u64,
+    offset: u64,
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.offset)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = QueryError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || QueryError::Invalid(format!("invalid cursor {:?}", value));
+        let (file, offset) = value.split_once(':').ok_or_else(invalid)?;
+        Ok(Cursor {
+            file: file.parse().map_err(|_| invalid())?,
+            offset: offset.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl Serialize for Cursor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Debug)]
+pub enum QueryError {
+    /// A bad query parameter
+    Invalid(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::Invalid(reason) => write!(f, "{}", reason),
+            QueryError::Io(e) => write!(f, "cannot read log file: {}", e),
+        }
+    }
+}
+
+impl Error for QueryError {}
+
+impl From<io::Error> for QueryError {
+    fn from(e: io::Error) -> Self {
+        QueryError::Io(e)
+    }
+}
+
+/// Query string of the log endpoints
+#[derive(Debug, Default, Deserialize)]
+pub struct LogQuery {
+    /// 0-based, counted from the newest entries
+    pub page: Option<usize>,
+    pub size: Option<usize>,
+    /// Minimum level: trace, debug, info, warn or error
+    pub level: Option<String>,
+    /// Time range, RFC 3339 or local `YYYY-MM-DD HH:MM:SS`
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// Case-insensitive text in the target or message
+    pub q: Option<String>,
+    /// Return the entries written after this cursor, oldest first, instead of a page
+    pub since: Option<String>,
+    /// Exact field values; only JSON logs have fields
+    pub agent_uuid: Option<String>,
+    pub subject: Option<String>,
+    pub handler: Option<String>,
+}
+
+impl LogQuery {
+    pub fn size(&self) -> usize {
+        self.size.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+
+    pub fn filter(&self) -> Result<LogFilter, QueryError> {
+        let level = match self.level.as_deref() {
+            Some(level) => Some(Level::parse(level).ok_or_else(|| QueryError::Invalid(format!("invalid level {:?}", level)))?),
+            None => None,
+        };
+        Ok(LogFilter {
+            level,
+            from: self.from.as_deref().map(parse_time).transpose()?,
+            to: self.to.as_deref().map(parse_time).transpose()?,
+            search: self.q.as_deref().filter(|q| !q.is_empty()).map(str::to_lowercase),
+            fields: [("agent_uuid", &self.agent_uuid), ("subject", &self.subject), ("handler", &self.handler)]
+                .into_iter()
+                .filter_map(|(name, value)| value.clone().map(|value| (name, value)))
+                .collect(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    level: Option<Level>,
+    from: Option<DateTime<FixedOffset>>,
+    to: Option<DateTime<FixedOffset>>,
+    search: Option<String>,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl LogFilter {
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        if let Some(min) = self.level
+            && entry.level.is_none_or(|level| level < min)
+        {
+            return false;
+        }
+        if self.from.is_some() || self.to.is_some() {
+            let Some(timestamp) = entry.timestamp else {
+                return false;
+            };
+            if self.from.is_some_and(|from| timestamp < from) || self.to.is_some_and(|to| timestamp > to) {
+                return false;
+            }
+        }
+        let field_matches = |(name, expected): &(&str, String)| match entry.fields.get(*name) {
+            Some(Value::String(value)) => value == expected,
+            Some(value) => serde_json::from_str::<Value>(expected).is_ok_and(|expected| expected == *value),
+            None => false,
+        };
+        if !self.fields.iter().all(field_matches) {
+            return false;
+        }
+        match &self.search {
+            Some(search) => {
+                entry.message.to_lowercase().contains(search)
+                    || entry.target.as_deref().is_some_and(|target| target.to_lowercase().contains(search))
+            }
+            None => true,
+        }
+    }
+}
+
+/// One page of a query, or the entries after a cursor
+#[derive(Debug, Serialize)]
+pub struct LogPage {
+    pub entries: Vec<LogEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<usize>,
+    pub size: usize,
+    /// Matching entries in the log and its rotated copies; only for pages, and only when the query
+    /// had to read all of them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<usize>,
+    /// Whether there are older (pages) or newer (`since`) matching entries beyond these
+    pub has_more: bool,
+    /// Pass as `since` to get what comes after these entries
+    pub cursor: Cursor,
+}
+
+fn parse_time(value: &str) -> Result<DateTime<FixedOffset>, QueryError> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time);
+    }
+    let invalid = || QueryError::Invalid(format!("invalid time {:?}, expected RFC 3339 or YYYY-MM-DD HH:MM:SS", value));
+    let naive = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S"))
+        .map_err(|_| invalid())?;
+    Local.from_local_datetime(&naive).earliest().map(|time| time.fixed_offset()).ok_or_else(invalid)
+}
+
+fn parse_line(line: &str) -> Option<LogEntry> {
+    if line.starts_with('{') {
+        return parse_json_line(line);
+    }
+    let (time, rest) = line.split_once(' ')?;
+    let timestamp = DateTime::parse_from_rfc3339(time).ok()?;
+    let rest = rest.trim_start();
+    let (level, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+    let level = Level::parse(level)?;
+    let mut rest = rest.trim_start();
+    // Spans, `name{fields}: `, come before the target
+    while let Some((span, after)) = rest.split_once(": ")
+        && span.ends_with('}')
+    {
+        rest = after;
+    }
+    let (target, message) = match rest.split_once(": ") {
+        Some((target, message)) => (Some(target.to_string()), message),
+        None => (None, rest),
+    };
+    Some(LogEntry {
+        time: Some(time.to_string()),
+        level: Some(level),
+        target,
+        message: message.to_string(),
+        fields: Map::new(),
+        timestamp: Some(timestamp),
+    })
+}
+
+/// A line written by the JSON formatter with flattened events and the span list
+fn parse_json_line(line: &str) -> Option<LogEntry> {
+    let Ok(Value::Object(mut object)) = serde_json::from_str::<Value>(line) else {
+        return None;
+    };
+    let Some(Value::String(time)) = object.remove("timestamp") else {
+        return None;
+    };
+    let level = object.get("level").and_then(Value::as_str).and_then(Level::parse);
+    let target = object.get("target").and_then(Value::as_str).map(str::to_string);
+    let message = match object.remove("message") {
+        Some(Value::String(message)) => message,
+        Some(message) => message.to_string(),
+        None => String::new(),
+    };
+
+    // Span fields first, so an event field of the same name wins
+    let mut fields = Map::new();
+    if let Some(Value::Array(spans)) = object.remove("spans") {
+        for span in spans {
+            if let Value::Object(span) = span {
+                fields.extend(span.into_iter().filter(|(name, _)| name != "name"));
+            }
+        }
+    }
+    for name in ["level", "target", "span", "filename", "line_number", "threadId", "threadName"] {
+        object.remove(name);
+    }
+    fields.extend(object);
+
+    Some(LogEntry {
+        timestamp: DateTime::parse_from_rfc3339(&time).ok(),
+        time: Some(time),
+        level,
+        target,
+        message,
+        fields,
+    })
+}
+
+/// Parses the complete lines of `data`, which starts at `start` in the file, into entries paired with
+/// the file offset just past each. Returns how many bytes were used; a trailing unterminated line is left over.
+fn parse_lines(data: &[u8], start: u64) -> (Vec<(LogEntry, u64)>, usize) {
+    let mut entries: Vec<(LogEntry, u64)> = Vec::new();
+    let mut consumed = 0;
+    while let Some(length) = data[consumed..].iter().position(|&byte| byte == b'\n') {
+        let raw = String::from_utf8_lossy(&data[consumed..consumed + length]);
+        let line = ANSI_ESCAPE.replace_all(raw.trim_end_matches('\r'), "");
+        consumed += length + 1;
+        let end = start + consumed as u64;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match (parse_line(&line), entries.last_mut()) {
+            (Some(entry), _) => entries.push((entry, end)),
+            (None, Some((entry, entry_end))) => {
+                entry.message.push('\n');
+                entry.message.push_str(&line);
+                *entry_end = end;
+            }
+            (None, None) => entries.push((
+                LogEntry {
+                    time: None,
+                    level: None,
+                    target: None,
+                    message: line.into_owned(),
+                    fields: Map::new(),
+                    timestamp: None,
+                },
+                end,
+            )),
+        }
+    }
+    (entries, consumed)
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(metadata)
+}
+
+#[cfg(not(unix))]
+fn file_id(metadata: &Metadata) -> u64 {
+    metadata
+        .created()
+        .ok()
+        .and_then(|created| created.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |created| created.as_nanos() as u64)
+}
+
+/// The rotated copies of the log file at `path`, oldest first
+pub fn rotated_files(path: &Path) -> Vec<PathBuf> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name().and_then(|name| name.to_str())) else {
+        return Vec::new();
+    };
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let Ok(listing) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let prefix = format!("{}.", name);
+    let mut files: Vec<PathBuf> = listing
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry.file_name().to_str().and_then(|file_name| file_name.strip_prefix(&prefix)).is_some_and(|suffix| {
+                NaiveDateTime::parse_from_str(suffix, ROTATED_SUFFIX_FORMAT).is_ok()
+            })
+        })
+        .map(|entry| entry.path())
+        .collect();
+    files.sort();
+    files
+}
+
+/// Runs a query against the log file at `path` and its rotated copies
+pub fn query(path: &Path, request: &LogQuery) -> Result<LogPage, QueryError> {
+    let filter = request.filter()?;
+    let size = request.size();
+    let since = request.since.as_deref().map(str::parse::<Cursor>).transpose()?;
+
+    let mut files = Vec::new();
+    for file_path in rotated_files(path).into_iter().chain([path.to_path_buf()]) {
+        match File::open(&file_path) {
+            Ok(file) => {
+                let metadata = file.metadata()?;
+                files.push((file, file_id(&metadata), metadata.len()));
+            }
+            // Rotated or pruned since the listing
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    match since {
+        Some(since) => query_since(files, &filter, size, since),
+        None => query_page(files, &filter, size, request.page.unwrap_or(0)),
+    }
+}
+
+/// The matching entries after `since`, oldest first
+fn query_since(files: Vec<(File, u64, u64)>, filter: &LogFilter, size: usize, since: Cursor) -> Result<LogPage, QueryError> {
+    // A cursor into a file that is gone continues from the oldest one left
+    let (first, mut start) = files
+        .iter()
+        .position(|(_, id, len)| *id == since.file && since.offset <= *len)
+        .map_or((0, 0), |index| (index, since.offset));
+
+    let mut matching: Vec<(LogEntry, Cursor)> = Vec::new();
+    let mut end = Cursor { file: 0, offset: 0 };
+    for (mut file, id, _) in files.into_iter().skip(first) {
+        file.seek(SeekFrom::Start(start))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let (entries, consumed) = parse_lines(&data, start);
+        end = Cursor { file: id, offset: start + consumed as u64 };
+        matching.extend(
+            entries
+                .into_iter()
+                .filter(|(entry, _)| filter.matches(entry))
+                .map(|(entry, offset)| (entry, Cursor { file: id, offset })),
+        );
+        start = 0;
+        if matching.len() > size {
+            break;
+        }
+    }
+
+    let has_more = matching.len() > size;
+    let cursor = if has_more { matching[size - 1].1 } else { end };
+    matching.truncate(size);
+    Ok(LogPage {
+        entries: matching.into_iter().map(|(entry, _)| entry).collect(),
+        page: None,
+        size,
+        total: None,
+        has_more,
+        cursor,
+    })
+}
+
+/// Page `page` counted from the newest matching entry. Files are read backwards, newest first, and
+/// only until one entry past the page has matched
+fn query_page(files: Vec<(File, u64, u64)>, filter: &LogFilter, size: usize, page: usize) -> Result<LogPage, QueryError> {
+    let skip = page.saturating_mul(size);
+    let wanted = skip.saturating_add(size);
+    let cursor = match files.last() {
+        Some((file, id, len)) => Cursor { file: *id, offset: end_of_last_line(file, *len)? },
+        None => Cursor { file: 0, offset: 0 },
+    };
+
+    // Newest first
+    let mut matching = Vec::new();
+    let mut has_more = false;
+    'files: for (file, _, len) in files.into_iter().rev() {
+        let mut entries = ReverseEntries::new(file, len);
+        while let Some(entry) = entries.next()? {
+            if !filter.matches(&entry) {
+                continue;
+            }
+            if matching.len() == wanted {
+                has_more = true;
+                break 'files;
+            }
+            matching.push(entry);
+        }
+    }
+
+    // Counting every match would mean reading every file, so there is a total only when they were read anyway
+    let total = (!has_more).then_some(matching.len());
+    let mut entries: Vec<LogEntry> = matching.into_iter().skip(skip).collect();
+    entries.reverse();
+    Ok(LogPage { entries, page: Some(page), size, total, has_more, cursor })
+}
+
+/// Bytes read at a time when reading a file backwards
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Reads `length` bytes at `offset`
+fn read_at(mut file: &File, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; length as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// The offset just past the last complete line of a file `len` bytes long
+fn end_of_last_line(file: &File, len: u64) -> io::Result<u64> {
+    let mut end = len;
+    while end > 0 {
+        let start = end.saturating_sub(CHUNK_SIZE);
+        let chunk = read_at(file, start, end - start)?;
+        if let Some(newline) = chunk.iter().rposition(|&byte| byte == b'\n') {
+            return Ok(start + newline as u64 + 1);
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+/// The entries of one log file, newest first, read backwards a chunk at a time
+struct ReverseEntries {
+    file: File,
+    /// Where `pending` starts in the file; everything before it is still unread
+    position: u64,
+    /// Read but not yet parsed: the cut-off first line of a chunk, and any lines that continue an
+    /// entry which starts further back
+    pending: Vec<u8>,
+    /// Parsed entries, oldest first
+    parsed: Vec<LogEntry>,
+}
+
+impl ReverseEntries {
+    fn new(file: File, len: u64) -> Self {
+        Self { file, position: len, pending: Vec::new(), parsed: Vec::new() }
+    }
+
+    fn next(&mut self) -> io::Result<Option<LogEntry>> {
+        loop {
+            if let Some(entry) = self.parsed.pop() {
+                return Ok(Some(entry));
+            }
+            if self.position == 0 && self.pending.is_empty() {
+                return Ok(None);
+            }
+            self.read_chunk()?;
+        }
+    }
+
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let start = self.position.saturating_sub(CHUNK_SIZE);
+        let mut data = read_at(&self.file, start, self.position - start)?;
+        data.append(&mut self.pending);
+        self.position = start;
+
+        // At the start of the file every line counts; elsewhere the first line may be cut off
+        let mut keep = 0;
+        if start > 0 {
+            match data.iter().position(|&byte| byte == b'\n') {
+                Some(newline) => keep = newline + 1,
+                None => {
+                    self.pending = data;
+                    return Ok(());
+                }
+            }
+        }
+        // A trailing unterminated line is left out, as `parse_lines` does going forwards
+        let (mut entries, _) = parse_lines(&data[keep..], start + keep as u64);
+        // Lines that do not start an entry belong to one in an earlier chunk
+        if start > 0
+            && let Some((first, first_end)) = entries.first()
+            && first.time.is_none()
+        {
+            keep = (first_end - start) as usize;
+            entries.remove(0);
+        }
+        data.truncate(keep);
+        self.pending = data;
+        self.parsed = entries.into_iter().map(|(entry, _)| entry).collect();
+        Ok(())
+    }
+}
+
+/// The result of `query`, plus a tail that picks up right after it
+pub fn follow(path: &Path, request: &LogQuery) -> Result<(LogPage, LogTail), QueryError> {
+    let page = query(path, request)?;
+    let tail = LogTail::new(path, request.filter()?, page.cursor);
+    Ok((page, tail))
+}
+
+/// Follows a log file like `tail -F`: finishes reading a file that was rotated away before moving on
+/// to its replacement, and starts over when the file is truncated
+pub struct LogTail {
+    path: PathBuf,
+    filter: LogFilter,
+    file: Option<File>,
+    cursor: Cursor,
+    /// Start of a line that is still being written
+    partial: Vec<u8>,
+}
+
+impl LogTail {
+    pub fn new(path: impl Into<PathBuf>, filter: LogFilter, cursor: Cursor) -> Self {
+        Self { path: path.into(), filter, file: None, cursor, partial: Vec::new() }
+    }
+
+    /// Matching entries written since the last call
+    pub fn poll(&mut self) -> io::Result<Vec<LogEntry>> {
+        let mut entries = Vec::new();
+        loop {
+            if self.file.is_none() && !self.open()? {
+                break;
+            }
+            self.read_new(&mut entries)?;
+            match fs::metadata(&self.path) {
+                Ok(metadata) if file_id(&metadata) != self.cursor.file => {
+                    // Rotated: the old file is drained and will not grow any more
+                    self.flush_partial(&mut entries);
+                    self.file = None;
+                    self.cursor = Cursor { file: file_id(&metadata), offset: 0 };
+                }
+                _ => break,
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Opens the file at the cursor; false if it does not exist right now
+    fn open(&mut self) -> io::Result<bool> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let metadata = file.metadata()?;
+        let id = file_id(&metadata);
+        if id != self.cursor.file || metadata.len() < self.cursor.offset {
+            self.cursor = Cursor { file: id, offset: 0 };
+        }
+        file.seek(SeekFrom::Start(self.cursor.offset))?;
+        self.file = Some(file);
+        Ok(true)
+    }
+
+    fn read_new(&mut self, entries: &mut Vec<LogEntry>) -> io::Result<()> {
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+        if file.metadata()?.len() < self.cursor.offset + self.partial.len() as u64 {
+            // Truncated in place
+            self.partial.clear();
+            self.cursor.offset = 0;
+            file.seek(SeekFrom::Start(0))?;
+        }
+        let mut data = std::mem::take(&mut self.partial);
+        file.read_to_end(&mut data)?;
+        let (parsed, consumed) = parse_lines(&data, self.cursor.offset);
+        self.cursor.offset += consumed as u64;
+        self.partial = data.split_off(consumed);
+        entries.extend(parsed.into_iter().map(|(entry, _)| entry).filter(|entry| self.filter.matches(entry)));
+        Ok(())
+    }
+
+    fn flush_partial(&mut self, entries: &mut Vec<LogEntry>) {
+        if self.partial.is_empty() {
+            return;
+        }
+        let mut data = std::mem::take(&mut self.partial);
+        data.push(b'\n');
+        let (parsed, _) = parse_lines(&data, 0);
+        entries.extend(parsed.into_iter().map(|(entry, _)| entry).filter(|entry| self.filter.matches(entry)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("log-store-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// `count` text entries, every fifth with a continuation line, and an unfinished line at the end
+    fn write_log(path: &Path, count: usize) {
+        let mut file = File::create(path).unwrap();
+        for i in 0..count {
+            writeln!(file, "2025-01-01T00:00:00.000000Z  INFO bridge::main: entry {}", i).unwrap();
+            if i % 5 == 0 {
+                writeln!(file, "  continued {}", i).unwrap();
+            }
+        }
+        write!(file, "2025-01-01T00:00:01.000000Z  INFO bridge::main: half writ").unwrap();
+    }
+
+    fn messages(page: &LogPage) -> Vec<String> {
+        page.entries.iter().map(|entry| entry.message.clone()).collect()
+    }
+
+    #[test]
+    fn parses_text_lines_with_spans() {
+        let entry = parse_line("2025-01-01T10:00:00.123456Z  WARN handler{handler=\"outbox\"}: agent_bridge::outbox: retrying").unwrap();
+        assert_eq!(entry.level, Some(Level::Warn));
+        assert_eq!(entry.target.as_deref(), Some("agent_bridge::outbox"));
+        assert_eq!(entry.message, "retrying");
+        assert!(entry.timestamp.is_some());
+    }
+
+    #[test]
+    fn parses_json_lines_with_span_fields() {
+        let line = r#"{"timestamp":"2025-01-01T10:00:00Z","level":"ERROR","target":"agent_bridge","message":"failed","subject":"agent.1.data","spans":[{"name":"handler","handler":"agent_data"}]}"#;
+        let entry = parse_line(line).unwrap();
+        assert_eq!(entry.level, Some(Level::Error));
+        assert_eq!(entry.message, "failed");
+        assert_eq!(entry.fields.get("handler"), Some(&Value::from("agent_data")));
+        assert_eq!(entry.fields.get("subject"), Some(&Value::from("agent.1.data")));
+        assert!(!entry.fields.contains_key("level"));
+    }
+
+    #[test]
+    fn lines_that_start_no_entry_are_rejected() {
+        assert!(parse_line("thread 'main' panicked at src/main.rs:1:1").is_none());
+        assert!(parse_line("2025-01-01T10:00:00Z LOUD target: message").is_none());
+        assert!(parse_line(r#"{"message":"no timestamp"}"#).is_none());
+    }
+
+    #[test]
+    fn continuation_lines_join_the_entry_before() {
+        let data = b"\x1b[2m2025-01-01T10:00:00Z\x1b[0m  INFO main: first\n  at line two\n2025-01-01T10:00:01Z ERROR main: second\n";
+        let (entries, consumed) = parse_lines(data, 0);
+        assert_eq!(consumed, data.len());
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0.message, "first\n  at line two");
+        assert_eq!(entries[1].0.level, Some(Level::Error));
+    }
+
+    #[test]
+    fn cursors_round_trip_and_reject_garbage() {
+        let cursor = Cursor { file: 42, offset: 1024 };
+        assert_eq!(cursor.to_string().parse::<Cursor>().unwrap(), cursor);
+        assert_eq!(serde_json::to_value(cursor).unwrap(), Value::from("42:1024"));
+        for invalid in ["", "42", "42:", "a:1", "1:-1"] {
+            assert!(matches!(invalid.parse::<Cursor>(), Err(QueryError::Invalid(_))), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn pages_read_backwards_match_reading_forwards() {
+        let dir = scratch_dir("pages");
+        let path = dir.join("bridge.log");
+        // Several chunks, so entries and their continuation lines straddle chunk boundaries
+        write_log(&path, 5000);
+        let data = fs::read(&path).unwrap();
+        let (all, consumed) = parse_lines(&data, 0);
+        let all: Vec<String> = all.into_iter().map(|(entry, _)| entry.message).collect();
+        assert!(data.len() as u64 > 3 * CHUNK_SIZE);
+
+        let size = 700;
+        for page in 0..8 {
+            let request = LogQuery { page: Some(page), size: Some(size), ..Default::default() };
+            let result = query(&path, &request).unwrap();
+            let stop = all.len().saturating_sub(page * size);
+            assert_eq!(messages(&result), all[stop.saturating_sub(size)..stop], "page {}", page);
+            assert_eq!(result.has_more, stop > size, "page {}", page);
+            assert_eq!(result.cursor.offset, consumed as u64);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn pages_stop_reading_once_full() {
+        let dir = scratch_dir("early");
+        let path = dir.join("bridge.log");
+        write_log(&path, 5000);
+
+        let first = query(&path, &LogQuery { size: Some(10), ..Default::default() }).unwrap();
+        assert!(first.has_more);
+        assert_eq!(first.total, None);
+        assert_eq!(first.entries.last().unwrap().message, "entry 4999");
+
+        let everything = LogQuery { size: Some(10), q: Some("entry 12".to_string()), ..Default::default() };
+        let last = query(&path, &LogQuery { page: Some(11), ..everything }).unwrap();
+        assert!(!last.has_more);
+        assert_eq!(messages(&last), ["entry 12"]);
+        // "entry 12" and "entry 120" to "entry 129", then "entry 1200" and up
+        assert_eq!(last.total, Some(111));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rotated_files_are_read_after_the_current_one() {
+        let dir = scratch_dir("rotated");
+        let path = dir.join("bridge.log");
+        fs::write(dir.join("bridge.log.20250101-000000.000"), "2025-01-01T00:00:00Z  INFO main: old\n").unwrap();
+        fs::write(&path, "2025-01-01T00:00:01Z  INFO main: new\n").unwrap();
+
+        let result = query(&path, &LogQuery::default()).unwrap();
+        assert_eq!(messages(&result), ["old", "new"]);
+        assert_eq!(result.total, Some(2));
+
+        let since = LogQuery { since: Some(Cursor { file: 0, offset: 0 }.to_string()), size: Some(1), ..Default::default() };
+        let result = query(&path, &since).unwrap();
+        assert_eq!(messages(&result), ["old"]);
+        assert!(result.has_more);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,141 @@
+use chrono::{DateTime, Local, NaiveDate};
+use shared_config::CONFIG;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::field::MakeExt;
+use tracing_subscriber::fmt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::{rotated_files, ROTATED_SUFFIX_FORMAT};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Daily,
+    /// Once the file would grow past this many bytes
+    Size(u64),
+    Never,
+}
+
+impl Rotation {
+    /// From `LOG_ROTATION` and `LOG_MAX_BYTES`
+    pub fn from_config() -> Rotation {
+        match CONFIG.log_rotation.to_ascii_lowercase().as_str() {
+            "daily" => Rotation::Daily,
+            "size" => Rotation::Size(CONFIG.log_max_bytes.max(1)),
+            "never" => Rotation::Never,
+            other => {
+                eprintln!("Unknown LOG_ROTATION {:?}, rotating daily", other);
+                Rotation::Daily
+            }
+        }
+    }
+}
+
+/// A log file at a fixed path. On rotation it is renamed to `<path>.<timestamp>` and a new one is
+/// started; only the newest `max_files` renamed files are kept.
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    max_files: usize,
+    file: File,
+    size: u64,
+    opened_on: NaiveDate,
+}
+
+impl RotatingFile {
+    pub fn open(path: impl Into<PathBuf>, rotation: Rotation, max_files: usize) -> io::Result<Self> {
+        let path = path.into();
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        // A file last written on an earlier day is rotated on the first write
+        let opened_on = metadata
+            .modified()
+            .map(|modified| DateTime::<Local>::from(modified).date_naive())
+            .unwrap_or_else(|_| Local::now().date_naive());
+        Ok(Self { path, rotation, max_files, file, size: metadata.len(), opened_on })
+    }
+
+    fn due(&self, incoming: usize) -> bool {
+        match self.rotation {
+            Rotation::Daily => Local::now().date_naive() != self.opened_on,
+            Rotation::Size(max_bytes) => self.size > 0 && self.size + incoming as u64 > max_bytes,
+            Rotation::Never => false,
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", Local::now().format(ROTATED_SUFFIX_FORMAT)));
+        fs::rename(&self.path, &rotated)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.opened_on = Local::now().date_naive();
+
+        for old in rotated_files(&self.path).iter().rev().skip(self.max_files) {
+            if let Err(e) = fs::remove_file(old) {
+                eprintln!("Failed to remove old log file {}: {}", old.display(), e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.due(buf.len())
+            && let Err(e) = self.rotate()
+        {
+            // Keep writing to the current file rather than lose lines; a daily rotation is retried tomorrow
+            eprintln!("Failed to rotate {}: {}", self.path.display(), e);
+            self.opened_on = Local::now().date_naive();
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Logs at INFO and above to stdout and to `path`, which is rotated per `LOG_ROTATION`, `LOG_MAX_BYTES`
+/// and `LOG_MAX_FILES` and holds JSON lines when `LOG_FORMAT=json`. Keep the guard for the life of the process.
+pub fn init(path: impl Into<PathBuf>) -> io::Result<WorkerGuard> {
+    let file = RotatingFile::open(path, Rotation::from_config(), CONFIG.log_max_files)?;
+    let (writer, guard) = tracing_appender::non_blocking(file);
+
+    let json_layer = CONFIG.log_json.then(|| {
+        fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .with_writer(writer.clone())
+    });
+    // Span fields are formatted once per field formatter type and then reused, so the file needs a
+    // formatter of its own or it picks up the colours of the stdout copy
+    let plain_fields = fmt::format::debug_fn(|writer, field, value| match field.name() {
+        "message" => write!(writer, "{:?}", value),
+        name => write!(writer, "{}={:?}", name, value),
+    })
+    .delimited(" ");
+    let text_layer =
+        (!CONFIG.log_json).then(|| fmt::layer().with_ansi(false).fmt_fields(plain_fields).with_writer(writer));
+
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(fmt::layer().with_writer(io::stdout))
+        .with(json_layer)
+        .with(text_layer)
+        .init();
+    Ok(guard)
+}
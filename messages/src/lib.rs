@@ -0,0 +1,597 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Schema version written into every message
+pub const SCHEMA_VERSION: u64 = 1;
+/// Oldest schema version this build still understands
+pub const MIN_SCHEMA_VERSION: u64 = 1;
+
+/// NATS subjects shared by the collector and the bridge, namespaced as `agent.<agent id>.<leaf>`
+pub mod subjects {
+    /// Request, answered with a `BridgeResponse`
+    pub const MASTER_KEY: &str = "master_key";
+    /// JetStream-backed; the bridge answers on `AGENT_RESPONSE` once the server has accepted it
+    pub const AGENT_DATA: &str = "data";
+    pub const AGENT_RESPONSE: &str = "response";
+    /// JetStream-backed
+    pub const MONITOR_DATA: &str = "monitor";
+    pub const MONITORING_STATUS: &str = "monitoring.status";
+    /// Request from the bridge, answered with a `ScanResult`
+    pub const SCAN: &str = "scan";
+    pub const CONTROL: &str = "control";
+    pub const POLICY: &str = "policy";
+    pub const POLICY_ACK: &str = "policy.ack";
+    /// Request from the bridge, answered with a `LogsReply`
+    pub const LOGS: &str = "logs";
+    /// Request, answered with a `CredentialsReply`; the only subject the shared bootstrap user may use
+    pub const CREDENTIALS: &str = "credentials";
+
+    /// Subject `leaf` for one agent
+    pub fn agent(agent_id: &str, leaf: &str) -> String {
+        format!("agent.{}.{}", agent_id, leaf)
+    }
+
+    /// Subject `leaf` for every agent, for the bridge's subscriptions
+    pub fn all_agents(leaf: &str) -> String {
+        format!("agent.*.{}", leaf)
+    }
+
+    /// Inbox prefix of the bridge's connections; collectors answer the bridge's requests there
+    pub const BRIDGE_INBOX: &str = "_INBOX.bridge";
+    /// Inbox prefix of the shared bootstrap user. Every collector without credentials can read it,
+    /// so only credential replies, whose JWTs are useless without the collector's own seed, go there.
+    pub const BOOTSTRAP_INBOX: &str = "_INBOX.bootstrap";
+
+    /// Inbox prefix of one agent's connections, so no other agent can read or forge its replies
+    pub fn inbox(agent_id: &str) -> String {
+        format!("_INBOX.agent.{}", agent_id)
+    }
+
+    /// Every reply subject under an inbox prefix
+    pub fn replies(inbox_prefix: &str) -> String {
+        format!("{}.>", inbox_prefix)
+    }
+
+    pub fn scan(agent_id: &str, action: &str) -> String {
+        agent(agent_id, &format!("{}.{}", SCAN, action))
+    }
+
+    /// Agent id of an `agent.<agent id>.<leaf>` subject
+    pub fn agent_id(subject: &str) -> Option<&str> {
+        let mut tokens = subject.splitn(3, '.');
+        match (tokens.next(), tokens.next(), tokens.next()) {
+            (Some("agent"), Some(id), Some(_)) if is_valid_agent_id(id) => Some(id),
+            _ => None,
+        }
+    }
+
+    /// Agent ids become a single subject token, so wildcards and separators are not allowed
+    pub fn is_valid_agent_id(agent_id: &str) -> bool {
+        !agent_id.is_empty()
+            && agent_id.len() <= 64
+            && agent_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+}
+
+/// Subjects a NATS user may publish and subscribe to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Permissions {
+    pub publish: Vec<String>,
+    pub subscribe: Vec<String>,
+}
+
+impl Permissions {
+    /// A collector may only talk on its own agent namespace
+    pub fn collector(agent_id: &str) -> Self {
+        let own = |leaf: &str| subjects::agent(agent_id, leaf);
+        Self {
+            publish: vec![
+                own(subjects::MASTER_KEY),
+                own(subjects::AGENT_DATA),
+                own(subjects::MONITOR_DATA),
+                own(subjects::MONITORING_STATUS),
+                own(subjects::POLICY_ACK),
+                // Answers to the bridge's scan, control and log requests
+                subjects::replies(subjects::BRIDGE_INBOX),
+            ],
+            subscribe: vec![
+                own(subjects::AGENT_RESPONSE),
+                subjects::scan(agent_id, "*"),
+                own(subjects::CONTROL),
+                own(subjects::POLICY),
+                own(subjects::LOGS),
+                subjects::replies(&subjects::inbox(agent_id)),
+            ],
+        }
+    }
+
+    /// The shared user every collector starts with: it can only ask the bridge for credentials of its own
+    pub fn bootstrap() -> Self {
+        Self {
+            publish: vec![subjects::all_agents(subjects::CREDENTIALS)],
+            subscribe: vec![subjects::replies(subjects::BOOTSTRAP_INBOX)],
+        }
+    }
+
+    /// The bridge serves every agent, so it gets the mirror image of the collector's permissions,
+    /// plus the JetStream API it needs to manage streams and consumers
+    pub fn bridge() -> Self {
+        let collector = Self::collector("*");
+        let mut publish = collector.subscribe;
+        publish.extend(Self::bootstrap().subscribe);
+        publish.extend(["$JS.API.>".to_string(), "$JS.ACK.>".to_string()]);
+        let mut subscribe = collector.publish;
+        subscribe.extend(Self::bootstrap().publish);
+        Self { publish, subscribe }
+    }
+}
+
+#[derive(Debug)]
+pub enum MessageError {
+    Malformed(serde_json::Error),
+    MissingVersion,
+    UnsupportedVersion(u64),
+    InvalidBody(serde_json::Error),
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::Malformed(e) => write!(f, "message is not valid JSON: {}", e),
+            MessageError::MissingVersion => write!(f, "message has no schema_version"),
+            MessageError::UnsupportedVersion(found) => write!(
+                f,
+                "schema_version {} is not supported (expected {} to {})",
+                found, MIN_SCHEMA_VERSION, SCHEMA_VERSION
+            ),
+            MessageError::InvalidBody(e) => write!(f, "message does not match its schema: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+/// Wire format of every message: the body's fields next to `schema_version`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub schema_version: u64,
+    #[serde(flatten)]
+    pub body: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(body: T) -> Self {
+        Self { schema_version: SCHEMA_VERSION, body }
+    }
+}
+
+/// Serializes a message body with the current schema version
+pub fn encode<T: Serialize>(body: &T) -> Result<Vec<u8>, serde_json::Error> {
+    serde_json::to_vec(&Envelope::new(body))
+}
+
+/// Parses a message, rejecting payloads without a supported schema version
+pub fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T, MessageError> {
+    let value: Value = serde_json::from_slice(payload).map_err(MessageError::Malformed)?;
+    decode_value(value)
+}
+
+pub fn decode_value<T: DeserializeOwned>(value: Value) -> Result<T, MessageError> {
+    let version = value
+        .get("schema_version")
+        .and_then(Value::as_u64)
+        .ok_or(MessageError::MissingVersion)?;
+    if !(MIN_SCHEMA_VERSION..=SCHEMA_VERSION).contains(&version) {
+        return Err(MessageError::UnsupportedVersion(version));
+    }
+    serde_json::from_value::<Envelope<T>>(value)
+        .map(|envelope| envelope.body)
+        .map_err(MessageError::InvalidBody)
+}
+
+/// `master_key`: collector identity sent for onboarding
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MasterKey {
+    pub master_key: String,
+    pub hostname: String,
+    pub os: String,
+    pub os_version: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenStatus {
+    Ok,
+    TokenExists,
+}
+
+/// Reply to `master_key`: result of onboarding and token setup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BridgeResponse {
+    pub status: TokenStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/// `data`: full inventory collected after onboarding
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentData {
+    /// Matches the `AgentResponse` to this upload and deduplicates republished copies
+    pub inventory_id: String,
+    /// Inventory JSON, forwarded to the server as a JSON-encoded string
+    pub inventory: String,
+}
+
+/// `response`: whether the server accepted and stored an inventory upload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentResponse {
+    pub inventory_id: String,
+    pub stored: bool,
+    pub message: String,
+}
+
+/// `monitor`: a batch of monitoring checkpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MonitorBatch {
+    /// Deduplicates a batch republished from the collector's outbox; empty for batches queued before ids existed
+    #[serde(default)]
+    pub batch_id: String,
+    pub checkpoints: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MonitoringState {
+    Running,
+    Stopped,
+}
+
+/// `monitoring.status`: collector monitoring loop transitions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MonitoringStatus {
+    pub status: MonitoringState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanAction {
+    Disk,
+    Partition,
+    Nic,
+}
+
+impl ScanAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScanAction::Disk => "disk",
+            ScanAction::Partition => "partition",
+            ScanAction::Nic => "nic",
+        }
+    }
+}
+
+/// `scan.<action>`: server request to rescan part of the inventory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanRequest {
+    pub action: ScanAction,
+    #[serde(default)]
+    pub uuid: String,
+}
+
+/// Reply to `scan.<action>`: rescanned inventory for the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanResult {
+    pub uuid: String,
+    pub action: ScanAction,
+    /// Scan JSON, forwarded to the server as a JSON-encoded string
+    pub result: String,
+    /// Why the scan failed; `result` is empty when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlAction {
+    Start,
+    Stop,
+    /// Start over with a fresh sampling schedule; also resumes a paused collector
+    Restart,
+}
+
+/// `control`: start, stop or restart monitoring on one collector
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlCommand {
+    pub action: ControlAction,
+}
+
+/// Reply to a `ControlCommand`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlReply {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// `logs`: bridge request for the collector's log entries, with the parameters of the log query API
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LogsRequest {
+    #[serde(default)]
+    pub query: BTreeMap<String, Value>,
+}
+
+/// Reply to a `LogsRequest`: a page from the log query API, or why there is none
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogsReply {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// `credentials`: asks the bridge to sign NATS credentials for this agent. The collector keeps the seed
+/// and sends only its public key; an agent id is bound to the first key it is issued for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CredentialsRequest {
+    pub public_key: String,
+}
+
+/// Reply to a `CredentialsRequest`: a user JWT confined to the agent's namespace, or why there is none
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CredentialsReply {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Metric groups a policy can toggle, keyed by their section in the monitoring checkpoint
+pub const METRIC_GROUPS: [(&str, &str); 5] = [
+    ("memory", "memory_monitoring"),
+    ("cpu", "cpu_monitoring"),
+    ("disk", "disk_monitoring"),
+    ("partition", "partition_monitoring"),
+    ("network", "network_monitoring"),
+];
+
+const MAX_BATCH_SIZE: usize = 1000;
+const MAX_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupPolicy {
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    /// Sampling interval for this group; the base interval when unset
+    #[serde(default)]
+    pub interval_secs: Option<u64>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// `policy`: versioned sampling settings pushed by the central server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CollectionPolicy {
+    pub version: u64,
+    pub interval_secs: u64,
+    pub batch_size: usize,
+    #[serde(default)]
+    pub groups: BTreeMap<String, GroupPolicy>,
+}
+
+impl Default for CollectionPolicy {
+    fn default() -> Self {
+        Self {
+            version: 0,
+            interval_secs: 1,
+            batch_size: 5,
+            groups: BTreeMap::new(),
+        }
+    }
+}
+
+impl CollectionPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        let interval_ok = |secs: u64| (1..=MAX_INTERVAL_SECS).contains(&secs);
+        if !interval_ok(self.interval_secs) {
+            return Err(format!("interval_secs must be between 1 and {}", MAX_INTERVAL_SECS));
+        }
+        if !(1..=MAX_BATCH_SIZE).contains(&self.batch_size) {
+            return Err(format!("batch_size must be between 1 and {}", MAX_BATCH_SIZE));
+        }
+        for (name, group) in &self.groups {
+            if !METRIC_GROUPS.iter().any(|(g, _)| g == name) {
+                return Err(format!("unknown metric group '{}'", name));
+            }
+            if group.interval_secs.is_some_and(|secs| !interval_ok(secs)) {
+                return Err(format!("interval_secs for '{}' must be between 1 and {}", name, MAX_INTERVAL_SECS));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn group_interval_secs(&self, name: &str) -> u64 {
+        self.groups.get(name).and_then(|g| g.interval_secs).unwrap_or(self.interval_secs)
+    }
+
+    pub fn group_enabled(&self, name: &str) -> bool {
+        self.groups.get(name).is_none_or(|g| g.enabled)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyStatus {
+    Applied,
+    Stale,
+    Rejected,
+}
+
+/// `policy.ack`: outcome of applying a `CollectionPolicy`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyAck {
+    /// Version that was pushed, when it could be read
+    pub version: Option<u64>,
+    /// Version the collector is running after handling the push
+    pub applied_version: u64,
+    pub status: PolicyStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> CollectionPolicy {
+        CollectionPolicy { version: 1, ..CollectionPolicy::default() }
+    }
+
+    fn group(enabled: bool, interval_secs: Option<u64>) -> GroupPolicy {
+        GroupPolicy { enabled, interval_secs }
+    }
+
+    /// Whether a permission `pattern`, with NATS `*` and `>` wildcards, covers `subject`
+    fn covers(pattern: &str, subject: &str) -> bool {
+        let mut subject = subject.split('.');
+        for token in pattern.split('.') {
+            match (token, subject.next()) {
+                (">", Some(_)) => return true,
+                ("*", Some(_)) => {}
+                (token, Some(actual)) if token == actual => {}
+                _ => return false,
+            }
+        }
+        subject.next().is_none()
+    }
+
+    fn allowed(patterns: &[String], subject: &str) -> bool {
+        patterns.iter().any(|pattern| covers(pattern, subject))
+    }
+
+    #[test]
+    fn collectors_are_confined_to_their_namespace() {
+        let collector = Permissions::collector("a1");
+        let own = |subject: &String| subject.starts_with("agent.a1.") || subject.starts_with("_INBOX.");
+        assert!(collector.publish.iter().chain(&collector.subscribe).all(own));
+
+        let bootstrap = Permissions::bootstrap();
+        assert_eq!(bootstrap.publish, ["agent.*.credentials"]);
+        assert_eq!(bootstrap.subscribe, ["_INBOX.bootstrap.>"]);
+        assert!(Permissions::bridge().subscribe.contains(&"agent.*.credentials".to_string()));
+    }
+
+    #[test]
+    fn collectors_only_read_their_own_inbox() {
+        let a = Permissions::collector("a");
+        assert!(allowed(&a.subscribe, "_INBOX.agent.a.reply1"));
+        for subject in ["_INBOX.>", "_INBOX.agent.b.>", "_INBOX.agent.b.reply1", "_INBOX.bridge.reply1", "_INBOX.bootstrap.reply1"] {
+            assert!(!allowed(&a.subscribe, subject), "a may subscribe to {}", subject);
+            assert!(!a.subscribe.iter().any(|pattern| pattern == subject), "a is granted {}", subject);
+        }
+        // Replies go to the bridge only, never into another agent's inbox
+        assert!(allowed(&a.publish, "_INBOX.bridge.reply1"));
+        assert!(!allowed(&a.publish, "_INBOX.agent.b.reply1"));
+        assert!(!allowed(&a.publish, "_INBOX.bootstrap.reply1"));
+
+        let bootstrap = Permissions::bootstrap();
+        assert!(!allowed(&bootstrap.subscribe, "_INBOX.agent.a.reply1"));
+        assert!(!allowed(&bootstrap.subscribe, "_INBOX.bridge.reply1"));
+    }
+
+    #[test]
+    fn the_bridge_answers_every_inbox_and_reads_only_its_own() {
+        let bridge = Permissions::bridge();
+        for subject in ["_INBOX.agent.a.reply1", "_INBOX.bootstrap.reply1"] {
+            assert!(allowed(&bridge.publish, subject), "{}", subject);
+        }
+        assert!(allowed(&bridge.subscribe, "_INBOX.bridge.reply1"));
+        assert!(!allowed(&bridge.subscribe, "_INBOX.agent.a.reply1"));
+    }
+
+    #[test]
+    fn encoded_messages_decode_to_the_same_body() {
+        let response = AgentResponse { inventory_id: "inv-1".into(), stored: true, message: "ok".into() };
+        let decoded: AgentResponse = decode(&encode(&response).unwrap()).unwrap();
+        assert_eq!(decoded.inventory_id, "inv-1");
+        assert!(decoded.stored);
+    }
+
+    #[test]
+    fn every_supported_version_decodes() {
+        for version in MIN_SCHEMA_VERSION..=SCHEMA_VERSION {
+            let payload = format!(r#"{{"schema_version":{},"inventory_id":"i","stored":false,"message":""}}"#, version);
+            assert!(decode::<AgentResponse>(payload.as_bytes()).is_ok(), "version {}", version);
+        }
+    }
+
+    #[test]
+    fn decode_rejects_bad_payloads() {
+        assert!(matches!(decode::<AgentResponse>(b"not json"), Err(MessageError::Malformed(_))));
+        assert!(matches!(
+            decode::<AgentResponse>(br#"{"inventory_id":"i","stored":true,"message":""}"#),
+            Err(MessageError::MissingVersion)
+        ));
+        let future = format!(r#"{{"schema_version":{},"inventory_id":"i","stored":true,"message":""}}"#, SCHEMA_VERSION + 1);
+        assert!(matches!(
+            decode::<AgentResponse>(future.as_bytes()),
+            Err(MessageError::UnsupportedVersion(v)) if v == SCHEMA_VERSION + 1
+        ));
+        let wrong_body = format!(r#"{{"schema_version":{},"stored":"yes"}}"#, SCHEMA_VERSION);
+        assert!(matches!(decode::<AgentResponse>(wrong_body.as_bytes()), Err(MessageError::InvalidBody(_))));
+    }
+
+    #[test]
+    fn default_policy_is_valid() {
+        assert_eq!(CollectionPolicy::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn interval_must_be_within_a_day() {
+        for interval_secs in [0, MAX_INTERVAL_SECS + 1] {
+            let err = CollectionPolicy { interval_secs, ..policy() }.validate().unwrap_err();
+            assert!(err.starts_with("interval_secs"), "{}", err);
+        }
+        assert_eq!(CollectionPolicy { interval_secs: MAX_INTERVAL_SECS, ..policy() }.validate(), Ok(()));
+    }
+
+    #[test]
+    fn batch_size_must_be_bounded() {
+        for batch_size in [0, MAX_BATCH_SIZE + 1] {
+            let err = CollectionPolicy { batch_size, ..policy() }.validate().unwrap_err();
+            assert!(err.starts_with("batch_size"), "{}", err);
+        }
+        assert_eq!(CollectionPolicy { batch_size: MAX_BATCH_SIZE, ..policy() }.validate(), Ok(()));
+    }
+
+    #[test]
+    fn groups_must_be_known_with_valid_intervals() {
+        let mut known = policy();
+        known.groups.insert("cpu".into(), group(false, None));
+        known.groups.insert("disk".into(), group(true, Some(60)));
+        assert_eq!(known.validate(), Ok(()));
+
+        let mut unknown = policy();
+        unknown.groups.insert("gpu".into(), group(true, None));
+        assert_eq!(unknown.validate(), Err("unknown metric group 'gpu'".to_string()));
+
+        let mut zero = policy();
+        zero.groups.insert("memory".into(), group(true, Some(0)));
+        assert!(zero.validate().unwrap_err().contains("'memory'"));
+    }
+
+    #[test]
+    fn group_settings_fall_back_to_the_base_policy() {
+        let mut policy = CollectionPolicy { interval_secs: 10, ..policy() };
+        policy.groups.insert("network".into(), group(false, Some(30)));
+        assert_eq!(policy.group_interval_secs("network"), 30);
+        assert_eq!(policy.group_interval_secs("cpu"), 10);
+        assert!(!policy.group_enabled("network"));
+        assert!(policy.group_enabled("cpu"));
+    }
+}
@@ -0,0 +1,348 @@
+use futures::{SinkExt, StreamExt};
+use messages::MasterKey;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use warp::filters::path::FullPath;
+use warp::http::{HeaderMap, Method, Response, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::ws::{Message, WebSocket, Ws};
+use warp::{Filter, Rejection, Reply};
+
+use crate::state::{OnboardedAgent, SharedState};
+
+const BRIDGE_PATH: &str = "/api/agent/bridge/";
+/// Inventory sections whose entries get a uuid from the server
+const INVENTORY_SECTIONS: [&str; 8] = ["cpu", "memory", "storage", "partition", "nic", "port", "ip", "gpu"];
+
+type Answer = (StatusCode, Value);
+
+/// The central server endpoints the bridge calls
+pub fn routes(state: SharedState) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static {
+    let with_state = warp::any().map(move || state.clone());
+
+    let bridge_socket = warp::ws()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(with_state.clone())
+        .and_then(open_bridge_socket);
+
+    let api = warp::path("api")
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(warp::body::bytes())
+        .and(with_state)
+        .and_then(handle_api);
+
+    bridge_socket.or(api)
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn new_uuid() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+fn detail(status: StatusCode, message: impl Into<String>) -> Answer {
+    (status, json!({ "detail": message.into() }))
+}
+
+async fn handle_api(method: Method, path: FullPath, headers: HeaderMap, body: Bytes, state: SharedState) -> Result<Response<String>, Infallible> {
+    let path = path.as_str().to_string();
+    let body = String::from_utf8_lossy(&body).to_string();
+
+    let failure = state.lock().unwrap().take_failure(method.as_str(), &path);
+    let (status, reply) = match failure {
+        Some(rule) => {
+            tokio::time::sleep(Duration::from_millis(rule.delay_ms)).await;
+            (StatusCode::from_u16(rule.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR), rule.body)
+        }
+        None => {
+            let (status, reply) = answer(&method, &path, &headers, &body, &state);
+            (status, reply.to_string())
+        }
+    };
+
+    info!("{} {} -> {}", method, path, status.as_u16());
+    state.lock().unwrap().record(method.as_str(), &path, &headers, &body, status.as_u16());
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(reply)
+        .unwrap())
+}
+
+fn answer(method: &Method, path: &str, headers: &HeaderMap, body: &str, state: &SharedState) -> Answer {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method.as_str(), segments.as_slice()) {
+        ("POST", ["api", "agent", "onboard"]) => onboard(headers, body, state),
+        ("POST", ["api", "agent", "get", "jwt"]) | ("POST", ["api", "agent", "get", "jwt", "access_token"]) => issue_token(headers, body, state),
+        ("POST", ["api", "agent", "init", "data"]) => inventory(headers, body, state),
+        ("PATCH", ["api", "agent", "init", "data", agent_uuid, action]) => rescan(agent_uuid, action, body, state),
+        ("POST", ["api", "agent", "bridge"]) => monitor(headers, state),
+        _ => detail(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+fn onboard(headers: &HeaderMap, body: &str, state: &SharedState) -> Answer {
+    let mut state = state.lock().unwrap();
+    if let Some(expected) = &state.scenario.api_key
+        && header(headers, "x-api-key") != Some(expected.as_str())
+    {
+        return detail(StatusCode::FORBIDDEN, "Invalid API key");
+    }
+    let request: MasterKey = match serde_json::from_str(body) {
+        Ok(request) => request,
+        Err(e) => return detail(StatusCode::BAD_REQUEST, format!("Invalid onboarding payload: {}", e)),
+    };
+
+    let agent = OnboardedAgent {
+        uuid: new_uuid(),
+        client_id: new_uuid(),
+        client_secret: new_uuid(),
+        master_key: request.master_key,
+        hostname: request.hostname,
+        device_uuid: None,
+    };
+    info!("Onboarded {} as agent {}", agent.hostname, agent.uuid);
+    let response = json!({
+        "uuid": agent.uuid,
+        "client_id": agent.client_id,
+        "client_secret": agent.client_secret,
+        "master_key": agent.master_key,
+    });
+    state.agents.insert(agent.uuid.clone(), agent);
+    (StatusCode::OK, response)
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    grant_type: String,
+    client_id: String,
+    client_secret: String,
+}
+
+fn issue_token(headers: &HeaderMap, body: &str, state: &SharedState) -> Answer {
+    let request: TokenRequest = match serde_urlencoded::from_str(body) {
+        Ok(request) => request,
+        Err(e) => return detail(StatusCode::BAD_REQUEST, format!("Invalid token request: {}", e)),
+    };
+    if request.grant_type != "client_credentials" {
+        return detail(StatusCode::BAD_REQUEST, format!("Unsupported grant_type {:?}", request.grant_type));
+    }
+    let agent_uuid = header(headers, "uuid").unwrap_or_default();
+
+    let mut state = state.lock().unwrap();
+    let known = state
+        .agents
+        .get(agent_uuid)
+        .is_some_and(|agent| agent.client_id == request.client_id && agent.client_secret == request.client_secret);
+    if !known {
+        return detail(StatusCode::UNAUTHORIZED, "Unknown agent or wrong client credentials");
+    }
+    let (token, expires_in) = state.issue_token(agent_uuid);
+    (StatusCode::OK, json!({ "access_token": token, "token_type": "Bearer", "expires_in": expires_in }))
+}
+
+/// Agent uuid of an authenticated call, or the 401 to answer it with
+fn authenticate(headers: &HeaderMap, token: Option<&str>, state: &SharedState) -> Result<String, Answer> {
+    let agent_uuid = header(headers, "uuid").unwrap_or_default();
+    match token {
+        Some(token) if state.lock().unwrap().token_valid(token, agent_uuid) => Ok(agent_uuid.to_string()),
+        _ => Err(detail(StatusCode::UNAUTHORIZED, "Invalid or expired access token")),
+    }
+}
+
+fn inventory(headers: &HeaderMap, body: &str, state: &SharedState) -> Answer {
+    let token = header(headers, "authorization").and_then(|value| value.strip_prefix("Bearer "));
+    let agent_uuid = match authenticate(headers, token, state) {
+        Ok(agent_uuid) => agent_uuid,
+        Err(answer) => return answer,
+    };
+    // The bridge posts the inventory as a JSON-encoded string
+    let mut inventory = match serde_json::from_str::<Value>(body) {
+        Ok(Value::String(inner)) => serde_json::from_str::<Value>(&inner),
+        other => other,
+    }
+    .unwrap_or(Value::Null);
+    if !inventory.get("device").is_some_and(Value::is_object) {
+        return detail(StatusCode::BAD_REQUEST, "Inventory has no device section");
+    }
+
+    let mut state = state.lock().unwrap();
+    let Some(agent) = state.agents.get_mut(&agent_uuid) else {
+        return detail(StatusCode::NOT_FOUND, "Unknown agent");
+    };
+    let device_uuid = agent.device_uuid.get_or_insert_with(new_uuid).clone();
+    if let Some(agent_info) = inventory.get_mut("agent").and_then(Value::as_object_mut) {
+        agent_info.insert("uuid".to_string(), json!(agent_uuid));
+    }
+    inventory["device"]["uuid"] = json!(device_uuid);
+    assign_uuids(&mut inventory["device"]);
+    (StatusCode::OK, inventory)
+}
+
+/// Gives every inventory entry below `value` a uuid, keeping the ones the agent already knows
+fn assign_uuids(value: &mut Value) {
+    for section in INVENTORY_SECTIONS {
+        if let Some(entries) = value.get_mut(section).and_then(Value::as_array_mut) {
+            for entry in entries {
+                ensure_uuid(entry);
+                assign_uuids(entry);
+            }
+        }
+    }
+}
+
+fn ensure_uuid(entry: &mut Value) {
+    let Some(entry) = entry.as_object_mut() else {
+        return;
+    };
+    let known = entry
+        .get("uuid")
+        .and_then(Value::as_str)
+        .is_some_and(|uuid| !uuid.is_empty() && uuid != "unknown");
+    if !known {
+        entry.insert("uuid".to_string(), json!(new_uuid()));
+    }
+}
+
+/// Rescan results come back as `{device_uuid, storage}` or `{device_uuid, nic}` entries
+fn rescan(agent_uuid: &str, action: &str, body: &str, state: &SharedState) -> Answer {
+    let kind = match action {
+        "disk" | "partition" | "storage" => "storage",
+        "nic" | "port" => "nic",
+        _ => return detail(StatusCode::BAD_REQUEST, format!("Unsupported scan action {:?}", action)),
+    };
+    let data: Value = match serde_json::from_str(body) {
+        Ok(data) => data,
+        Err(e) => return detail(StatusCode::BAD_REQUEST, format!("Invalid scan payload: {}", e)),
+    };
+    let entries = data
+        .get(action)
+        .or_else(|| data.as_object().and_then(|fields| fields.values().find(|value| value.is_array())))
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+
+    let mut state = state.lock().unwrap();
+    let Some(agent) = state.agents.get_mut(agent_uuid) else {
+        return detail(StatusCode::NOT_FOUND, "Unknown agent");
+    };
+    let device_uuid = agent.device_uuid.get_or_insert_with(new_uuid).clone();
+    let results: Vec<Value> = entries
+        .into_iter()
+        .map(|mut entry| {
+            ensure_uuid(&mut entry);
+            assign_uuids(&mut entry);
+            json!({ "device_uuid": device_uuid, kind: entry })
+        })
+        .collect();
+    (StatusCode::OK, Value::Array(results))
+}
+
+fn monitor(headers: &HeaderMap, state: &SharedState) -> Answer {
+    if let Err(answer) = authenticate(headers, header(headers, "access-token"), state) {
+        return answer;
+    }
+    (StatusCode::OK, state.lock().unwrap().monitor_reply())
+}
+
+async fn open_bridge_socket(ws: Ws, path: FullPath, headers: HeaderMap, state: SharedState) -> Result<warp::reply::Response, Rejection> {
+    if path.as_str() != BRIDGE_PATH {
+        return Err(warp::reject::not_found());
+    }
+
+    let failure = state.lock().unwrap().take_failure("GET", BRIDGE_PATH);
+    let refused = match failure {
+        Some(rule) => Some((StatusCode::from_u16(rule.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR), rule.body)),
+        None => authenticate(&headers, header(&headers, "access-token"), &state)
+            .err()
+            .map(|(status, reply)| (status, reply.to_string())),
+    };
+    if let Some((status, reply)) = refused {
+        info!("WS {} refused -> {}", BRIDGE_PATH, status.as_u16());
+        state.lock().unwrap().record("GET", BRIDGE_PATH, &headers, "", status.as_u16());
+        return Ok(Response::builder().status(status).body(reply.into()).unwrap());
+    }
+
+    let agent_uuid = header(&headers, "uuid").unwrap_or_default().to_string();
+    state.lock().unwrap().record("GET", BRIDGE_PATH, &headers, "", StatusCode::SWITCHING_PROTOCOLS.as_u16());
+    Ok(ws.on_upgrade(move |socket| serve_bridge_socket(socket, agent_uuid, state)).into_response())
+}
+
+/// Answers each frame like a monitoring batch and forwards frames pushed through the control API
+async fn serve_bridge_socket(socket: WebSocket, agent_uuid: String, state: SharedState) {
+    let (mut sink, mut stream) = socket.split();
+    let (outgoing, mut queue) = mpsc::unbounded_channel();
+    let id = state.lock().unwrap().add_session(outgoing);
+    info!("Bridge socket {} opened for agent {}", id, agent_uuid);
+
+    loop {
+        tokio::select! {
+            frame = queue.recv() => match frame {
+                Some(Some(text)) => {
+                    if sink.send(Message::text(text)).await.is_err() {
+                        break;
+                    }
+                }
+                // Dropped through the control API
+                _ => {
+                    let _ = sink.send(Message::close()).await;
+                    break;
+                }
+            },
+            message = stream.next() => match message {
+                Some(Ok(message)) if message.is_text() => {
+                    let Some(reply) = answer_frame(message.to_str().unwrap_or_default(), &state) else {
+                        continue;
+                    };
+                    if sink.send(Message::text(reply)).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(message)) if message.is_close() => break,
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    warn!("Bridge socket {} failed: {}", id, e);
+                    break;
+                }
+                None => break,
+            },
+        }
+    }
+
+    state.lock().unwrap().sessions.remove(&id);
+    info!("Bridge socket {} closed", id);
+}
+
+/// Reply to one frame, echoing its `message_id`; `None` when a `WS` failure rule swallows it
+fn answer_frame(text: &str, state: &SharedState) -> Option<String> {
+    let mut state = state.lock().unwrap();
+    let failure = state.take_failure("WS", BRIDGE_PATH);
+    let status = failure.as_ref().map_or(StatusCode::OK.as_u16(), |rule| rule.status);
+    state.record("WS", BRIDGE_PATH, &HeaderMap::new(), text, status);
+    if failure.is_some() {
+        info!("WS frame left unanswered by failure rule");
+        return None;
+    }
+
+    let message_id = serde_json::from_str::<Value>(text)
+        .ok()
+        .and_then(|frame| frame.get("message_id").cloned())
+        .unwrap_or(Value::Null);
+    let reply = match state.monitor_reply() {
+        Value::Object(mut fields) => {
+            fields.insert("message_id".to_string(), message_id);
+            Value::Object(fields)
+        }
+        other => json!({ "message_id": message_id, "result": other }),
+    };
+    Some(reply.to_string())
+}
@@ -0,0 +1,136 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use warp::{Filter, Rejection, Reply};
+
+use crate::state::{FailureRule, Scenario, SharedState};
+
+#[derive(Debug, Deserialize)]
+struct RequestQuery {
+    /// Only requests recorded after this sequence number
+    #[serde(default)]
+    since: u64,
+    /// Only requests whose path starts with this
+    path: Option<String>,
+}
+
+/// Test-only endpoints under `/mock/` for steering the mock and inspecting what it received
+pub fn routes(state: SharedState) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static {
+    let with_state = warp::any().map(move || state.clone());
+
+    let list_requests = warp::path!("mock" / "requests")
+        .and(warp::get())
+        .and(warp::query::<RequestQuery>())
+        .and(with_state.clone())
+        .map(|query: RequestQuery, state: SharedState| warp::reply::json(&state.lock().unwrap().requests(query.since, query.path.as_deref())));
+
+    let clear_requests = warp::path!("mock" / "requests")
+        .and(warp::delete())
+        .and(with_state.clone())
+        .map(|state: SharedState| {
+            state.lock().unwrap().clear_requests();
+            warp::reply::json(&json!({ "cleared": true }))
+        });
+
+    let list_failures = warp::path!("mock" / "failures")
+        .and(warp::get())
+        .and(with_state.clone())
+        .map(|state: SharedState| warp::reply::json(&state.lock().unwrap().failures()));
+
+    let add_failure = warp::path!("mock" / "failures")
+        .and(warp::post())
+        .and(warp::body::json::<FailureRule>())
+        .and(with_state.clone())
+        .map(|rule: FailureRule, state: SharedState| {
+            let reply = warp::reply::json(&rule);
+            state.lock().unwrap().add_failure(rule);
+            reply
+        });
+
+    let clear_failures = warp::path!("mock" / "failures")
+        .and(warp::delete())
+        .and(with_state.clone())
+        .map(|state: SharedState| {
+            state.lock().unwrap().clear_failures();
+            warp::reply::json(&json!({ "cleared": true }))
+        });
+
+    let queue_action = warp::path!("mock" / "actions")
+        .and(warp::post())
+        .and(warp::body::json::<Value>())
+        .and(with_state.clone())
+        .map(|action: Value, state: SharedState| {
+            let mut state = state.lock().unwrap();
+            state.actions.push_back(action);
+            warp::reply::json(&json!({ "queued": state.actions.len() }))
+        });
+
+    let clear_actions = warp::path!("mock" / "actions")
+        .and(warp::delete())
+        .and(with_state.clone())
+        .map(|state: SharedState| {
+            state.lock().unwrap().actions.clear();
+            warp::reply::json(&json!({ "cleared": true }))
+        });
+
+    let push = warp::path!("mock" / "push")
+        .and(warp::post())
+        .and(warp::body::json::<Value>())
+        .and(with_state.clone())
+        .map(|command: Value, state: SharedState| {
+            let delivered = state.lock().unwrap().push(&command.to_string());
+            warp::reply::json(&json!({ "delivered": delivered }))
+        });
+
+    let drop_sessions = warp::path!("mock" / "sessions")
+        .and(warp::delete())
+        .and(with_state.clone())
+        .map(|state: SharedState| warp::reply::json(&json!({ "closed": state.lock().unwrap().drop_sessions() })));
+
+    let expire_tokens = warp::path!("mock" / "tokens" / "expire")
+        .and(warp::post())
+        .and(with_state.clone())
+        .map(|state: SharedState| warp::reply::json(&json!({ "expired": state.lock().unwrap().expire_tokens() })));
+
+    let get_config = warp::path!("mock" / "config")
+        .and(warp::get())
+        .and(with_state.clone())
+        .map(|state: SharedState| warp::reply::json(&state.lock().unwrap().scenario));
+
+    let set_config = warp::path!("mock" / "config")
+        .and(warp::put())
+        .and(warp::body::json::<Scenario>())
+        .and(with_state.clone())
+        .map(|scenario: Scenario, state: SharedState| {
+            let reply = warp::reply::json(&scenario);
+            state.lock().unwrap().scenario = scenario;
+            reply
+        });
+
+    let summary = warp::path!("mock" / "state")
+        .and(warp::get())
+        .and(with_state)
+        .map(|state: SharedState| {
+            let state = state.lock().unwrap();
+            warp::reply::json(&json!({
+                "agents": state.agents.values().collect::<Vec<_>>(),
+                "live_tokens": state.live_tokens(),
+                "sessions": state.sessions.len(),
+                "queued_actions": state.actions,
+                "failures": state.failures(),
+            }))
+        });
+
+    list_requests
+        .or(clear_requests)
+        .or(list_failures)
+        .or(add_failure)
+        .or(clear_failures)
+        .or(queue_action)
+        .or(clear_actions)
+        .or(push)
+        .or(drop_sessions)
+        .or(expire_tokens)
+        .or(get_config)
+        .or(set_config)
+        .or(summary)
+}
@@ -0,0 +1,125 @@
+//! Stand-in for the central server, for end-to-end testing of the bridge on one machine.
+//!
+//! Serves the agent endpoints the bridge calls (onboarding, tokens, inventory upload, rescans and
+//! the `/api/agent/bridge/` monitoring uplink over HTTPS and WSS) and a `/mock/` control API that
+//! takes JSON bodies:
+//!
+//! - `GET /mock/requests?since=&path=`, `DELETE /mock/requests`: recorded requests and WS frames
+//! - `POST /mock/failures` `{"path", "method", "status", "body", "delay_ms", "times"}`: fail matching
+//!   calls; method `WS` leaves bridge socket frames unanswered. `GET`/`DELETE` list or clear the rules
+//! - `POST /mock/actions`: queue a reply for the next monitoring batch, e.g.
+//!   `{"action": "deleted_partition", "uuid": [...]}` or `{"action": "disk", "uuid": ...}`; the bridge
+//!   reports each command's outcome as a `COMMAND_RESULT` monitoring event
+//! - `POST /mock/push`: send a command down every bridge socket; it must carry `agent_id`
+//! - `DELETE /mock/sessions`: close every bridge socket
+//! - `POST /mock/tokens/expire`: invalidate all access tokens, so the next call gets a 401
+//! - `GET`/`PUT /mock/config`: the scenario (`api_key`, `expires_in`, `monitor_reply`, `require_auth`)
+//! - `GET /mock/state`: onboarded agents, live tokens, sockets and queued actions
+//!
+//! Configured from the environment:
+//! - `MOCK_BIND` (default `127.0.0.1:8443`)
+//! - `MOCK_CERT` / `MOCK_KEY`: PEM certificate chain and key; plain HTTP when unset
+//! - `MOCK_SCENARIO`: JSON file with the initial scenario
+//!
+//! Point the bridge at it with `CENTRAL_SERVER_URL=https://localhost:8443`,
+//! `WEB_SOCKET_URL=wss://localhost:8443` and `CENTRAL_CA_PATH` set to the CA that signed `MOCK_CERT`.
+
+mod central;
+mod control;
+mod state;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::env;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn};
+use warp::Filter;
+
+use state::{MockState, Scenario};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+fn load_scenario() -> Result<Scenario, BoxError> {
+    match env::var("MOCK_SCENARIO") {
+        Ok(path) => {
+            let text = std::fs::read_to_string(&path).map_err(|e| format!("cannot read scenario {}: {}", path, e))?;
+            Ok(serde_json::from_str(&text).map_err(|e| format!("invalid scenario {}: {}", path, e))?)
+        }
+        Err(_) => Ok(Scenario::default()),
+    }
+}
+
+fn tls_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, BoxError> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .map_err(|e| format!("cannot read certificate {}: {}", cert_path, e))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| format!("cannot read key {}: {}", key_path, e))?;
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Accepts TCP connections and hands over the ones that complete a TLS handshake
+async fn tls_connections(addr: SocketAddr, acceptor: TlsAcceptor) -> Result<mpsc::Receiver<tokio_rustls::server::TlsStream<tokio::net::TcpStream>>, BoxError> {
+    let listener = TcpListener::bind(addr).await?;
+    let (tx, rx) = mpsc::channel(32);
+    tokio::spawn(async move {
+        loop {
+            let (tcp, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Accept failed: {}", e);
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            // A slow or failing handshake must not hold up the next client
+            tokio::spawn(async move {
+                match acceptor.accept(tcp).await {
+                    Ok(stream) => {
+                        let _ = tx.send(stream).await;
+                    }
+                    Err(e) => warn!("TLS handshake with {} failed: {}", peer, e),
+                }
+            });
+        }
+    });
+    Ok(rx)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), BoxError> {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
+        .init();
+
+    let addr: SocketAddr = env::var("MOCK_BIND").unwrap_or_else(|_| "127.0.0.1:8443".to_string()).parse()?;
+    let state = MockState::new(load_scenario()?);
+    let routes = central::routes(state.clone()).or(control::routes(state));
+
+    match (env::var("MOCK_CERT"), env::var("MOCK_KEY")) {
+        (Ok(cert), Ok(key)) => {
+            let acceptor = tls_acceptor(&cert, &key)?;
+            let connections = tls_connections(addr, acceptor).await?;
+            let incoming = futures::stream::unfold(connections, |mut connections| async move {
+                connections.recv().await.map(|stream| (Ok::<_, std::io::Error>(stream), connections))
+            });
+            info!("Mock central server listening on https://{} and wss://{}", addr, addr);
+            warp::serve(routes).run_incoming(incoming).await;
+        }
+        _ => {
+            warn!("MOCK_CERT/MOCK_KEY not set, serving plain HTTP; the bridge only talks HTTPS and WSS");
+            info!("Mock central server listening on http://{}", addr);
+            warp::serve(routes).run(addr).await;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,231 @@
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use warp::http::HeaderMap;
+
+pub type SharedState = Arc<Mutex<MockState>>;
+
+/// How many requests are kept before the oldest are dropped
+const MAX_RECORDED: usize = 1000;
+
+/// Canned behaviour, loaded from `MOCK_SCENARIO` and replaceable through `PUT /mock/config`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Scenario {
+    /// Value the onboarding call must send in `X-API-KEY`; `None` accepts any key
+    pub api_key: Option<String>,
+    /// Lifetime of issued access tokens, in seconds
+    pub expires_in: i64,
+    /// Reply to a monitoring batch when no action is queued
+    pub monitor_reply: Value,
+    /// Whether tokens, inventory uploads and monitoring batches are checked for a valid token
+    pub require_auth: bool,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self {
+            api_key: Some("1234567890abcdef1234567890abcdef".to_string()),
+            expires_in: 3600,
+            monitor_reply: json!({ "status": "ok" }),
+            require_auth: true,
+        }
+    }
+}
+
+/// Makes matching calls fail instead of being served
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailureRule {
+    /// Matches any request whose path starts with this
+    pub path: String,
+    /// HTTP method to match, or `WS` for frames on the bridge socket; any method when absent
+    pub method: Option<String>,
+    #[serde(default = "default_failure_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub body: String,
+    /// Wait this long before answering, e.g. to trip client timeouts
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// How many more calls to fail; forever when absent
+    pub times: Option<u32>,
+}
+
+fn default_failure_status() -> u16 {
+    500
+}
+
+impl FailureRule {
+    fn matches(&self, method: &str, path: &str) -> bool {
+        path.starts_with(&self.path) && self.method.as_deref().is_none_or(|m| m.eq_ignore_ascii_case(method))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordedRequest {
+    pub seq: u64,
+    pub at: String,
+    pub method: String,
+    pub path: String,
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+    pub status: u16,
+}
+
+/// An agent that completed onboarding
+#[derive(Debug, Clone, Serialize)]
+pub struct OnboardedAgent {
+    pub uuid: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub master_key: String,
+    pub hostname: String,
+    /// Assigned on the first inventory upload and reused for rescans
+    pub device_uuid: Option<String>,
+}
+
+struct IssuedToken {
+    agent_uuid: String,
+    expires_at: Instant,
+}
+
+#[derive(Default)]
+pub struct MockState {
+    pub scenario: Scenario,
+    pub agents: HashMap<String, OnboardedAgent>,
+    tokens: HashMap<String, IssuedToken>,
+    failures: Vec<FailureRule>,
+    /// Actions handed out one per monitoring reply, oldest first
+    pub actions: VecDeque<Value>,
+    /// Outgoing frame queues of the connected bridge sockets
+    pub sessions: HashMap<u64, mpsc::UnboundedSender<Option<String>>>,
+    next_session: u64,
+    requests: VecDeque<RecordedRequest>,
+    next_seq: u64,
+}
+
+impl MockState {
+    pub fn new(scenario: Scenario) -> SharedState {
+        Arc::new(Mutex::new(MockState { scenario, ..Default::default() }))
+    }
+
+    pub fn record(&mut self, method: &str, path: &str, headers: &HeaderMap, body: &str, status: u16) {
+        self.next_seq += 1;
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_str().unwrap_or("<binary>").to_string()))
+            .collect();
+        self.requests.push_back(RecordedRequest {
+            seq: self.next_seq,
+            at: chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+            method: method.to_string(),
+            path: path.to_string(),
+            headers,
+            body: body.to_string(),
+            status,
+        });
+        if self.requests.len() > MAX_RECORDED {
+            self.requests.pop_front();
+        }
+    }
+
+    /// Recorded requests after `since`, optionally limited to paths starting with `path`
+    pub fn requests(&self, since: u64, path: Option<&str>) -> Vec<RecordedRequest> {
+        self.requests
+            .iter()
+            .filter(|r| r.seq > since && path.is_none_or(|p| r.path.starts_with(p)))
+            .cloned()
+            .collect()
+    }
+
+    pub fn clear_requests(&mut self) {
+        self.requests.clear();
+    }
+
+    pub fn add_failure(&mut self, rule: FailureRule) {
+        self.failures.push(rule);
+    }
+
+    pub fn failures(&self) -> &[FailureRule] {
+        &self.failures
+    }
+
+    pub fn clear_failures(&mut self) {
+        self.failures.clear();
+    }
+
+    /// The first rule matching the call, counting it against the rule's `times`
+    pub fn take_failure(&mut self, method: &str, path: &str) -> Option<FailureRule> {
+        let index = self.failures.iter().position(|rule| rule.matches(method, path))?;
+        let rule = self.failures[index].clone();
+        if let Some(times) = &mut self.failures[index].times {
+            *times = times.saturating_sub(1);
+            if *times == 0 {
+                self.failures.remove(index);
+            }
+        }
+        Some(rule)
+    }
+
+    pub fn issue_token(&mut self, agent_uuid: &str) -> (String, i64) {
+        let token: String = rand::thread_rng().sample_iter(&Alphanumeric).take(48).map(char::from).collect();
+        let expires_in = self.scenario.expires_in;
+        let expires_at = Instant::now() + Duration::from_secs(expires_in.max(0) as u64);
+        self.tokens.insert(token.clone(), IssuedToken { agent_uuid: agent_uuid.to_string(), expires_at });
+        (token, expires_in)
+    }
+
+    /// Whether `token` was issued to `agent_uuid` and has not expired
+    pub fn token_valid(&self, token: &str, agent_uuid: &str) -> bool {
+        if !self.scenario.require_auth {
+            return true;
+        }
+        self.tokens
+            .get(token)
+            .is_some_and(|issued| issued.agent_uuid == agent_uuid && issued.expires_at > Instant::now())
+    }
+
+    /// Invalidates every issued token, so the next authenticated call gets a 401
+    pub fn expire_tokens(&mut self) -> usize {
+        let count = self.tokens.len();
+        self.tokens.clear();
+        count
+    }
+
+    pub fn live_tokens(&self) -> usize {
+        let now = Instant::now();
+        self.tokens.values().filter(|issued| issued.expires_at > now).count()
+    }
+
+    /// Reply to a monitoring batch: the next queued action, or the scenario default
+    pub fn monitor_reply(&mut self) -> Value {
+        self.actions.pop_front().unwrap_or_else(|| self.scenario.monitor_reply.clone())
+    }
+
+    pub fn add_session(&mut self, outgoing: mpsc::UnboundedSender<Option<String>>) -> u64 {
+        self.next_session += 1;
+        self.sessions.insert(self.next_session, outgoing);
+        self.next_session
+    }
+
+    /// Sends a frame to every connected bridge; returns how many received it
+    pub fn push(&mut self, frame: &str) -> usize {
+        self.sessions.retain(|_, outgoing| outgoing.send(Some(frame.to_string())).is_ok());
+        self.sessions.len()
+    }
+
+    /// Closes every bridge socket
+    pub fn drop_sessions(&mut self) -> usize {
+        let count = self.sessions.len();
+        for outgoing in self.sessions.values() {
+            let _ = outgoing.send(None);
+        }
+        self.sessions.clear();
+        count
+    }
+}
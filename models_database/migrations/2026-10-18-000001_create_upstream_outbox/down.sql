@@ -0,0 +1,3 @@
+DROP TABLE upstream_dead_letter;
+DROP INDEX upstream_outbox_next_attempt;
+DROP TABLE upstream_outbox;
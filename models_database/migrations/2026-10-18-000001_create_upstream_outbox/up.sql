@@ -0,0 +1,23 @@
+-- Central-server calls waiting to be delivered by the bridge
+CREATE TABLE upstream_outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    created_at TEXT NOT NULL,
+    next_attempt_at TEXT NOT NULL
+);
+
+CREATE INDEX upstream_outbox_next_attempt ON upstream_outbox (next_attempt_at, id);
+
+-- Calls that kept failing after the maximum number of attempts
+CREATE TABLE upstream_dead_letter (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    last_error TEXT,
+    created_at TEXT NOT NULL,
+    failed_at TEXT NOT NULL
+);
@@ -0,0 +1,11 @@
+-- Calls to the local admin endpoints of the bridge and collector, allowed or not
+CREATE TABLE admin_audit (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    at TEXT NOT NULL,
+    service TEXT NOT NULL,
+    method TEXT NOT NULL,
+    path TEXT NOT NULL,
+    subject TEXT,
+    role TEXT,
+    outcome TEXT NOT NULL
+);
@@ -0,0 +1,87 @@
+-- Every version of every inventory row: the row as JSON, valid from valid_from until valid_to
+-- (NULL while it is the current version)
+CREATE TABLE inventory_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    entity TEXT NOT NULL,
+    uuid TEXT NOT NULL,
+    device_uuid TEXT,
+    data TEXT NOT NULL,
+    valid_from TEXT NOT NULL,
+    valid_to TEXT
+);
+
+CREATE INDEX inventory_history_entity ON inventory_history (entity, uuid, valid_from);
+CREATE INDEX inventory_history_valid ON inventory_history (valid_from, valid_to);
+
+-- What is stored today becomes the first version, valid from now
+INSERT INTO inventory_history (entity, uuid, device_uuid, data, valid_from)
+SELECT 'agent', uuid, (SELECT CASE WHEN COUNT(*) = 1 THEN MAX(uuid) END FROM device),
+    json_object('uuid', uuid, 'os', os, 'hostname', hostname, 'os_version', os_version),
+    strftime('%Y-%m-%d %H:%M:%f', 'now')
+FROM agent WHERE uuid IS NOT NULL;
+
+INSERT INTO inventory_history (entity, uuid, device_uuid, data, valid_from)
+SELECT 'device', uuid, uuid,
+    json_object('uuid', uuid, 'make', make, 'model', model, 'serial_number', serial_number, 'dev_phy_vm', dev_phy_vm),
+    strftime('%Y-%m-%d %H:%M:%f', 'now')
+FROM device;
+
+INSERT INTO inventory_history (entity, uuid, device_uuid, data, valid_from)
+SELECT 'cpu', uuid, device_uuid,
+    json_object('uuid', uuid, 'device_uuid', device_uuid, 'make', make, 'model', model, 'p_cores', p_cores,
+        'l_cores', l_cores, 'speed', speed, 'os_uuid', os_uuid),
+    strftime('%Y-%m-%d %H:%M:%f', 'now')
+FROM cpu;
+
+INSERT INTO inventory_history (entity, uuid, device_uuid, data, valid_from)
+SELECT 'memory', uuid, device_uuid,
+    json_object('uuid', uuid, 'device_uuid', device_uuid, 'make', make, 'model', model, 'speed', speed,
+        'size', size, 'serial_number', serial_number, 'os_uuid', os_uuid),
+    strftime('%Y-%m-%d %H:%M:%f', 'now')
+FROM memory;
+
+INSERT INTO inventory_history (entity, uuid, device_uuid, data, valid_from)
+SELECT 'storage', uuid, device_uuid,
+    json_object('uuid', uuid, 'device_uuid', device_uuid, 'hw_disk_type', hw_disk_type, 'make', make,
+        'model', model, 'serial_number', serial_number, 'base_fs_type', base_fs_type, 'free_space', free_space,
+        'total_disk_usage', total_disk_usage, 'total_disk_size', total_disk_size, 'os_uuid', os_uuid),
+    strftime('%Y-%m-%d %H:%M:%f', 'now')
+FROM storage;
+
+INSERT INTO inventory_history (entity, uuid, device_uuid, data, valid_from)
+SELECT 'partition', p.uuid, s.device_uuid,
+    json_object('uuid', p.uuid, 'storage_uuid', p.storage_uuid, 'name', p.name, 'serial_number', p.serial_number,
+        'fs_type', p.fs_type, 'free_space', p.free_space, 'used_space', p.used_space, 'total_size', p.total_size,
+        'os_uuid', p.os_uuid),
+    strftime('%Y-%m-%d %H:%M:%f', 'now')
+FROM partition p LEFT JOIN storage s ON s.uuid = p.storage_uuid;
+
+INSERT INTO inventory_history (entity, uuid, device_uuid, data, valid_from)
+SELECT 'nic', uuid, device_uuid,
+    json_object('uuid', uuid, 'device_uuid', device_uuid, 'make', make, 'model', model,
+        'number_of_ports', number_of_ports, 'max_speed', max_speed, 'supported_speeds', supported_speeds,
+        'serial_number', serial_number, 'mac_address', mac_address, 'os_uuid', os_uuid),
+    strftime('%Y-%m-%d %H:%M:%f', 'now')
+FROM nic;
+
+INSERT INTO inventory_history (entity, uuid, device_uuid, data, valid_from)
+SELECT 'port', p.uuid, n.device_uuid,
+    json_object('uuid', p.uuid, 'nic_uuid', p.nic_uuid, 'interface_name', p.interface_name,
+        'operating_speed', p.operating_speed, 'is_physical_logical', p.is_physical_logical,
+        'logical_type', p.logical_type, 'os_uuid', p.os_uuid),
+    strftime('%Y-%m-%d %H:%M:%f', 'now')
+FROM port p LEFT JOIN nic n ON n.uuid = p.nic_uuid;
+
+INSERT INTO inventory_history (entity, uuid, device_uuid, data, valid_from)
+SELECT 'ip', i.uuid, n.device_uuid,
+    json_object('uuid', i.uuid, 'port_uuid', i.port_uuid, 'address', i.address, 'gateway', i.gateway,
+        'subnet_mask', i.subnet_mask, 'dns', i.dns, 'os_uuid', i.os_uuid),
+    strftime('%Y-%m-%d %H:%M:%f', 'now')
+FROM ip_address i LEFT JOIN port p ON p.uuid = i.port_uuid LEFT JOIN nic n ON n.uuid = p.nic_uuid;
+
+INSERT INTO inventory_history (entity, uuid, device_uuid, data, valid_from)
+SELECT 'gpu', uuid, device_uuid,
+    json_object('uuid', uuid, 'device_uuid', device_uuid, 'make', make, 'model', model,
+        'serial_number', serial_number, 'size', size, 'driver', driver, 'os_uuid', os_uuid),
+    strftime('%Y-%m-%d %H:%M:%f', 'now')
+FROM gpu;
@@ -0,0 +1,185 @@
+-- Back to free-form text: sizes and link speeds as bare numbers in their base unit, CPU speeds in MHz
+
+CREATE TABLE cpu_old (
+    uuid TEXT PRIMARY KEY  NOT NULL,
+    device_uuid TEXT NOT NULL,
+    make TEXT NOT NULL,
+    model TEXT NOT NULL,
+    p_cores INTEGER NOT NULL,
+    l_cores INTEGER NOT NULL,
+    speed TEXT NOT NULL,
+    os_uuid TEXT,
+    FOREIGN KEY (device_uuid) REFERENCES device(uuid)ON DELETE CASCADE
+);
+
+INSERT INTO cpu_old (uuid, device_uuid, make, model, p_cores, l_cores, speed, os_uuid)
+SELECT
+    uuid,
+    device_uuid,
+    make,
+    model,
+    p_cores,
+    l_cores,
+    CAST(speed / 1000000 AS TEXT) || ' MHz',
+    os_uuid
+FROM cpu;
+
+DROP TABLE cpu;
+ALTER TABLE cpu_old RENAME TO cpu;
+
+CREATE TABLE memory_old (
+    uuid TEXT PRIMARY KEY  NOT NULL,
+    device_uuid TEXT NOT NULL,
+    make TEXT NOT NULL,
+    model TEXT NOT NULL,
+    speed TEXT NOT NULL,
+    size TEXT NOT NULL,
+    serial_number TEXT NOT NULL,
+    os_uuid TEXT,
+    FOREIGN KEY (device_uuid) REFERENCES device(uuid)ON DELETE CASCADE
+);
+
+INSERT INTO memory_old (uuid, device_uuid, make, model, speed, size, serial_number, os_uuid)
+SELECT
+    uuid,
+    device_uuid,
+    make,
+    model,
+    speed,
+    CAST(size AS TEXT),
+    serial_number,
+    os_uuid
+FROM memory;
+
+DROP TABLE memory;
+ALTER TABLE memory_old RENAME TO memory;
+
+CREATE TABLE storage_old (
+    uuid TEXT PRIMARY KEY  NOT NULL,
+    device_uuid TEXT NOT NULL,
+    hw_disk_type TEXT NOT NULL,
+    make TEXT NOT NULL,
+    model TEXT NOT NULL,
+    serial_number TEXT NOT NULL UNIQUE,
+    base_fs_type TEXT NOT NULL,
+    free_space TEXT NOT NULL,
+    total_disk_usage TEXT NOT NULL,
+    total_disk_size TEXT NOT NULL,
+    os_uuid TEXT,
+    FOREIGN KEY (device_uuid) REFERENCES device(uuid)ON DELETE CASCADE
+);
+
+INSERT INTO storage_old (uuid, device_uuid, hw_disk_type, make, model, serial_number, base_fs_type, free_space, total_disk_usage, total_disk_size, os_uuid)
+SELECT
+    uuid,
+    device_uuid,
+    hw_disk_type,
+    make,
+    model,
+    serial_number,
+    base_fs_type,
+    CAST(free_space AS TEXT),
+    CAST(total_disk_usage AS TEXT),
+    CAST(total_disk_size AS TEXT),
+    os_uuid
+FROM storage;
+
+DROP TABLE storage;
+ALTER TABLE storage_old RENAME TO storage;
+
+CREATE TABLE partition_old (
+    uuid TEXT PRIMARY KEY  NOT NULL,
+    storage_uuid TEXT NOT NULL,
+    name TEXT NOT NULL,
+    serial_number TEXT NOT NULL UNIQUE,
+    fs_type TEXT NOT NULL,
+    free_space TEXT NOT NULL,
+    used_space TEXT NOT NULL,
+    total_size TEXT NOT NULL,
+    os_uuid TEXT,
+    FOREIGN KEY (storage_uuid) REFERENCES storage(uuid)ON DELETE CASCADE
+);
+
+INSERT INTO partition_old (uuid, storage_uuid, name, serial_number, fs_type, free_space, used_space, total_size, os_uuid)
+SELECT
+    uuid,
+    storage_uuid,
+    name,
+    serial_number,
+    fs_type,
+    CAST(free_space AS TEXT),
+    CAST(used_space AS TEXT),
+    CAST(total_size AS TEXT),
+    os_uuid
+FROM partition;
+
+DROP TABLE partition;
+ALTER TABLE partition_old RENAME TO partition;
+
+CREATE TABLE nic_old (
+    uuid TEXT PRIMARY KEY  NOT NULL,
+    device_uuid TEXT NOT NULL,
+    make TEXT NOT NULL,
+    model TEXT NOT NULL,
+    number_of_ports INTEGER NOT NULL,
+    max_speed TEXT NOT NULL,
+    supported_speeds TEXT NOT NULL,
+    serial_number TEXT NOT NULL,
+    mac_address TEXT NOT NULL,
+    os_uuid TEXT,
+    FOREIGN KEY (device_uuid) REFERENCES device(uuid)ON DELETE CASCADE
+);
+
+INSERT INTO nic_old (uuid, device_uuid, make, model, number_of_ports, max_speed, supported_speeds, serial_number, mac_address, os_uuid)
+SELECT
+    uuid,
+    device_uuid,
+    make,
+    model,
+    number_of_ports,
+    CAST(max_speed AS TEXT),
+    supported_speeds,
+    serial_number,
+    mac_address,
+    os_uuid
+FROM nic;
+
+DROP TABLE nic;
+ALTER TABLE nic_old RENAME TO nic;
+
+CREATE TABLE port_old (
+    uuid TEXT PRIMARY KEY  NOT NULL,
+    nic_uuid TEXT NOT NULL,
+    interface_name TEXT NOT NULL,
+    operating_speed TEXT NOT NULL,
+    is_physical_logical TEXT NOT NULL,
+    logical_type TEXT NOT NULL,
+    os_uuid TEXT,
+    FOREIGN KEY (nic_uuid)  REFERENCES nic(uuid)ON DELETE CASCADE
+);
+
+INSERT INTO port_old (uuid, nic_uuid, interface_name, operating_speed, is_physical_logical, logical_type, os_uuid)
+SELECT
+    uuid,
+    nic_uuid,
+    interface_name,
+    CAST(operating_speed AS TEXT),
+    is_physical_logical,
+    logical_type,
+    os_uuid
+FROM port;
+
+DROP TABLE port;
+ALTER TABLE port_old RENAME TO port;
+
+UPDATE inventory_history SET data = json_set(data, '$.speed', CAST(json_extract(data, '$.speed') / 1000000 AS TEXT) || ' MHz') WHERE entity = 'cpu';
+
+UPDATE inventory_history SET data = json_set(data, '$.size', CAST(json_extract(data, '$.size') AS TEXT)) WHERE entity = 'memory';
+
+UPDATE inventory_history SET data = json_set(data, '$.free_space', CAST(json_extract(data, '$.free_space') AS TEXT), '$.total_disk_usage', CAST(json_extract(data, '$.total_disk_usage') AS TEXT), '$.total_disk_size', CAST(json_extract(data, '$.total_disk_size') AS TEXT)) WHERE entity = 'storage';
+
+UPDATE inventory_history SET data = json_set(data, '$.free_space', CAST(json_extract(data, '$.free_space') AS TEXT), '$.used_space', CAST(json_extract(data, '$.used_space') AS TEXT), '$.total_size', CAST(json_extract(data, '$.total_size') AS TEXT)) WHERE entity = 'partition';
+
+UPDATE inventory_history SET data = json_set(data, '$.max_speed', CAST(json_extract(data, '$.max_speed') AS TEXT)) WHERE entity = 'nic';
+
+UPDATE inventory_history SET data = json_set(data, '$.operating_speed', CAST(json_extract(data, '$.operating_speed') AS TEXT)) WHERE entity = 'port';
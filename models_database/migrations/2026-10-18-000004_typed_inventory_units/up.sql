@@ -0,0 +1,285 @@
+-- Sizes and speeds become integers in their base unit: bytes, hertz and bits per second. Values are
+-- parsed from the strings agents have sent so far ("69.31 GB", "2400 MHz", "1 Gbps" or a bare number),
+-- with the same units models_database::units accepts; anything unreadable, such as "Unknown", becomes 0.
+
+CREATE TEMP TABLE unit_scale (
+    kind TEXT NOT NULL,
+    suffix TEXT NOT NULL,
+    scale REAL NOT NULL,
+    PRIMARY KEY (kind, suffix)
+);
+
+INSERT INTO unit_scale (kind, suffix, scale) VALUES
+    ('bytes', '', 1),
+    ('bytes', 'b', 1),
+    ('bytes', 'byte', 1),
+    ('bytes', 'bytes', 1),
+    ('bytes', 'k', 1024),
+    ('bytes', 'kb', 1024),
+    ('bytes', 'kib', 1024),
+    ('bytes', 'm', 1048576),
+    ('bytes', 'mb', 1048576),
+    ('bytes', 'mib', 1048576),
+    ('bytes', 'g', 1073741824),
+    ('bytes', 'gb', 1073741824),
+    ('bytes', 'gib', 1073741824),
+    ('bytes', 't', 1099511627776),
+    ('bytes', 'tb', 1099511627776),
+    ('bytes', 'tib', 1099511627776),
+    ('bytes', 'p', 1125899906842624),
+    ('bytes', 'pb', 1125899906842624),
+    ('bytes', 'pib', 1125899906842624);
+
+INSERT INTO unit_scale (kind, suffix, scale) VALUES
+    ('hz', '', 1),
+    ('hz', 'hz', 1),
+    ('hz', 'khz', 1000),
+    ('hz', 'mhz', 1000000),
+    ('hz', 'ghz', 1000000000);
+
+INSERT INTO unit_scale (kind, suffix, scale) VALUES
+    ('bps', '', 1),
+    ('bps', 'bps', 1),
+    ('bps', 'b/s', 1),
+    ('bps', 'bit/s', 1),
+    ('bps', 'kbps', 1000),
+    ('bps', 'kb/s', 1000),
+    ('bps', 'kbit/s', 1000),
+    ('bps', 'mbps', 1000000),
+    ('bps', 'mb/s', 1000000),
+    ('bps', 'mbit/s', 1000000),
+    ('bps', 'gbps', 1000000000),
+    ('bps', 'gb/s', 1000000000),
+    ('bps', 'gbit/s', 1000000000),
+    ('bps', 'tbps', 1000000000000),
+    ('bps', 'tb/s', 1000000000000),
+    ('bps', 'tbit/s', 1000000000000);
+
+-- Collectors, Linux and Windows alike, reported CPU speeds as bare MHz
+INSERT INTO unit_scale (kind, suffix, scale)
+SELECT 'cpu_speed', suffix, scale FROM unit_scale WHERE kind = 'hz' AND suffix <> '';
+INSERT INTO unit_scale (kind, suffix, scale) VALUES ('cpu_speed', '', 1000000);
+
+CREATE TABLE cpu_new (
+    uuid TEXT PRIMARY KEY  NOT NULL,
+    device_uuid TEXT NOT NULL,
+    make TEXT NOT NULL,
+    model TEXT NOT NULL,
+    p_cores INTEGER NOT NULL,
+    l_cores INTEGER NOT NULL,
+    speed BIGINT NOT NULL,
+    os_uuid TEXT,
+    FOREIGN KEY (device_uuid) REFERENCES device(uuid)ON DELETE CASCADE
+);
+
+INSERT INTO cpu_new (uuid, device_uuid, make, model, p_cores, l_cores, speed, os_uuid)
+SELECT
+    uuid,
+    device_uuid,
+    make,
+    model,
+    p_cores,
+    l_cores,
+    COALESCE(CAST(ROUND(CAST(replace(trim(speed), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'cpu_speed'
+        AND suffix = lower(trim(ltrim(replace(trim(speed), ',', ''), '0123456789.'))))) AS INTEGER), 0),
+    os_uuid
+FROM cpu;
+
+DROP TABLE cpu;
+ALTER TABLE cpu_new RENAME TO cpu;
+
+CREATE TABLE memory_new (
+    uuid TEXT PRIMARY KEY  NOT NULL,
+    device_uuid TEXT NOT NULL,
+    make TEXT NOT NULL,
+    model TEXT NOT NULL,
+    speed TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    serial_number TEXT NOT NULL,
+    os_uuid TEXT,
+    FOREIGN KEY (device_uuid) REFERENCES device(uuid)ON DELETE CASCADE
+);
+
+INSERT INTO memory_new (uuid, device_uuid, make, model, speed, size, serial_number, os_uuid)
+SELECT
+    uuid,
+    device_uuid,
+    make,
+    model,
+    speed,
+    COALESCE(CAST(ROUND(CAST(replace(trim(size), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bytes'
+        AND suffix = lower(trim(ltrim(replace(trim(size), ',', ''), '0123456789.'))))) AS INTEGER), 0),
+    serial_number,
+    os_uuid
+FROM memory;
+
+DROP TABLE memory;
+ALTER TABLE memory_new RENAME TO memory;
+
+CREATE TABLE storage_new (
+    uuid TEXT PRIMARY KEY  NOT NULL,
+    device_uuid TEXT NOT NULL,
+    hw_disk_type TEXT NOT NULL,
+    make TEXT NOT NULL,
+    model TEXT NOT NULL,
+    serial_number TEXT NOT NULL UNIQUE,
+    base_fs_type TEXT NOT NULL,
+    free_space BIGINT NOT NULL,
+    total_disk_usage BIGINT NOT NULL,
+    total_disk_size BIGINT NOT NULL,
+    os_uuid TEXT,
+    FOREIGN KEY (device_uuid) REFERENCES device(uuid)ON DELETE CASCADE
+);
+
+INSERT INTO storage_new (uuid, device_uuid, hw_disk_type, make, model, serial_number, base_fs_type, free_space, total_disk_usage, total_disk_size, os_uuid)
+SELECT
+    uuid,
+    device_uuid,
+    hw_disk_type,
+    make,
+    model,
+    serial_number,
+    base_fs_type,
+    COALESCE(CAST(ROUND(CAST(replace(trim(free_space), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bytes'
+        AND suffix = lower(trim(ltrim(replace(trim(free_space), ',', ''), '0123456789.'))))) AS INTEGER), 0),
+    COALESCE(CAST(ROUND(CAST(replace(trim(total_disk_usage), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bytes'
+        AND suffix = lower(trim(ltrim(replace(trim(total_disk_usage), ',', ''), '0123456789.'))))) AS INTEGER), 0),
+    COALESCE(CAST(ROUND(CAST(replace(trim(total_disk_size), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bytes'
+        AND suffix = lower(trim(ltrim(replace(trim(total_disk_size), ',', ''), '0123456789.'))))) AS INTEGER), 0),
+    os_uuid
+FROM storage;
+
+DROP TABLE storage;
+ALTER TABLE storage_new RENAME TO storage;
+
+CREATE TABLE partition_new (
+    uuid TEXT PRIMARY KEY  NOT NULL,
+    storage_uuid TEXT NOT NULL,
+    name TEXT NOT NULL,
+    serial_number TEXT NOT NULL UNIQUE,
+    fs_type TEXT NOT NULL,
+    free_space BIGINT NOT NULL,
+    used_space BIGINT NOT NULL,
+    total_size BIGINT NOT NULL,
+    os_uuid TEXT,
+    FOREIGN KEY (storage_uuid) REFERENCES storage(uuid)ON DELETE CASCADE
+);
+
+INSERT INTO partition_new (uuid, storage_uuid, name, serial_number, fs_type, free_space, used_space, total_size, os_uuid)
+SELECT
+    uuid,
+    storage_uuid,
+    name,
+    serial_number,
+    fs_type,
+    COALESCE(CAST(ROUND(CAST(replace(trim(free_space), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bytes'
+        AND suffix = lower(trim(ltrim(replace(trim(free_space), ',', ''), '0123456789.'))))) AS INTEGER), 0),
+    COALESCE(CAST(ROUND(CAST(replace(trim(used_space), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bytes'
+        AND suffix = lower(trim(ltrim(replace(trim(used_space), ',', ''), '0123456789.'))))) AS INTEGER), 0),
+    COALESCE(CAST(ROUND(CAST(replace(trim(total_size), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bytes'
+        AND suffix = lower(trim(ltrim(replace(trim(total_size), ',', ''), '0123456789.'))))) AS INTEGER), 0),
+    os_uuid
+FROM partition;
+
+DROP TABLE partition;
+ALTER TABLE partition_new RENAME TO partition;
+
+CREATE TABLE nic_new (
+    uuid TEXT PRIMARY KEY  NOT NULL,
+    device_uuid TEXT NOT NULL,
+    make TEXT NOT NULL,
+    model TEXT NOT NULL,
+    number_of_ports INTEGER NOT NULL,
+    max_speed BIGINT NOT NULL,
+    supported_speeds TEXT NOT NULL,
+    serial_number TEXT NOT NULL,
+    mac_address TEXT NOT NULL,
+    os_uuid TEXT,
+    FOREIGN KEY (device_uuid) REFERENCES device(uuid)ON DELETE CASCADE
+);
+
+INSERT INTO nic_new (uuid, device_uuid, make, model, number_of_ports, max_speed, supported_speeds, serial_number, mac_address, os_uuid)
+SELECT
+    uuid,
+    device_uuid,
+    make,
+    model,
+    number_of_ports,
+    COALESCE(CAST(ROUND(CAST(replace(trim(max_speed), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bps'
+        AND suffix = lower(trim(ltrim(replace(trim(max_speed), ',', ''), '0123456789.'))))) AS INTEGER), 0),
+    supported_speeds,
+    serial_number,
+    mac_address,
+    os_uuid
+FROM nic;
+
+DROP TABLE nic;
+ALTER TABLE nic_new RENAME TO nic;
+
+CREATE TABLE port_new (
+    uuid TEXT PRIMARY KEY  NOT NULL,
+    nic_uuid TEXT NOT NULL,
+    interface_name TEXT NOT NULL,
+    operating_speed BIGINT NOT NULL,
+    is_physical_logical TEXT NOT NULL,
+    logical_type TEXT NOT NULL,
+    os_uuid TEXT,
+    FOREIGN KEY (nic_uuid)  REFERENCES nic(uuid)ON DELETE CASCADE
+);
+
+INSERT INTO port_new (uuid, nic_uuid, interface_name, operating_speed, is_physical_logical, logical_type, os_uuid)
+SELECT
+    uuid,
+    nic_uuid,
+    interface_name,
+    COALESCE(CAST(ROUND(CAST(replace(trim(operating_speed), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bps'
+        AND suffix = lower(trim(ltrim(replace(trim(operating_speed), ',', ''), '0123456789.'))))) AS INTEGER), 0),
+    is_physical_logical,
+    logical_type,
+    os_uuid
+FROM port;
+
+DROP TABLE port;
+ALTER TABLE port_new RENAME TO port;
+
+-- Keep recorded versions comparable with new ones, so the upgrade does not show up as a change
+
+UPDATE inventory_history SET data = json_set(data,
+    '$.speed', COALESCE(CAST(ROUND(CAST(replace(trim(json_extract(data, '$.speed')), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'cpu_speed'
+        AND suffix = lower(trim(ltrim(replace(trim(json_extract(data, '$.speed')), ',', ''), '0123456789.'))))) AS INTEGER), 0))
+WHERE entity = 'cpu';
+
+UPDATE inventory_history SET data = json_set(data,
+    '$.size', COALESCE(CAST(ROUND(CAST(replace(trim(json_extract(data, '$.size')), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bytes'
+        AND suffix = lower(trim(ltrim(replace(trim(json_extract(data, '$.size')), ',', ''), '0123456789.'))))) AS INTEGER), 0))
+WHERE entity = 'memory';
+
+UPDATE inventory_history SET data = json_set(data,
+    '$.free_space', COALESCE(CAST(ROUND(CAST(replace(trim(json_extract(data, '$.free_space')), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bytes'
+        AND suffix = lower(trim(ltrim(replace(trim(json_extract(data, '$.free_space')), ',', ''), '0123456789.'))))) AS INTEGER), 0),
+    '$.total_disk_usage', COALESCE(CAST(ROUND(CAST(replace(trim(json_extract(data, '$.total_disk_usage')), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bytes'
+        AND suffix = lower(trim(ltrim(replace(trim(json_extract(data, '$.total_disk_usage')), ',', ''), '0123456789.'))))) AS INTEGER), 0),
+    '$.total_disk_size', COALESCE(CAST(ROUND(CAST(replace(trim(json_extract(data, '$.total_disk_size')), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bytes'
+        AND suffix = lower(trim(ltrim(replace(trim(json_extract(data, '$.total_disk_size')), ',', ''), '0123456789.'))))) AS INTEGER), 0))
+WHERE entity = 'storage';
+
+UPDATE inventory_history SET data = json_set(data,
+    '$.free_space', COALESCE(CAST(ROUND(CAST(replace(trim(json_extract(data, '$.free_space')), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bytes'
+        AND suffix = lower(trim(ltrim(replace(trim(json_extract(data, '$.free_space')), ',', ''), '0123456789.'))))) AS INTEGER), 0),
+    '$.used_space', COALESCE(CAST(ROUND(CAST(replace(trim(json_extract(data, '$.used_space')), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bytes'
+        AND suffix = lower(trim(ltrim(replace(trim(json_extract(data, '$.used_space')), ',', ''), '0123456789.'))))) AS INTEGER), 0),
+    '$.total_size', COALESCE(CAST(ROUND(CAST(replace(trim(json_extract(data, '$.total_size')), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bytes'
+        AND suffix = lower(trim(ltrim(replace(trim(json_extract(data, '$.total_size')), ',', ''), '0123456789.'))))) AS INTEGER), 0))
+WHERE entity = 'partition';
+
+UPDATE inventory_history SET data = json_set(data,
+    '$.max_speed', COALESCE(CAST(ROUND(CAST(replace(trim(json_extract(data, '$.max_speed')), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bps'
+        AND suffix = lower(trim(ltrim(replace(trim(json_extract(data, '$.max_speed')), ',', ''), '0123456789.'))))) AS INTEGER), 0))
+WHERE entity = 'nic';
+
+UPDATE inventory_history SET data = json_set(data,
+    '$.operating_speed', COALESCE(CAST(ROUND(CAST(replace(trim(json_extract(data, '$.operating_speed')), ',', '') AS REAL) * (SELECT scale FROM unit_scale WHERE kind = 'bps'
+        AND suffix = lower(trim(ltrim(replace(trim(json_extract(data, '$.operating_speed')), ',', ''), '0123456789.'))))) AS INTEGER), 0))
+WHERE entity = 'port';
+
+DROP TABLE unit_scale;
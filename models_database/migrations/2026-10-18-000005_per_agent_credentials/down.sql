@@ -0,0 +1,19 @@
+-- Back to one credential and its tokens; the oldest credential wins
+CREATE TABLE tokens_old (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    token TEXT NOT NULL ,
+    expiration TEXT NOT NULL,
+    token_type TEXT NOT NULL UNIQUE
+);
+
+INSERT OR IGNORE INTO tokens_old (token, expiration, token_type)
+SELECT token, expiration, token_type FROM tokens
+WHERE agent_id = COALESCE((SELECT agent_id FROM agent_credential ORDER BY id LIMIT 1), '')
+ORDER BY id;
+
+DROP TABLE tokens;
+ALTER TABLE tokens_old RENAME TO tokens;
+
+DELETE FROM agent_credential WHERE id <> (SELECT MIN(id) FROM agent_credential);
+DROP INDEX agent_credential_agent_id;
+ALTER TABLE agent_credential DROP COLUMN agent_id;
@@ -0,0 +1,20 @@
+-- A bridge serves many collectors, and each is onboarded with the central server as an agent of its own,
+-- with its own credentials and tokens. A credential from before this has no agent_id; the first
+-- collector to ask for one claims it, together with the tokens issued for it.
+ALTER TABLE agent_credential ADD COLUMN agent_id TEXT;
+CREATE UNIQUE INDEX agent_credential_agent_id ON agent_credential (agent_id);
+
+CREATE TABLE tokens_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    agent_id TEXT NOT NULL DEFAULT '',
+    token TEXT NOT NULL,
+    expiration TEXT NOT NULL,
+    token_type TEXT NOT NULL,
+    UNIQUE (agent_id, token_type)
+);
+
+INSERT INTO tokens_new (agent_id, token, expiration, token_type)
+SELECT '', token, expiration, token_type FROM tokens;
+
+DROP TABLE tokens;
+ALTER TABLE tokens_new RENAME TO tokens;
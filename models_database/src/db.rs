@@ -1,13 +1,12 @@
 use diesel::prelude::*;
 use diesel::sqlite::SqliteConnection;
-use std::fs;
-use std::path::Path;
-use crate::models::{AgentCredential,Token};
+use crate::models::{AdminAudit,AgentCredential,DeadLetter,OutboxItem,Token};
 use crate::schema::agent::dsl::{agent, os};
 use crate::schema::agent_credential::dsl::*;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use crate::initail_response::{insert_or_update, store_json_data,delete_action}; 
+use crate::initail_response::{insert_or_update, delete_action};
+use crate::reconcile::{reconcile_inventory, ChangeSet};
 use chrono::NaiveDateTime;
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ServerResponse {
@@ -17,36 +16,15 @@ pub struct ServerResponse {
     pub master_key: String,
 }
 
-pub fn establish_connection(db_path: &str) -> SqliteConnection {
-    let db_dir = Path::new(db_path).parent().unwrap_or_else(|| Path::new("."));
-
-    // Create directory if it doesn't exist
-    if !db_dir.exists() {
-        println!("Creating directory: {:?}", db_dir);
-        fs::create_dir_all(db_dir).expect("Failed to create database directory");
-    }
-
-    // Create database file if it doesn't exist
-    if !Path::new(db_path).exists() {
-        println!("Creating database at: {}", db_path);
-        fs::File::create(db_path).expect("Failed to create database file");
-    }
-
-    // Connect to the database
-    let conn = SqliteConnection::establish(db_path)
-        .unwrap_or_else(|_| panic!("Error connecting to {}", db_path));
-
-    conn
-}
-
 // Function to save the response into the database
-pub fn save_agent(conn: &mut SqliteConnection, response: &ServerResponse) -> Result<(), diesel::result::Error> {
+pub fn save_agent(conn: &mut SqliteConnection, collector_id: &str, response: &ServerResponse) -> Result<(), diesel::result::Error> {
     let new_agent = AgentCredential {
         id: None, // Changed to None for auto-increment
         uuid: response.uuid.clone(),
         client_id: response.client_id.clone(),
         client_secret: response.client_secret.clone(),
         master_key: response.master_key.clone(),
+        agent_id: Some(collector_id.to_string()),
     };
 
     diesel::insert_into(agent_credential)
@@ -56,29 +34,51 @@ pub fn save_agent(conn: &mut SqliteConnection, response: &ServerResponse) -> Res
     Ok(())
 }
 
-pub fn is_agent_onboarded(conn: &mut SqliteConnection) -> bool {
-    use crate::schema::agent_credential::dsl::*;
-    use diesel::prelude::*;
+pub fn is_agent_onboarded(conn: &mut SqliteConnection, collector_id: &str) -> bool {
+    get_agent_credential(conn, collector_id).is_some()
+}
 
-    match agent_credential
-        .select(id)
-        .first::<Option<i32>>(conn)
-    {
-        Ok(_) => true, 
-        Err(diesel::result::Error::NotFound) => false, 
-        Err(_) => {
-            println!("[ERROR] Failed to check onboarding status from the database.");
-            false
-        }
+/// The server credential of one collector. A credential stored before the bridge served several
+/// collectors is claimed by the first collector that asks, along with its tokens.
+pub fn get_agent_credential(conn: &mut SqliteConnection, collector_id: &str) -> Option<AgentCredential> {
+    use crate::schema::tokens;
+
+    if collector_id.is_empty() {
+        return None;
     }
+    let result = conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        if let Some(credential) = agent_credential
+            .filter(agent_id.eq(collector_id))
+            .first::<AgentCredential>(conn)
+            .optional()?
+        {
+            return Ok(Some(credential));
+        }
+        let Some(legacy) = agent_credential.filter(agent_id.is_null()).order(id.asc()).first::<AgentCredential>(conn).optional()? else {
+            return Ok(None);
+        };
+        diesel::update(agent_credential.filter(id.eq(legacy.id)))
+            .set(agent_id.eq(collector_id))
+            .execute(conn)?;
+        diesel::update(tokens::table.filter(tokens::agent_id.eq("")))
+            .set(tokens::agent_id.eq(collector_id))
+            .execute(conn)?;
+        Ok(Some(AgentCredential { agent_id: Some(collector_id.to_string()), ..legacy }))
+    });
+    result.unwrap_or_else(|e| {
+        println!("[ERROR] Failed to read agent credentials: {}", e);
+        None
+    })
 }
 
-pub fn get_agent_credential(conn: &mut SqliteConnection) -> Option<AgentCredential> {
+/// Collectors that have been onboarded with the central server
+pub fn onboarded_agent_ids(conn: &mut SqliteConnection) -> Result<Vec<String>, diesel::result::Error> {
     agent_credential
-        .limit(1)
-        .first::<AgentCredential>(conn)
-        .ok()
-} 
+        .filter(agent_id.is_not_null())
+        .order(id.asc())
+        .select(agent_id.assume_not_null())
+        .load::<String>(conn)
+}
 
 pub fn get_agent_details(conn: &mut SqliteConnection) -> Option<String> {
     agent
@@ -88,21 +88,121 @@ pub fn get_agent_details(conn: &mut SqliteConnection) -> Option<String> {
         .ok()
 }
 
-pub fn initial_data_save(conn: &mut SqliteConnection, json_data: &Value) -> Result<(), diesel::result::Error> {
-    store_json_data(conn, json_data)?; // Fixed function call
-    Ok(())
+/// Looks up the uuid assigned to a storage device by its serial number
+pub fn find_storage_uuid(conn: &mut SqliteConnection, serial: &str) -> Option<String> {
+    use crate::schema::storage::dsl::{storage, serial_number, uuid as storage_uuid};
+
+    storage
+        .filter(serial_number.eq(serial))
+        .select(storage_uuid)
+        .first::<String>(conn)
+        .ok()
+}
+
+/// Looks up the uuid assigned to a partition by its OS volume identifier
+pub fn find_partition_uuid(conn: &mut SqliteConnection, volume_id: &str) -> Option<String> {
+    use crate::schema::partition::dsl::{partition, os_uuid as partition_os_uuid, uuid as partition_uuid};
+
+    partition
+        .filter(partition_os_uuid.eq(volume_id))
+        .select(partition_uuid)
+        .first::<String>(conn)
+        .ok()
+}
+
+/// Looks up the uuid assigned to a NIC by its OS adapter identifier
+pub fn find_nic_uuid(conn: &mut SqliteConnection, adapter_id: &str) -> Option<String> {
+    use crate::schema::nic::dsl::{nic, os_uuid as nic_os_uuid, uuid as nic_uuid};
+
+    nic
+        .filter(nic_os_uuid.eq(adapter_id))
+        .select(nic_uuid)
+        .first::<String>(conn)
+        .ok()
+}
+
+/// Looks up the uuid assigned to a port by its interface name
+pub fn find_port_uuid(conn: &mut SqliteConnection, interface: &str) -> Option<String> {
+    use crate::schema::port::dsl::{port, interface_name, uuid as port_uuid};
+
+    port
+        .filter(interface_name.eq(interface))
+        .select(port_uuid)
+        .first::<String>(conn)
+        .ok()
+}
+
+/// Looks up the uuid of the device with the given model name
+pub fn find_device_uuid(conn: &mut SqliteConnection, model_name: &str) -> Option<String> {
+    use crate::schema::device::dsl::{device, model, uuid as device_uuid};
+
+    device
+        .filter(model.eq(model_name))
+        .select(device_uuid)
+        .first::<String>(conn)
+        .ok()
+}
+
+/// Looks up the uuid of the CPU with the given model name
+pub fn find_cpu_uuid(conn: &mut SqliteConnection, model_name: &str) -> Option<String> {
+    use crate::schema::cpu::dsl::{cpu, model, uuid as cpu_uuid};
+
+    cpu
+        .filter(model.eq(model_name))
+        .select(cpu_uuid)
+        .first::<String>(conn)
+        .ok()
+}
+
+/// Looks up the uuid of the first memory module from the given manufacturer
+pub fn find_memory_uuid(conn: &mut SqliteConnection, make_name: &str) -> Option<String> {
+    use crate::schema::memory::dsl::{memory, make, uuid as memory_uuid};
+
+    memory
+        .filter(make.eq(make_name))
+        .select(memory_uuid)
+        .first::<String>(conn)
+        .ok()
+}
+
+/// Looks up a partition by serial number, returning its uuid and the owning storage uuid
+pub fn find_partition_by_serial(conn: &mut SqliteConnection, serial: &str) -> Option<(String, String)> {
+    use crate::schema::partition::dsl::{partition, serial_number, storage_uuid, uuid as partition_uuid};
+
+    partition
+        .filter(serial_number.eq(serial))
+        .select((partition_uuid, storage_uuid))
+        .first::<(String, String)>(conn)
+        .ok()
+}
+
+/// Looks up a port by interface name, returning its uuid and the owning NIC uuid
+pub fn find_port_by_interface(conn: &mut SqliteConnection, interface: &str) -> Option<(String, String)> {
+    use crate::schema::port::dsl::{port, interface_name, nic_uuid, uuid as port_uuid};
+
+    port
+        .filter(interface_name.eq(interface))
+        .select((port_uuid, nic_uuid))
+        .first::<(String, String)>(conn)
+        .ok()
+}
+
+/// Stores the inventory the server returned for `agent.data`, reconciling it with what is already stored
+pub fn initial_data_save(conn: &mut SqliteConnection, json_data: &Value) -> Result<ChangeSet, diesel::result::Error> {
+    reconcile_inventory(conn, json_data)
 }
 
-pub fn save_token(conn: &mut SqliteConnection, token_str: &str, expiration_str: &str, token_type_str: &str) -> Result<(), diesel::result::Error> {
+pub fn save_token(conn: &mut SqliteConnection, collector_id: &str, token_str: &str, expiration_str: &str, token_type_str: &str) -> Result<(), diesel::result::Error> {
     use crate::schema::tokens::dsl::*;
 
     diesel::insert_into(tokens)
         .values((
+            agent_id.eq(collector_id),
             token.eq(token_str),
             expiration.eq(expiration_str),
             token_type.eq(token_type_str),
         ))
-        .on_conflict(token_type)
+        .on_conflict((agent_id, token_type))
         .do_update()
         .set((
             token.eq(token_str),
@@ -113,10 +213,11 @@ pub fn save_token(conn: &mut SqliteConnection, token_str: &str, expiration_str:
     Ok(())
 }
 
-pub fn get_token(conn: &mut SqliteConnection, token_type_str: &str) -> Option<Token> {
+pub fn get_token(conn: &mut SqliteConnection, collector_id: &str, token_type_str: &str) -> Option<Token> {
     use crate::schema::tokens::dsl::*;
     
     let result = tokens
+        .filter(agent_id.eq(collector_id))
         .filter(token_type.eq(token_type_str))
         .first::<Token>(conn)
         .optional()
@@ -132,10 +233,11 @@ pub fn get_token(conn: &mut SqliteConnection, token_type_str: &str) -> Option<To
     })
 }
 
-pub fn token_exists(conn: &mut SqliteConnection, token_type_str: &str) -> bool {
+pub fn token_exists(conn: &mut SqliteConnection, collector_id: &str, token_type_str: &str) -> bool {
     use crate::schema::tokens::dsl::*;
 
     tokens
+        .filter(agent_id.eq(collector_id))
         .filter(token_type.eq(token_type_str))
         .first::<Token>(conn)
         .optional()
@@ -165,4 +267,189 @@ pub fn delete_initial_data(conn: &mut SqliteConnection, json_data: &Value) -> Re
         })
         .map(|_| println!("Data deleted successfully."))
    
-}
\ No newline at end of file
+}
+/// Queues a central-server call; timestamps use the same `%Y-%m-%d %H:%M:%S` format as tokens
+pub fn enqueue_outbox(conn: &mut SqliteConnection, kind_str: &str, payload_str: &str, now: &str) -> Result<(), diesel::result::Error> {
+    use crate::schema::upstream_outbox::dsl::*;
+
+    diesel::insert_into(upstream_outbox)
+        .values((
+            kind.eq(kind_str),
+            payload.eq(payload_str),
+            attempts.eq(0),
+            created_at.eq(now),
+            next_attempt_at.eq(now),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Oldest call that is due for an attempt among the first queued call of each kind. Calls of one kind
+/// are delivered in the order they were queued: while the first one waits for a retry, the rest wait too.
+pub fn next_due_outbox_item(conn: &mut SqliteConnection, now: &str) -> Result<Option<OutboxItem>, diesel::result::Error> {
+    use crate::schema::upstream_outbox::dsl::*;
+
+    let heads = diesel::alias!(crate::schema::upstream_outbox as heads);
+    upstream_outbox
+        .filter(next_attempt_at.le(now))
+        .filter(id.eq_any(heads.group_by(heads.field(kind)).select(diesel::dsl::min(heads.field(id)))))
+        .order(id.asc())
+        .first::<OutboxItem>(conn)
+        .optional()
+}
+
+pub fn delete_outbox_item(conn: &mut SqliteConnection, item_id: i32) -> Result<(), diesel::result::Error> {
+    use crate::schema::upstream_outbox::dsl::*;
+
+    diesel::delete(upstream_outbox.filter(id.eq(item_id))).execute(conn)?;
+    Ok(())
+}
+
+/// Records a failed attempt and when to try again
+pub fn reschedule_outbox_item(conn: &mut SqliteConnection, item_id: i32, attempt_count: i32, error: &str, next_at: &str) -> Result<(), diesel::result::Error> {
+    use crate::schema::upstream_outbox::dsl::*;
+
+    diesel::update(upstream_outbox.filter(id.eq(item_id)))
+        .set((attempts.eq(attempt_count), last_error.eq(error), next_attempt_at.eq(next_at)))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Moves a call that has run out of attempts to the dead-letter table
+pub fn dead_letter_outbox_item(conn: &mut SqliteConnection, item: &OutboxItem, error: &str, now: &str) -> Result<(), diesel::result::Error> {
+    use crate::schema::upstream_dead_letter::dsl::upstream_dead_letter;
+    use crate::schema::upstream_outbox::dsl::{id, upstream_outbox};
+
+    let dead = DeadLetter {
+        id: None,
+        kind: item.kind.clone(),
+        payload: item.payload.clone(),
+        attempts: item.attempts + 1,
+        last_error: Some(error.to_string()),
+        created_at: item.created_at.clone(),
+        failed_at: now.to_string(),
+    };
+    conn.transaction(|conn| {
+        diesel::insert_into(upstream_dead_letter).values(&dead).execute(conn)?;
+        diesel::delete(upstream_outbox.filter(id.eq(item.id))).execute(conn)?;
+        Ok(())
+    })
+}
+
+/// Number of queued calls and the creation time of the oldest one
+pub fn outbox_depth(conn: &mut SqliteConnection) -> Result<(i64, Option<String>), diesel::result::Error> {
+    use crate::schema::upstream_outbox::dsl::*;
+
+    let depth = upstream_outbox.count().get_result::<i64>(conn)?;
+    let oldest = upstream_outbox
+        .select(diesel::dsl::min(created_at))
+        .first::<Option<String>>(conn)?;
+    Ok((depth, oldest))
+}
+
+pub fn dead_letter_count(conn: &mut SqliteConnection) -> Result<i64, diesel::result::Error> {
+    use crate::schema::upstream_dead_letter::dsl::*;
+
+    upstream_dead_letter.count().get_result(conn)
+}
+
+pub fn record_admin_audit(conn: &mut SqliteConnection, entry: &AdminAudit) -> Result<(), diesel::result::Error> {
+    use crate::schema::admin_audit::dsl::*;
+
+    diesel::insert_into(admin_audit).values(entry).execute(conn)?;
+    Ok(())
+}
+
+/// Most recent admin calls first
+pub fn recent_admin_audit(conn: &mut SqliteConnection, limit: i64) -> Result<Vec<AdminAudit>, diesel::result::Error> {
+    use crate::schema::admin_audit::dsl::*;
+
+    admin_audit.order(id.desc()).limit(limit).load(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations;
+
+    fn connection() -> SqliteConnection {
+        let mut conn = SqliteConnection::establish(":memory:").unwrap();
+        migrations::run_pending(&mut conn).unwrap();
+        conn
+    }
+
+    fn next(conn: &mut SqliteConnection, now: &str) -> Option<(i32, String)> {
+        next_due_outbox_item(conn, now).unwrap().map(|item| (item.id.unwrap(), item.kind))
+    }
+
+    #[test]
+    fn outbox_items_of_a_kind_wait_for_the_one_ahead() {
+        let mut conn = connection();
+        let queued = "2026-01-01 00:00:00";
+        for kind_str in ["monitor", "monitor", "scan"] {
+            enqueue_outbox(&mut conn, kind_str, "{}", queued).unwrap();
+        }
+        assert_eq!(next(&mut conn, queued), Some((1, "monitor".to_string())));
+
+        // The first monitor call is retried later, so the second one must not overtake it
+        reschedule_outbox_item(&mut conn, 1, 1, "down", "2026-01-01 00:05:00").unwrap();
+        assert_eq!(next(&mut conn, "2026-01-01 00:01:00"), Some((3, "scan".to_string())));
+        delete_outbox_item(&mut conn, 3).unwrap();
+        assert_eq!(next(&mut conn, "2026-01-01 00:01:00"), None);
+        assert_eq!(next(&mut conn, "2026-01-01 00:05:00"), Some((1, "monitor".to_string())));
+
+        delete_outbox_item(&mut conn, 1).unwrap();
+        assert_eq!(next(&mut conn, "2026-01-01 00:05:00"), Some((2, "monitor".to_string())));
+    }
+
+    fn server_response(server_uuid: &str) -> ServerResponse {
+        ServerResponse {
+            uuid: server_uuid.to_string(),
+            client_id: format!("client-{}", server_uuid),
+            client_secret: "secret".to_string(),
+            master_key: "key".to_string(),
+        }
+    }
+
+    const LATER: &str = "2999-01-01 00:00:00";
+
+    #[test]
+    fn every_collector_has_its_own_credential_and_tokens() {
+        let mut conn = connection();
+        save_agent(&mut conn, "collector-a", &server_response("uuid-a")).unwrap();
+        assert!(is_agent_onboarded(&mut conn, "collector-a"));
+        assert!(!is_agent_onboarded(&mut conn, "collector-b"));
+
+        save_agent(&mut conn, "collector-b", &server_response("uuid-b")).unwrap();
+        assert_eq!(get_agent_credential(&mut conn, "collector-b").unwrap().uuid, "uuid-b");
+        assert_eq!(onboarded_agent_ids(&mut conn).unwrap(), ["collector-a", "collector-b"]);
+
+        save_token(&mut conn, "collector-a", "token-a", LATER, "access_token").unwrap();
+        save_token(&mut conn, "collector-b", "token-b", LATER, "access_token").unwrap();
+        save_token(&mut conn, "collector-a", "token-a2", LATER, "access_token").unwrap();
+        assert_eq!(get_token(&mut conn, "collector-a", "access_token").unwrap().token, "token-a2");
+        assert_eq!(get_token(&mut conn, "collector-b", "access_token").unwrap().token, "token-b");
+        assert!(!token_exists(&mut conn, "collector-c", "access_token"));
+    }
+
+    #[test]
+    fn a_credential_from_before_per_collector_onboarding_is_claimed_once() {
+        let mut conn = connection();
+        diesel::sql_query(
+            "INSERT INTO agent_credential (uuid, client_id, client_secret, master_key) VALUES ('uuid-old', 'c', 's', 'k')",
+        )
+        .execute(&mut conn)
+        .unwrap();
+        save_token(&mut conn, "", "token-old", LATER, "access_token").unwrap();
+        assert!(onboarded_agent_ids(&mut conn).unwrap().is_empty());
+        assert!(get_agent_credential(&mut conn, "").is_none());
+
+        let claimed = get_agent_credential(&mut conn, "collector-a").unwrap();
+        assert_eq!((claimed.uuid.as_str(), claimed.agent_id.as_deref()), ("uuid-old", Some("collector-a")));
+        assert_eq!(get_token(&mut conn, "collector-a", "access_token").unwrap().token, "token-old");
+
+        // The next collector is onboarded on its own
+        assert!(get_agent_credential(&mut conn, "collector-b").is_none());
+        assert_eq!(onboarded_agent_ids(&mut conn).unwrap(), ["collector-a"]);
+    }
+}
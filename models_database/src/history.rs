@@ -0,0 +1,234 @@
+//! Versioned inventory history. Every change a reconcile, rescan or delete makes to an inventory row is
+//! kept in `inventory_history`, so the inventory can be read as it was at any point in time.
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::sql_types::Text;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::models::InventoryVersion;
+use crate::reconcile::{diff_fields, ChangeSet, EntityChange};
+use crate::schema::inventory_history;
+
+/// Timestamps in `inventory_history`, UTC so they sort as text across DST changes
+pub const HISTORY_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
+/// Inventory entities and the tables that hold them
+pub const ENTITIES: [(&str, &str); 10] = [
+    ("agent", "agent"),
+    ("device", "device"),
+    ("cpu", "cpu"),
+    ("memory", "memory"),
+    ("storage", "storage"),
+    ("partition", "partition"),
+    ("nic", "nic"),
+    ("port", "port"),
+    ("ip", "ip_address"),
+    ("gpu", "gpu"),
+];
+
+/// One version of an inventory row
+#[derive(Debug, Clone, Serialize)]
+pub struct EntityVersion {
+    pub entity: String,
+    pub uuid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_uuid: Option<String>,
+    pub data: Value,
+    pub valid_from: String,
+    /// When the next version replaced this one, or the row was removed; absent while current
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub valid_to: Option<String>,
+}
+
+impl TryFrom<InventoryVersion> for EntityVersion {
+    type Error = Error;
+
+    fn try_from(version: InventoryVersion) -> Result<Self, Error> {
+        let data = serde_json::from_str(&version.data).map_err(|e| Error::DeserializationError(Box::new(e)))?;
+        Ok(EntityVersion {
+            entity: version.entity,
+            uuid: version.uuid,
+            device_uuid: version.device_uuid,
+            data,
+            valid_from: version.valid_from,
+            valid_to: version.valid_to,
+        })
+    }
+}
+
+pub fn now() -> String {
+    Utc::now().naive_utc().format(HISTORY_TIME_FORMAT).to_string()
+}
+
+/// Normalises a point in time for history queries to UTC: RFC 3339, `YYYY-MM-DD HH:MM:SS[.fff]` in
+/// UTC, or a bare date meaning its start in UTC
+pub fn parse_time(value: &str) -> Option<String> {
+    let value = value.trim();
+    let utc = if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        time.naive_utc()
+    } else if let Ok(time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f") {
+        time
+    } else if let Ok(time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f") {
+        time
+    } else {
+        NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?.and_hms_opt(0, 0, 0)?
+    };
+    Some(utc.format(HISTORY_TIME_FORMAT).to_string())
+}
+
+/// Makes `row` the current version of an entity, closing the previous one at `at`. Nothing is written
+/// when the row is unchanged; `None` closes the entity because it was removed.
+pub fn record<T: Serialize>(
+    conn: &mut SqliteConnection,
+    entity: &str,
+    uuid: &str,
+    device_uuid: Option<&str>,
+    row: Option<&T>,
+    at: &str,
+) -> Result<(), Error> {
+    let data = row
+        .map(serde_json::to_value)
+        .transpose()
+        .map_err(|e| Error::SerializationError(Box::new(e)))?;
+    let open = inventory_history::table
+        .filter(inventory_history::entity.eq(entity))
+        .filter(inventory_history::uuid.eq(uuid))
+        .filter(inventory_history::valid_to.is_null())
+        .order(inventory_history::id.desc())
+        .first::<InventoryVersion>(conn)
+        .optional()?;
+
+    if let (Some(open), Some(data)) = (&open, &data)
+        && serde_json::from_str::<Value>(&open.data).is_ok_and(|stored| stored == *data)
+    {
+        return Ok(());
+    }
+    if let Some(open) = open {
+        diesel::update(inventory_history::table.filter(inventory_history::id.eq(open.id)))
+            .set(inventory_history::valid_to.eq(at))
+            .execute(conn)?;
+    }
+    if let Some(data) = data {
+        let version = InventoryVersion {
+            id: None,
+            entity: entity.to_string(),
+            uuid: uuid.to_string(),
+            device_uuid: device_uuid.map(str::to_string),
+            data: data.to_string(),
+            valid_from: at.to_string(),
+            valid_to: None,
+        };
+        diesel::insert_into(inventory_history::table).values(&version).execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Closes the current version of every row that no longer exists, which also covers rows removed
+/// through ON DELETE CASCADE
+pub fn close_removed(conn: &mut SqliteConnection, at: &str) -> Result<usize, Error> {
+    let mut closed = 0;
+    for (entity, table) in ENTITIES {
+        closed += diesel::sql_query(format!(
+            "UPDATE inventory_history SET valid_to = ? WHERE valid_to IS NULL AND entity = ? \
+             AND uuid NOT IN (SELECT uuid FROM {} WHERE uuid IS NOT NULL)",
+            table
+        ))
+        .bind::<Text, _>(at)
+        .bind::<Text, _>(entity)
+        .execute(conn)?;
+    }
+    Ok(closed)
+}
+
+fn versions_at(conn: &mut SqliteConnection, at: &str, device_uuid: Option<&str>) -> Result<Vec<EntityVersion>, Error> {
+    let mut query = inventory_history::table
+        .filter(inventory_history::valid_from.le(at))
+        .filter(inventory_history::valid_to.is_null().or(inventory_history::valid_to.gt(at)))
+        .order((inventory_history::entity, inventory_history::uuid))
+        .into_boxed();
+    if let Some(device_uuid) = device_uuid {
+        query = query.filter(inventory_history::device_uuid.eq(device_uuid));
+    }
+    query.load::<InventoryVersion>(conn)?.into_iter().map(EntityVersion::try_from).collect()
+}
+
+/// The inventory as it was at `at`, grouped by entity; only one device's when `device_uuid` is given
+pub fn inventory_at(
+    conn: &mut SqliteConnection,
+    at: &str,
+    device_uuid: Option<&str>,
+) -> Result<BTreeMap<String, Vec<Value>>, Error> {
+    let mut inventory: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for version in versions_at(conn, at, device_uuid)? {
+        inventory.entry(version.entity).or_default().push(version.data);
+    }
+    Ok(inventory)
+}
+
+/// What was added, changed and removed between two points in time
+pub fn inventory_diff(
+    conn: &mut SqliteConnection,
+    from: &str,
+    to: &str,
+    device_uuid: Option<&str>,
+) -> Result<ChangeSet, Error> {
+    let keyed = |versions: Vec<EntityVersion>| -> BTreeMap<(String, String), Value> {
+        versions.into_iter().map(|version| ((version.entity, version.uuid), version.data)).collect()
+    };
+    let before = keyed(versions_at(conn, from, device_uuid)?);
+    let after = keyed(versions_at(conn, to, device_uuid)?);
+
+    let mut changes = ChangeSet::default();
+    for ((entity, uuid), data) in &after {
+        match before.get(&(entity.clone(), uuid.clone())) {
+            None => changes.added.push(EntityChange::new(entity, uuid.as_str())),
+            Some(old) => {
+                let fields = diff_fields(old, data);
+                if !fields.is_empty() {
+                    changes.changed.push(EntityChange { fields, ..EntityChange::new(entity, uuid.as_str()) });
+                }
+            }
+        }
+    }
+    for (entity, uuid) in before.keys().filter(|key| !after.contains_key(*key)) {
+        changes.removed.push(EntityChange::new(entity, uuid.as_str()));
+    }
+    Ok(changes)
+}
+
+/// Every version of one entity, oldest first
+pub fn entity_history(conn: &mut SqliteConnection, entity: &str, uuid: &str) -> Result<Vec<EntityVersion>, Error> {
+    inventory_history::table
+        .filter(inventory_history::entity.eq(entity))
+        .filter(inventory_history::uuid.eq(uuid))
+        .order((inventory_history::valid_from, inventory_history::id))
+        .load::<InventoryVersion>(conn)?
+        .into_iter()
+        .map(EntityVersion::try_from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn points_in_time_are_normalised_to_utc() {
+        assert_eq!(parse_time("2026-03-29T03:30:00+02:00").unwrap(), "2026-03-29 01:30:00.000");
+        assert_eq!(parse_time("2026-03-29T01:30:00.25Z").unwrap(), "2026-03-29 01:30:00.250");
+        assert_eq!(parse_time(" 2026-03-29 01:30:00 ").unwrap(), "2026-03-29 01:30:00.000");
+        assert_eq!(parse_time("2026-03-29").unwrap(), "2026-03-29 00:00:00.000");
+        assert_eq!(parse_time("yesterday"), None);
+    }
+
+    #[test]
+    fn now_sorts_with_parsed_times() {
+        let now = now();
+        assert_eq!(parse_time(&now).unwrap(), now);
+        assert!(parse_time(&Utc::now().to_rfc3339()).unwrap() >= now);
+    }
+}
@@ -1,174 +1,15 @@
 use diesel::prelude::*;
 use diesel::result::Error;
 use serde_json::Value;
+use crate::history::{self, record};
 use crate::models::*;
 use crate::schema::*;
 use anyhow::{Result};
 
-pub fn store_json_data(conn: &mut SqliteConnection, json_data: &Value) -> Result<(), Error> {
-    conn.transaction::<_, Error, _>(|conn| {
-        println!("Storing JSON data into the database...");
-        println!("JSON data: {:?}", json_data.to_string());
-        // Insert Agent
-        if let Some(agent_value) = json_data.get("agent") {
-            let agent: Agent = serde_json::from_value(agent_value.clone())
-                .map_err(|e| {
-                    println!("Failed to parse agent: {e}");
-                    Error::RollbackTransaction
-                })?;
-            diesel::insert_into(agent::table).values(&agent).execute(conn)
-                .map_err(|e| {
-                    println!("Failed to insert into agent table: {e}");
-                    Error::RollbackTransaction
-                })?;
-        }
-
-        // Insert Device and Related Tables
-        if let Some(device_value) = json_data.get("device") {
-            let device: Device = serde_json::from_value(device_value.clone())
-                .map_err(|e| {
-                    println!("Failed to parse device: {e}");
-                    Error::RollbackTransaction
-                })?;
-            diesel::insert_into(device::table).values(&device).execute(conn)
-                .map_err(|e| {
-                    println!("Failed to insert into device table: {e}");
-                    Error::RollbackTransaction
-                })?;
-
-            let device_uuid = device.uuid.clone();
-
-            // CPU
-            if let Some(cpu_array) = device_value.get("cpu").and_then(|v| v.as_array()) {
-                for c in cpu_array {
-                    let mut cpu: Cpu = serde_json::from_value(c.clone()).map_err(|e| {
-                        println!("Failed to parse CPU: {e}");
-                        Error::RollbackTransaction
-                    })?;
-                    cpu.device_uuid = device_uuid.clone();
-                    diesel::insert_into(cpu::table).values(&cpu).execute(conn).map_err(|e| {
-                        println!("Failed to insert into cpu table: {e}");
-                        Error::RollbackTransaction
-                    })?;
-                }
-            }
-
-            // Memory
-            if let Some(mem_array) = device_value.get("memory").and_then(|v| v.as_array()) {
-                for m in mem_array {
-                    let mut memory: Memory = serde_json::from_value(m.clone()).map_err(|e| {
-                        println!("Failed to parse memory: {e}");
-                        Error::RollbackTransaction
-                    })?;
-                    memory.device_uuid = device_uuid.clone();
-                    diesel::insert_into(memory::table).values(&memory).execute(conn).map_err(|e| {
-                        println!("Failed to insert into memory table: {e}");
-                        Error::RollbackTransaction
-                    })?;
-                }
-            }
-
-            // Storage + Partition
-            if let Some(stor_array) = device_value.get("storage").and_then(|v| v.as_array()) {
-                for s in stor_array {
-                    let mut storage: Storage = serde_json::from_value(s.clone()).map_err(|e| {
-                        println!("Failed to parse storage: {e}");
-                        Error::RollbackTransaction
-                    })?;
-                    storage.device_uuid = device_uuid.clone();
-                    let storage_uuid = storage.uuid.clone();
-                    diesel::insert_into(storage::table).values(&storage).execute(conn).map_err(|e| {
-                        println!("Failed to insert into storage table: {e}");
-                        Error::RollbackTransaction
-                    })?;
-
-                    if let Some(part_array) = s.get("partition").and_then(|v| v.as_array()) {
-                        for p in part_array {
-                            let mut partition: Partition = serde_json::from_value(p.clone()).map_err(|e| {
-                                println!("Failed to parse partition: {e}");
-                                Error::RollbackTransaction
-                            })?;
-                            partition.storage_uuid = storage_uuid.clone();
-                            diesel::insert_into(partition::table).values(&partition).execute(conn).map_err(|e| {
-                                println!("Failed to insert into partition table: {e}");
-                                Error::RollbackTransaction
-                            })?;
-                        }
-                    }
-                }
-            }
-
-            // NIC + Port + IP
-            if let Some(nics) = device_value.get("nic").and_then(|v| v.as_array()) {
-                for n in nics {
-                    let mut nic: Nic = serde_json::from_value(n.clone()).map_err(|e| {
-                        println!("Failed to parse NIC: {e}");
-                        Error::RollbackTransaction
-                    })?;
-                    nic.device_uuid = device_uuid.clone();
-                    let nic_uuid = nic.uuid.clone();
-                    diesel::insert_into(nic::table).values(&nic).execute(conn).map_err(|e| {
-                        println!("Failed to insert into nic table: {e}");
-                        Error::RollbackTransaction
-                    })?;
-
-                    if let Some(port_array) = n.get("port").and_then(|v| v.as_array()) {
-                        for port_v in port_array {
-                            let mut port: Port = serde_json::from_value(port_v.clone()).map_err(|e| {
-                                println!("Failed to parse port: {e}");
-                                Error::RollbackTransaction
-                            })?;
-                            port.nic_uuid = nic_uuid.clone();
-                            let port_uuid = port.uuid.clone();
-                            diesel::insert_into(port::table).values(&port).execute(conn).map_err(|e| {
-                                println!("Failed to insert into port table: {e}");
-                                Error::RollbackTransaction
-                            })?;
-
-                            if let Some(ip_array) = port_v.get("ip").and_then(|v| v.as_array()) {
-                                for ip_v in ip_array {
-                                    let mut ip: Ip = serde_json::from_value(ip_v.clone()).map_err(|e| {
-                                        println!("Failed to parse IP: {e}");
-                                        Error::RollbackTransaction
-                                    })?;
-                                    ip.port_uuid = port_uuid.clone();
-                                    diesel::insert_into(ip_address::table).values(&ip).execute(conn).map_err(|e| {
-                                        println!("Failed to insert into ip_address table: {e}");
-                                        Error::RollbackTransaction
-                                    })?;
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-
-            // GPU
-            if let Some(gpus) = device_value.get("gpu").and_then(|v| v.as_array()) {
-                for g in gpus {
-                    let mut gpu: Gpu = serde_json::from_value(g.clone()).map_err(|e| {
-                        println!("Failed to parse GPU: {e}");
-                        Error::RollbackTransaction
-                    })?;
-                    gpu.device_uuid = device_uuid.clone();
-                    diesel::insert_into(gpu::table).values(&gpu).execute(conn).map_err(|e| {
-                        println!("Failed to insert into gpu table: {e}");
-                        Error::RollbackTransaction
-                    })?;
-                }
-            }
-        }
-
-        println!("JSON data stored successfully.");
-        Ok(())
-    })
-}
-
-
 pub fn insert_or_update(conn: &mut SqliteConnection, device_values: &[Value]) -> Result<(), Error> {
     conn.transaction::<_, Error, _>(|conn| {
         println!("Storing JSON data into the database...");
-        println!("All device_values: {:#?}", device_values);
+        let at = history::now();
 
         for device_value in device_values {
             let device_uuid = device_value
@@ -216,12 +57,16 @@ pub fn insert_or_update(conn: &mut SqliteConnection, device_values: &[Value]) ->
                         })?;
                     println!("Updated storage: {}", storage_uuid);
                 }
+                record(conn, "storage", &storage_uuid, Some(&device_uuid), Some(&storage), &at).map_err(|e| {
+                    println!("Failed to record storage history: {e}");
+                    Error::RollbackTransaction
+                })?;
 
                 // === PARTITIONS ===
                 if let Some(partitions) = storage_value.get("partition").and_then(|v| v.as_array()) {
+                    // The device's NIC still needs handling, so this only skips the partitions
                     if partitions.is_empty() {
                         println!("No partitions found for storage {}, skipping partition insertions.", storage_uuid);
-                        continue;
                     }
 
                     for part in partitions {
@@ -252,14 +97,18 @@ pub fn insert_or_update(conn: &mut SqliteConnection, device_values: &[Value]) ->
                             println!("Inserted partition: {}", partition_uuid);
                         } else {
                             diesel::update(partition::table.filter(partition::uuid.eq(&partition_uuid)))
-                            .set(&partition)
-                            .execute(conn)
-                            .map_err(|e| {
-                                println!("Failed to update storage: {e}");
-                                Error::RollbackTransaction
-                            })?;
-                        println!("Updated storage: {}", storage_uuid);
+                                .set(&partition)
+                                .execute(conn)
+                                .map_err(|e| {
+                                    println!("Failed to update partition: {e}");
+                                    Error::RollbackTransaction
+                                })?;
+                            println!("Updated partition: {}", partition_uuid);
                         }
+                        record(conn, "partition", &partition_uuid, Some(&device_uuid), Some(&partition), &at).map_err(|e| {
+                            println!("Failed to record partition history: {e}");
+                            Error::RollbackTransaction
+                        })?;
                     }
                 }
             }
@@ -303,6 +152,10 @@ pub fn insert_or_update(conn: &mut SqliteConnection, device_values: &[Value]) ->
                         })?;
                     println!("Updated NIC: {}", nic_uuid);
                 }
+                record(conn, "nic", &nic_uuid, Some(&device_uuid), Some(&nic), &at).map_err(|e| {
+                    println!("Failed to record nic history: {e}");
+                    Error::RollbackTransaction
+                })?;
 
                 // === PORT HANDLING ===
                 if let Some(port_array) = nic_value.get("port").and_then(|v| v.as_array()) {
@@ -333,15 +186,19 @@ pub fn insert_or_update(conn: &mut SqliteConnection, device_values: &[Value]) ->
                                 })?;
                             println!("Inserted port: {}", port_uuid);
                         } else {
-                           diesel::update(port::table.filter(port::uuid.eq(&port_uuid)))
-                            .set(&port)
-                            .execute(conn)
-                            .map_err(|e| {
-                                println!("Failed to update storage: {e}");
-                                Error::RollbackTransaction
-                            })?;
+                            diesel::update(port::table.filter(port::uuid.eq(&port_uuid)))
+                                .set(&port)
+                                .execute(conn)
+                                .map_err(|e| {
+                                    println!("Failed to update port: {e}");
+                                    Error::RollbackTransaction
+                                })?;
                             println!("Updated port: {}", port_uuid);
                         }
+                        record(conn, "port", &port_uuid, Some(&device_uuid), Some(&port), &at).map_err(|e| {
+                            println!("Failed to record port history: {e}");
+                            Error::RollbackTransaction
+                        })?;
 
                         // === IP HANDLING ===
                         if let Some(ip_array) = port_value.get("ip").and_then(|v| v.as_array()) {
@@ -360,6 +217,10 @@ pub fn insert_or_update(conn: &mut SqliteConnection, device_values: &[Value]) ->
                                         Error::RollbackTransaction
                                     })?;
                                 println!("Inserted IP.");
+                                record(conn, "ip", &ip.uuid, Some(&device_uuid), Some(&ip), &at).map_err(|e| {
+                                    println!("Failed to record ip history: {e}");
+                                    Error::RollbackTransaction
+                                })?;
                             }
                         }
                     }
@@ -412,6 +273,8 @@ pub fn delete_action(conn: &mut SqliteConnection, json_data: &Value) -> Result<(
         };
         // Call perform_delete with extracted table_name and UUIDs
         perform_delete(conn, table_name, &uuid_list)?;
+        // Also closes the history of children that went with them through ON DELETE CASCADE
+        history::close_removed(conn, &history::now())?;
 
         Ok(())
     })
@@ -442,3 +305,35 @@ fn perform_delete(conn: &mut SqliteConnection, table_name: &str, uuid_list: &[St
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations;
+    use serde_json::json;
+
+    #[test]
+    fn a_disk_without_partitions_does_not_skip_the_nic() {
+        let mut conn = SqliteConnection::establish(":memory:").unwrap();
+        migrations::run_pending(&mut conn).unwrap();
+        let device = json!({
+            "device_uuid": "d1",
+            "storage": {
+                "uuid": "s1", "hw_disk_type": "SSD", "make": "Acme", "model": "X1", "serial_number": "s1",
+                "base_fs_type": "NTFS", "free_space": "10 GB", "total_disk_usage": "20 GB", "total_disk_size": "30 GB",
+                "os_uuid": null, "partition": []
+            },
+            "nic": {
+                "uuid": "n1", "make": "Intel", "model": "I219", "number_of_ports": 1, "max_speed": "1 Gbps",
+                "supported_speeds": "1 Gbps", "serial_number": "n1", "mac_address": "00:11:22:33:44:55", "os_uuid": null
+            }
+        });
+
+        insert_or_update(&mut conn, &[device]).unwrap();
+
+        let nics = nic::table.select(nic::uuid).load::<String>(&mut conn).unwrap();
+        assert_eq!(nics, ["n1"]);
+        let storage = storage::table.select(storage::uuid).load::<String>(&mut conn).unwrap();
+        assert_eq!(storage, ["s1"]);
+    }
+}
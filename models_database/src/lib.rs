@@ -1,9 +1,17 @@
 pub mod db;
+pub mod history;
 pub mod models;
 pub mod schema;
 pub mod initail_response; 
+pub mod migrations;
+pub mod pool;
+pub mod reconcile;
+pub mod units;
 
-pub use db::{save_agent, establish_connection,initial_data_save,is_agent_onboarded,get_agent_credential}; 
+pub use db::{save_agent, initial_data_save,is_agent_onboarded,get_agent_credential,onboarded_agent_ids};
+pub use pool::{get_connection, with_connection, DbConnection, DbError, DbPool};
+pub use history::{entity_history, inventory_at, inventory_diff, EntityVersion};
+pub use reconcile::{reconcile_inventory, ChangeSet, EntityChange};
  
 use std::fs::write;
 use shared_config::CONFIG;
@@ -1,8 +1,39 @@
+use diesel::{Connection, SqliteConnection};
 use models_database::initialize;
- 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize the library (e.g., generate diesel.toml)
-    initialize()?;
-    println!("models_database initialized successfully.");
+use models_database::migrations::{run_pending, status};
+use shared_config::CONFIG;
+
+const USAGE: &str = "usage: models_database [status|migrate] [database path]";
+
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let db_path = args.get(1).map(String::as_str).unwrap_or(&CONFIG.db_path);
+
+    match args.first().map(String::as_str) {
+        None => {
+            // Initialize the library (e.g., generate diesel.toml)
+            initialize().map_err(|e| e.to_string())?;
+            println!("models_database initialized successfully.");
+        }
+        // Opened directly rather than through the pool, which migrates on its own
+        Some("status") => {
+            let mut conn = SqliteConnection::establish(db_path)?;
+            let migrations = status(&mut conn)?;
+            for migration in &migrations {
+                println!("[{}] {}", if migration.applied { "x" } else { " " }, migration.name);
+            }
+            let pending = migrations.iter().filter(|migration| !migration.applied).count();
+            println!("{}: {} migrations, {} pending", db_path, migrations.len(), pending);
+        }
+        Some("migrate") => {
+            let mut conn = SqliteConnection::establish(db_path)?;
+            let applied = run_pending(&mut conn)?;
+            for version in &applied {
+                println!("Applied migration {}", version);
+            }
+            println!("{}: {} migrations applied, schema is up to date", db_path, applied.len());
+        }
+        Some(other) => return Err(format!("unknown command '{}'; {}", other, USAGE).into()),
+    }
     Ok(())
-}
\ No newline at end of file
+}
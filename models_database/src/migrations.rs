@@ -0,0 +1,70 @@
+use diesel::connection::SimpleConnection;
+use diesel::migration::{Migration, MigrationSource, MigrationVersion};
+use diesel::sqlite::{Sqlite, SqliteConnection};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use std::error::Error;
+
+/// The SQL in `migrations/`, compiled into the crate. Applied versions are tracked by diesel in
+/// `__diesel_schema_migrations`.
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// One embedded migration and whether the database has it
+#[derive(Debug, Clone)]
+pub struct MigrationStatus {
+    pub version: String,
+    pub name: String,
+    pub applied: bool,
+}
+
+fn embedded() -> Result<Vec<Box<dyn Migration<Sqlite>>>, BoxError> {
+    MigrationSource::<Sqlite>::migrations(&MIGRATIONS)
+}
+
+/// Versions recorded in the database that this build does not ship. Their schema may not match
+/// what the code expects, and there is no way back, so such a database is left alone.
+fn unknown_versions(conn: &mut SqliteConnection) -> Result<Vec<String>, BoxError> {
+    let known: Vec<MigrationVersion> = embedded()?.iter().map(|m| m.name().version().as_owned()).collect();
+    Ok(conn
+        .applied_migrations()?
+        .into_iter()
+        .filter(|version| !known.contains(version))
+        .map(|version| version.to_string())
+        .collect())
+}
+
+/// Every embedded migration, oldest first
+pub fn status(conn: &mut SqliteConnection) -> Result<Vec<MigrationStatus>, BoxError> {
+    let applied = conn.applied_migrations()?;
+    Ok(embedded()?
+        .iter()
+        .map(|migration| MigrationStatus {
+            version: migration.name().version().to_string(),
+            name: migration.name().to_string(),
+            applied: applied.contains(&migration.name().version()),
+        })
+        .collect())
+}
+
+/// Applies the migrations the database does not have yet, each in its own transaction, and returns
+/// their versions. Upgrades are forward-only: a database migrated by a newer build is refused.
+///
+/// Foreign keys are switched off on `conn` first, and stay off: a migration that rebuilds a table must
+/// not cascade into its children, and SQLite ignores the pragma inside the migration's transaction.
+pub fn run_pending(conn: &mut SqliteConnection) -> Result<Vec<String>, BoxError> {
+    let unknown = unknown_versions(conn)?;
+    if !unknown.is_empty() {
+        return Err(format!(
+            "database has migrations this build does not know ({}); it was upgraded by a newer version",
+            unknown.join(", ")
+        )
+        .into());
+    }
+    conn.batch_execute("PRAGMA foreign_keys = OFF;")?;
+    Ok(conn
+        .run_pending_migrations(MIGRATIONS)?
+        .into_iter()
+        .map(|version| version.to_string())
+        .collect())
+}
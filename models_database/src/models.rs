@@ -1,13 +1,14 @@
 use diesel::prelude::*;
 use diesel::associations::HasTable;
 use serde::{Deserialize, Serialize};
+use crate::units::{BitsPerSecond, Bytes, Hertz};
 use crate::schema::agent::dsl::agent;
 use crate::schema::cpu::dsl::cpu;
 use crate::schema::memory::dsl::memory;
 
 
 
-#[derive(Debug, Queryable, Insertable, Serialize, Deserialize)]
+#[derive(Queryable, Insertable, Serialize, Deserialize)]
 #[diesel(table_name = crate::schema::agent_credential)]
 pub struct AgentCredential {
     pub id: Option<i32>,
@@ -15,11 +16,29 @@ pub struct AgentCredential {
     pub client_id: String,
     pub client_secret: String,
     pub master_key: String,
+    /// The collector this credential belongs to; `None` only for a credential from before the bridge
+    /// served several collectors, until one claims it
+    pub agent_id: Option<String>,
+}
+
+/// Leaves the client secret and master key out, so a credential can be logged
+impl std::fmt::Debug for AgentCredential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AgentCredential")
+            .field("id", &self.id)
+            .field("uuid", &self.uuid)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"<redacted>")
+            .field("master_key", &"<redacted>")
+            .field("agent_id", &self.agent_id)
+            .finish()
+    }
 }
 
 
-#[derive(Debug, Queryable, Insertable, Deserialize)]
+#[derive(Debug, Queryable, Insertable, AsChangeset, Serialize, Deserialize)]
 #[diesel(table_name = crate::schema::agent)]
+#[diesel(treat_none_as_null = true)]
 pub struct Agent {
     pub uuid: Option<String>,
     pub os: String,
@@ -32,7 +51,7 @@ impl Agent {
     }
 }
 
-#[derive(Debug, Queryable, Insertable, Deserialize)]
+#[derive(Debug, Queryable, Insertable, AsChangeset, Serialize, Deserialize)]
 #[diesel(table_name = crate::schema::device)]
 pub struct Device {
     pub uuid: String,
@@ -42,8 +61,9 @@ pub struct Device {
     pub dev_phy_vm: String,
 }
 
-#[derive(Debug, Queryable, Insertable, Deserialize)]
+#[derive(Debug, Queryable, Insertable, AsChangeset, Serialize, Deserialize)]
 #[diesel(table_name = crate::schema::cpu)]
+#[diesel(treat_none_as_null = true)]
 pub struct Cpu {
     pub uuid: String,
     #[serde(skip_deserializing)] 
@@ -52,7 +72,8 @@ pub struct Cpu {
     pub model: String,
     pub p_cores: i32,
     pub l_cores: i32,
-    pub speed: String,
+    #[serde(deserialize_with = "crate::units::cpu_speed::deserialize")]
+    pub speed: Hertz,
     pub os_uuid: Option<String>,
 }
 
@@ -62,8 +83,9 @@ impl Cpu {
     }
 }
 
-#[derive(Debug, Queryable, Insertable, Deserialize)]
+#[derive(Debug, Queryable, Insertable, AsChangeset, Serialize, Deserialize)]
 #[diesel(table_name = crate::schema::memory)]
+#[diesel(treat_none_as_null = true)]
 pub struct Memory {
     pub uuid: String,
     #[serde(skip_deserializing)] 
@@ -71,7 +93,7 @@ pub struct Memory {
     pub make: String,
     pub model: String,
     pub speed: String,
-    pub size: String,
+    pub size: Bytes,
     pub serial_number: String,
     pub os_uuid: Option<String>,
 }
@@ -81,8 +103,9 @@ impl Memory {
     }
 }
 
-#[derive(Debug, Insertable, AsChangeset, Deserialize,Queryable)]
+#[derive(Debug, Insertable, AsChangeset, Serialize, Deserialize,Queryable)]
 #[diesel(table_name = crate::schema::storage)]
+#[diesel(treat_none_as_null = true)]
 pub struct Storage {
     pub uuid: String,
     #[serde(skip_deserializing)] 
@@ -92,14 +115,15 @@ pub struct Storage {
     pub model: String,
     pub serial_number: String,
     pub base_fs_type: String,
-    pub free_space: String,
-    pub total_disk_usage: String,
-    pub total_disk_size: String,
+    pub free_space: Bytes,
+    pub total_disk_usage: Bytes,
+    pub total_disk_size: Bytes,
     pub os_uuid: Option<String>,
 }
 
-#[derive(Debug, Insertable,Queryable, Deserialize,AsChangeset)]
+#[derive(Debug, Insertable,Queryable, Serialize, Deserialize,AsChangeset)]
 #[diesel(table_name = crate::schema::partition)]
+#[diesel(treat_none_as_null = true)]
 pub struct Partition {
     pub uuid: String,
     #[serde(skip_deserializing)] 
@@ -107,14 +131,15 @@ pub struct Partition {
     pub name: String,
     pub serial_number: String,
     pub fs_type: String,
-    pub free_space: String,
-    pub used_space: String,
-    pub total_size: String,
+    pub free_space: Bytes,
+    pub used_space: Bytes,
+    pub total_size: Bytes,
     pub os_uuid: Option<String>,
 }
 
-#[derive(Debug, Insertable, Deserialize, Queryable,AsChangeset)]
+#[derive(Debug, Insertable, Serialize, Deserialize, Queryable,AsChangeset)]
 #[diesel(table_name = crate::schema::nic)]
+#[diesel(treat_none_as_null = true)]
 pub struct Nic {
     pub uuid: String,
     #[serde(skip_deserializing)] 
@@ -122,28 +147,30 @@ pub struct Nic {
     pub make: String,
     pub model: String,
     pub number_of_ports: i32,
-    pub max_speed: String,
+    pub max_speed: BitsPerSecond,
     pub supported_speeds: String,
     pub serial_number: String,
     pub mac_address: String,
     pub os_uuid: Option<String>,
 }
 
-#[derive(Debug, Insertable, Deserialize, Queryable ,AsChangeset)]
+#[derive(Debug, Insertable, Serialize, Deserialize, Queryable ,AsChangeset)]
 #[diesel(table_name = crate::schema::port)]
+#[diesel(treat_none_as_null = true)]
 pub struct Port {
     pub uuid: String,
     #[serde(skip_deserializing)] 
     pub nic_uuid: String,
     pub interface_name: String,
-    pub operating_speed: String,
+    pub operating_speed: BitsPerSecond,
     pub is_physical_logical: String,
     pub logical_type: String,
     pub os_uuid: Option<String>,
 }
 
-#[derive(Debug, Insertable, Deserialize , Queryable)]
+#[derive(Debug, Insertable, AsChangeset, Serialize, Deserialize , Queryable)]
 #[diesel(table_name = crate::schema::ip_address)]
+#[diesel(treat_none_as_null = true)]
 pub struct Ip {
     pub uuid: String,
     #[serde(skip_deserializing)] 
@@ -161,8 +188,9 @@ impl Ip {
 }
 
 
-#[derive(Debug, Queryable, Insertable, Deserialize)]
+#[derive(Debug, Queryable, Insertable, AsChangeset, Serialize, Deserialize)]
 #[diesel(table_name = crate::schema::gpu)]
+#[diesel(treat_none_as_null = true)]
 pub struct Gpu {
     pub uuid: String,
     #[serde(skip_deserializing)] 
@@ -180,9 +208,66 @@ pub struct Gpu {
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 pub struct Token {
     pub id: Option<i32>, 
+    pub agent_id: String,
     pub token: String,
     pub expiration: String,
     pub token_type: String,
 }
 
 
+
+/// A central-server call queued by the bridge until it is delivered
+#[derive(Debug, Queryable, Insertable, Serialize, Deserialize, Clone, Selectable)]
+#[diesel(table_name = crate::schema::upstream_outbox)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct OutboxItem {
+    pub id: Option<i32>,
+    pub kind: String,
+    pub payload: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created_at: String,
+    pub next_attempt_at: String,
+}
+
+#[derive(Debug, Queryable, Insertable, Serialize, Deserialize, Clone, Selectable)]
+#[diesel(table_name = crate::schema::upstream_dead_letter)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct DeadLetter {
+    pub id: Option<i32>,
+    pub kind: String,
+    pub payload: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created_at: String,
+    pub failed_at: String,
+}
+
+#[derive(Debug, Queryable, Insertable, Serialize, Deserialize, Clone, Selectable)]
+#[diesel(table_name = crate::schema::admin_audit)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct AdminAudit {
+    pub id: Option<i32>,
+    pub at: String,
+    pub service: String,
+    pub method: String,
+    pub path: String,
+    pub subject: Option<String>,
+    pub role: Option<String>,
+    pub outcome: String,
+}
+
+/// One version of an inventory row, as stored in `inventory_history`
+#[derive(Debug, Queryable, Insertable, Serialize, Deserialize, Clone, Selectable)]
+#[diesel(table_name = crate::schema::inventory_history)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct InventoryVersion {
+    pub id: Option<i32>,
+    pub entity: String,
+    pub uuid: String,
+    pub device_uuid: Option<String>,
+    /// The row as JSON
+    pub data: String,
+    pub valid_from: String,
+    pub valid_to: Option<String>,
+}
@@ -0,0 +1,97 @@
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool, PooledConnection};
+use diesel::sqlite::SqliteConnection;
+use shared_config::CONFIG;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use crate::migrations::run_pending;
+
+pub type DbError = Box<dyn Error + Send + Sync>;
+pub type DbPool = Pool<ConnectionManager<SqliteConnection>>;
+pub type DbConnection = PooledConnection<ConnectionManager<SqliteConnection>>;
+
+/// How long a caller waits for a free connection before giving up
+const CHECKOUT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// One pool per database file, opened on first use
+static POOLS: LazyLock<Mutex<HashMap<String, DbPool>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Settings every pooled connection gets when it is opened
+#[derive(Debug, Clone, Copy)]
+struct ConnectionOptions {
+    busy_timeout_ms: u64,
+}
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for ConnectionOptions {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        // WAL lets the bridge and the collector read while the other writes, and the busy timeout makes
+        // writers wait for each other instead of failing. SQLite leaves foreign keys off unless asked,
+        // which would make the schema's ON DELETE CASCADE do nothing.
+        conn.batch_execute(&format!(
+            "PRAGMA busy_timeout = {}; PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL; PRAGMA foreign_keys = ON;",
+            self.busy_timeout_ms
+        ))
+        .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+/// Creates the database if needed, applies pending migrations and opens a pool on it
+pub fn open_pool(db_path: &str) -> Result<DbPool, DbError> {
+    if let Some(db_dir) = Path::new(db_path).parent().filter(|dir| !dir.as_os_str().is_empty() && !dir.exists()) {
+        println!("Creating directory: {:?}", db_dir);
+        fs::create_dir_all(db_dir)?;
+    }
+
+    // Migrations get a connection of their own, since run_pending turns foreign keys off on it
+    let mut conn = SqliteConnection::establish(db_path)?;
+    conn.batch_execute(&format!("PRAGMA busy_timeout = {};", CONFIG.db_busy_timeout_ms))?;
+    for version in run_pending(&mut conn)? {
+        println!("Applied migration {} to {}", version, db_path);
+    }
+    drop(conn);
+
+    let pool = Pool::builder()
+        .max_size(CONFIG.db_pool_size)
+        .min_idle(Some(1))
+        .connection_timeout(CHECKOUT_TIMEOUT)
+        .connection_customizer(Box::new(ConnectionOptions { busy_timeout_ms: CONFIG.db_busy_timeout_ms }))
+        .build(ConnectionManager::<SqliteConnection>::new(db_path))?;
+    Ok(pool)
+}
+
+/// The process-wide pool for `db_path`
+pub fn pool(db_path: &str) -> Result<DbPool, DbError> {
+    let mut pools = POOLS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(pool) = pools.get(db_path) {
+        return Ok(pool.clone());
+    }
+    let pool = open_pool(db_path)?;
+    pools.insert(db_path.to_string(), pool.clone());
+    Ok(pool)
+}
+
+/// A connection from the pool for `db_path`. It blocks while every connection is in use, so async
+/// code should go through `with_connection` instead.
+pub fn get_connection(db_path: &str) -> Result<DbConnection, DbError> {
+    Ok(pool(db_path)?.get()?)
+}
+
+/// Runs `f` with a connection to `CONFIG.db_path` on tokio's blocking threads, so SQLite I/O and lock
+/// waits never stall the async executor
+pub async fn with_connection<T, F>(f: F) -> Result<T, DbError>
+where
+    F: FnOnce(&mut SqliteConnection) -> Result<T, DbError> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let mut conn = get_connection(&CONFIG.db_path)?;
+        f(&mut conn)
+    })
+    .await?
+}
@@ -0,0 +1,374 @@
+//! Brings the stored inventory of one host in line with a complete snapshot of it
+
+use diesel::prelude::*;
+use diesel::result::Error;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::history::{self, record};
+use crate::models::*;
+use crate::schema::*;
+
+/// One inventory row the reconcile touched
+#[derive(Debug, Clone, Serialize)]
+pub struct EntityChange {
+    pub entity: String,
+    pub uuid: String,
+    /// Columns whose value changed; empty for additions and removals
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<String>,
+}
+
+impl EntityChange {
+    pub(crate) fn new(entity: &str, uuid: impl Into<String>) -> Self {
+        Self { entity: entity.to_string(), uuid: uuid.into(), fields: Vec::new() }
+    }
+}
+
+/// What a reconcile added, changed and removed
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChangeSet {
+    pub added: Vec<EntityChange>,
+    pub changed: Vec<EntityChange>,
+    pub removed: Vec<EntityChange>,
+}
+
+impl ChangeSet {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// A host's inventory as the server returns it for `agent.data`, with every child tied to its parent
+struct Snapshot {
+    agent: Option<Agent>,
+    device: Device,
+    cpu: Vec<Cpu>,
+    memory: Vec<Memory>,
+    storage: Vec<Storage>,
+    partition: Vec<Partition>,
+    nic: Vec<Nic>,
+    port: Vec<Port>,
+    ip: Vec<Ip>,
+    gpu: Vec<Gpu>,
+}
+
+fn parse<T: serde::de::DeserializeOwned>(entity: &str, value: &Value) -> Result<T, Error> {
+    serde_json::from_value(value.clone())
+        .map_err(|e| Error::DeserializationError(format!("invalid {} in inventory snapshot: {}", entity, e).into()))
+}
+
+fn children<'a>(value: &'a Value, key: &str) -> impl Iterator<Item = &'a Value> {
+    value.get(key).and_then(Value::as_array).into_iter().flatten()
+}
+
+impl Snapshot {
+    fn parse(data: &Value) -> Result<Self, Error> {
+        let agent = data.get("agent").map(|agent| parse::<Agent>("agent", agent)).transpose()?;
+        let device_value = data
+            .get("device")
+            .ok_or_else(|| Error::DeserializationError("inventory snapshot has no device".into()))?;
+        let device: Device = parse("device", device_value)?;
+
+        let mut snapshot = Snapshot {
+            agent,
+            device,
+            cpu: Vec::new(),
+            memory: Vec::new(),
+            storage: Vec::new(),
+            partition: Vec::new(),
+            nic: Vec::new(),
+            port: Vec::new(),
+            ip: Vec::new(),
+            gpu: Vec::new(),
+        };
+        let device_uuid = snapshot.device.uuid.clone();
+
+        for value in children(device_value, "cpu") {
+            let mut cpu_row: Cpu = parse("cpu", value)?;
+            cpu_row.device_uuid = device_uuid.clone();
+            snapshot.cpu.push(cpu_row);
+        }
+        for value in children(device_value, "memory") {
+            let mut memory_row: Memory = parse("memory", value)?;
+            memory_row.device_uuid = device_uuid.clone();
+            snapshot.memory.push(memory_row);
+        }
+        for value in children(device_value, "storage") {
+            let mut storage_row: Storage = parse("storage", value)?;
+            storage_row.device_uuid = device_uuid.clone();
+            for part in children(value, "partition") {
+                let mut partition_row: Partition = parse("partition", part)?;
+                partition_row.storage_uuid = storage_row.uuid.clone();
+                snapshot.partition.push(partition_row);
+            }
+            snapshot.storage.push(storage_row);
+        }
+        for value in children(device_value, "nic") {
+            let mut nic_row: Nic = parse("nic", value)?;
+            nic_row.device_uuid = device_uuid.clone();
+            for port_value in children(value, "port") {
+                let mut port_row: Port = parse("port", port_value)?;
+                port_row.nic_uuid = nic_row.uuid.clone();
+                for ip_value in children(port_value, "ip") {
+                    let mut ip_row: Ip = parse("ip", ip_value)?;
+                    ip_row.port_uuid = port_row.uuid.clone();
+                    snapshot.ip.push(ip_row);
+                }
+                snapshot.port.push(port_row);
+            }
+            snapshot.nic.push(nic_row);
+        }
+        for value in children(device_value, "gpu") {
+            let mut gpu_row: Gpu = parse("gpu", value)?;
+            gpu_row.device_uuid = device_uuid.clone();
+            snapshot.gpu.push(gpu_row);
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Top-level fields that differ between two rows of the same table
+fn changed_fields<T: Serialize>(stored: &T, incoming: &T) -> Vec<String> {
+    match (serde_json::to_value(stored), serde_json::to_value(incoming)) {
+        (Ok(stored), Ok(incoming)) => diff_fields(&stored, &incoming),
+        _ => Vec::new(),
+    }
+}
+
+/// Top-level fields that differ between two serialized rows, including ones only one of them has
+pub(crate) fn diff_fields(stored: &Value, incoming: &Value) -> Vec<String> {
+    let (Value::Object(stored), Value::Object(incoming)) = (stored, incoming) else {
+        return Vec::new();
+    };
+    let mut fields: Vec<String> = incoming
+        .iter()
+        .filter(|(field, value)| stored.get(*field) != Some(value))
+        .map(|(field, _)| field.clone())
+        .collect();
+    fields.extend(stored.keys().filter(|field| !incoming.contains_key(*field)).cloned());
+    fields
+}
+
+/// Inserts a row that is not stored yet, or updates the columns that differ, and records the new
+/// version in the inventory history
+macro_rules! upsert {
+    ($conn:expr, $changes:expr, $at:expr, $device:expr, $entity:literal, $table:ident, $rows:expr) => {
+        for row in $rows {
+            match $table::table.find(row.uuid.as_str()).first($conn).optional()? {
+                None => {
+                    diesel::insert_into($table::table).values(row).execute($conn)?;
+                    record($conn, $entity, &row.uuid, Some($device), Some(row), $at)?;
+                    $changes.added.push(EntityChange::new($entity, row.uuid.as_str()));
+                }
+                Some(stored) => {
+                    let fields = changed_fields(&stored, row);
+                    if !fields.is_empty() {
+                        diesel::update($table::table.find(row.uuid.as_str())).set(row).execute($conn)?;
+                        record($conn, $entity, &row.uuid, Some($device), Some(row), $at)?;
+                        $changes.changed.push(EntityChange { fields, ..EntityChange::new($entity, row.uuid.as_str()) });
+                    }
+                }
+            }
+        }
+    };
+}
+
+/// Deletes the rows under `parents` whose uuid is not in `kept`
+macro_rules! remove_missing {
+    ($conn:expr, $changes:expr, $entity:literal, $table:ident, $parent:ident, $parents:expr, $kept:expr) => {{
+        let gone: Vec<String> = $table::table
+            .filter($table::$parent.eq_any($parents))
+            .filter($table::uuid.ne_all($kept))
+            .select($table::uuid)
+            .load($conn)?;
+        if !gone.is_empty() {
+            diesel::delete($table::table.filter($table::uuid.eq_any(&gone))).execute($conn)?;
+        }
+        $changes.removed.extend(gone.into_iter().map(|uuid| EntityChange::new($entity, uuid)));
+    }};
+}
+
+fn uuids<'a, T: 'a>(rows: &'a [T], uuid: impl Fn(&'a T) -> &'a str) -> Vec<&'a str> {
+    rows.iter().map(uuid).collect()
+}
+
+/// Makes the stored inventory of the snapshot's device match it exactly, in one transaction: every
+/// entity is inserted or updated, and children the snapshot no longer lists are deleted. Other
+/// devices are left alone. Every change is recorded in the inventory history under one timestamp.
+pub fn reconcile_inventory(conn: &mut SqliteConnection, data: &Value) -> Result<ChangeSet, Error> {
+    let snapshot = Snapshot::parse(data)?;
+    conn.transaction::<_, Error, _>(|conn| {
+        let mut changes = ChangeSet::default();
+        let at = history::now();
+        let device_uuid = snapshot.device.uuid.as_str();
+
+        if let Some(agent_row) = &snapshot.agent
+            && let Some(agent_uuid) = agent_row.uuid.as_deref()
+        {
+            match agent::table.filter(agent::uuid.eq(agent_uuid)).first::<Agent>(conn).optional()? {
+                None => {
+                    diesel::insert_into(agent::table).values(agent_row).execute(conn)?;
+                    record(conn, "agent", agent_uuid, Some(device_uuid), Some(agent_row), &at)?;
+                    changes.added.push(EntityChange::new("agent", agent_uuid));
+                }
+                Some(stored) => {
+                    let fields = changed_fields(&stored, agent_row);
+                    if !fields.is_empty() {
+                        diesel::update(agent::table.filter(agent::uuid.eq(agent_uuid))).set(agent_row).execute(conn)?;
+                        record(conn, "agent", agent_uuid, Some(device_uuid), Some(agent_row), &at)?;
+                        changes.changed.push(EntityChange { fields, ..EntityChange::new("agent", agent_uuid) });
+                    }
+                }
+            }
+        }
+
+        // Parents first, so children always have a row to point at
+        upsert!(conn, changes, &at, device_uuid, "device", device, std::slice::from_ref(&snapshot.device));
+        upsert!(conn, changes, &at, device_uuid, "cpu", cpu, &snapshot.cpu);
+        upsert!(conn, changes, &at, device_uuid, "memory", memory, &snapshot.memory);
+        upsert!(conn, changes, &at, device_uuid, "storage", storage, &snapshot.storage);
+        upsert!(conn, changes, &at, device_uuid, "partition", partition, &snapshot.partition);
+        upsert!(conn, changes, &at, device_uuid, "nic", nic, &snapshot.nic);
+        upsert!(conn, changes, &at, device_uuid, "port", port, &snapshot.port);
+        upsert!(conn, changes, &at, device_uuid, "ip", ip_address, &snapshot.ip);
+        upsert!(conn, changes, &at, device_uuid, "gpu", gpu, &snapshot.gpu);
+
+        // Children before parents, so nothing goes through ON DELETE CASCADE without being reported
+        let storage_uuids: Vec<String> =
+            storage::table.filter(storage::device_uuid.eq(device_uuid)).select(storage::uuid).load(conn)?;
+        let nic_uuids: Vec<String> = nic::table.filter(nic::device_uuid.eq(device_uuid)).select(nic::uuid).load(conn)?;
+        let port_uuids: Vec<String> = port::table.filter(port::nic_uuid.eq_any(&nic_uuids)).select(port::uuid).load(conn)?;
+
+        remove_missing!(conn, changes, "ip", ip_address, port_uuid, &port_uuids, uuids(&snapshot.ip, |r| &r.uuid));
+        remove_missing!(conn, changes, "port", port, nic_uuid, &nic_uuids, uuids(&snapshot.port, |r| &r.uuid));
+        remove_missing!(conn, changes, "partition", partition, storage_uuid, &storage_uuids, uuids(&snapshot.partition, |r| &r.uuid));
+        remove_missing!(conn, changes, "storage", storage, device_uuid, [device_uuid], uuids(&snapshot.storage, |r| &r.uuid));
+        remove_missing!(conn, changes, "nic", nic, device_uuid, [device_uuid], uuids(&snapshot.nic, |r| &r.uuid));
+        remove_missing!(conn, changes, "cpu", cpu, device_uuid, [device_uuid], uuids(&snapshot.cpu, |r| &r.uuid));
+        remove_missing!(conn, changes, "memory", memory, device_uuid, [device_uuid], uuids(&snapshot.memory, |r| &r.uuid));
+        remove_missing!(conn, changes, "gpu", gpu, device_uuid, [device_uuid], uuids(&snapshot.gpu, |r| &r.uuid));
+
+        history::close_removed(conn, &at)?;
+        Ok(changes)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations;
+    use serde_json::json;
+
+    fn connection() -> SqliteConnection {
+        let mut conn = SqliteConnection::establish(":memory:").unwrap();
+        migrations::run_pending(&mut conn).unwrap();
+        conn
+    }
+
+    fn snapshot(device_uuid: &str, disks: &[(&str, &[&str])], cpu_speed: &str) -> Value {
+        let storage: Vec<Value> = disks
+            .iter()
+            .map(|(uuid, partitions)| {
+                let partition: Vec<Value> = partitions
+                    .iter()
+                    .map(|uuid| {
+                        json!({
+                            "uuid": uuid, "name": "C:", "serial_number": uuid, "fs_type": "NTFS",
+                            "free_space": "10 GB", "used_space": "20 GB", "total_size": "30 GB", "os_uuid": null
+                        })
+                    })
+                    .collect();
+                json!({
+                    "uuid": uuid, "hw_disk_type": "SSD", "make": "Acme", "model": "X1", "serial_number": uuid,
+                    "base_fs_type": "NTFS", "free_space": "10 GB", "total_disk_usage": "20 GB",
+                    "total_disk_size": "30 GB", "os_uuid": null, "partition": partition
+                })
+            })
+            .collect();
+        json!({
+            "device": {
+                "uuid": device_uuid, "make": "Acme", "model": "Box", "serial_number": device_uuid, "dev_phy_vm": "physical",
+                "cpu": [{
+                    "uuid": format!("{}-cpu", device_uuid), "make": "Intel", "model": "i7", "p_cores": 4, "l_cores": 8,
+                    "speed": cpu_speed, "os_uuid": null
+                }],
+                "storage": storage
+            }
+        })
+    }
+
+    fn names(changes: &[EntityChange]) -> Vec<(&str, &str)> {
+        let mut names: Vec<(&str, &str)> = changes.iter().map(|c| (c.entity.as_str(), c.uuid.as_str())).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn first_snapshot_adds_everything() {
+        let mut conn = connection();
+        let changes = reconcile_inventory(&mut conn, &snapshot("d1", &[("s1", &["p1", "p2"])], "2.4 GHz")).unwrap();
+        assert_eq!(
+            names(&changes.added),
+            [("cpu", "d1-cpu"), ("device", "d1"), ("partition", "p1"), ("partition", "p2"), ("storage", "s1")]
+        );
+        assert!(changes.changed.is_empty() && changes.removed.is_empty());
+        let stored: Storage = storage::table.find("s1").first(&mut conn).unwrap();
+        assert_eq!(stored.device_uuid, "d1");
+        assert_eq!(stored.total_disk_size.0, 30 << 30);
+    }
+
+    #[test]
+    fn same_snapshot_twice_changes_nothing() {
+        let mut conn = connection();
+        let data = snapshot("d1", &[("s1", &["p1"])], "2.4 GHz");
+        reconcile_inventory(&mut conn, &data).unwrap();
+        assert!(reconcile_inventory(&mut conn, &data).unwrap().is_empty());
+    }
+
+    #[test]
+    fn changed_fields_are_reported() {
+        let mut conn = connection();
+        reconcile_inventory(&mut conn, &snapshot("d1", &[], "2.4 GHz")).unwrap();
+        let changes = reconcile_inventory(&mut conn, &snapshot("d1", &[], "3 GHz")).unwrap();
+        assert_eq!(names(&changes.changed), [("cpu", "d1-cpu")]);
+        assert_eq!(changes.changed[0].fields, ["speed"]);
+        let cpu_row: Cpu = cpu::table.find("d1-cpu").first(&mut conn).unwrap();
+        assert_eq!(cpu_row.speed.0, 3_000_000_000);
+    }
+
+    #[test]
+    fn missing_children_are_removed_with_their_children() {
+        let mut conn = connection();
+        reconcile_inventory(&mut conn, &snapshot("d1", &[("s1", &["p1"]), ("s2", &["p2", "p3"])], "2.4 GHz")).unwrap();
+        let changes = reconcile_inventory(&mut conn, &snapshot("d1", &[("s1", &[])], "2.4 GHz")).unwrap();
+        assert_eq!(
+            names(&changes.removed),
+            [("partition", "p1"), ("partition", "p2"), ("partition", "p3"), ("storage", "s2")]
+        );
+        let left: Vec<String> = partition::table.select(partition::uuid).load(&mut conn).unwrap();
+        assert!(left.is_empty());
+        let current = history::inventory_at(&mut conn, &history::now(), Some("d1")).unwrap();
+        assert!(!current.contains_key("partition"));
+        assert_eq!(current["storage"].len(), 1);
+    }
+
+    #[test]
+    fn other_devices_are_left_alone() {
+        let mut conn = connection();
+        reconcile_inventory(&mut conn, &snapshot("d1", &[("s1", &[])], "2.4 GHz")).unwrap();
+        let changes = reconcile_inventory(&mut conn, &snapshot("d2", &[], "2.4 GHz")).unwrap();
+        assert!(changes.removed.is_empty());
+        assert!(storage::table.find("s1").first::<Storage>(&mut conn).is_ok());
+    }
+
+    #[test]
+    fn invalid_snapshot_stores_nothing() {
+        let mut conn = connection();
+        assert!(reconcile_inventory(&mut conn, &json!({ "agent": null })).is_err());
+        let mut bad_cpu = snapshot("d1", &[("s1", &[])], "2.4 GHz");
+        bad_cpu["device"]["cpu"][0]["speed"] = json!("fast");
+        assert!(reconcile_inventory(&mut conn, &bad_cpu).is_err());
+        assert_eq!(device::table.count().get_result::<i64>(&mut conn).unwrap(), 0);
+    }
+}
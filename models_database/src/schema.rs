@@ -1,5 +1,18 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    admin_audit (id) {
+        id -> Nullable<Integer>,
+        at -> Text,
+        service -> Text,
+        method -> Text,
+        path -> Text,
+        subject -> Nullable<Text>,
+        role -> Nullable<Text>,
+        outcome -> Text,
+    }
+}
+
 diesel::table! {
     agent (uuid) {
         uuid -> Nullable<Text>,
@@ -16,6 +29,7 @@ diesel::table! {
         client_id -> Text,
         client_secret -> Text,
         master_key -> Text,
+        agent_id -> Nullable<Text>,
     }
 }
 
@@ -27,7 +41,7 @@ diesel::table! {
         model -> Text,
         p_cores -> Integer,
         l_cores -> Integer,
-        speed -> Text,
+        speed -> BigInt,
         os_uuid -> Nullable<Text>,
     }
 }
@@ -55,6 +69,18 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    inventory_history (id) {
+        id -> Nullable<Integer>,
+        entity -> Text,
+        uuid -> Text,
+        device_uuid -> Nullable<Text>,
+        data -> Text,
+        valid_from -> Text,
+        valid_to -> Nullable<Text>,
+    }
+}
+
 diesel::table! {
     ip_address (uuid) {
         uuid -> Text,
@@ -74,7 +100,7 @@ diesel::table! {
         make -> Text,
         model -> Text,
         speed -> Text,
-        size -> Text,
+        size -> BigInt,
         serial_number -> Text,
         os_uuid -> Nullable<Text>,
     }
@@ -87,7 +113,7 @@ diesel::table! {
         make -> Text,
         model -> Text,
         number_of_ports -> Integer,
-        max_speed -> Text,
+        max_speed -> BigInt,
         supported_speeds -> Text,
         serial_number -> Text,
         mac_address -> Text,
@@ -102,9 +128,9 @@ diesel::table! {
         name -> Text,
         serial_number -> Text,
         fs_type -> Text,
-        free_space -> Text,
-        used_space -> Text,
-        total_size -> Text,
+        free_space -> BigInt,
+        used_space -> BigInt,
+        total_size -> BigInt,
         os_uuid -> Nullable<Text>,
     }
 }
@@ -114,7 +140,7 @@ diesel::table! {
         uuid -> Text,
         nic_uuid -> Text,
         interface_name -> Text,
-        operating_speed -> Text,
+        operating_speed -> BigInt,
         is_physical_logical -> Text,
         logical_type -> Text,
         os_uuid -> Nullable<Text>,
@@ -130,9 +156,9 @@ diesel::table! {
         model -> Text,
         serial_number -> Text,
         base_fs_type -> Text,
-        free_space -> Text,
-        total_disk_usage -> Text,
-        total_disk_size -> Text,
+        free_space -> BigInt,
+        total_disk_usage -> BigInt,
+        total_disk_size -> BigInt,
         os_uuid -> Nullable<Text>,
     }
 }
@@ -140,12 +166,37 @@ diesel::table! {
 diesel::table! {
     tokens (id) {
         id -> Nullable<Integer>,
+        agent_id -> Text,
         token -> Text,
         expiration -> Text,
         token_type -> Text,
     }
 }
 
+diesel::table! {
+    upstream_dead_letter (id) {
+        id -> Nullable<Integer>,
+        kind -> Text,
+        payload -> Text,
+        attempts -> Integer,
+        last_error -> Nullable<Text>,
+        created_at -> Text,
+        failed_at -> Text,
+    }
+}
+
+diesel::table! {
+    upstream_outbox (id) {
+        id -> Nullable<Integer>,
+        kind -> Text,
+        payload -> Text,
+        attempts -> Integer,
+        last_error -> Nullable<Text>,
+        created_at -> Text,
+        next_attempt_at -> Text,
+    }
+}
+
 diesel::joinable!(cpu -> device (device_uuid));
 diesel::joinable!(gpu -> device (device_uuid));
 diesel::joinable!(ip_address -> port (port_uuid));
@@ -156,11 +207,13 @@ diesel::joinable!(port -> nic (nic_uuid));
 diesel::joinable!(storage -> device (device_uuid));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    admin_audit,
     agent,
     agent_credential,
     cpu,
     device,
     gpu,
+    inventory_history,
     ip_address,
     memory,
     nic,
@@ -168,4 +221,6 @@ diesel::allow_tables_to_appear_in_same_query!(
     port,
     storage,
     tokens,
+    upstream_dead_letter,
+    upstream_outbox,
 );
@@ -0,0 +1,364 @@
+//! Unit-aware inventory quantities. Each is stored as an integer in its base unit (bytes, hertz, bits
+//! per second) and serialized as that raw number; `Display` gives the human-readable form ("8 GB",
+//! "2.4 GHz", "1 Gbps"), and parsing accepts both, along with the strings agents have always sent.
+
+use diesel::deserialize::{self, FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::BigInt;
+use diesel::sqlite::{Sqlite, SqliteValue};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+const KIB: u64 = 1 << 10;
+const MIB: u64 = 1 << 20;
+const GIB: u64 = 1 << 30;
+const TIB: u64 = 1 << 40;
+const PIB: u64 = 1 << 50;
+
+/// Values agents send when they could not read a quantity; they are stored as 0
+const UNKNOWN_VALUES: [&str; 4] = ["unknown", "n/a", "na", "-"];
+
+/// Suffixes a unit accepts (lowercase) and what one of each is worth in the base unit
+type Suffixes = &'static [(&'static str, u64)];
+
+/// Reads a quantity such as "69.31 GB", "2400 MHz", "1 Gbps" or a bare number in the base unit
+fn parse_quantity(text: &str, suffixes: Suffixes) -> Result<u64, String> {
+    let cleaned = text.trim().replace(',', "");
+    if cleaned.is_empty() || UNKNOWN_VALUES.contains(&cleaned.to_ascii_lowercase().as_str()) {
+        return Ok(0);
+    }
+    if let Ok(value) = cleaned.parse::<u64>() {
+        return Ok(value);
+    }
+
+    let split = cleaned.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(cleaned.len());
+    let (number, suffix) = cleaned.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("'{}' does not start with a number", text))?;
+    let suffix = suffix.trim().to_ascii_lowercase();
+    let scale = suffixes
+        .iter()
+        .find(|(name, _)| *name == suffix)
+        .map(|(_, scale)| *scale)
+        .ok_or_else(|| format!("'{}' has an unknown unit '{}'", text, suffix))?;
+    Ok((number * scale as f64).round() as u64)
+}
+
+/// Writes `value` in the largest unit it reaches, with up to three decimals so a CPU speed in GHz
+/// keeps its MHz
+fn format_quantity(f: &mut fmt::Formatter<'_>, value: u64, units: Suffixes) -> fmt::Result {
+    let (name, scale) = units.iter().rev().find(|(_, scale)| value >= *scale).unwrap_or(&units[0]);
+    let scaled = format!("{:.3}", value as f64 / *scale as f64);
+    let scaled = scaled.trim_end_matches('0').trim_end_matches('.');
+    write!(f, "{} {}", scaled, name)
+}
+
+macro_rules! unit {
+    ($(#[$meta:meta])* $name:ident, $what:literal, parse: $suffixes:expr, display: $units:expr) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, AsExpression, FromSqlRow)]
+        #[diesel(sql_type = BigInt)]
+        pub struct $name(pub u64);
+
+        impl $name {
+            const SUFFIXES: Suffixes = $suffixes;
+            const UNITS: Suffixes = $units;
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                format_quantity(f, self.0, Self::UNITS)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = String;
+
+            fn from_str(text: &str) -> Result<Self, String> {
+                parse_quantity(text, Self::SUFFIXES).map(Self)
+            }
+        }
+
+        impl From<u64> for $name {
+            fn from(value: u64) -> Self {
+                Self(value)
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_u64(self.0)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                struct QuantityVisitor;
+
+                impl Visitor<'_> for QuantityVisitor {
+                    type Value = $name;
+
+                    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                        write!(f, "{} as a number or a string with a unit", $what)
+                    }
+
+                    fn visit_u64<E: de::Error>(self, value: u64) -> Result<$name, E> {
+                        Ok($name(value))
+                    }
+
+                    fn visit_i64<E: de::Error>(self, value: i64) -> Result<$name, E> {
+                        u64::try_from(value).map($name).map_err(|_| E::custom(format!("{} cannot be negative", $what)))
+                    }
+
+                    fn visit_f64<E: de::Error>(self, value: f64) -> Result<$name, E> {
+                        if value.is_finite() && value >= 0.0 {
+                            Ok($name(value.round() as u64))
+                        } else {
+                            Err(E::custom(format!("{} cannot be {}", $what, value)))
+                        }
+                    }
+
+                    fn visit_str<E: de::Error>(self, value: &str) -> Result<$name, E> {
+                        value.parse().map_err(E::custom)
+                    }
+                }
+
+                deserializer.deserialize_any(QuantityVisitor)
+            }
+        }
+
+        impl ToSql<BigInt, Sqlite> for $name {
+            fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> serialize::Result {
+                out.set_value(i64::try_from(self.0)?);
+                Ok(IsNull::No)
+            }
+        }
+
+        impl FromSql<BigInt, Sqlite> for $name {
+            fn from_sql(value: SqliteValue<'_, '_, '_>) -> deserialize::Result<Self> {
+                let raw = <i64 as FromSql<BigInt, Sqlite>>::from_sql(value)?;
+                Ok(Self(u64::try_from(raw)?))
+            }
+        }
+    };
+}
+
+unit!(
+    /// A size in bytes. KB, MB, GB and TB are read as powers of 1024, which is how agents have
+    /// always reported memory and disk sizes.
+    Bytes,
+    "a size",
+    parse: &[
+        ("", 1), ("b", 1), ("byte", 1), ("bytes", 1),
+        ("k", KIB), ("kb", KIB), ("kib", KIB),
+        ("m", MIB), ("mb", MIB), ("mib", MIB),
+        ("g", GIB), ("gb", GIB), ("gib", GIB),
+        ("t", TIB), ("tb", TIB), ("tib", TIB),
+        ("p", PIB), ("pb", PIB), ("pib", PIB),
+    ],
+    display: &[("B", 1), ("KB", KIB), ("MB", MIB), ("GB", GIB), ("TB", TIB), ("PB", PIB)]
+);
+
+unit!(
+    /// A clock speed in hertz. A bare number is hertz too, except for inventory CPU speeds, which
+    /// agents have always sent as bare MHz; see `cpu_speed`.
+    Hertz,
+    "a frequency",
+    parse: &[("", 1), ("hz", 1), ("khz", 1_000), ("mhz", 1_000_000), ("ghz", 1_000_000_000)],
+    display: &[("Hz", 1), ("kHz", 1_000), ("MHz", 1_000_000), ("GHz", 1_000_000_000)]
+);
+
+unit!(
+    /// A link speed in bits per second
+    BitsPerSecond,
+    "a link speed",
+    parse: &[
+        ("", 1), ("bps", 1), ("b/s", 1), ("bit/s", 1),
+        ("kbps", 1_000), ("kb/s", 1_000), ("kbit/s", 1_000),
+        ("mbps", 1_000_000), ("mb/s", 1_000_000), ("mbit/s", 1_000_000),
+        ("gbps", 1_000_000_000), ("gb/s", 1_000_000_000), ("gbit/s", 1_000_000_000),
+        ("tbps", 1_000_000_000_000), ("tb/s", 1_000_000_000_000), ("tbit/s", 1_000_000_000_000),
+    ],
+    display: &[("bps", 1), ("Kbps", 1_000), ("Mbps", 1_000_000), ("Gbps", 1_000_000_000), ("Tbps", 1_000_000_000_000)]
+);
+
+impl Hertz {
+    pub fn from_mhz(mhz: u64) -> Self {
+        Self(mhz * 1_000_000)
+    }
+}
+
+impl BitsPerSecond {
+    pub fn from_mbps(mbps: u64) -> Self {
+        Self(mbps * 1_000_000)
+    }
+}
+
+/// `#[serde(with = "models_database::units::readable")]` writes a quantity as its human-readable
+/// string instead of the raw number; reading accepts either
+pub mod readable {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::fmt::Display;
+
+    pub fn serialize<T: Display, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+        T::deserialize(deserializer)
+    }
+}
+
+/// `#[serde(deserialize_with = "crate::units::cpu_speed::deserialize")]` reads an inventory CPU speed.
+/// Agents report CPU speeds in MHz, so a bare number, or a string without a unit, is MHz rather than
+/// hertz; a speed with its unit ("2.4 GHz") is read as such.
+pub mod cpu_speed {
+    use super::{Hertz, UNKNOWN_VALUES};
+    use serde::de::{self, Deserializer, Visitor};
+    use std::fmt;
+
+    const MHZ: f64 = 1_000_000.0;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Hertz, D::Error> {
+        deserializer.deserialize_any(CpuSpeedVisitor)
+    }
+
+    fn from_mhz<E: de::Error>(mhz: f64) -> Result<Hertz, E> {
+        if mhz.is_finite() && mhz >= 0.0 {
+            Ok(Hertz((mhz * MHZ).round() as u64))
+        } else {
+            Err(E::custom(format!("a CPU speed cannot be {} MHz", mhz)))
+        }
+    }
+
+    struct CpuSpeedVisitor;
+
+    impl Visitor<'_> for CpuSpeedVisitor {
+        type Value = Hertz;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "a CPU speed in MHz or a string with a unit")
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<Hertz, E> {
+            from_mhz(value as f64)
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<Hertz, E> {
+            from_mhz(value as f64)
+        }
+
+        fn visit_f64<E: de::Error>(self, value: f64) -> Result<Hertz, E> {
+            from_mhz(value)
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Hertz, E> {
+            let cleaned = value.trim().replace(',', "");
+            if cleaned.is_empty() || UNKNOWN_VALUES.contains(&cleaned.to_ascii_lowercase().as_str()) {
+                return Ok(Hertz(0));
+            }
+            match cleaned.parse::<f64>() {
+                Ok(mhz) => from_mhz(mhz),
+                Err(_) => value.parse().map_err(E::custom),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_are_read_in_powers_of_1024() {
+        assert_eq!("69.31 GB".parse::<Bytes>(), Ok(Bytes((69.31 * GIB as f64).round() as u64)));
+        assert_eq!("512MiB".parse::<Bytes>(), Ok(Bytes(512 * MIB)));
+        assert_eq!(" 1,024 kb ".parse::<Bytes>(), Ok(Bytes(1024 * KIB)));
+        assert_eq!("2 T".parse::<Bytes>(), Ok(Bytes(2 * TIB)));
+        assert_eq!("4096".parse::<Bytes>(), Ok(Bytes(4096)));
+    }
+
+    #[test]
+    fn speeds_are_read_in_powers_of_1000() {
+        assert_eq!("2400 MHz".parse::<Hertz>(), Ok(Hertz::from_mhz(2400)));
+        assert_eq!("2.4GHz".parse::<Hertz>(), Ok(Hertz(2_400_000_000)));
+        assert_eq!("100".parse::<Hertz>(), Ok(Hertz(100)));
+        assert_eq!("1 Gbps".parse::<BitsPerSecond>(), Ok(BitsPerSecond(1_000_000_000)));
+        assert_eq!("100 Mb/s".parse::<BitsPerSecond>(), Ok(BitsPerSecond::from_mbps(100)));
+    }
+
+    #[test]
+    fn unknown_values_are_zero() {
+        for value in ["", "  ", "Unknown", "N/A", "na", "-"] {
+            assert_eq!(value.parse::<Bytes>(), Ok(Bytes(0)), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        assert!("fast".parse::<Hertz>().unwrap_err().contains("does not start with a number"));
+        assert!("3 parsecs".parse::<Bytes>().unwrap_err().contains("unknown unit 'parsecs'"));
+        // Units of one kind are not accepted for another
+        assert!("1 GB".parse::<Hertz>().is_err());
+        assert!("2400 MHz".parse::<BitsPerSecond>().is_err());
+        assert!("-5 GB".parse::<Bytes>().is_err());
+    }
+
+    #[test]
+    fn display_uses_the_largest_unit_reached() {
+        assert_eq!(Bytes(8 * GIB).to_string(), "8 GB");
+        assert_eq!(Bytes(1536 * MIB).to_string(), "1.5 GB");
+        assert_eq!(Bytes(0).to_string(), "0 B");
+        assert_eq!(Hertz(2_400_000_000).to_string(), "2.4 GHz");
+        assert_eq!(BitsPerSecond::from_mbps(100).to_string(), "100 Mbps");
+        assert_eq!(Bytes(8 * GIB).to_string().parse::<Bytes>(), Ok(Bytes(8 * GIB)));
+    }
+
+    #[test]
+    fn readable_fields_are_written_with_their_unit_and_read_either_way() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Disk {
+            #[serde(with = "readable")]
+            size: Bytes,
+        }
+
+        let disk = Disk { size: Bytes(8 * GIB) };
+        assert_eq!(serde_json::to_string(&disk).unwrap(), r#"{"size":"8 GB"}"#);
+        assert_eq!(serde_json::from_str::<Disk>(r#"{"size":"8 GB"}"#).unwrap(), disk);
+        assert_eq!(serde_json::from_str::<Disk>(&format!(r#"{{"size":{}}}"#, 8 * GIB)).unwrap(), disk);
+    }
+
+    #[test]
+    fn cpu_speeds_without_a_unit_are_mhz() {
+        #[derive(Debug, Deserialize)]
+        struct Cpu {
+            #[serde(deserialize_with = "cpu_speed::deserialize")]
+            speed: Hertz,
+        }
+
+        let speed = |json: &str| serde_json::from_str::<Cpu>(&format!(r#"{{"speed":{}}}"#, json)).map(|cpu| cpu.speed);
+        assert_eq!(speed("2400").unwrap(), Hertz::from_mhz(2400));
+        assert_eq!(speed("2394.5").unwrap(), Hertz(2_394_500_000));
+        assert_eq!(speed(r#""2400""#).unwrap(), Hertz::from_mhz(2400));
+        assert_eq!(speed(r#""2.4 GHz""#).unwrap(), Hertz::from_mhz(2400));
+        assert_eq!(speed(r#""Unknown""#).unwrap(), Hertz(0));
+        assert!(speed("-1").is_err());
+        assert!(speed(r#""fast""#).is_err());
+
+        // What a collector writes with `readable` comes back unchanged
+        let written = Hertz::from_mhz(2394).to_string();
+        assert_eq!(written, "2.394 GHz");
+        assert_eq!(speed(&format!("{:?}", written)).unwrap(), Hertz::from_mhz(2394));
+    }
+
+    #[test]
+    fn json_takes_numbers_or_strings_and_writes_numbers() {
+        assert_eq!(serde_json::from_str::<Bytes>("1024").unwrap(), Bytes(1024));
+        assert_eq!(serde_json::from_str::<Bytes>("\"1 KB\"").unwrap(), Bytes(1024));
+        assert_eq!(serde_json::from_str::<Hertz>("2.5").unwrap(), Hertz(3));
+        assert!(serde_json::from_str::<Bytes>("-1").is_err());
+        assert_eq!(serde_json::to_string(&Hertz::from_mhz(1)).unwrap(), "1000000");
+    }
+}
@@ -0,0 +1,109 @@
+//! NATS user credentials for collectors. Each collector holds its own user nkey; the bridge signs a user
+//! JWT for it with the account key, carrying the permissions of that collector's agent namespace, so
+//! the server enforces that a collector only publishes as itself.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use messages::Permissions;
+use nkeys::{KeyPair, KeyPairType};
+use serde_json::json;
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Seed of a new user nkey, to be kept by the collector
+pub fn new_user_seed() -> Result<String, BoxError> {
+    Ok(KeyPair::new_user().seed()?)
+}
+
+/// Public key of a user seed
+pub fn user_public_key(seed: &str) -> Result<String, BoxError> {
+    let key = KeyPair::from_seed(seed.trim())?;
+    if key.key_pair_type() != KeyPairType::User {
+        return Err("seed is not a user nkey".into());
+    }
+    Ok(key.public_key())
+}
+
+/// Signs a user JWT for `user_public_key` with the account seed (or one of its signing keys, in which
+/// case `issuer_account` is the account's public key). The JWT does not expire.
+pub fn issue_user_jwt(
+    account_seed: &str,
+    issuer_account: Option<&str>,
+    name: &str,
+    user_public_key: &str,
+    permissions: &Permissions,
+) -> Result<String, BoxError> {
+    let signer = KeyPair::from_seed(account_seed.trim())?;
+    if signer.key_pair_type() != KeyPairType::Account {
+        return Err("NATS signing seed is not an account key".into());
+    }
+    if KeyPair::from_public_key(user_public_key)?.key_pair_type() != KeyPairType::User {
+        return Err(format!("{} is not a user public key", user_public_key).into());
+    }
+
+    let mut nats = json!({
+        "pub": { "allow": permissions.publish },
+        "sub": { "allow": permissions.subscribe },
+        "subs": -1,
+        "data": -1,
+        "payload": -1,
+        "type": "user",
+        "version": 2,
+    });
+    if let Some(account) = issuer_account {
+        nats["issuer_account"] = json!(account);
+    }
+    let claims = json!({
+        "jti": nuid::next().to_string(),
+        "iat": SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        "iss": signer.public_key(),
+        "name": name,
+        "sub": user_public_key,
+        "nats": nats,
+    });
+
+    let header = URL_SAFE_NO_PAD.encode(json!({ "typ": "JWT", "alg": "ed25519-nkey" }).to_string());
+    let body = URL_SAFE_NO_PAD.encode(claims.to_string());
+    let signing_input = format!("{}.{}", header, body);
+    let signature = URL_SAFE_NO_PAD.encode(signer.sign(signing_input.as_bytes())?);
+    Ok(format!("{}.{}", signing_input, signature))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn issued_jwt_is_signed_by_the_account_for_the_user() {
+        let account = KeyPair::new_account();
+        let user_seed = new_user_seed().unwrap();
+        let user = user_public_key(&user_seed).unwrap();
+        let permissions = Permissions::collector("a1");
+
+        let jwt = issue_user_jwt(&account.seed().unwrap(), None, "agent-a1", &user, &permissions).unwrap();
+        let parts: Vec<&str> = jwt.split('.').collect();
+        assert_eq!(parts.len(), 3);
+        let signature = URL_SAFE_NO_PAD.decode(parts[2]).unwrap();
+        account.verify(format!("{}.{}", parts[0], parts[1]).as_bytes(), &signature).unwrap();
+
+        let claims: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[1]).unwrap()).unwrap();
+        assert_eq!(claims["iss"], account.public_key());
+        assert_eq!(claims["sub"], user);
+        assert_eq!(claims["nats"]["pub"]["allow"], json!(permissions.publish));
+        assert_eq!(claims["nats"]["sub"]["allow"], json!(permissions.subscribe));
+        assert!(claims["nats"].get("issuer_account").is_none());
+    }
+
+    #[test]
+    fn only_user_keys_are_issued_and_only_by_accounts() {
+        let account = KeyPair::new_account();
+        let user = KeyPair::new_user().public_key();
+        let permissions = Permissions::collector("a1");
+        assert!(issue_user_jwt(&account.seed().unwrap(), None, "x", &account.public_key(), &permissions).is_err());
+        assert!(issue_user_jwt(&KeyPair::new_operator().seed().unwrap(), None, "x", &user, &permissions).is_err());
+        assert!(user_public_key(&account.seed().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use async_nats::jetstream::consumer::{pull, AckPolicy, PullConsumer};
+use async_nats::jetstream::stream::{self, DiscardPolicy, RetentionPolicy, StorageType};
+use async_nats::Client;
+use messages::subjects;
+use std::error::Error;
+use std::time::Duration;
+
+pub const MONITOR_STREAM: &str = "MONITOR";
+pub const INVENTORY_STREAM: &str = "INVENTORY";
+
+/// Republishes with the same message id inside this window are dropped as duplicates
+const DUPLICATE_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// How long a delivered message may stay unacknowledged before it is redelivered
+const ACK_WAIT: Duration = Duration::from_secs(120);
+
+/// Streams the collectors publish into and the bridge consumes from
+fn stream_configs() -> Vec<stream::Config> {
+    let work_queue = |name: &str, leaf: &str, max_age_days: u64, max_bytes: i64| stream::Config {
+        name: name.to_string(),
+        subjects: vec![subjects::all_agents(leaf)],
+        retention: RetentionPolicy::WorkQueue,
+        storage: StorageType::File,
+        discard: DiscardPolicy::Old,
+        max_age: Duration::from_secs(max_age_days * 24 * 60 * 60),
+        max_bytes,
+        duplicate_window: DUPLICATE_WINDOW,
+        ..Default::default()
+    };
+    vec![
+        work_queue(MONITOR_STREAM, subjects::MONITOR_DATA, 7, 1024 * 1024 * 1024),
+        work_queue(INVENTORY_STREAM, subjects::AGENT_DATA, 30, 256 * 1024 * 1024),
+    ]
+}
+
+/// Creates the monitoring and inventory streams, or brings existing ones up to date
+pub async fn ensure_streams(client: &Client) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let jetstream = async_nats::jetstream::new(client.clone());
+    for config in stream_configs() {
+        match jetstream.get_stream(&config.name).await {
+            Ok(_) => {
+                jetstream.update_stream(&config).await?;
+            }
+            Err(_) => {
+                jetstream.create_stream(config).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Durable pull consumer with explicit acks; anything not acked is redelivered after `ACK_WAIT`
+pub async fn durable_consumer(client: &Client, stream: &str, durable_name: &str) -> Result<PullConsumer, Box<dyn Error + Send + Sync>> {
+    let jetstream = async_nats::jetstream::new(client.clone());
+    let stream = jetstream.get_stream(stream).await?;
+    let consumer = stream
+        .get_or_create_consumer(
+            durable_name,
+            pull::Config {
+                durable_name: Some(durable_name.to_string()),
+                ack_policy: AckPolicy::Explicit,
+                ack_wait: ACK_WAIT,
+                // One message in flight keeps them in order until the bridge's outbox takes over; it
+                // acks each one once queued there, and delivers them to the server in that order
+                max_ack_pending: 1,
+                ..Default::default()
+            },
+        )
+        .await?;
+    Ok(consumer)
+}
@@ -6,8 +6,12 @@ use std::io::BufReader;
 use std::sync::Arc;
 use std::error::Error;
 use shared_config::CONFIG;
+use messages::Permissions;
 
+pub mod credentials;
+pub mod jetstream;
 pub mod publisher;
+pub mod request;
 pub mod subscriber;
 
 /// Loads TLS certificates and returns a configured `ClientConfig` for secure communication
@@ -87,4 +91,21 @@ authorization {{
     write(output_path, config_content)?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Writes the `nsc` command that restricts a NATS user to the given subjects
+pub fn generate_nsc_permissions(user_name: &str, permissions: &Permissions, output_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let script = format!(
+        "nsc edit user --name {} --allow-pub \"{}\" --allow-sub \"{}\"\n",
+        user_name,
+        permissions.publish.join(","),
+        permissions.subscribe.join(","),
+    );
+
+    if let Some(parent) = std::path::Path::new(output_path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    write(output_path, script)?;
+
+    Ok(())
+}
@@ -11,7 +11,8 @@ use serde_json::json;
  
 // Import from the nats crate properly
 use nats::publisher::NatsPublisher;
-use nats::generate_nats_server_config;
+use nats::{generate_nats_server_config, generate_nsc_permissions};
+use messages::{subjects, Permissions};
  
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -21,6 +22,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     if !Path::new(&config_path).exists() {   
         generate_nats_server_config(&config_path)?;
     }
+
+    let bridge_permissions_path = format!("{}/BridgeUser.sh", CONFIG.nats_permissions_dir);
+    generate_nsc_permissions("BridgeUser", &Permissions::bridge(), &bridge_permissions_path)?;
+    println!("Bridge NATS permissions written to {}", bridge_permissions_path);
+    // Collectors share this user only to obtain their own credentials from the bridge
+    let collector_permissions_path = format!("{}/CollectorUser.sh", CONFIG.nats_permissions_dir);
+    generate_nsc_permissions("CollectorUser", &Permissions::bootstrap(), &collector_permissions_path)?;
+    println!("Collector bootstrap NATS permissions written to {}", collector_permissions_path);
  
     // Start NATS server asynchronously (note: now mutable)
     let mut child = tokio::process::Command::new("nats-server")
@@ -123,6 +132,7 @@ async fn try_authenticated_connection() -> Result<(), Box<dyn std::error::Error
         &CONFIG.ca_cert_path,
         &CONFIG.client_cert_path,
         &CONFIG.client_key_path,
+        subjects::BOOTSTRAP_INBOX,
     ).await?;
  
     // Add a timeout for the health check
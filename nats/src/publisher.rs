@@ -1,7 +1,11 @@
-use async_nats::ConnectOptions;
+use async_nats::jetstream::context::Publish;
+use async_nats::jetstream::publish::PublishAck;
+use async_nats::{ConnectOptions, HeaderMap, Message, Request};
+use bytes::Bytes;
 use serde::Serialize;
 use std::sync::Arc;
 use crate::load_tls_certificates;
+use crate::request::{correlation_id, RequestOptions, CORRELATION_ID_HEADER};
 use nkeys::KeyPair;
 use std::error::Error;
  
@@ -11,7 +15,7 @@ pub struct NatsPublisher {
 }
  
 impl NatsPublisher {
-    /// Creates a new NATS publisher with secure TLS and JWT authentication
+    /// Creates a new NATS publisher with secure TLS and JWT authentication, receiving replies under `inbox_prefix`
     pub async fn new(
         nats_url: &str,
         c_jwt: &str,
@@ -19,6 +23,7 @@ impl NatsPublisher {
         ca_cert_path: &str,
         client_cert_path: &str,
         client_key_path: &str,
+        inbox_prefix: &str,
     ) -> Result<Self, Box<dyn Error + Send + Sync>> {
         // Load the NATS key pair and TLS certificates
         let kp = Arc::new(KeyPair::from_seed(c_nkey)?);
@@ -35,7 +40,9 @@ impl NatsPublisher {
                     })
                 })
                 .require_tls(true)
-                .tls_client_config((*tls_config).clone()),
+                .tls_client_config((*tls_config).clone())
+                // Replies only arrive on subjects this user is allowed to subscribe to
+                .custom_inbox_prefix(inbox_prefix),
         )
         .await?;
  
@@ -50,4 +57,100 @@ impl NatsPublisher {
         self.client.publish(subject.to_string(), json.into()).await?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Sends a request and waits for its reply, retrying with backoff when an attempt times out.
+    ///
+    /// Every attempt carries the same correlation id, and a reply is only accepted if it echoes it.
+    pub async fn request<T: Serialize>(
+        &self,
+        subject: &str,
+        message: &T,
+        options: &RequestOptions,
+    ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+        let payload: Bytes = serde_json::to_vec(message)?.into();
+        let id = nuid::next().to_string();
+        let mut backoff = options.initial_backoff;
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            let mut headers = HeaderMap::new();
+            headers.insert(CORRELATION_ID_HEADER, id.as_str());
+            let request = Request::new()
+                .headers(headers)
+                .payload(payload.clone())
+                .timeout(Some(options.timeout));
+
+            let failure = match self.client.send_request(subject.to_string(), request).await {
+                Ok(reply) if correlation_id(&reply) == Some(id.as_str()) => return Ok(reply),
+                Ok(_) => "reply does not carry the request's correlation id".to_string(),
+                Err(e) => e.to_string(),
+            };
+            if options.max_attempts.is_some_and(|max| attempt >= max) {
+                return Err(format!("request {} on {} failed after {} attempts: {}", id, subject, attempt, failure).into());
+            }
+
+            tracing::warn!(
+                "Request {} on {} failed (attempt {}): {}; retrying in {:?}",
+                id, subject, attempt, failure, backoff
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(options.max_backoff);
+        }
+    }
+
+    /// Publishes into a JetStream stream and waits until the server has stored the message.
+    ///
+    /// Attempts are retried with backoff under the same message id, so the stream drops any duplicate.
+    pub async fn publish_durable(
+        &self,
+        subject: &str,
+        message_id: Option<&str>,
+        payload: Bytes,
+        options: &RequestOptions,
+    ) -> Result<PublishAck, Box<dyn Error + Send + Sync>> {
+        let jetstream = async_nats::jetstream::new(self.client.clone());
+        let mut backoff = options.initial_backoff;
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            let mut publish = Publish::build().payload(payload.clone());
+            if let Some(id) = message_id {
+                publish = publish.message_id(id);
+            }
+
+            let failure = match jetstream.send_publish(subject.to_string(), publish).await {
+                Ok(ack) => match tokio::time::timeout(options.timeout, ack).await {
+                    Ok(Ok(ack)) => return Ok(ack),
+                    Ok(Err(e)) => e.to_string(),
+                    Err(_) => "timed out waiting for the stream to store the message".to_string(),
+                },
+                Err(e) => e.to_string(),
+            };
+            if options.max_attempts.is_some_and(|max| attempt >= max) {
+                return Err(format!("publish on {} failed after {} attempts: {}", subject, attempt, failure).into());
+            }
+
+            tracing::warn!(
+                "Durable publish on {} failed (attempt {}): {}; retrying in {:?}",
+                subject, attempt, failure, backoff
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(options.max_backoff);
+        }
+    }
+
+    /// Replies to a request, echoing its correlation id
+    pub async fn respond(&self, request: &Message, payload: Bytes) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Some(reply) = request.reply.clone() else {
+            return Err(format!("message on {} expects no reply", request.subject).into());
+        };
+        let mut headers = HeaderMap::new();
+        if let Some(id) = correlation_id(request) {
+            headers.insert(CORRELATION_ID_HEADER, id);
+        }
+        self.client.publish_with_headers(reply, headers, payload).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+use async_nats::Message;
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Header carrying the id that ties a reply to its request across retries
+pub const CORRELATION_ID_HEADER: &str = "Correlation-Id";
+
+/// Timeout and retry schedule for `NatsPublisher::request`
+#[derive(Debug, Clone, Copy)]
+pub struct RequestOptions {
+    /// How long to wait for a reply to each attempt
+    pub timeout: Duration,
+    /// Attempts before giving up; `None` retries until a reply arrives
+    pub max_attempts: Option<u32>,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            max_attempts: Some(5),
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RequestOptions {
+    /// Same schedule, but never gives up
+    pub fn forever(self) -> Self {
+        Self { max_attempts: None, ..self }
+    }
+}
+
+/// Correlation id of a request or reply, if it carries one
+pub fn correlation_id(message: &Message) -> Option<&str> {
+    message
+        .headers
+        .as_ref()?
+        .get(CORRELATION_ID_HEADER)
+        .map(|value| value.as_ref())
+}
+
+/// Recent replies by correlation id, so a retried request is answered without redoing its work
+pub struct ReplyCache {
+    capacity: usize,
+    entries: VecDeque<(String, Bytes)>,
+}
+
+impl ReplyCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, entries: VecDeque::with_capacity(capacity) }
+    }
+
+    /// Reply already sent for this request, if it is a retry
+    pub fn get(&self, request: &Message) -> Option<Bytes> {
+        let id = correlation_id(request)?;
+        self.entries.iter().find(|(key, _)| key == id).map(|(_, reply)| reply.clone())
+    }
+
+    pub fn insert(&mut self, request: &Message, reply: Bytes) {
+        let Some(id) = correlation_id(request) else {
+            return;
+        };
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((id.to_string(), reply));
+    }
+}
@@ -11,7 +11,7 @@ pub struct NatsSubscriber {
 }
 
 impl NatsSubscriber {
-    /// Creates a new NATS subscriber with secure TLS and JWT authentication
+    /// Creates a new NATS subscriber with secure TLS and JWT authentication, receiving replies under `inbox_prefix`
     pub async fn new(
         nats_url: &str,
         b_jwt: &str,
@@ -19,6 +19,7 @@ impl NatsSubscriber {
         ca_cert_path: &str,
         client_cert_path: &str,
         client_key_path: &str,
+        inbox_prefix: &str,
     ) -> Result<Self, Box<dyn Error + Send + Sync>> {
         // Load the NATS key pair and TLS certificates
         let kp = Arc::new(KeyPair::from_seed(b_nkey)?);
@@ -35,7 +36,9 @@ impl NatsSubscriber {
                     })
                 })
                 .require_tls(true)
-                .tls_client_config((*tls_config).clone()),
+                .tls_client_config((*tls_config).clone())
+                // Replies only arrive on subjects this user is allowed to subscribe to
+                .custom_inbox_prefix(inbox_prefix),
         )
         .await?;
 
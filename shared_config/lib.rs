@@ -16,7 +16,30 @@ pub struct Config {
     pub client_key_path: String,
     pub central_server_url: String,
     pub db_path: String,
+    pub db_pool_size: u32,
+    pub db_busy_timeout_ms: u64,
     pub web_socket_url: String,
+    pub central_ca_path: Option<String>,
+    pub central_spki_pins: Vec<String>,
+    pub central_tls_insecure: bool,
+    pub outbox_dir: String,
+    pub outbox_max_bytes: u64,
+    pub outbox_max_age_secs: u64,
+    pub policy_path: String,
+    pub agent_id_path: String,
+    pub nats_permissions_dir: String,
+    pub agent_jwt_path: String,
+    pub agent_nkey_path: String,
+    pub nats_account_seed_path: String,
+    pub nats_issuer_account: Option<String>,
+    pub upstream_max_attempts: u32,
+    pub admin_token: Option<String>,
+    pub admin_read_token: Option<String>,
+    pub log_json: bool,
+    pub log_rotation: String,
+    pub log_max_bytes: u64,
+    pub log_max_files: usize,
+    pub server_commands: Vec<String>,
 }
 
 impl Config {
@@ -43,11 +66,56 @@ impl Config {
             
 
             db_path: env::var("DB_PATH").unwrap_or_else(|_| format!("{}/models_database/models_database.sqlite", app_dir)),
+            //connections kept open per process, and how long a write waits for another process's lock:
+            db_pool_size: env::var("DB_POOL_SIZE").ok().and_then(|v| v.parse().ok()).filter(|&size| size > 0).unwrap_or(8),
+            db_busy_timeout_ms: env::var("DB_BUSY_TIMEOUT_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(5000),
             
 
             central_server_url :env::var("CENTRAL_SERVER_URL").unwrap_or_else(|_| "https://192.168.100.13".to_string()),
             web_socket_url:env::var("WEB_SOCKET_URL").unwrap_or_else(|_| "wss://192.168.100.13".to_string()),
 
+            //central server TLS (HTTPS and WSS):
+            central_ca_path: env::var("CENTRAL_CA_PATH").ok().filter(|v| !v.is_empty()),
+            central_spki_pins: env::var("CENTRAL_SPKI_PINS")
+                .map(|v| v.split(',').map(|pin| pin.trim().to_string()).filter(|pin| !pin.is_empty()).collect())
+                .unwrap_or_default(),
+            central_tls_insecure: env::var("CENTRAL_TLS_INSECURE").is_ok_and(|v| v == "1" || v.eq_ignore_ascii_case("true")),
+
+            //collector monitoring outbox:
+            outbox_dir: env::var("OUTBOX_DIR").unwrap_or_else(|_| format!("{}/agent_collector/outbox", app_dir)),
+            outbox_max_bytes: env::var("OUTBOX_MAX_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(64 * 1024 * 1024),
+            outbox_max_age_secs: env::var("OUTBOX_MAX_AGE_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(7 * 24 * 60 * 60),
+            policy_path: env::var("POLICY_PATH").unwrap_or_else(|_| format!("{}/agent_collector/collection_policy.json", app_dir)),
+            agent_id_path: env::var("AGENT_ID_PATH").unwrap_or_else(|_| format!("{}/agent_collector/agent_id", app_dir)),
+            nats_permissions_dir: env::var("NATS_PERMISSIONS_DIR").unwrap_or_else(|_| format!("{}/nats/permissions", app_dir)),
+
+            //per-agent NATS credentials: the collector's own user nkey and the JWT the bridge signs for it
+            //with the account seed (a signing key needs NATS_ISSUER_ACCOUNT set to the account public key):
+            agent_jwt_path: env::var("AGENT_JWT_PATH").unwrap_or_else(|_| format!("{}/agent_collector/agent.jwt", app_dir)),
+            agent_nkey_path: env::var("AGENT_NKEY_PATH").unwrap_or_else(|_| format!("{}/agent_collector/agent.nk", app_dir)),
+            nats_account_seed_path: env::var("NATS_ACCOUNT_SEED_PATH").unwrap_or_else(|_| format!("{}/nats/nsc_creds/account_signing.nk", app_dir)),
+            nats_issuer_account: env::var("NATS_ISSUER_ACCOUNT").ok().filter(|v| !v.is_empty()),
+
+            //bridge upstream outbox:
+            upstream_max_attempts: env::var("UPSTREAM_MAX_ATTEMPTS").ok().and_then(|v| v.parse().ok()).unwrap_or(10),
+
+            //local admin API (bridge :3030, collector :3033); signed JWTs use jwt_private_key_path/jwt_public_key_path:
+            admin_token: env::var("ADMIN_TOKEN").ok().filter(|v| !v.is_empty()),
+            admin_read_token: env::var("ADMIN_READ_TOKEN").ok().filter(|v| !v.is_empty()),
+
+            //bridge and collector log files: LOG_FORMAT text|json, LOG_ROTATION daily|size|never; LOG_MAX_FILES rotated files are kept:
+            log_json: env::var("LOG_FORMAT").is_ok_and(|v| v.eq_ignore_ascii_case("json")),
+            log_rotation: env::var("LOG_ROTATION").unwrap_or_else(|_| "daily".to_string()),
+            log_max_bytes: env::var("LOG_MAX_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(10 * 1024 * 1024),
+            log_max_files: env::var("LOG_MAX_FILES").ok().and_then(|v| v.parse().ok()).unwrap_or(7),
+
+            //commands the bridge accepts from the central server; every command when unset:
+            server_commands: env::var("SERVER_COMMANDS")
+                .map(|v| v.split(',').map(|command| command.trim().to_string()).filter(|command| !command.is_empty()).collect())
+                .unwrap_or_else(|_| {
+                    ["delete_inventory", "rescan", "update_policy", "restart_collector", "fetch_logs", "ping"].map(String::from).to_vec()
+                }),
+
             app_dir,
         };
 